@@ -0,0 +1,41 @@
+//! Compile policies with string literals and attribute names of random
+//! length under random memory limits. A literal too large for the pool must
+//! be a `CompilerError`, never a panic or a module with wrapped offsets.
+//!
+//! Run with `cargo +nightly fuzz run string_literals` from the repository
+//! root.
+
+#![no_main]
+
+use cedar_policy_compiler::{Compiler, CompilerOptions};
+use libfuzzer_sys::fuzz_target;
+
+/// Upper bound on a generated literal, well past the default memory limit
+const MAX_LITERAL: usize = 4 << 20;
+
+fuzz_target!(|data: &[u8]| {
+    let [pages, repeat_lo, repeat_hi, body @ ..] = data else {
+        return;
+    };
+    let body = String::from_utf8_lossy(body);
+    let repeat = usize::from(u16::from_le_bytes([*repeat_lo, *repeat_hi]));
+    let literal: String = body
+        .repeat(repeat.min(MAX_LITERAL / body.len().max(1)))
+        .chars()
+        .take(MAX_LITERAL)
+        .collect();
+    // Cedar string escapes are a superset of what `escape_default` emits
+    let literal = literal.escape_default().to_string();
+    let attribute = format!("a{}", literal.len());
+
+    let source = format!(
+        r#"permit(principal, action, resource) when {{ resource["{literal}"] == "{literal}" && resource.{attribute} like "{literal}*" }};"#
+    );
+    let compiler = Compiler::with_options(CompilerOptions {
+        max_memory_pages: u32::from(*pages),
+        strict: true,
+        ..CompilerOptions::default()
+    });
+    // Rejections are fine; `strict` turns a malformed module into an error
+    let _ = compiler.compile_str(&source);
+});
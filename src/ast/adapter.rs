@@ -0,0 +1,719 @@
+//! Thin adapter over the `cedar_policy_core` API surface the compiler uses
+//!
+//! Parser entry points and the way a policy exposes its effect and condition
+//! have shifted between core releases (e.g. `condition()` used to return an
+//! `Option<Expr>`). Everything version-sensitive goes through this module, so
+//! bumping the core dependency should only require changes here.
+
+use cedar_policy_core::ast::{
+    ActionConstraint, Annotation, AnyId, Effect, EntityReference, PrincipalOrResourceConstraint,
+};
+use cedar_policy_core::est;
+use cedar_policy_core::extensions::Extensions;
+use cedar_policy_core::parser;
+use cedar_policy_core::validator::types::{EntityRecordKind, Primitive, Type};
+use cedar_policy_core::validator::ValidatorSchema;
+use cedar_policy_core::validator::{CedarSchemaError, SchemaError as CoreSchemaError};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub use cedar_policy_core::ast::{
+    BinaryOp, Expr, ExprKind, Literal, Pattern, PatternElem, Policy, PolicyID, PolicySet, Template,
+    UnaryOp, Var,
+};
+pub use cedar_policy_core::parser::err::ParseErrors;
+pub use cedar_policy_core::parser::Loc;
+
+pub use crate::ast::lowering::PolicyEffect;
+
+/// A policy in Cedar's JSON format that could not be parsed
+#[derive(Debug, thiserror::Error)]
+pub enum JsonPolicyError {
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Policy(#[from] Box<est::FromJsonError>),
+}
+
+/// A Cedar schema that could not be parsed
+#[derive(Debug, thiserror::Error)]
+pub enum SchemaError {
+    #[error(transparent)]
+    Cedar(#[from] Box<CedarSchemaError>),
+    #[error(transparent)]
+    Json(#[from] Box<CoreSchemaError>),
+}
+
+/// Type of a schema attribute, as far as the compiler tells them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AttributeType {
+    Bool,
+    Long,
+    String,
+    Entity,
+    Record,
+    Set,
+    /// `ipaddr` extension value
+    Ip,
+    /// `decimal` extension value
+    Decimal,
+    /// `datetime` extension value
+    DateTime,
+}
+
+impl AttributeType {
+    fn of(ty: &Type) -> Option<Self> {
+        match ty {
+            Type::True | Type::False => Some(Self::Bool),
+            Type::Primitive { primitive_type } => Some(match primitive_type {
+                Primitive::Bool => Self::Bool,
+                Primitive::Long => Self::Long,
+                Primitive::String => Self::String,
+            }),
+            Type::Set { .. } => Some(Self::Set),
+            Type::EntityOrRecord(EntityRecordKind::Record { .. }) => Some(Self::Record),
+            Type::EntityOrRecord(_) => Some(Self::Entity),
+            Type::ExtensionType { name } => match name.to_string().as_str() {
+                "ipaddr" => Some(Self::Ip),
+                "decimal" => Some(Self::Decimal),
+                "datetime" => Some(Self::DateTime),
+                _ => None,
+            },
+            Type::Never => None,
+        }
+    }
+}
+
+/// The parts of a Cedar schema lowering consumes: the entity types it
+/// declares, the attributes of each entity type, action context and record
+/// type, and the principal and resource types each action applies to.
+///
+/// What an attribute is read from is a set of owners, each an entity type
+/// (`App::User`), an action's context (`App::Action::"read".context`) or a
+/// record attribute of another owner (`App::User.address`). An empty set
+/// means the owner isn't known, and stands for every owner.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Schema {
+    entity_types: BTreeSet<String>,
+    /// Declared attributes by owner
+    attributes: BTreeMap<String, BTreeMap<String, Attribute>>,
+    /// Principal and resource types by action UID
+    actions: BTreeMap<String, (BTreeSet<String>, BTreeSet<String>)>,
+}
+
+/// A declared attribute
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Attribute {
+    /// `None` when the type is one the compiler doesn't distinguish
+    ty: Option<AttributeType>,
+    /// Owners of the value's own attributes: its entity type, or the record
+    /// it is
+    owners: BTreeSet<String>,
+}
+
+impl Schema {
+    /// Parse a schema in Cedar's schema syntax
+    pub fn from_cedarschema_str(source: &str) -> Result<Self, SchemaError> {
+        let (schema, _warnings) =
+            ValidatorSchema::from_cedarschema_str(source, Extensions::all_available())
+                .map_err(Box::new)?;
+        Ok(Self::from_validator_schema(&schema))
+    }
+
+    /// Parse a schema in Cedar's JSON schema format
+    pub fn from_json_str(source: &str) -> Result<Self, SchemaError> {
+        let schema = ValidatorSchema::from_json_str(source, Extensions::all_available())
+            .map_err(Box::new)?;
+        Ok(Self::from_validator_schema(&schema))
+    }
+
+    fn from_validator_schema(schema: &ValidatorSchema) -> Self {
+        let mut summary = Self::default();
+        for entity_type in schema.entity_types() {
+            let owner = entity_type.name().to_string();
+            summary.entity_types.insert(owner.clone());
+            for (name, attr) in entity_type.attributes().iter() {
+                summary.add_attribute(&owner, name, &attr.attr_type);
+            }
+        }
+        for action in schema.action_ids() {
+            let uid = action.name().to_string();
+            summary
+                .entity_types
+                .insert(action.name().entity_type().to_string());
+            summary.add_attributes_of(&format!("{}.context", uid), action.context());
+            let types = |types: &mut dyn Iterator<Item = _>| {
+                types.map(ToString::to_string).collect::<BTreeSet<_>>()
+            };
+            summary.actions.insert(
+                uid,
+                (
+                    types(&mut action.applies_to_principals()),
+                    types(&mut action.applies_to_resources()),
+                ),
+            );
+        }
+        summary
+    }
+
+    fn add_attribute(&mut self, owner: &str, name: &str, ty: &Type) {
+        let record = format!("{}.{}", owner, name);
+        let owners = match ty {
+            Type::EntityOrRecord(EntityRecordKind::Entity(lub)) => {
+                lub.get_single_entity().map(ToString::to_string)
+            }
+            Type::EntityOrRecord(EntityRecordKind::Record { .. })
+            | Type::Set {
+                element_type: Some(_),
+            } => Some(record.clone()),
+            _ => None,
+        };
+        self.attributes
+            .entry(owner.to_string())
+            .or_default()
+            .insert(
+                name.to_string(),
+                Attribute {
+                    ty: AttributeType::of(ty),
+                    owners: owners.into_iter().collect(),
+                },
+            );
+        self.add_attributes_of(&record, ty);
+    }
+
+    /// Add the attributes of a record type, and of records nested in it
+    fn add_attributes_of(&mut self, owner: &str, ty: &Type) {
+        match ty {
+            Type::EntityOrRecord(EntityRecordKind::Record { attrs, .. }) => {
+                for (name, attr) in attrs.iter() {
+                    self.add_attribute(owner, name, &attr.attr_type);
+                }
+            }
+            Type::Set {
+                element_type: Some(element),
+            } => self.add_attributes_of(owner, element),
+            _ => {}
+        }
+    }
+
+    /// Whether the schema declares an entity type, e.g. `App::User`
+    pub fn has_entity_type(&self, name: &str) -> bool {
+        self.entity_types.contains(name)
+    }
+
+    /// Declarations of the attribute by any of `owners`
+    fn declarations<'a>(
+        &'a self,
+        owners: &'a BTreeSet<String>,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Attribute> {
+        self.attributes
+            .iter()
+            .filter(|(owner, _)| owners.is_empty() || owners.contains(*owner))
+            .filter_map(move |(_, attributes)| attributes.get(name))
+    }
+
+    /// Whether any of `owners` declares the attribute
+    pub fn has_attribute(&self, owners: &BTreeSet<String>, name: &str) -> bool {
+        self.declarations(owners, name).next().is_some()
+    }
+
+    /// Type of the attribute, if `owners` declare it and all their
+    /// declarations agree
+    pub fn attribute_type(&self, owners: &BTreeSet<String>, name: &str) -> Option<AttributeType> {
+        let mut types = self.declarations(owners, name).map(|attr| attr.ty);
+        let first = types.next()??;
+        types.all(|ty| ty == Some(first)).then_some(first)
+    }
+
+    /// Owners of the attributes of the attribute's value
+    pub fn attribute_owners(&self, owners: &BTreeSet<String>, name: &str) -> BTreeSet<String> {
+        let mut result = BTreeSet::new();
+        for attr in self.declarations(owners, name) {
+            // One value of unknown type makes all of them unknown
+            if attr.owners.is_empty() {
+                return BTreeSet::new();
+            }
+            result.extend(attr.owners.iter().cloned());
+        }
+        result
+    }
+
+    /// Principal types of `action`, or of any action if it isn't known
+    pub fn principal_types(&self, action: Option<&str>) -> BTreeSet<String> {
+        self.applies_to(action, |(principals, _)| principals)
+    }
+
+    /// Resource types of `action`, or of any action if it isn't known
+    pub fn resource_types(&self, action: Option<&str>) -> BTreeSet<String> {
+        self.applies_to(action, |(_, resources)| resources)
+    }
+
+    /// The context of `action`, or of any action if it isn't known
+    pub fn context(&self, action: Option<&str>) -> BTreeSet<String> {
+        self.actions
+            .keys()
+            .filter(|uid| action.is_none_or(|action| action == *uid))
+            .map(|uid| format!("{}.context", uid))
+            .collect()
+    }
+
+    fn applies_to(
+        &self,
+        action: Option<&str>,
+        types: impl Fn(&(BTreeSet<String>, BTreeSet<String>)) -> &BTreeSet<String>,
+    ) -> BTreeSet<String> {
+        self.actions
+            .iter()
+            .filter(|(uid, _)| action.is_none_or(|action| action == *uid))
+            .flat_map(|(_, applies_to)| types(applies_to).iter().cloned())
+            .collect()
+    }
+}
+
+/// Annotation marking a policy whose string literals are secrets
+pub const SENSITIVE_ANNOTATION: &str = "sensitive";
+
+/// Annotation naming a policy in place of the ID the parser assigns
+pub const ID_ANNOTATION: &str = "id";
+
+impl From<Effect> for PolicyEffect {
+    fn from(effect: Effect) -> Self {
+        match effect {
+            Effect::Permit => PolicyEffect::Permit,
+            Effect::Forbid => PolicyEffect::Forbid,
+        }
+    }
+}
+
+/// Render a `like` pattern as a glob: `*` is the wildcard, `\*` a literal
+/// asterisk and `\\` a literal backslash. Unlike the core `Display`, other
+/// characters are not escaped.
+pub fn like_pattern(pattern: &Pattern) -> String {
+    let mut out = String::new();
+    for elem in pattern.iter() {
+        match elem {
+            PatternElem::Wildcard => out.push('*'),
+            PatternElem::Char(c @ ('*' | '\\')) => {
+                out.push('\\');
+                out.push(*c);
+            }
+            PatternElem::Char(c) => out.push(*c),
+        }
+    }
+    out
+}
+
+/// Entity named in a scope constraint, as `Type::"id"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeEntity {
+    Uid(String),
+    /// Template slot, filled when the template is linked
+    Slot(Option<Loc>),
+}
+
+impl From<&EntityReference> for ScopeEntity {
+    fn from(entity: &EntityReference) -> Self {
+        match entity {
+            EntityReference::EUID(uid) => ScopeEntity::Uid(uid.to_string()),
+            EntityReference::Slot(loc) => ScopeEntity::Slot(loc.clone()),
+        }
+    }
+}
+
+/// Constraint on `principal` or `resource` in a policy scope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeConstraint {
+    Any,
+    Eq(ScopeEntity),
+    In(ScopeEntity),
+    /// Entity type name, e.g. `App::User`
+    Is(String),
+    IsIn(String, ScopeEntity),
+}
+
+impl From<&PrincipalOrResourceConstraint> for ScopeConstraint {
+    fn from(constraint: &PrincipalOrResourceConstraint) -> Self {
+        use PrincipalOrResourceConstraint as C;
+
+        match constraint {
+            C::Any => ScopeConstraint::Any,
+            C::Eq(entity) => ScopeConstraint::Eq(entity.into()),
+            C::In(entity) => ScopeConstraint::In(entity.into()),
+            C::Is(entity_type) => ScopeConstraint::Is(entity_type.to_string()),
+            C::IsIn(entity_type, entity) => {
+                ScopeConstraint::IsIn(entity_type.to_string(), entity.into())
+            }
+        }
+    }
+}
+
+/// Constraint on `action` in a policy scope, with actions as `Type::"id"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActionScope {
+    Any,
+    Eq(String),
+    In(Vec<String>),
+}
+
+impl From<&ActionConstraint> for ActionScope {
+    fn from(constraint: &ActionConstraint) -> Self {
+        match constraint {
+            ActionConstraint::Any => ActionScope::Any,
+            ActionConstraint::Eq(uid) => ActionScope::Eq(uid.to_string()),
+            ActionConstraint::In(uids) => {
+                ActionScope::In(uids.iter().map(|uid| uid.to_string()).collect())
+            }
+        }
+    }
+}
+
+/// The scope of a policy: `permit(principal .., action .., resource ..)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub principal: ScopeConstraint,
+    pub action: ActionScope,
+    pub resource: ScopeConstraint,
+}
+
+/// A `when` or `unless` clause of a policy
+#[derive(Debug, Clone)]
+pub enum Clause {
+    When(Expr),
+    /// Holds the expression inside `unless { .. }`, not its negation
+    Unless(Expr),
+}
+
+/// The parts of a policy or template that lowering consumes
+#[derive(Debug, Clone)]
+pub struct PolicyParts {
+    /// The policy's `@id` annotation if it has one, else its policy ID
+    pub id: String,
+    pub effect: PolicyEffect,
+    pub scope: Scope,
+    /// `when`/`unless` clauses, in source order
+    pub clauses: Vec<Clause>,
+    /// Annotated `@sensitive`: string literals must not be embedded in the
+    /// compiled module
+    pub sensitive: bool,
+}
+
+impl PolicyParts {
+    pub fn from_template(template: &Template) -> Self {
+        Self {
+            id: policy_name(template.id(), template.annotations()),
+            effect: template.effect().into(),
+            scope: Scope {
+                principal: template.principal_constraint().as_inner().into(),
+                action: template.action_constraint().into(),
+                resource: template.resource_constraint().as_inner().into(),
+            },
+            clauses: clauses(template.non_scope_constraints(), template.loc()),
+            sensitive: template
+                .annotations()
+                .any(|(key, _)| key.as_ref() == SENSITIVE_ANNOTATION),
+        }
+    }
+
+    pub fn from_policy(policy: &Policy) -> Self {
+        Self {
+            id: policy_name(policy.id(), policy.annotations()),
+            effect: policy.effect().into(),
+            scope: Scope {
+                principal: policy.principal_constraint().as_inner().into(),
+                action: policy.action_constraint().into(),
+                resource: policy.resource_constraint().as_inner().into(),
+            },
+            clauses: clauses(policy.non_scope_constraints(), policy.loc()),
+            sensitive: policy
+                .annotations()
+                .any(|(key, _)| key.as_ref() == SENSITIVE_ANNOTATION),
+        }
+    }
+}
+
+fn policy_name<'a>(
+    id: &PolicyID,
+    mut annotations: impl Iterator<Item = (&'a AnyId, &'a Annotation)>,
+) -> String {
+    annotations
+        .find(|(key, _)| key.as_ref() == ID_ANNOTATION)
+        .map_or_else(|| id.to_string(), |(_, name)| name.as_ref().to_string())
+}
+
+/// Recover the clauses the parser folded into one expression. Core joins
+/// them with `&&` nodes located at the whole policy and turns `unless { e }`
+/// into `!e` located at the clause, so neither can be confused with
+/// operators the author wrote. Without source locations the conditions
+/// stay a single `when` clause.
+fn clauses(conditions: Option<&Expr>, policy_loc: Option<&Loc>) -> Vec<Clause> {
+    let mut clauses = Vec::new();
+    let mut rest = conditions;
+    while let Some(expr) = rest {
+        rest = None;
+        let clause = match expr.expr_kind() {
+            ExprKind::And { left, right }
+                if policy_loc.is_some() && expr.source_loc() == policy_loc =>
+            {
+                rest = Some(right.as_ref());
+                left.as_ref()
+            }
+            _ => expr,
+        };
+        let is_unless = clause
+            .source_loc()
+            .and_then(|loc| loc.src.get(loc.start()..))
+            .is_some_and(|text| text.starts_with("unless"));
+        clauses.push(match clause.expr_kind() {
+            ExprKind::UnaryApp {
+                op: UnaryOp::Not,
+                arg,
+            } if is_unless => Clause::Unless(arg.as_ref().clone()),
+            _ => Clause::When(clause.clone()),
+        });
+    }
+    clauses
+}
+
+/// Parse a single policy or template from Cedar text
+pub fn parse_template(source: &str) -> Result<Template, Box<ParseErrors>> {
+    parse_template_with_id(None, source)
+}
+
+/// Parse a single policy or template from Cedar text, giving it `id`
+pub fn parse_template_with_id(
+    id: Option<PolicyID>,
+    source: &str,
+) -> Result<Template, Box<ParseErrors>> {
+    // Core 4.4+ takes an optional PolicyID and defaults it to `policy0`
+    parser::parse_policy_or_template(id, source).map_err(Box::new)
+}
+
+/// Parse a single policy or template from Cedar's JSON policy format (the
+/// EST). It gets the same ID as [`parse_template`] gives a policy, but no
+/// source locations.
+pub fn parse_json_template(source: &str) -> Result<Template, JsonPolicyError> {
+    let policy: est::Policy = serde_json::from_str(source)?;
+    let id = cedar_policy_core::ast::PolicyID::from_string("policy0");
+    Ok(policy
+        .try_into_ast_policy_or_template(Some(id))
+        .map_err(Box::new)?)
+}
+
+/// 1-based line and column of a byte offset into `text`, counting columns in
+/// characters
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Parse any number of policies from Cedar text
+pub fn parse_policy_set(source: &str) -> Result<PolicySet, Box<ParseErrors>> {
+    parser::parse_policyset(source).map_err(Box::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_parts_effect_and_condition() {
+        let template =
+            parse_template("forbid(principal, action, resource) when { 1 < 2 };").unwrap();
+        let parts = PolicyParts::from_template(&template);
+
+        assert_eq!(parts.effect, PolicyEffect::Forbid);
+        assert_eq!(parts.id, "policy0");
+        assert_eq!(parts.scope.principal, ScopeConstraint::Any);
+        assert_eq!(parts.scope.action, ActionScope::Any);
+        assert!(matches!(parts.clauses.as_slice(), [Clause::When(e)] if e.to_string() == "1 < 2"));
+        assert!(!parts.sensitive);
+
+        let template = parse_template("@sensitive permit(principal, action, resource);").unwrap();
+        assert!(PolicyParts::from_template(&template).sensitive);
+    }
+
+    #[test]
+    fn test_schema_attribute_types() {
+        let schema = Schema::from_cedarschema_str(
+            r#"
+            namespace App {
+                entity Group;
+                entity User in [Group] {
+                    name: String,
+                    level: Long,
+                    manager: User,
+                    address: { city: String },
+                };
+                entity Doc { name: Long };
+                action read appliesTo {
+                    principal: User,
+                    resource: Doc,
+                    context: { ip: ipaddr, trusted: Bool },
+                };
+            }
+            "#,
+        )
+        .unwrap();
+
+        assert!(schema.has_entity_type("App::User"));
+        assert!(schema.has_entity_type("App::Action"));
+        assert!(!schema.has_entity_type("User"));
+
+        let owners = |names: &[&str]| names.iter().map(ToString::to_string).collect();
+        let user = owners(&["App::User"]);
+        let doc = owners(&["App::Doc"]);
+        assert_eq!(
+            schema.attribute_type(&user, "level"),
+            Some(AttributeType::Long)
+        );
+        assert_eq!(
+            schema.attribute_type(&user, "manager"),
+            Some(AttributeType::Entity)
+        );
+        assert_eq!(schema.attribute_owners(&user, "manager"), user);
+        assert_eq!(
+            schema.attribute_type(&user, "address"),
+            Some(AttributeType::Record)
+        );
+        let address = schema.attribute_owners(&user, "address");
+        assert_eq!(
+            schema.attribute_type(&address, "city"),
+            Some(AttributeType::String)
+        );
+        // A record's attributes aren't its owner's
+        assert!(!schema.has_attribute(&user, "city"));
+
+        // The request variables are what the actions apply to
+        let principals = schema.principal_types(None);
+        assert_eq!(principals, user);
+        assert_eq!(schema.resource_types(Some(r#"App::Action::"read""#)), doc);
+        assert!(schema
+            .resource_types(Some(r#"App::Action::"write""#))
+            .is_empty());
+        let context = schema.context(None);
+        assert_eq!(
+            schema.attribute_type(&context, "ip"),
+            Some(AttributeType::Ip)
+        );
+        assert_eq!(
+            schema.attribute_type(&context, "trusted"),
+            Some(AttributeType::Bool)
+        );
+        assert!(!schema.has_attribute(&context, "level"));
+
+        // Declared as String on User and Long on Doc
+        assert_eq!(
+            schema.attribute_type(&user, "name"),
+            Some(AttributeType::String)
+        );
+        assert_eq!(
+            schema.attribute_type(&doc, "name"),
+            Some(AttributeType::Long)
+        );
+        // Unknown owners could be either
+        assert!(schema.has_attribute(&BTreeSet::new(), "name"));
+        assert_eq!(schema.attribute_type(&BTreeSet::new(), "name"), None);
+        assert!(!schema.has_attribute(&BTreeSet::new(), "age"));
+
+        assert!(Schema::from_cedarschema_str("entity User { name: Strin };").is_err());
+    }
+
+    #[test]
+    fn test_when_and_unless_clauses() {
+        let template = parse_template(
+            "permit(principal, action, resource)\n\
+             when { 1 < 2 && !(3 < 4) }\n\
+             unless { 5 < 6 }\n\
+             when { !(7 < 8) };",
+        )
+        .unwrap();
+        let parts = PolicyParts::from_template(&template);
+
+        let clauses: Vec<_> = parts
+            .clauses
+            .iter()
+            .map(|clause| match clause {
+                Clause::When(e) => format!("when {}", e),
+                Clause::Unless(e) => format!("unless {}", e),
+            })
+            .collect();
+        assert_eq!(
+            clauses,
+            [
+                "when (1 < 2) && (!(3 < 4))",
+                "unless 5 < 6",
+                "when !(7 < 8)"
+            ]
+        );
+
+        let template = parse_template("permit(principal, action, resource);").unwrap();
+        assert!(PolicyParts::from_template(&template).clauses.is_empty());
+    }
+
+    #[test]
+    fn test_parse_json_template() {
+        let template = parse_json_template(
+            r#"{
+                "effect": "forbid",
+                "principal": { "op": "==", "entity": { "type": "User", "id": "bob" } },
+                "action": { "op": "All" },
+                "resource": { "op": "All" },
+                "conditions": []
+            }"#,
+        )
+        .unwrap();
+        let parts = PolicyParts::from_template(&template);
+
+        assert_eq!(parts.id, "policy0");
+        assert_eq!(parts.effect, PolicyEffect::Forbid);
+        assert_eq!(
+            parts.scope.principal,
+            ScopeConstraint::Eq(ScopeEntity::Uid(r#"User::"bob""#.to_string()))
+        );
+
+        assert!(matches!(
+            parse_json_template("{ \"effect\": "),
+            Err(JsonPolicyError::Json(_))
+        ));
+        assert!(matches!(
+            parse_json_template(r#"{ "effect": "permit" }"#),
+            Err(JsonPolicyError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_scope_constraints() {
+        let template = parse_template(
+            r#"permit(
+                principal is User in Group::"admins",
+                action in [Action::"read", Action::"list"],
+                resource == ?resource
+            );"#,
+        )
+        .unwrap();
+        let scope = PolicyParts::from_template(&template).scope;
+
+        assert_eq!(
+            scope.principal,
+            ScopeConstraint::IsIn(
+                "User".to_string(),
+                ScopeEntity::Uid(r#"Group::"admins""#.to_string())
+            )
+        );
+        assert_eq!(
+            scope.action,
+            ActionScope::In(vec![
+                r#"Action::"read""#.to_string(),
+                r#"Action::"list""#.to_string()
+            ])
+        );
+        assert!(matches!(
+            scope.resource,
+            ScopeConstraint::Eq(ScopeEntity::Slot(_))
+        ));
+    }
+}
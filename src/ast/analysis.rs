@@ -0,0 +1,163 @@
+//! Read-only analyses over Cedar policies, used by tooling rather than codegen
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::{json, Map, Value};
+
+use crate::ast::adapter::{Expr, ExprKind, Literal, PolicySet, Template};
+
+use crate::ast::lowering::{LoweredModule, LoweringError, Span};
+
+/// A constant value a policy compares against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LiteralKind {
+    Long(i64),
+    String(String),
+    /// Entity UID, rendered as `Type::"id"`
+    Entity(String),
+    /// Extension constructor applied to a literal, e.g. `ip("10.0.0.0/8")`
+    Extension {
+        function: String,
+        argument: String,
+    },
+}
+
+/// A literal together with where it appears in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiteralValue {
+    pub kind: LiteralKind,
+    /// Source span, if the parser recorded one
+    pub span: Option<Span>,
+}
+
+/// Collect every literal in the condition that lowering compiles, in source order
+pub fn literals(template: &Template) -> Vec<LiteralValue> {
+    let mut out = Vec::new();
+    collect_literals(&template.condition(), &mut out);
+    out
+}
+
+/// The entity table a policy set compiles with: the UID at position `i` is
+/// passed to `evaluate` as handle `i`. See [`LoweredModule::entities`] for
+/// the ordering.
+pub fn entity_table(policy_set: &PolicySet) -> Result<Vec<String>, LoweringError> {
+    Ok(LoweredModule::from_policy_set(policy_set)?.entities)
+}
+
+/// Attributes read or tested directly on each request variable (e.g.
+/// `principal.level`, `context has mfa`), keyed by variable name
+pub fn request_attributes(policy_set: &PolicySet) -> BTreeMap<String, BTreeSet<String>> {
+    let mut out: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for policy in policy_set.policies() {
+        for expr in policy.condition().subexpressions() {
+            if let ExprKind::GetAttr { expr, attr } | ExprKind::HasAttr { expr, attr } =
+                expr.expr_kind()
+            {
+                if let ExprKind::Var(var) = expr.expr_kind() {
+                    out.entry(var.to_string())
+                        .or_default()
+                        .insert(attr.to_string());
+                }
+            }
+        }
+    }
+    out
+}
+
+/// A JSON skeleton for testing a policy set: the entity table and one
+/// request whose referenced attributes are `null` placeholders, with a
+/// `decision` (`"permit"`, `"deny"` or `"no-decision"`) to fill in
+pub fn test_vector_template(policy_set: &PolicySet) -> Result<Value, LoweringError> {
+    let attributes = request_attributes(policy_set);
+    let attrs = |var: &str| -> Map<String, Value> {
+        attributes
+            .get(var)
+            .into_iter()
+            .flatten()
+            .map(|attr| (attr.clone(), Value::Null))
+            .collect()
+    };
+    let entity = |var: &str| json!({ "uid": null, "attrs": attrs(var) });
+
+    Ok(json!({
+        "entities": entity_table(policy_set)?,
+        "requests": [{
+            "principal": entity("principal"),
+            "action": entity("action"),
+            "resource": entity("resource"),
+            "context": attrs("context"),
+            "decision": null,
+        }],
+    }))
+}
+
+fn collect_literals(expr: &Expr, out: &mut Vec<LiteralValue>) {
+    use ExprKind::*;
+
+    let span = expr.source_loc().map(Span::from_loc);
+    match expr.expr_kind() {
+        Lit(lit) => {
+            let kind = match lit {
+                // Booleans are structural (scope constraints desugar to `true`),
+                // not values a policy gates on
+                Literal::Bool(_) => return,
+                Literal::Long(i) => LiteralKind::Long(*i),
+                Literal::String(s) => LiteralKind::String(s.to_string()),
+                Literal::EntityUID(uid) => LiteralKind::Entity(uid.to_string()),
+            };
+            out.push(LiteralValue { kind, span });
+        }
+
+        ExtensionFunctionApp { fn_name, args } => {
+            // Constructor calls like `decimal("1.5")` are a single literal value
+            if let [arg] = args.as_slice() {
+                if let Lit(Literal::String(s)) = arg.expr_kind() {
+                    out.push(LiteralValue {
+                        kind: LiteralKind::Extension {
+                            function: fn_name.to_string(),
+                            argument: s.to_string(),
+                        },
+                        span,
+                    });
+                    return;
+                }
+            }
+            for arg in args.iter() {
+                collect_literals(arg, out);
+            }
+        }
+
+        If {
+            test_expr,
+            then_expr,
+            else_expr,
+        } => {
+            collect_literals(test_expr, out);
+            collect_literals(then_expr, out);
+            collect_literals(else_expr, out);
+        }
+        And { left, right } | Or { left, right } => {
+            collect_literals(left, out);
+            collect_literals(right, out);
+        }
+        BinaryApp { arg1, arg2, .. } => {
+            collect_literals(arg1, out);
+            collect_literals(arg2, out);
+        }
+        UnaryApp { arg, .. } => collect_literals(arg, out),
+        GetAttr { expr, .. } | HasAttr { expr, .. } | Like { expr, .. } | Is { expr, .. } => {
+            collect_literals(expr, out)
+        }
+        Set(elems) => {
+            for elem in elems.iter() {
+                collect_literals(elem, out);
+            }
+        }
+        Record(fields) => {
+            for value in fields.values() {
+                collect_literals(value, out);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,690 @@
+//! Intermediate representation the compiler lowers Cedar policies to, and
+//! optimizes and generates code from. The IR needs only `alloc`; lowering
+//! from Cedar's AST is built with the `std` feature.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::wasm::types::WasmType;
+
+#[cfg(feature = "std")]
+mod translate;
+
+#[cfg(feature = "std")]
+pub use translate::LoweringError;
+
+/// Byte range of a construct in the original policy text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// An expression whose operands the schema shows to have types it doesn't
+/// accept, such as a Long compared with a String
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeError {
+    /// ID of the policy the expression is in
+    pub policy: String,
+    pub message: String,
+}
+
+impl core::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}: {}", self.policy, self.message)
+    }
+}
+
+/// Weight of one call into the host in [`LoweredModule::estimated_eval_cost`],
+/// in WASM operators. Metering charges a call like any operator, but it
+/// leaves the module through wasmtime's trampolines and the runtime function
+/// then looks up an entity or compares strings; 20 is a rough guess at that
+/// cost rather than a measurement, enough to rank host calls well above
+/// stack operations.
+pub const HOST_CALL_COST: u64 = 20;
+
+/// Intermediate representation of a Cedar policy or policy set
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoweredModule {
+    /// One evaluation function per policy, in policy-set order. The generated
+    /// `evaluate` export combines their decisions (forbid overrides permit).
+    pub policies: Vec<LoweredFunction>,
+    /// Interned entity UIDs (`Type::"id"`); entity literals lower to an index
+    /// into this table, and the host passes request entities the same way.
+    /// Indices follow first appearance: policies in policy-set order, each
+    /// walked scope first, then conditions left to right.
+    pub entities: Vec<String>,
+    /// Whether the policies are templates with slots; `evaluate` then takes
+    /// the entities bound to `?principal` and `?resource` as two more
+    /// parameters
+    pub slots: bool,
+    /// Attributes the policies read or test, with what they are read on, in
+    /// order of first appearance
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub attribute_refs: Vec<(EntityRef, String)>,
+    /// Entity types the policies name in entity literals and `is` tests, in
+    /// order of first appearance
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub entity_types: Vec<String>,
+    /// Functions shared by the policies, each computing one value from the
+    /// request; [`Instruction::Call`] calls them by index. A helper only
+    /// calls helpers before it. Their effects are unused.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub helpers: Vec<LoweredFunction>,
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoweredFunction {
+    /// ID of the policy, which names its export when policies are exported
+    /// individually
+    pub id: String,
+    /// Effect of the policy, listed with its ID in the module's policy table
+    pub effect: PolicyEffect,
+    /// Function body as a sequence of instructions
+    pub instructions: Vec<Instruction>,
+    /// Span of the innermost expression each instruction was lowered from,
+    /// parallel to `instructions`; empty for IR not lowered from source
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub spans: Vec<Option<Span>>,
+}
+
+/// Request variables, numbered by their `evaluate` parameter position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RequestVar {
+    Principal = 0,
+    Action = 1,
+    Resource = 2,
+    Context = 3,
+}
+
+/// What a referenced attribute is read on
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EntityRef {
+    /// A request variable, e.g. `principal.role`
+    Var(RequestVar),
+    /// An entity literal (`Type::"id"`), e.g. `User::"alice".role`
+    Entity(String),
+    /// A value computed at evaluation time, e.g. the manager in
+    /// `principal.manager.role`
+    Computed,
+}
+
+/// Element of a set literal or field of a record literal. Both are laid out
+/// in static data, so only constants can be elements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SetElement {
+    Bool(bool),
+    Int(i64),
+    String(String),
+    /// Index into [`LoweredModule::entities`]
+    Entity(usize),
+}
+
+/// Simple stack-based instruction set
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Instruction {
+    // Literals
+    /// Boolean constant. Booleans are i32 0 or 1 throughout the IR, like
+    /// the results of comparisons and logical operators; only attribute
+    /// values carry them as i64.
+    PushBool(bool),
+    PushInt(i64),
+    /// Decimal literal, as an i64 scaled by 10^[`DECIMAL_DIGITS`]
+    PushDecimal(i64),
+    /// `datetime` literal, as milliseconds since the Unix epoch
+    PushDateTime(i64),
+    PushString(String),
+    /// String literal that must not be embedded in the module; the host
+    /// resolves it at runtime
+    PushSecret(String),
+    /// Entity literal, by index into [`LoweredModule::entities`]
+    LoadConstEntity(usize),
+    /// Set literal; elements all have the same type
+    PushSet(Vec<SetElement>),
+    /// Record literal, with fields sorted by name
+    PushRecord(Vec<(String, SetElement)>),
+
+    // Request inputs
+    LoadVar(RequestVar),
+    /// Entity bound to the template's `?principal` slot
+    SlotPrincipal,
+    /// Entity bound to the template's `?resource` slot
+    SlotResource,
+
+    // Comparison operations
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+
+    // Logical operations
+    And,
+    Or,
+    Not,
+
+    // Arithmetic
+    /// Negate the integer on top of the stack; negating `i64::MIN`
+    /// overflows, which is an evaluation error unless codegen is set to
+    /// another [`Overflow`](crate::wasm::codegen::Overflow) behavior
+    Neg,
+
+    // Entity/attribute operations
+    GetAttribute(String),
+    /// `GetAttribute` of an attribute a `has` check has shown to be present,
+    /// so the read can't fail
+    GetPresentAttribute(String),
+    HasAttribute(String),
+    /// The attribute value `GetAttribute` left on top of the stack, which
+    /// the schema says has this type
+    Narrow(WasmType),
+    /// Save a copy of the attribute value on top of the stack in local `n`
+    /// of the policy function
+    TeeLocal(u32),
+    /// Push the attribute value saved in local `n`
+    GetLocal(u32),
+    /// Push a copy of the value on top of the stack
+    Dup,
+    /// Exchange the two values on top of the stack
+    Swap,
+    /// Discard the value on top of the stack
+    Drop,
+    In,
+    /// Whether the entity on top of the stack has the named entity type
+    IsEntityType(String),
+    /// Match the string on top of the stack against a glob pattern, in the
+    /// form [`crate::ast::adapter::like_pattern`] renders
+    Like(String),
+    /// [`Instruction::Like`] with a pattern that must not be embedded in the
+    /// module; the host resolves it at runtime
+    LikeSecret(String),
+
+    // Set operations: pop the set, then the value or second set on top
+    Contains,
+    ContainsAll,
+    ContainsAny,
+
+    // IP address operations (the `ipaddr` extension)
+    /// Parse the string on top of the stack as an IP address or range
+    Ip,
+    /// Whether the address below the range on top of the stack is in it
+    IsInRange,
+    IsIpv4,
+    IsIpv6,
+    IsLoopback,
+    IsMulticast,
+
+    // Control flow
+    IfThenElse,
+    Return,
+    /// Run helper `n` of [`LoweredModule::helpers`] on the same request and
+    /// push the value it computes. If it fails, so does the policy.
+    Call(usize),
+    /// A value of the given type whose evaluation always fails, such as a
+    /// comparison the schema shows to be mistyped: the policy returns
+    /// [`Decision::Error`](crate::wasm::runtime::Decision::Error) here
+    Error(WasmType),
+    /// Structured branch: pops an i32 condition and runs the following
+    /// instructions up to the matching `Else`/`End` only if it is nonzero.
+    /// Carries the type each branch leaves on the stack; with `None` the code
+    /// generator infers it from what the branches leave, if anything.
+    If(Option<WasmType>),
+    Else,
+    End,
+
+    // Policy decision
+    Permit,
+    Forbid,
+    NoDecision,
+}
+
+impl Instruction {
+    /// Whether this instruction opens, splits, or closes a structured branch
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Instruction::If(_) | Instruction::Else | Instruction::End
+        )
+    }
+
+    /// Number of values this instruction pops from and pushes onto the stack.
+    /// For branch markers this only counts the condition popped by `If`; the
+    /// values produced inside branches are accounted for by their bodies.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        use Instruction::*;
+
+        match self {
+            PushBool(_) | PushInt(_) | PushDecimal(_) | PushDateTime(_) | PushString(_)
+            | PushSecret(_) | LoadConstEntity(_) | PushSet(_) | PushRecord(_) | LoadVar(_)
+            | SlotPrincipal | SlotResource | GetLocal(_) | Error(_) | Call(_) => (0, 1),
+            Permit | Forbid | NoDecision => (0, 1),
+            Equal | NotEqual | LessThan | LessThanOrEqual | GreaterThan | GreaterThanOrEqual => {
+                (2, 1)
+            }
+            And | Or | In | Contains | ContainsAll | ContainsAny | IsInRange => (2, 1),
+            Not | GetAttribute(_) | GetPresentAttribute(_) | HasAttribute(_) | Narrow(_) => (1, 1),
+            IsEntityType(_) | Like(_) | LikeSecret(_) => (1, 1),
+            TeeLocal(_) | Neg => (1, 1),
+            Dup => (1, 2),
+            Swap => (2, 2),
+            Drop => (1, 0),
+            Ip | IsIpv4 | IsIpv6 | IsLoopback | IsMulticast => (1, 1),
+            IfThenElse => (3, 1),
+            Return => (1, 0),
+            If(_) => (1, 0),
+            Else | End => (0, 0),
+        }
+    }
+
+    /// Upper bound on the fuel (WASM operators executed) the code generated
+    /// for this instruction consumes, including operand coercions. A host
+    /// call counts as one operator; time spent in the host is not metered.
+    pub fn fuel_cost(&self) -> u64 {
+        use Instruction::*;
+
+        match self {
+            // Batch-mode memo lookup and update, plus resetting the memo slot
+            // when `evaluate` is entered, and the error check
+            GetAttribute(_) => 27,
+            // The same without the error check
+            GetPresentAttribute(_) => 22,
+            // Host call and the error check
+            PushSecret(_) | Ip => 6,
+            // Overflow check, then subtraction from zero
+            Neg => 7,
+            // Resolving the pattern, then matching it
+            LikeSecret(_) => 8,
+            // Passing the request and the error check, but not the helper's
+            // body; see `LoweredModule::max_fuel`
+            Call(_) => 8,
+            // Both values set aside in locals and read back
+            Swap => 4,
+            // Setting the error flag and returning
+            Error(_) => 4,
+            // Both operands coerced from i64
+            And | Or => 7,
+            // Value widened to i64 and set aside to unwrap the set pointer;
+            // string comparison with an attribute operand, then negation
+            Contains | NotEqual => 6,
+            // Both operands unwrapped from i64 before the host call
+            Equal | In | ContainsAll | ContainsAny | IsInRange => 5,
+            Not | IfThenElse | If(_) | HasAttribute(_) | IsEntityType(_) | Like(_) => 3,
+            IsIpv4 | IsIpv6 | IsLoopback | IsMulticast => 3,
+            _ => 2,
+        }
+    }
+
+    /// Whether the code generated for this instruction calls the host. `==`
+    /// and `!=` only do on strings, which isn't known before code
+    /// generation, so they count as calls.
+    pub fn calls_host(&self) -> bool {
+        use Instruction::*;
+
+        matches!(
+            self,
+            GetAttribute(_)
+                | GetPresentAttribute(_)
+                | HasAttribute(_)
+                | PushSecret(_)
+                | Equal
+                | NotEqual
+                | In
+                | IsEntityType(_)
+                | Like(_)
+                | LikeSecret(_)
+                | Contains
+                | ContainsAll
+                | ContainsAny
+                | Ip
+                | IsInRange
+                | IsIpv4
+                | IsIpv6
+                | IsLoopback
+                | IsMulticast
+        )
+    }
+
+    /// Bytes the literal this instruction pushes takes in the string pool,
+    /// counting strings it shares with other literals again
+    fn pool_size(&self) -> u64 {
+        // Length prefix, then the bytes
+        let string = |s: &str| 4 + s.len() as u64;
+        let element = |element: &SetElement| match element {
+            SetElement::String(s) => 8 + string(s),
+            _ => 8,
+        };
+        match self {
+            Instruction::PushString(s) | Instruction::Like(s) => string(s),
+            // Count and kind, then one i64 per element
+            Instruction::PushSet(elements) => 8 + elements.iter().map(element).sum::<u64>(),
+            // Count, then attribute index and kind per field before the value
+            Instruction::PushRecord(fields) => {
+                4 + fields
+                    .iter()
+                    .map(|(_, value)| 8 + element(value))
+                    .sum::<u64>()
+            }
+            _ => 0,
+        }
+    }
+}
+
+impl core::fmt::Display for Instruction {
+    /// Disassembly: a lowercase mnemonic, then the operands. Redacted
+    /// strings are not shown.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        use Instruction::*;
+
+        match self {
+            PushBool(b) => write!(f, "push_bool {}", b),
+            PushInt(i) => write!(f, "push_int {}", i),
+            PushDecimal(i) => {
+                let scale = 10u64.pow(DECIMAL_DIGITS);
+                let sign = if *i < 0 { "-" } else { "" };
+                let (whole, fraction) = (i.unsigned_abs() / scale, i.unsigned_abs() % scale);
+                let digits = DECIMAL_DIGITS as usize;
+                write!(f, "push_decimal {}{}.{:0digits$}", sign, whole, fraction)
+            }
+            PushDateTime(ms) => write!(f, "push_datetime {}", ms),
+            PushString(s) => write!(f, "push_string {:?}", s),
+            PushSecret(_) => f.write_str("push_secret"),
+            LoadConstEntity(idx) => write!(f, "load_entity {}", idx),
+            PushSet(elems) => {
+                f.write_str("push_set [")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{}", sep, elem)?;
+                }
+                f.write_str("]")
+            }
+            PushRecord(fields) => {
+                f.write_str("push_record {")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{:?}: {}", sep, name, value)?;
+                }
+                f.write_str("}")
+            }
+            LoadVar(var) => write!(f, "load_var {}", var),
+            SlotPrincipal => f.write_str("slot_principal"),
+            SlotResource => f.write_str("slot_resource"),
+            Equal => f.write_str("eq"),
+            NotEqual => f.write_str("ne"),
+            LessThan => f.write_str("lt"),
+            LessThanOrEqual => f.write_str("le"),
+            GreaterThan => f.write_str("gt"),
+            GreaterThanOrEqual => f.write_str("ge"),
+            And => f.write_str("and"),
+            Or => f.write_str("or"),
+            Not => f.write_str("not"),
+            Neg => f.write_str("neg"),
+            GetAttribute(attr) => write!(f, "get_attr {:?}", attr),
+            GetPresentAttribute(attr) => write!(f, "get_present_attr {:?}", attr),
+            HasAttribute(attr) => write!(f, "has_attr {:?}", attr),
+            Narrow(ty) => write!(f, "narrow {}", ty),
+            TeeLocal(idx) => write!(f, "tee_local {}", idx),
+            GetLocal(idx) => write!(f, "get_local {}", idx),
+            Dup => f.write_str("dup"),
+            Call(idx) => write!(f, "call {}", idx),
+            Swap => f.write_str("swap"),
+            Drop => f.write_str("drop"),
+            In => f.write_str("in"),
+            IsEntityType(name) => write!(f, "is {}", name),
+            Like(pattern) => write!(f, "like {:?}", pattern),
+            LikeSecret(_) => f.write_str("like_secret"),
+            Contains => f.write_str("contains"),
+            ContainsAll => f.write_str("contains_all"),
+            ContainsAny => f.write_str("contains_any"),
+            Ip => f.write_str("ip"),
+            IsInRange => f.write_str("is_in_range"),
+            IsIpv4 => f.write_str("is_ipv4"),
+            IsIpv6 => f.write_str("is_ipv6"),
+            IsLoopback => f.write_str("is_loopback"),
+            IsMulticast => f.write_str("is_multicast"),
+            IfThenElse => f.write_str("if_then_else"),
+            Return => f.write_str("return"),
+            Error(ty) => write!(f, "error {}", ty),
+            If(Some(ty)) => write!(f, "if {}", ty),
+            If(None) => f.write_str("if"),
+            Else => f.write_str("else"),
+            End => f.write_str("end"),
+            Permit => f.write_str("permit"),
+            Forbid => f.write_str("forbid"),
+            NoDecision => f.write_str("no_decision"),
+        }
+    }
+}
+
+impl core::fmt::Display for SetElement {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SetElement::Bool(b) => write!(f, "{}", b),
+            SetElement::Int(i) => write!(f, "{}", i),
+            SetElement::String(s) => write!(f, "{:?}", s),
+            SetElement::Entity(idx) => write!(f, "entity {}", idx),
+        }
+    }
+}
+
+impl core::fmt::Display for RequestVar {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            RequestVar::Principal => "principal",
+            RequestVar::Action => "action",
+            RequestVar::Resource => "resource",
+            RequestVar::Context => "context",
+        })
+    }
+}
+
+impl LoweredFunction {
+    /// The function body
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Carry the spans of `before` over to the instructions that replaced
+    /// it. Each instruction is matched to the next equal one shortly ahead
+    /// in `before` and keeps its span; instructions a rewrite introduced
+    /// have none.
+    pub(crate) fn realign_spans(&mut self, before: &[Instruction]) {
+        // How far ahead to look for a match, so an instruction can't pair
+        // with an unrelated copy at the other end of the function
+        const WINDOW: usize = 16;
+
+        if self.spans.len() != before.len() {
+            self.spans.clear();
+            return;
+        }
+        let mut next = 0;
+        let mut spans = Vec::with_capacity(self.instructions.len());
+        for inst in &self.instructions {
+            let found = before[next..]
+                .iter()
+                .take(WINDOW)
+                .position(|old| old == inst);
+            spans.push(found.and_then(|offset| {
+                next += offset + 1;
+                self.spans[next - 1]
+            }));
+        }
+        self.spans = spans;
+    }
+}
+
+impl core::fmt::Display for LoweredFunction {
+    /// Disassembly of the policy: its ID and effect, then one instruction
+    /// per line, indented inside branches
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let effect = match self.effect {
+            PolicyEffect::Permit => "permit",
+            PolicyEffect::Forbid => "forbid",
+        };
+        writeln!(f, "{} ({}):", self.id, effect)?;
+        write_body(f, &self.instructions)
+    }
+}
+
+/// One instruction per line, indented inside branches
+fn write_body(f: &mut core::fmt::Formatter<'_>, instructions: &[Instruction]) -> core::fmt::Result {
+    let mut depth = 1;
+    for inst in instructions {
+        if matches!(inst, Instruction::Else | Instruction::End) {
+            depth -= 1;
+        }
+        writeln!(f, "{:width$}{}", "", inst, width = depth * 2)?;
+        if matches!(inst, Instruction::If(_) | Instruction::Else) {
+            depth += 1;
+        }
+    }
+    Ok(())
+}
+
+impl core::fmt::Display for LoweredModule {
+    /// Disassembly of the entity table, then of every policy and helper
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (idx, uid) in self.entities.iter().enumerate() {
+            writeln!(f, "entity {}: {}", idx, uid)?;
+        }
+        for (i, policy) in self.policies.iter().enumerate() {
+            if i > 0 || !self.entities.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "{}", policy)?;
+        }
+        for (idx, helper) in self.helpers.iter().enumerate() {
+            writeln!(f)?;
+            writeln!(f, "helper {}:", idx)?;
+            write_body(f, &helper.instructions)?;
+        }
+        Ok(())
+    }
+}
+
+impl LoweredModule {
+    /// The policy functions, in policy-set order
+    pub fn policies(&self) -> &[LoweredFunction] {
+        &self.policies
+    }
+
+    /// Every instruction of every policy, then of every helper, in order
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.policies
+            .iter()
+            .chain(&self.helpers)
+            .flat_map(LoweredFunction::instructions)
+    }
+
+    /// Attributes the policies read or test, each with what it is read on,
+    /// so a host can fetch exactly those before evaluating
+    pub fn referenced_attributes(&self) -> &[(EntityRef, String)] {
+        &self.attribute_refs
+    }
+
+    /// Entity types the policies name in entity literals and `is` tests
+    pub fn referenced_entity_types(&self) -> &[String] {
+        &self.entity_types
+    }
+
+    /// Worst-case fuel one `evaluate` call consumes, for hosts that meter
+    /// execution. Policy bodies contain no loops and both arms of a branch
+    /// are charged, so the bound holds for every request; it grows linearly
+    /// with the policies, e.g. with the members of a set tested as a chain of
+    /// `==`.
+    pub fn max_fuel(&self) -> u64 {
+        // Counted from the generated code, charging wasmtime's one unit per
+        // operator. The wrapper of modules with error codes (12 operators:
+        // passing the request, and selecting the error code), and storing
+        // the error flag and selecting the decision at the end (6)
+        const EVALUATE_FUEL: u64 = 18;
+        // Passing the request to one policy and keeping its result (6),
+        // clearing its error flag in the policy (2) and collecting it (4),
+        // returning early on `Deny` (8) and combining a `Permit` (6)
+        const POLICY_FUEL: u64 = 26;
+
+        let policies: u64 = self
+            .policies
+            .iter()
+            .map(|policy| POLICY_FUEL + self.body_fuel(&policy.instructions, self.helpers.len()))
+            .sum();
+        EVALUATE_FUEL + policies
+    }
+
+    /// Fuel bound of a function body, charging each call the body of the
+    /// helper it calls; only the first `callable` helpers can be called
+    fn body_fuel(&self, instructions: &[Instruction], callable: usize) -> u64 {
+        instructions
+            .iter()
+            .map(|inst| match inst {
+                Instruction::Call(idx) if *idx < callable => {
+                    inst.fuel_cost() + self.body_fuel(&self.helpers[*idx].instructions, *idx)
+                }
+                _ => inst.fuel_cost(),
+            })
+            .sum()
+    }
+
+    /// Rough size in bytes of the module compiled from these policies at
+    /// the current point of the pipeline, for comparing optimization
+    /// levels: about two bytes of code per operator (see
+    /// [`Instruction::fuel_cost`]), plus the string pool and the runtime
+    /// imports if any instruction calls the host.
+    pub fn estimated_wasm_size(&self) -> u64 {
+        // Section headers, types, memory, globals, exports and custom sections
+        const MODULE_SIZE: u64 = 110;
+        // Import entries of the runtime functions
+        const IMPORTS_SIZE: u64 = 350;
+        // Function and code section entries and locals of one policy
+        const POLICY_SIZE: u64 = 16;
+
+        let imports = if self.instructions().any(Instruction::calls_host) {
+            IMPORTS_SIZE
+        } else {
+            0
+        };
+        let entities: u64 = self.entities.iter().map(|uid| 8 + uid.len() as u64).sum();
+        let policies: u64 = self
+            .policies
+            .iter()
+            .chain(&self.helpers)
+            .map(|policy| {
+                let body: u64 = policy
+                    .instructions
+                    .iter()
+                    .map(|inst| 2 * inst.fuel_cost() + inst.pool_size())
+                    .sum();
+                POLICY_SIZE + body
+            })
+            .sum();
+        MODULE_SIZE + imports + entities + policies
+    }
+
+    /// Rough cost of one `evaluate` call, in WASM operators: the
+    /// [`LoweredModule::max_fuel`] bound plus [`HOST_CALL_COST`] per call
+    /// into the host, which dominates for policies reading entity data.
+    /// Like the fuel bound it charges both arms of every branch.
+    pub fn estimated_eval_cost(&self) -> u64 {
+        let host_calls = self.instructions().filter(|inst| inst.calls_host()).count() as u64;
+        self.max_fuel() + HOST_CALL_COST * host_calls
+    }
+}
+
+/// Number of fractional digits of a Cedar decimal
+pub const DECIMAL_DIGITS: u32 = 4;
+
+/// Effect of a policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PolicyEffect {
+    #[default]
+    Permit,
+    Forbid,
+}
@@ -0,0 +1,2096 @@
+//! Lowering of Cedar's AST into the IR
+
+use crate::ast::adapter::{
+    like_pattern, line_column, ActionScope, AttributeType, BinaryOp, Clause, Expr, ExprKind,
+    Literal, Loc, Policy, PolicyParts, PolicySet, Schema, Scope, ScopeConstraint, ScopeEntity,
+    Template, UnaryOp,
+};
+use crate::wasm::codegen::{check_operands, result_type, unify_branches};
+use crate::wasm::types::WasmType;
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{
+    EntityRef, Instruction, LoweredFunction, LoweredModule, PolicyEffect, RequestVar, SetElement,
+    Span, TypeError, DECIMAL_DIGITS,
+};
+
+impl Span {
+    /// Convert a Cedar source location into a span
+    pub fn from_loc(loc: &Loc) -> Self {
+        Self {
+            start: loc.start(),
+            end: loc.end(),
+        }
+    }
+}
+
+/// A policy the compiler can't lower, with where in the source it happened
+#[derive(Debug, Clone)]
+pub struct LoweringError {
+    pub message: String,
+    /// Location of the offending expression, if the parser recorded one
+    pub loc: Option<Loc>,
+    /// The construct the compiler doesn't support, in Cedar terms (e.g.
+    /// "multiplication"), when that is why lowering failed
+    pub feature: Option<String>,
+}
+
+impl LoweringError {
+    fn new(message: impl Into<String>, expr: &Expr) -> Self {
+        Self {
+            message: message.into(),
+            loc: expr.source_loc().cloned(),
+            feature: None,
+        }
+    }
+
+    fn unsupported(feature: impl Into<String>, expr: &Expr) -> Self {
+        let feature = feature.into();
+        Self {
+            message: format!("{} is not supported", feature),
+            loc: expr.source_loc().cloned(),
+            feature: Some(feature),
+        }
+    }
+
+    /// 1-based line and column of the offending expression
+    pub fn line_column(&self) -> Option<(usize, usize)> {
+        let loc = self.loc.as_ref()?;
+        let start = loc.start();
+        loc.src
+            .is_char_boundary(start)
+            .then(|| line_column(&loc.src, start))
+    }
+}
+
+impl std::fmt::Display for LoweringError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line_column() {
+            Some((line, column)) => write!(f, "line {}, column {}: {}", line, column, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl From<crate::ast::adapter::Var> for RequestVar {
+    fn from(var: crate::ast::adapter::Var) -> Self {
+        use crate::ast::adapter::Var;
+        match var {
+            Var::Principal => RequestVar::Principal,
+            Var::Action => RequestVar::Action,
+            Var::Resource => RequestVar::Resource,
+            Var::Context => RequestVar::Context,
+        }
+    }
+}
+
+impl LoweredModule {
+    /// Convert a Cedar Policy to the intermediate representation
+    pub fn from_policy(policy: &Policy) -> Result<Self, LoweringError> {
+        Self::from_parts(&[PolicyParts::from_policy(policy)])
+    }
+
+    /// Convert a Cedar Template to the intermediate representation
+    /// Templates are policy templates that can be instantiated
+    pub fn from_template(template: &Template) -> Result<Self, LoweringError> {
+        Self::from_parts(&[PolicyParts::from_template(template)])
+    }
+
+    /// Convert every policy in a Cedar PolicySet, one function per policy
+    pub fn from_policy_set(policy_set: &PolicySet) -> Result<Self, LoweringError> {
+        let parts: Vec<_> = policy_set
+            .policies()
+            .map(PolicyParts::from_policy)
+            .collect();
+        Self::from_parts(&parts)
+    }
+
+    /// Convert every template in a Cedar PolicySet, static policies
+    /// included, one function per template. Links are left to the host,
+    /// which passes the bound entities to `evaluate`.
+    pub fn from_templates(policy_set: &PolicySet) -> Result<Self, LoweringError> {
+        let parts: Vec<_> = policy_set
+            .all_templates()
+            .map(PolicyParts::from_template)
+            .collect();
+        Self::from_parts(&parts)
+    }
+
+    /// Turn every embedded string literal and `like` pattern for which
+    /// `is_sensitive` returns true into a host-resolved secret. Set and
+    /// record literals are static data in the module, so a sensitive string
+    /// in one is an error.
+    pub fn redact_strings(
+        &mut self,
+        is_sensitive: impl Fn(&str) -> bool,
+    ) -> Result<(), LoweringError> {
+        let embedded = |elem: &SetElement| matches!(elem, SetElement::String(s) if is_sensitive(s));
+
+        for inst in self.policies.iter_mut().flat_map(|p| &mut p.instructions) {
+            let leaks = match inst {
+                Instruction::PushString(s) if is_sensitive(s) => {
+                    *inst = Instruction::PushSecret(std::mem::take(s));
+                    false
+                }
+                Instruction::Like(pattern) if is_sensitive(pattern) => {
+                    *inst = Instruction::LikeSecret(std::mem::take(pattern));
+                    false
+                }
+                Instruction::PushSet(elements) => elements.iter().any(embedded),
+                Instruction::PushRecord(fields) => fields.iter().any(|(_, value)| embedded(value)),
+                _ => false,
+            };
+            if leaks {
+                return Err(LoweringError {
+                    message: "Sensitive strings in set or record literals cannot be redacted"
+                        .to_string(),
+                    loc: None,
+                    feature: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the policies against a schema: every attribute they read or
+    /// test must be declared by what it is read from, and every entity type
+    /// they name must be declared. Attribute reads whose type the schema pins
+    /// down are followed by a [`Instruction::Narrow`] to it, so code
+    /// generation works on the value's own WASM type.
+    pub fn apply_schema(&mut self, schema: &Schema) -> Result<(), LoweringError> {
+        let undeclared = |message: String| LoweringError {
+            message,
+            loc: None,
+            feature: None,
+        };
+
+        for uid in &self.entities {
+            let entity_type = entity_type(uid);
+            if !schema.has_entity_type(entity_type) {
+                return Err(undeclared(format!(
+                    "Entity type `{}` is not declared in the schema",
+                    entity_type
+                )));
+            }
+        }
+
+        for policy in &mut self.policies {
+            let before = std::mem::take(&mut policy.instructions);
+            let owners = attribute_owners(&before, &self.entities, schema);
+            let mut instructions = Vec::with_capacity(before.len());
+            for (idx, inst) in before.iter().cloned().enumerate() {
+                let owners = owners.get(&idx).cloned().unwrap_or_default();
+                let narrow = match &inst {
+                    Instruction::GetAttribute(attr)
+                    | Instruction::GetPresentAttribute(attr)
+                    | Instruction::HasAttribute(attr)
+                        if !schema.has_attribute(&owners, attr) =>
+                    {
+                        return Err(undeclared(format!(
+                            "Attribute `{}` is not declared in the schema",
+                            attr
+                        )));
+                    }
+                    Instruction::IsEntityType(name) if !schema.has_entity_type(name) => {
+                        return Err(undeclared(format!(
+                            "Entity type `{}` is not declared in the schema",
+                            name
+                        )));
+                    }
+                    Instruction::GetAttribute(attr) | Instruction::GetPresentAttribute(attr) => {
+                        schema.attribute_type(&owners, attr).map(wasm_type)
+                    }
+                    _ => None,
+                };
+                instructions.push(inst);
+                instructions.extend(narrow.map(Instruction::Narrow));
+            }
+            policy.instructions = isolate_type_errors(&instructions).unwrap_or(instructions);
+            policy.realign_spans(&before);
+        }
+        Ok(())
+    }
+
+    /// Check the policies' expressions against the attribute types `schema`
+    /// declares, as Cedar's validator would: comparisons, set operations and
+    /// arithmetic on operands known to have the wrong types are rejected.
+    /// Attributes without a single declared type are accepted anywhere.
+    pub fn type_check(&self, schema: &Schema) -> Result<(), TypeError> {
+        for policy in &self.policies {
+            let owners = attribute_owners(&policy.instructions, &self.entities, schema);
+            check_types(&policy.instructions, schema, &owners).map_err(|message| TypeError {
+                policy: policy.id.clone(),
+                message,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn from_parts(parts: &[PolicyParts]) -> Result<Self, LoweringError> {
+        let mut ctx = LoweringContext::default();
+        let policies = parts
+            .iter()
+            .map(|policy| {
+                let _span = tracing::debug_span!("lower_policy", id = %policy.id).entered();
+                ctx.redact_strings = policy.sensitive;
+                lower_policy_body(policy, &mut ctx).map_err(|mut e| {
+                    if parts.len() > 1 {
+                        e.message = format!("{}: {}", policy.id, e.message);
+                    }
+                    e
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ctx.finish(policies))
+    }
+}
+
+/// State shared by all policies lowered into the same module
+#[derive(Debug, Default)]
+struct LoweringContext {
+    entities: Vec<String>,
+    /// Lower string literals of the current policy as secrets
+    redact_strings: bool,
+    /// Whether any policy so far reads a template slot
+    slots: bool,
+    /// Spans of the instructions of the current policy
+    spans: Vec<Option<Span>>,
+    attribute_refs: Vec<(EntityRef, String)>,
+    entity_types: Vec<String>,
+}
+
+impl LoweringContext {
+    /// Index of an entity UID in the module's entity table, interning it on
+    /// first use
+    fn intern_entity(&mut self, uid: String) -> usize {
+        match self.entities.iter().position(|e| *e == uid) {
+            Some(idx) => idx,
+            None => {
+                self.reference_entity_type(entity_type(&uid));
+                self.entities.push(uid);
+                self.entities.len() - 1
+            }
+        }
+    }
+
+    fn reference_entity_type(&mut self, name: &str) {
+        if !self.entity_types.iter().any(|ty| ty == name) {
+            self.entity_types.push(name.to_string());
+        }
+    }
+
+    /// Record a read or test of `attr` on `entity`
+    fn reference_attribute(&mut self, entity: &Expr, attr: &str) {
+        let on = match entity.expr_kind() {
+            ExprKind::Var(var) => EntityRef::Var((*var).into()),
+            ExprKind::Lit(Literal::EntityUID(uid)) => EntityRef::Entity(uid.to_string()),
+            _ => EntityRef::Computed,
+        };
+        if !self
+            .attribute_refs
+            .iter()
+            .any(|(e, a)| *e == on && a == attr)
+        {
+            self.attribute_refs.push((on, attr.to_string()));
+        }
+    }
+
+    /// Attribute the instructions from `start` to `end` that no
+    /// subexpression claimed to `expr`, so each instruction maps to the
+    /// innermost expression it was lowered from
+    fn mark(&mut self, expr: &Expr, start: usize, end: usize) {
+        self.spans.resize(end, None);
+        if let Some(loc) = expr.source_loc() {
+            let span = Span::from_loc(loc);
+            for slot in &mut self.spans[start..] {
+                slot.get_or_insert(span);
+            }
+        }
+    }
+
+    fn finish(self, policies: Vec<LoweredFunction>) -> LoweredModule {
+        LoweredModule {
+            policies,
+            entities: self.entities,
+            slots: self.slots,
+            attribute_refs: self.attribute_refs,
+            entity_types: self.entity_types,
+            helpers: Vec::new(),
+        }
+    }
+}
+
+/// Follow the Cedar type of every value through `instructions`, `None` where
+/// it isn't known, and report the first operation whose operands have the
+/// wrong types. Code that doesn't balance is left to the code generator.
+fn check_types(
+    instructions: &[Instruction],
+    schema: &Schema,
+    owners: &BTreeMap<usize, BTreeSet<String>>,
+) -> Result<(), String> {
+    use AttributeType::*;
+    use Instruction::*;
+
+    let mut stack: Vec<Option<AttributeType>> = Vec::new();
+    // Open branches: stack height at entry and the type the then arm left
+    let mut branches: Vec<(usize, Option<AttributeType>)> = Vec::new();
+    let mut locals: Vec<(u32, Option<AttributeType>)> = Vec::new();
+
+    for (idx, inst) in instructions.iter().enumerate() {
+        let (pops, _) = inst.stack_effect();
+        let Some(height) = stack.len().checked_sub(pops) else {
+            return Ok(());
+        };
+        let operands = stack.split_off(height);
+        let result = match (inst, operands.as_slice()) {
+            (If(_), [condition]) => {
+                expect(inst, &[*condition], &[Bool])?;
+                branches.push((stack.len(), None));
+                continue;
+            }
+            (Else, _) => {
+                if let Some((height, then_ty)) = branches.last_mut() {
+                    *then_ty = stack.get(*height).copied().flatten();
+                    stack.truncate(*height);
+                }
+                continue;
+            }
+            (End, _) => {
+                let Some((height, then_ty)) = branches.pop() else {
+                    return Ok(());
+                };
+                if stack.len() > height {
+                    let else_ty = stack.get(height).copied().flatten();
+                    stack.truncate(height);
+                    stack.push(then_ty.filter(|_| then_ty == else_ty));
+                }
+                continue;
+            }
+            (Dup, [value]) => {
+                stack.extend([*value, *value]);
+                continue;
+            }
+            (Swap, [lower, top]) => {
+                stack.extend([*top, *lower]);
+                continue;
+            }
+            (PushBool(_), _) => Some(Bool),
+            (PushInt(_) | Neg, _) => Some(Long),
+            (PushDecimal(_), _) => Some(Decimal),
+            (PushDateTime(_), _) => Some(DateTime),
+            (PushString(_) | PushSecret(_), _) => Some(String),
+            (LoadVar(RequestVar::Context) | PushRecord(_), _) => Some(Record),
+            (LoadConstEntity(_) | LoadVar(_) | SlotPrincipal | SlotResource, _) => Some(Entity),
+            (PushSet(_), _) => Some(Set),
+            (Instruction::Ip, _) => Some(AttributeType::Ip),
+            (GetAttribute(attr) | GetPresentAttribute(attr), _) => {
+                schema.attribute_type(&owners.get(&idx).cloned().unwrap_or_default(), attr)
+            }
+            (Narrow(_) | TeeLocal(_), [value]) => *value,
+            (GetLocal(idx), _) => locals
+                .iter()
+                .rev()
+                .find(|(local, _)| local == idx)
+                .and_then(|(_, ty)| *ty),
+            (IfThenElse, [then_ty, else_ty, _]) => then_ty.filter(|_| then_ty == else_ty),
+            (Error(_) | Permit | Forbid | NoDecision | Return, _) => None,
+            _ => Some(Bool),
+        };
+        match (inst, operands.as_slice()) {
+            (Equal | NotEqual, [Some(a), Some(b)]) if a != b => {
+                return Err(format!(
+                    "`{}` compares {:?} with {:?}",
+                    operator(inst),
+                    a,
+                    b
+                ));
+            }
+            (LessThan | LessThanOrEqual | GreaterThan | GreaterThanOrEqual, [a, b]) => {
+                let ordered = [*a, *b]
+                    .iter()
+                    .all(|ty| matches!(ty, None | Some(Long) | Some(Decimal) | Some(DateTime)));
+                if !ordered || (a.is_some() && b.is_some() && a != b) {
+                    return Err(format!(
+                        "`{}` expects two Longs, two Decimals or two DateTimes, found {}",
+                        operator(inst),
+                        describe(&operands)
+                    ));
+                }
+            }
+            (And | Or | Not, _) => expect(inst, &operands, &[Bool])?,
+            (Neg, _) => expect(inst, &operands, &[Long])?,
+            (Contains, [set, _]) => expect(inst, &[*set], &[Set])?,
+            (ContainsAll | ContainsAny, _) => expect(inst, &operands, &[Set])?,
+            (TeeLocal(idx), _) => locals.push((*idx, result)),
+            _ => {}
+        }
+        stack.extend(std::iter::repeat_n(result, inst.stack_effect().1));
+    }
+    Ok(())
+}
+
+/// Check that every operand whose type is known has one of `accepted`
+fn expect(
+    inst: &Instruction,
+    operands: &[Option<AttributeType>],
+    accepted: &[AttributeType],
+) -> Result<(), String> {
+    if operands.iter().flatten().all(|ty| accepted.contains(ty)) {
+        return Ok(());
+    }
+    let accepted: Vec<String> = accepted.iter().map(|ty| format!("{:?}", ty)).collect();
+    Err(format!(
+        "`{}` expects {}, found {}",
+        operator(inst),
+        accepted.join(" or "),
+        describe(operands)
+    ))
+}
+
+/// Cedar spelling of the operation an instruction performs, for messages
+fn operator(inst: &Instruction) -> String {
+    match inst {
+        Instruction::Equal => "==".to_string(),
+        Instruction::NotEqual => "!=".to_string(),
+        Instruction::LessThan => "<".to_string(),
+        Instruction::LessThanOrEqual => "<=".to_string(),
+        Instruction::GreaterThan => ">".to_string(),
+        Instruction::GreaterThanOrEqual => ">=".to_string(),
+        Instruction::And => "&&".to_string(),
+        Instruction::Or => "||".to_string(),
+        Instruction::Not => "!".to_string(),
+        Instruction::Neg => "-".to_string(),
+        Instruction::If(_) => "if".to_string(),
+        Instruction::Contains => "contains".to_string(),
+        Instruction::ContainsAll => "containsAll".to_string(),
+        Instruction::ContainsAny => "containsAny".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Render operand types for messages, `?` for unknown ones
+fn describe(operands: &[Option<AttributeType>]) -> String {
+    let types: Vec<String> = operands
+        .iter()
+        .map(|ty| ty.map_or("?".to_string(), |ty| format!("{:?}", ty)))
+        .collect();
+    types.join(" and ")
+}
+
+/// Owners (see [`Schema`]) of the value each attribute read or `has` test in
+/// `instructions` applies to, by instruction index; missing or empty where
+/// they aren't known. The scope comes first, as a conjunction of checks, so
+/// an `is` or `==` check in it pins the type of a request variable wherever
+/// the condition runs; an unpinned one can be any the actions apply to.
+fn attribute_owners(
+    instructions: &[Instruction],
+    entities: &[String],
+    schema: &Schema,
+) -> BTreeMap<usize, BTreeSet<String>> {
+    use Instruction::*;
+
+    let scope_end = instructions
+        .iter()
+        .position(|inst| matches!(inst, If(_) | IfThenElse))
+        .unwrap_or(instructions.len());
+    let scope = &instructions[..scope_end];
+    // Entity type each request variable is pinned to, and the action's UID
+    let mut pinned: [Option<String>; 4] = Default::default();
+    let mut action = None;
+    for (i, inst) in scope.iter().enumerate() {
+        match (inst, scope.get(i + 1), scope.get(i + 2)) {
+            (LoadVar(var), Some(IsEntityType(type_name)), _) => {
+                pinned[*var as usize] = Some(type_name.clone());
+            }
+            (LoadVar(var), Some(LoadConstEntity(idx)), Some(Equal)) => {
+                let Some(uid) = entities.get(*idx) else {
+                    continue;
+                };
+                pinned[*var as usize] = Some(entity_type(uid).to_string());
+                if *var == RequestVar::Action {
+                    action = Some(uid.as_str());
+                }
+            }
+            _ => {}
+        }
+    }
+    let request = |var: RequestVar| match (&pinned[var as usize], var) {
+        (Some(type_name), RequestVar::Principal | RequestVar::Resource) => {
+            BTreeSet::from([type_name.clone()])
+        }
+        (_, RequestVar::Principal) => schema.principal_types(action),
+        (_, RequestVar::Resource) => schema.resource_types(action),
+        (_, RequestVar::Context) => schema.context(action),
+        (_, RequestVar::Action) => BTreeSet::new(),
+    };
+
+    let mut owners = BTreeMap::new();
+    let mut stack: Vec<BTreeSet<String>> = Vec::new();
+    // Open branches: stack height at entry and what the then arm left
+    let mut branches: Vec<(usize, Option<BTreeSet<String>>)> = Vec::new();
+    let mut locals: BTreeMap<u32, BTreeSet<String>> = BTreeMap::new();
+    for (idx, inst) in instructions.iter().enumerate() {
+        match inst {
+            If(_) => {
+                stack.pop();
+                branches.push((stack.len(), None));
+            }
+            Else => {
+                if let Some((height, then_val)) = branches.last_mut() {
+                    *then_val = stack.get(*height).cloned();
+                    stack.truncate(*height);
+                }
+            }
+            End => {
+                if let Some((height, then_val)) = branches.pop() {
+                    let else_val = stack.get(height).cloned();
+                    stack.truncate(height);
+                    if let (Some(then_val), Some(else_val)) = (then_val, else_val) {
+                        // Either arm's value, unless one of them is unknown
+                        stack.push(if then_val.is_empty() || else_val.is_empty() {
+                            BTreeSet::new()
+                        } else {
+                            then_val.union(&else_val).cloned().collect()
+                        });
+                    }
+                }
+            }
+            Dup => stack.extend(stack.last().cloned()),
+            Swap => {
+                if let Some(top) = stack.pop() {
+                    let at = stack.len().saturating_sub(1);
+                    stack.insert(at, top);
+                }
+            }
+            TeeLocal(local) => {
+                locals.insert(*local, stack.last().cloned().unwrap_or_default());
+            }
+            GetLocal(local) => stack.push(locals.get(local).cloned().unwrap_or_default()),
+            _ => {
+                let (pops, pushes) = inst.stack_effect();
+                let mut operands = stack.split_off(stack.len().saturating_sub(pops));
+                let value = match inst {
+                    LoadVar(var) => request(*var),
+                    LoadConstEntity(entity) => entities
+                        .get(*entity)
+                        .map(|uid| BTreeSet::from([entity_type(uid).to_string()]))
+                        .unwrap_or_default(),
+                    GetAttribute(attr) | GetPresentAttribute(attr) => {
+                        let from = operands.pop().unwrap_or_default();
+                        let value = schema.attribute_owners(&from, attr);
+                        owners.insert(idx, from);
+                        value
+                    }
+                    HasAttribute(_) => {
+                        owners.insert(idx, operands.pop().unwrap_or_default());
+                        BTreeSet::new()
+                    }
+                    Narrow(_) => operands.pop().unwrap_or_default(),
+                    _ => BTreeSet::new(),
+                };
+                stack.extend(std::iter::repeat_n(value, pushes));
+            }
+        }
+    }
+    owners
+}
+
+/// Replace each instruction whose operands have types no coercion can fix,
+/// together with the code computing them, by an `Error` of the type it would
+/// have produced. Only schema-typed values are precise enough for this;
+/// `None` if the code doesn't balance, which the code generator reports.
+fn isolate_type_errors(instructions: &[Instruction]) -> Option<Vec<Instruction>> {
+    let mut out = Vec::with_capacity(instructions.len());
+    // Type of each value on the stack and where the code computing it starts
+    let mut stack: Vec<(WasmType, usize)> = Vec::new();
+    // Open branches: stack height at entry, start of the condition, declared
+    // type and the type the then arm left
+    let mut branches: Vec<(usize, usize, Option<WasmType>, Option<WasmType>)> = Vec::new();
+
+    for inst in instructions {
+        match inst {
+            Instruction::If(result) => {
+                let (condition, start) = stack.pop()?;
+                if check_operands(inst, &[condition]).is_err() {
+                    out.truncate(start);
+                    out.push(Instruction::Error(WasmType::Bool));
+                }
+                branches.push((stack.len(), start, *result, None));
+            }
+            Instruction::Else => {
+                let (height, _, _, then_ty) = branches.last_mut()?;
+                *then_ty = stack.get(*height).map(|(ty, _)| *ty);
+                stack.truncate(*height);
+            }
+            Instruction::End => {
+                let (height, start, result, then_ty) = branches.pop()?;
+                let else_ty = stack.get(height).map(|(ty, _)| *ty);
+                stack.truncate(height);
+                let result = match (result, then_ty, else_ty) {
+                    (Some(ty), ..) => Some(ty),
+                    (None, Some(then_ty), Some(else_ty)) => {
+                        Some(unify_branches(then_ty, else_ty).ok()?)
+                    }
+                    _ => None,
+                };
+                stack.extend(result.map(|ty| (ty, start)));
+            }
+            // The copy starts at the `Dup`, so replacing what consumes it
+            // leaves the original in place
+            Instruction::Dup => {
+                let (ty, _) = *stack.last()?;
+                stack.push((ty, out.len()));
+            }
+            // Neither value can be replaced without the other any more, so
+            // both start where the lower one did
+            Instruction::Swap => {
+                let (top, _) = stack.pop()?;
+                let (lower, start) = stack.pop()?;
+                stack.extend([(top, start), (lower, start)]);
+            }
+            _ => {
+                let (pops, _) = inst.stack_effect();
+                let operands = stack.split_off(stack.len().checked_sub(pops)?);
+                let types: Vec<WasmType> = operands.iter().map(|(ty, _)| *ty).collect();
+                let start = operands.first().map_or(out.len(), |(_, start)| *start);
+                let result = result_type(inst, &types);
+                if check_operands(inst, &types).is_err() {
+                    // The code computing the operands also computed a value
+                    // that stays on the stack
+                    if stack.last().is_some_and(|(_, below)| *below >= start) {
+                        return None;
+                    }
+                    out.truncate(start);
+                    out.push(Instruction::Error(result?));
+                } else {
+                    out.push(inst.clone());
+                }
+                stack.extend(result.map(|ty| (ty, start)));
+                continue;
+            }
+        }
+        out.push(inst.clone());
+    }
+    Some(out)
+}
+
+/// Type of an entity UID (`Type::"id"`)
+fn entity_type(uid: &str) -> &str {
+    // Type names can't contain quotes, so the first `::"` ends the type
+    uid.find("::\"").map_or(uid, |end| &uid[..end])
+}
+
+/// WASM type of attribute values of a schema type
+fn wasm_type(ty: AttributeType) -> WasmType {
+    match ty {
+        AttributeType::Bool => WasmType::Bool,
+        AttributeType::Long | AttributeType::Decimal | AttributeType::DateTime => WasmType::Int,
+        AttributeType::String => WasmType::String,
+        AttributeType::Entity => WasmType::Entity,
+        AttributeType::Record => WasmType::Record,
+        AttributeType::Set => WasmType::Set,
+        AttributeType::Ip => WasmType::Ip,
+    }
+}
+
+/// Lower a policy's effect and condition into a single evaluation function
+fn lower_policy_body(
+    policy: &PolicyParts,
+    ctx: &mut LoweringContext,
+) -> Result<LoweredFunction, LoweringError> {
+    // The policy returns NoDecision unless the condition holds, in which
+    // case it returns its effect. IfThenElse wants the value for true below
+    // the one for false, so the two are swapped into place.
+    ctx.spans.clear();
+    let mut instructions = vec![Instruction::NoDecision];
+    match policy.effect {
+        PolicyEffect::Permit => instructions.push(Instruction::Permit),
+        PolicyEffect::Forbid => instructions.push(Instruction::Forbid),
+    }
+    instructions.push(Instruction::Swap);
+
+    // Compile the condition: the scope constraints, then each clause
+    // conjoined in source order, with `unless` clauses negated. A clause only
+    // runs if everything before it held, as in Cedar.
+    tracing::trace!(scope = ?policy.scope, "compiling scope");
+    compile_scope(&policy.scope, &mut instructions, ctx);
+    for clause in &policy.clauses {
+        let start = instructions.len();
+        match clause {
+            Clause::When(condition) => {
+                tracing::trace!(%condition, "compiling when clause");
+                compile_and_then(condition, &mut instructions, ctx)?;
+                ctx.mark(condition, start, instructions.len());
+            }
+            Clause::Unless(condition) => {
+                tracing::trace!(%condition, "compiling unless clause");
+                instructions.push(Instruction::If(Some(WasmType::Bool)));
+                compile_expr(condition, &mut instructions, ctx)?;
+                instructions.push(Instruction::Not);
+                instructions.push(Instruction::Else);
+                instructions.push(Instruction::PushBool(false));
+                instructions.push(Instruction::End);
+                ctx.mark(condition, start, instructions.len());
+            }
+        }
+    }
+
+    instructions.push(Instruction::IfThenElse);
+    instructions.push(Instruction::Return);
+    ctx.spans.resize(instructions.len(), None);
+
+    let function = LoweredFunction {
+        id: policy.id.clone(),
+        effect: policy.effect,
+        instructions,
+        spans: std::mem::take(&mut ctx.spans),
+    };
+    tracing::trace!("lowered\n{}", function);
+    Ok(function)
+}
+
+/// Compile the scope as checks on the request parameters, conjoined in
+/// principal, action, resource order. Unconstrained variables add no check.
+fn compile_scope(scope: &Scope, instructions: &mut Vec<Instruction>, ctx: &mut LoweringContext) {
+    let checks =
+        compile_scope_constraint(RequestVar::Principal, &scope.principal, instructions, ctx)
+            + compile_action_scope(&scope.action, instructions, ctx)
+            + compile_scope_constraint(RequestVar::Resource, &scope.resource, instructions, ctx);
+
+    // All checks are pushed first, then joined
+    match checks {
+        0 => instructions.push(Instruction::PushBool(true)),
+        n => instructions.extend(std::iter::repeat_n(Instruction::And, n - 1)),
+    }
+}
+
+fn compile_scope_constraint(
+    var: RequestVar,
+    constraint: &ScopeConstraint,
+    instructions: &mut Vec<Instruction>,
+    ctx: &mut LoweringContext,
+) -> usize {
+    // A slot is the parameter bound to it; only `principal` and `resource`
+    // constraints can have one, each its own
+    let entity = |entity: &ScopeEntity, ctx: &mut LoweringContext| match entity {
+        ScopeEntity::Uid(uid) => Instruction::LoadConstEntity(ctx.intern_entity(uid.clone())),
+        ScopeEntity::Slot(_) => {
+            ctx.slots = true;
+            match var {
+                RequestVar::Principal => Instruction::SlotPrincipal,
+                _ => Instruction::SlotResource,
+            }
+        }
+    };
+
+    match constraint {
+        ScopeConstraint::Any => return 0,
+        ScopeConstraint::Eq(target) => {
+            let target = entity(target, ctx);
+            instructions.push(Instruction::LoadVar(var));
+            instructions.push(target);
+            instructions.push(Instruction::Equal);
+        }
+        ScopeConstraint::In(target) => {
+            let target = entity(target, ctx);
+            instructions.push(Instruction::LoadVar(var));
+            instructions.push(target);
+            instructions.push(Instruction::In);
+        }
+        ScopeConstraint::Is(entity_type) => {
+            ctx.reference_entity_type(entity_type);
+            instructions.push(Instruction::LoadVar(var));
+            instructions.push(Instruction::IsEntityType(entity_type.clone()));
+        }
+        ScopeConstraint::IsIn(entity_type, target) => {
+            ctx.reference_entity_type(entity_type);
+            let target = entity(target, ctx);
+            instructions.push(Instruction::LoadVar(var));
+            instructions.push(Instruction::IsEntityType(entity_type.clone()));
+            instructions.push(Instruction::LoadVar(var));
+            instructions.push(target);
+            instructions.push(Instruction::In);
+            instructions.push(Instruction::And);
+        }
+    }
+    1
+}
+
+/// `action in [a, b]` holds if the action is in any of the listed actions,
+/// checked in order until one matches, as `||` would
+fn compile_action_scope(
+    constraint: &ActionScope,
+    instructions: &mut Vec<Instruction>,
+    ctx: &mut LoweringContext,
+) -> usize {
+    match constraint {
+        ActionScope::Any => return 0,
+        ActionScope::Eq(uid) => {
+            let idx = ctx.intern_entity(uid.clone());
+            instructions.push(Instruction::LoadVar(RequestVar::Action));
+            instructions.push(Instruction::LoadConstEntity(idx));
+            instructions.push(Instruction::Equal);
+        }
+        ActionScope::In(uids) if uids.is_empty() => {
+            instructions.push(Instruction::PushBool(false));
+        }
+        ActionScope::In(uids) => {
+            for (i, uid) in uids.iter().enumerate() {
+                let idx = ctx.intern_entity(uid.clone());
+                instructions.push(Instruction::LoadVar(RequestVar::Action));
+                instructions.push(Instruction::LoadConstEntity(idx));
+                instructions.push(Instruction::In);
+                if i + 1 < uids.len() {
+                    instructions.push(Instruction::If(Some(WasmType::Bool)));
+                    instructions.push(Instruction::PushBool(true));
+                    instructions.push(Instruction::Else);
+                }
+            }
+            instructions.extend(std::iter::repeat_n(Instruction::End, uids.len() - 1));
+        }
+    }
+    1
+}
+
+/// The comparison a negated `<`, `<=` or `==` is equivalent to, with its
+/// operands
+fn negated_comparison(expr: &Expr) -> Option<(Instruction, &Expr, &Expr)> {
+    match expr.expr_kind() {
+        ExprKind::BinaryApp {
+            op: BinaryOp::Eq,
+            arg1,
+            arg2,
+        } => Some((Instruction::NotEqual, arg1, arg2)),
+        ExprKind::BinaryApp {
+            op: BinaryOp::LessEq,
+            arg1,
+            arg2,
+        } => Some((Instruction::GreaterThan, arg1, arg2)),
+        ExprKind::BinaryApp {
+            op: BinaryOp::Less,
+            arg1,
+            arg2,
+        } => Some((Instruction::GreaterThanOrEqual, arg1, arg2)),
+        _ => None,
+    }
+}
+
+/// Conjoin `expr` with the boolean on top of the stack, evaluating it only
+/// if that boolean is true
+fn compile_and_then(
+    expr: &Expr,
+    instructions: &mut Vec<Instruction>,
+    ctx: &mut LoweringContext,
+) -> Result<(), LoweringError> {
+    instructions.push(Instruction::If(Some(WasmType::Bool)));
+    compile_expr(expr, instructions, ctx)?;
+    instructions.push(Instruction::Else);
+    instructions.push(Instruction::PushBool(false));
+    instructions.push(Instruction::End);
+    Ok(())
+}
+
+/// Test `entity in parent` for each parent in turn, like
+/// `entity in a || entity in b`, stopping at the first that holds
+fn compile_in_any(
+    entity: &Expr,
+    parents: &[Expr],
+    instructions: &mut Vec<Instruction>,
+    ctx: &mut LoweringContext,
+) -> Result<(), LoweringError> {
+    let Some((parent, rest)) = parents.split_first() else {
+        instructions.push(Instruction::PushBool(false));
+        return Ok(());
+    };
+    compile_expr(entity, instructions, ctx)?;
+    compile_expr(parent, instructions, ctx)?;
+    instructions.push(Instruction::In);
+    if !rest.is_empty() {
+        instructions.push(Instruction::If(Some(WasmType::Bool)));
+        instructions.push(Instruction::PushBool(true));
+        instructions.push(Instruction::Else);
+        compile_in_any(entity, rest, instructions, ctx)?;
+        instructions.push(Instruction::End);
+    }
+    Ok(())
+}
+
+/// Compile a Cedar expression into instructions, recording the span each
+/// one came from
+fn compile_expr(
+    expr: &Expr,
+    instructions: &mut Vec<Instruction>,
+    ctx: &mut LoweringContext,
+) -> Result<(), LoweringError> {
+    let start = instructions.len();
+    compile_expr_kind(expr, instructions, ctx)?;
+    ctx.mark(expr, start, instructions.len());
+    Ok(())
+}
+
+fn compile_expr_kind(
+    expr: &Expr,
+    instructions: &mut Vec<Instruction>,
+    ctx: &mut LoweringContext,
+) -> Result<(), LoweringError> {
+    use ExprKind::*;
+
+    match expr.expr_kind() {
+        Lit(lit) => {
+            compile_literal(lit, instructions, ctx);
+            Ok(())
+        }
+
+        // Binary operations
+        BinaryApp { op, arg1, arg2 } => {
+            // `x in [a, b]` holds if `x` is in any of the listed entities
+            if let (BinaryOp::In, Set(parents)) = (op, arg2.expr_kind()) {
+                return compile_in_any(arg1, parents, instructions, ctx);
+            }
+            compile_expr(arg1, instructions, ctx)?;
+            compile_expr(arg2, instructions, ctx)?;
+
+            match op {
+                BinaryOp::Eq => instructions.push(Instruction::Equal),
+                BinaryOp::In => instructions.push(Instruction::In),
+                BinaryOp::Less => instructions.push(Instruction::LessThan),
+                BinaryOp::LessEq => instructions.push(Instruction::LessThanOrEqual),
+                BinaryOp::Contains => instructions.push(Instruction::Contains),
+                BinaryOp::ContainsAll => instructions.push(Instruction::ContainsAll),
+                BinaryOp::ContainsAny => instructions.push(Instruction::ContainsAny),
+                BinaryOp::Add => return Err(LoweringError::unsupported("addition", expr)),
+                BinaryOp::Sub => return Err(LoweringError::unsupported("subtraction", expr)),
+                BinaryOp::Mul => return Err(LoweringError::unsupported("multiplication", expr)),
+                BinaryOp::GetTag | BinaryOp::HasTag => {
+                    let feature = format!("entity tag method `{}`", op);
+                    return Err(LoweringError::unsupported(feature, expr));
+                }
+            }
+            Ok(())
+        }
+
+        // Unary operations
+        UnaryApp { op, arg } => {
+            // Cedar parses `a > b` as `!(a <= b)`, `a >= b` as `!(a < b)` and
+            // `a != b` as `!(a == b)`
+            if let (UnaryOp::Not, Some((inst, arg1, arg2))) = (op, negated_comparison(arg)) {
+                compile_expr(arg1, instructions, ctx)?;
+                compile_expr(arg2, instructions, ctx)?;
+                instructions.push(inst);
+                return Ok(());
+            }
+            compile_expr(arg, instructions, ctx)?;
+
+            match op {
+                UnaryOp::Not => instructions.push(Instruction::Not),
+                UnaryOp::Neg => instructions.push(Instruction::Neg),
+                UnaryOp::IsEmpty => {
+                    return Err(LoweringError::unsupported("set method `isEmpty`", expr))
+                }
+            }
+            Ok(())
+        }
+
+        // Logical AND: the right side only runs if the left is true, so its
+        // errors are suppressed otherwise
+        And { left, right } => {
+            compile_expr(left, instructions, ctx)?;
+            compile_and_then(right, instructions, ctx)
+        }
+
+        // Logical OR: the right side only runs if the left is false
+        Or { left, right } => {
+            compile_expr(left, instructions, ctx)?;
+            instructions.push(Instruction::If(Some(WasmType::Bool)));
+            instructions.push(Instruction::PushBool(true));
+            instructions.push(Instruction::Else);
+            compile_expr(right, instructions, ctx)?;
+            instructions.push(Instruction::End);
+            Ok(())
+        }
+
+        // Attribute access: entity.attribute
+        GetAttr { expr: entity, attr } => {
+            compile_expr(entity, instructions, ctx)?;
+            ctx.reference_attribute(entity, attr);
+            instructions.push(Instruction::GetAttribute(attr.to_string()));
+            Ok(())
+        }
+
+        // Check if attribute exists
+        HasAttr { expr: entity, attr } => {
+            compile_expr(entity, instructions, ctx)?;
+            ctx.reference_attribute(entity, attr);
+            instructions.push(Instruction::HasAttribute(attr.to_string()));
+            Ok(())
+        }
+
+        // Set literals are constants in static data
+        Set(elems) => {
+            let elems = elems
+                .iter()
+                .map(|elem| constant(elem, ctx, "Set literal elements"))
+                .collect::<Result<Vec<_>, _>>()?;
+            // Static set data holds one type of element
+            let kind = |elem: &SetElement| std::mem::discriminant(elem);
+            if elems.iter().any(|elem| kind(elem) != kind(&elems[0])) {
+                return Err(LoweringError::unsupported(
+                    "set literal with elements of different types",
+                    expr,
+                ));
+            }
+            instructions.push(Instruction::PushSet(elems));
+            Ok(())
+        }
+
+        // Record literals too, in field-name order
+        Record(fields) => {
+            let fields = fields
+                .iter()
+                .map(|(name, field)| {
+                    Ok((
+                        name.to_string(),
+                        constant(field, ctx, "Record literal fields")?,
+                    ))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            instructions.push(Instruction::PushRecord(fields));
+            Ok(())
+        }
+
+        // Entity type test: expr is Type. The parser already splits
+        // `expr is Type in parent` into an `is` and an `in` joined by `&&`.
+        Is { expr, entity_type } => {
+            ctx.reference_entity_type(&entity_type.to_string());
+            compile_expr(expr, instructions, ctx)?;
+            instructions.push(Instruction::IsEntityType(entity_type.to_string()));
+            Ok(())
+        }
+
+        // Decimal and datetime literals are integers, parsed here
+        ExtensionFunctionApp { fn_name, args }
+            if matches!(fn_name.to_string().as_str(), "decimal" | "datetime") =>
+        {
+            let name = fn_name.to_string();
+            let literal = match args.as_slice() {
+                [arg] => match arg.expr_kind() {
+                    Lit(Literal::String(s)) => s,
+                    _ => {
+                        let message = format!("{}() takes a string literal", name);
+                        return Err(LoweringError::new(message, expr));
+                    }
+                },
+                _ => {
+                    let message = format!("{}() takes one argument", name);
+                    return Err(LoweringError::new(message, expr));
+                }
+            };
+            let inst = if name == "decimal" {
+                parse_decimal(literal).map(Instruction::PushDecimal)
+            } else {
+                parse_datetime(literal).map(Instruction::PushDateTime)
+            };
+            instructions.push(inst.map_err(|e| LoweringError::new(e, expr))?);
+            Ok(())
+        }
+
+        // The `ipaddr` and `decimal` extensions; methods take their receiver
+        // as the first argument
+        ExtensionFunctionApp { fn_name, args } => {
+            let inst = match fn_name.to_string().as_str() {
+                "lessThan" => Instruction::LessThan,
+                "lessThanOrEqual" => Instruction::LessThanOrEqual,
+                "greaterThan" => Instruction::GreaterThan,
+                "greaterThanOrEqual" => Instruction::GreaterThanOrEqual,
+                "ip" => Instruction::Ip,
+                "isInRange" => Instruction::IsInRange,
+                "isIpv4" => Instruction::IsIpv4,
+                "isIpv6" => Instruction::IsIpv6,
+                "isLoopback" => Instruction::IsLoopback,
+                "isMulticast" => Instruction::IsMulticast,
+                name => {
+                    let feature = format!("extension call `{}`", name);
+                    return Err(LoweringError::unsupported(feature, expr));
+                }
+            };
+            for arg in args.iter() {
+                compile_expr(arg, instructions, ctx)?;
+            }
+            instructions.push(inst);
+            Ok(())
+        }
+
+        // Glob match: expr like "pattern"
+        Like { expr, pattern } => {
+            compile_expr(expr, instructions, ctx)?;
+            let pattern = like_pattern(pattern);
+            instructions.push(if ctx.redact_strings {
+                Instruction::LikeSecret(pattern)
+            } else {
+                Instruction::Like(pattern)
+            });
+            Ok(())
+        }
+
+        // If-then-else
+        If {
+            test_expr,
+            then_expr,
+            else_expr,
+        } => {
+            // Only the branch taken is evaluated, as in Cedar; the code
+            // generator unifies the types the two branches leave
+            compile_expr(test_expr, instructions, ctx)?;
+            instructions.push(Instruction::If(None));
+            compile_expr(then_expr, instructions, ctx)?;
+            instructions.push(Instruction::Else);
+            compile_expr(else_expr, instructions, ctx)?;
+            instructions.push(Instruction::End);
+            Ok(())
+        }
+
+        // Variable references (principal, action, resource, context)
+        Var(var) => {
+            // The request arrives as `evaluate` parameters
+            instructions.push(Instruction::LoadVar((*var).into()));
+            Ok(())
+        }
+
+        Slot(_) => Err(LoweringError::unsupported(
+            "template slot in a condition",
+            expr,
+        )),
+        // Only partial evaluation puts unknowns in policies; the compiled
+        // module always decides on a complete request
+        Unknown(unknown) => {
+            let feature = format!("partial evaluation (unknown `{}`)", unknown.name);
+            Err(LoweringError::unsupported(feature, expr))
+        }
+    }
+}
+
+/// Compile a literal value
+fn compile_literal(lit: &Literal, instructions: &mut Vec<Instruction>, ctx: &mut LoweringContext) {
+    match lit {
+        // Booleans are i32, matching what comparisons produce
+        Literal::Bool(b) => instructions.push(Instruction::PushBool(*b)),
+        Literal::Long(i) => instructions.push(Instruction::PushInt(*i)),
+        Literal::String(s) if ctx.redact_strings => {
+            instructions.push(Instruction::PushSecret(s.to_string()))
+        }
+        Literal::String(s) => instructions.push(Instruction::PushString(s.to_string())),
+        Literal::EntityUID(uid) => {
+            // Entities are compared by their index in the module's entity table
+            let idx = ctx.intern_entity(uid.to_string());
+            instructions.push(Instruction::LoadConstEntity(idx));
+        }
+    }
+}
+
+/// Parse a datetime literal into milliseconds since the Unix epoch. Cedar
+/// accepts a date (`YYYY-MM-DD`), optionally followed by a time
+/// (`Thh:mm:ss`), milliseconds (`.SSS`) and either `Z` or a UTC offset
+/// (`+hhmm` or `-hhmm`).
+fn parse_datetime(literal: &str) -> Result<i64, String> {
+    let invalid = || format!("Invalid datetime literal {:?}", literal);
+    // Fixed-width runs of ASCII digits, separated by single characters
+    let number = |s: &str| {
+        s.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| s.parse::<i64>().ok())
+            .flatten()
+            .ok_or_else(invalid)
+    };
+    let bytes = literal.as_bytes();
+    let separated = |at: usize, sep: u8| bytes.get(at) == Some(&sep);
+
+    if literal.len() < 10 || !literal.is_ascii() || !separated(4, b'-') || !separated(7, b'-') {
+        return Err(invalid());
+    }
+    let (year, month, day) = (
+        number(&literal[..4])?,
+        number(&literal[5..7])?,
+        number(&literal[8..10])?,
+    );
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [
+        31,
+        if leap { 29 } else { 28 },
+        31,
+        30,
+        31,
+        30,
+        31,
+        31,
+        30,
+        31,
+        30,
+        31,
+    ];
+    if !(1..=12).contains(&month) || day < 1 || day > month_days[month as usize - 1] {
+        return Err(format!(
+            "Datetime literal {:?} is not a valid date",
+            literal
+        ));
+    }
+
+    let mut ms = 0;
+    let mut offset = 0;
+    let rest = &literal[10..];
+    if !rest.is_empty() {
+        if rest.len() < 10 || !separated(10, b'T') || !separated(13, b':') || !separated(16, b':') {
+            return Err(invalid());
+        }
+        let (hour, minute, second) = (
+            number(&rest[1..3])?,
+            number(&rest[4..6])?,
+            number(&rest[7..9])?,
+        );
+        if hour > 23 || minute > 59 || second > 59 {
+            return Err(format!(
+                "Datetime literal {:?} is not a valid time",
+                literal
+            ));
+        }
+        let mut zone = &rest[9..];
+        let mut millis = 0;
+        if let Some(fraction) = zone.strip_prefix('.') {
+            millis = number(fraction.get(..3).ok_or_else(invalid)?)?;
+            zone = &fraction[3..];
+        }
+        offset = match zone.as_bytes() {
+            [b'Z'] => 0,
+            [sign @ (b'+' | b'-'), ..] if zone.len() == 5 => {
+                let (hours, minutes) = (number(&zone[1..3])?, number(&zone[3..])?);
+                if hours > 23 || minutes > 59 {
+                    return Err(format!(
+                        "Datetime literal {:?} has an invalid offset",
+                        literal
+                    ));
+                }
+                let offset = (hours * 60 + minutes) * 60_000;
+                if *sign == b'+' {
+                    offset
+                } else {
+                    -offset
+                }
+            }
+            _ => return Err(invalid()),
+        };
+        ms = ((hour * 60 + minute) * 60 + second) * 1000 + millis;
+    }
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Ok(days * 86_400_000 + ms - offset)
+}
+
+/// Parse a decimal literal (`-?digits.digits`) into its scaled value
+fn parse_decimal(literal: &str) -> Result<i64, String> {
+    let invalid = || format!("Invalid decimal literal {:?}", literal);
+    let (whole, fraction) = literal.split_once('.').ok_or_else(invalid)?;
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let magnitude = whole.strip_prefix('-').unwrap_or(whole);
+    if !digits(magnitude) || !digits(fraction) {
+        return Err(invalid());
+    }
+    if fraction.len() > DECIMAL_DIGITS as usize {
+        return Err(format!(
+            "Decimal literal {:?} has more than {} fractional digits",
+            literal, DECIMAL_DIGITS
+        ));
+    }
+
+    // Scale by appending zeros, so the sign applies to the whole value
+    let scaled = format!(
+        "{}{}{}",
+        whole,
+        fraction,
+        "0".repeat(DECIMAL_DIGITS as usize - fraction.len())
+    );
+    scaled
+        .parse()
+        .map_err(|_| format!("Decimal literal {:?} is out of range", literal))
+}
+
+/// Lower a set element or record field, which must be a constant; `what`
+/// names them in errors
+fn constant(
+    expr: &Expr,
+    ctx: &mut LoweringContext,
+    what: &str,
+) -> Result<SetElement, LoweringError> {
+    match expr.expr_kind() {
+        ExprKind::Lit(Literal::Bool(b)) => Ok(SetElement::Bool(*b)),
+        ExprKind::Lit(Literal::Long(i)) => Ok(SetElement::Int(*i)),
+        ExprKind::Lit(Literal::String(_)) if ctx.redact_strings => Err(LoweringError::new(
+            format!("{} cannot be strings in @sensitive policies", what),
+            expr,
+        )),
+        ExprKind::Lit(Literal::String(s)) => Ok(SetElement::String(s.to_string())),
+        ExprKind::Lit(Literal::EntityUID(uid)) => {
+            Ok(SetElement::Entity(ctx.intern_entity(uid.to_string())))
+        }
+        _ => Err(LoweringError::new(
+            format!("{} must be literals", what),
+            expr,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lowering_simple_permit() {
+        let template =
+            crate::ast::adapter::parse_template("permit(principal, action, resource);").unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        assert!(module.entities.is_empty());
+        assert_eq!(module.policies().len(), 1);
+        assert_eq!(module.policies()[0].effect, PolicyEffect::Permit);
+        // An unconstrained scope and no conditions: permit when `true`
+        assert_eq!(
+            module.policies()[0].instructions(),
+            &[
+                Instruction::NoDecision,
+                Instruction::Permit,
+                Instruction::Swap,
+                Instruction::PushBool(true),
+                Instruction::IfThenElse,
+                Instruction::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassembly() {
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(principal == User::"alice", action, resource)
+            when { resource.owner == principal && resource.size < 10 };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        let disassembly = module.to_string();
+        let expected = [
+            r#"entity 0: User::"alice""#,
+            "",
+            "policy0 (permit):",
+            "  no_decision",
+            "  permit",
+            "  swap",
+            "  load_var principal",
+            "  load_entity 0",
+            "  eq",
+            "  if bool",
+            "    load_var resource",
+            r#"    get_attr "owner""#,
+            "    load_var principal",
+            "    eq",
+            "    if bool",
+            "      load_var resource",
+            r#"      get_attr "size""#,
+            "      push_int 10",
+            "      lt",
+            "    else",
+            "      push_bool false",
+            "    end",
+            "  else",
+            "    push_bool false",
+            "  end",
+            "  if_then_else",
+            "  return",
+        ];
+        assert_eq!(disassembly.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_entity_interning_across_policies() {
+        let policy_set = crate::ast::adapter::parse_policy_set(
+            r#"
+            permit(principal, action, resource) when { principal == User::"alice" };
+            forbid(principal, action, resource) when { resource == User::"bob" };
+            permit(principal, action, resource) when { resource == User::"alice" };
+            "#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_policy_set(&policy_set).unwrap();
+
+        assert_eq!(
+            module.entities,
+            vec![r#"User::"alice""#.to_string(), r#"User::"bob""#.to_string()]
+        );
+        let entity_of = |policy: &LoweredFunction| {
+            policy.instructions.iter().find_map(|inst| match inst {
+                Instruction::LoadConstEntity(idx) => Some(*idx),
+                _ => None,
+            })
+        };
+        assert_eq!(entity_of(&module.policies[0]), Some(0));
+        assert_eq!(entity_of(&module.policies[1]), Some(1));
+        assert_eq!(entity_of(&module.policies[2]), Some(0));
+    }
+
+    #[test]
+    fn test_entity_literal_lowers_to_load_const_entity() {
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(principal, action, resource) when { principal == User::"alice" };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        assert_eq!(module.entities, vec![r#"User::"alice""#.to_string()]);
+        let instructions = &module.policies[0].instructions;
+        assert!(instructions
+            .iter()
+            .any(|inst| matches!(inst, Instruction::LoadConstEntity(0))));
+        assert!(!instructions
+            .iter()
+            .any(|inst| matches!(inst, Instruction::PushInt(_))));
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(principal, action, resource)
+            when { context.groups.contains("x") && [User::"a", User::"b"].containsAny(resource.owners) };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        let instructions = &module.policies[0].instructions;
+        assert!(instructions
+            .iter()
+            .any(|inst| matches!(inst, Instruction::Contains)));
+        assert!(instructions.iter().any(|inst| matches!(
+            inst,
+            Instruction::PushSet(elems) if *elems == [SetElement::Entity(0), SetElement::Entity(1)]
+        )));
+        assert!(instructions
+            .iter()
+            .any(|inst| matches!(inst, Instruction::ContainsAny)));
+
+        let mixed = crate::ast::adapter::parse_template(
+            r#"permit(principal, action, resource) when { [1, "one"].contains(2) };"#,
+        )
+        .unwrap();
+        let err = LoweredModule::from_template(&mixed).unwrap_err();
+        assert_eq!(
+            err.feature.as_deref(),
+            Some("set literal with elements of different types")
+        );
+    }
+
+    #[test]
+    fn test_when_and_unless_clauses() {
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(principal, action, resource)
+            when { principal == User::"a" }
+            unless { resource == Doc::"secret" };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        let instructions = &module.policies[0].instructions;
+        let clauses = &instructions[instructions.len() - 17..];
+        assert!(matches!(
+            clauses,
+            [
+                Instruction::If(Some(WasmType::Bool)),
+                Instruction::LoadVar(RequestVar::Principal),
+                Instruction::LoadConstEntity(0),
+                Instruction::Equal,
+                Instruction::Else,
+                Instruction::PushBool(false),
+                Instruction::End,
+                Instruction::If(Some(WasmType::Bool)),
+                Instruction::LoadVar(RequestVar::Resource),
+                Instruction::LoadConstEntity(1),
+                Instruction::Equal,
+                Instruction::Not,
+                Instruction::Else,
+                Instruction::PushBool(false),
+                Instruction::End,
+                Instruction::IfThenElse,
+                Instruction::Return,
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_greater_than() {
+        let lower = |condition: &str| {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            let template = crate::ast::adapter::parse_template(&source).unwrap();
+            LoweredModule::from_template(&template).unwrap().policies[0]
+                .instructions
+                .clone()
+        };
+        let comparison = |instructions: &[Instruction]| {
+            let get = instructions
+                .iter()
+                .position(|inst| matches!(inst, Instruction::GetAttribute(attr) if attr == "age"))
+                .unwrap();
+            instructions[get + 1..get + 4].to_vec()
+        };
+
+        assert!(matches!(
+            comparison(&lower("resource.age > 18")).as_slice(),
+            [
+                Instruction::PushInt(18),
+                Instruction::GreaterThan,
+                Instruction::Else
+            ]
+        ));
+        assert!(matches!(
+            comparison(&lower("resource.age >= 18")).as_slice(),
+            [
+                Instruction::PushInt(18),
+                Instruction::GreaterThanOrEqual,
+                Instruction::Else
+            ]
+        ));
+        // An explicit negation is the same comparison
+        assert!(matches!(
+            comparison(&lower("!(resource.age <= 18)")).as_slice(),
+            [
+                Instruction::PushInt(18),
+                Instruction::GreaterThan,
+                Instruction::Else
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_not_equal() {
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(principal, action, resource) when { principal.team != "red" };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        let instructions = &module.policies[0].instructions;
+        let get = instructions
+            .iter()
+            .position(|inst| matches!(inst, Instruction::GetAttribute(attr) if attr == "team"))
+            .unwrap();
+        assert!(matches!(
+            &instructions[get + 1..get + 3],
+            [Instruction::PushString(s), Instruction::NotEqual] if s == "red"
+        ));
+    }
+
+    #[test]
+    fn test_records() {
+        let lower = |condition: &str| {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            let template = crate::ast::adapter::parse_template(&source).unwrap();
+            LoweredModule::from_template(&template).map(|module| module.policies[0].clone())
+        };
+
+        // Nested attributes are chained reads from the context record
+        let policy = lower("context.auth.level > 2").unwrap();
+        let context = policy
+            .instructions
+            .iter()
+            .position(|inst| matches!(inst, Instruction::LoadVar(RequestVar::Context)))
+            .unwrap();
+        assert!(matches!(
+            &policy.instructions[context..context + 5],
+            [
+                Instruction::LoadVar(RequestVar::Context),
+                Instruction::GetAttribute(auth),
+                Instruction::GetAttribute(level),
+                Instruction::PushInt(2),
+                Instruction::GreaterThan,
+            ] if auth == "auth" && level == "level"
+        ));
+
+        // A context attribute is read from the `context` parameter by name
+        let policy = lower("context.authenticated == true").unwrap();
+        let context = policy
+            .instructions
+            .iter()
+            .position(|inst| matches!(inst, Instruction::LoadVar(RequestVar::Context)))
+            .unwrap();
+        assert!(matches!(
+            &policy.instructions[context..context + 4],
+            [
+                Instruction::LoadVar(RequestVar::Context),
+                Instruction::GetAttribute(attr),
+                Instruction::PushBool(true),
+                Instruction::Equal,
+            ] if attr == "authenticated"
+        ));
+
+        // Record literal fields are sorted by name
+        let policy = lower(r#"{ b: "x", a: 1 }.a == 1"#).unwrap();
+        let fields = policy
+            .instructions
+            .iter()
+            .find_map(|inst| match inst {
+                Instruction::PushRecord(fields) => Some(fields.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            fields,
+            [
+                ("a".to_string(), SetElement::Int(1)),
+                ("b".to_string(), SetElement::String("x".to_string())),
+            ]
+        );
+
+        let err = lower("{ a: principal }.a == principal").unwrap_err();
+        assert_eq!(err.message, "Record literal fields must be literals");
+    }
+
+    #[test]
+    fn test_decimal() {
+        let lower = |condition: &str| {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            let template = crate::ast::adapter::parse_template(&source).unwrap();
+            LoweredModule::from_template(&template).map(|module| module.policies[0].clone())
+        };
+
+        let policy = lower(r#"decimal("10.5").lessThan(context.price)"#).unwrap();
+        let decimal = policy
+            .instructions
+            .iter()
+            .position(|inst| matches!(inst, Instruction::PushDecimal(105_000)))
+            .unwrap();
+        assert!(matches!(
+            &policy.instructions[decimal + 1..decimal + 4],
+            [
+                Instruction::LoadVar(RequestVar::Context),
+                Instruction::GetAttribute(attr),
+                Instruction::LessThan,
+            ] if attr == "price"
+        ));
+
+        assert_eq!(parse_decimal("-1.25"), Ok(-12_500));
+        assert_eq!(parse_decimal("0.0001"), Ok(1));
+        assert_eq!(parse_decimal("922337203685477.5807"), Ok(i64::MAX));
+        assert!(parse_decimal("922337203685477.5808").is_err());
+        assert!(parse_decimal("1").is_err());
+        assert!(parse_decimal("1.").is_err());
+        assert!(parse_decimal("+1.0").is_err());
+
+        let err = lower(r#"decimal("1.23456").greaterThan(decimal("1.0"))"#).unwrap_err();
+        assert_eq!(
+            err.message,
+            r#"Decimal literal "1.23456" has more than 4 fractional digits"#
+        );
+    }
+
+    #[test]
+    fn test_datetime() {
+        let lower = |condition: &str| {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            let template = crate::ast::adapter::parse_template(&source).unwrap();
+            LoweredModule::from_template(&template).map(|module| module.policies[0].clone())
+        };
+
+        let policy = lower(r#"context.now < datetime("2024-01-01T00:00:00Z")"#).unwrap();
+        let now = policy
+            .instructions
+            .iter()
+            .position(|inst| matches!(inst, Instruction::LoadVar(RequestVar::Context)))
+            .unwrap();
+        assert!(matches!(
+            &policy.instructions[now + 1..now + 4],
+            [
+                Instruction::GetAttribute(attr),
+                Instruction::PushDateTime(1_704_067_200_000),
+                Instruction::LessThan,
+            ] if attr == "now"
+        ));
+
+        assert_eq!(parse_datetime("1970-01-01"), Ok(0));
+        assert_eq!(parse_datetime("1969-12-31T23:59:59.999Z"), Ok(-1));
+        assert_eq!(
+            parse_datetime("2024-02-29T12:30:00.250Z"),
+            Ok(1_709_209_800_250)
+        );
+        assert_eq!(
+            parse_datetime("2024-01-01T01:00:00+0100"),
+            Ok(1_704_067_200_000)
+        );
+        assert_eq!(
+            parse_datetime("2023-12-31T23:00:00-0100"),
+            Ok(1_704_067_200_000)
+        );
+        assert!(parse_datetime("2023-02-29").is_err());
+        assert!(parse_datetime("2024-01-01T24:00:00Z").is_err());
+        assert!(parse_datetime("2024-01-01T00:00:00").is_err());
+        assert!(parse_datetime("2024-01-01T00:00:00.1Z").is_err());
+        assert!(parse_datetime("2024-01-01T00:00:00+2400").is_err());
+        assert!(parse_datetime("24-01-01").is_err());
+
+        let err = lower(r#"context.now < datetime("2024-01-32")"#).unwrap_err();
+        assert_eq!(
+            err.message,
+            r#"Datetime literal "2024-01-32" is not a valid date"#
+        );
+    }
+
+    #[test]
+    fn test_is_entity_type() {
+        let lower = |source: &str| {
+            let template = crate::ast::adapter::parse_template(source).unwrap();
+            LoweredModule::from_template(&template).unwrap().policies[0]
+                .instructions
+                .clone()
+        };
+
+        let bare = lower("permit(principal, action, resource) when { principal is App::User };");
+        assert!(bare
+            .iter()
+            .any(|inst| matches!(inst, Instruction::IsEntityType(name) if name == "App::User")));
+
+        let combined = lower(
+            r#"permit(principal, action, resource) when { principal is User in Group::"g" };"#,
+        );
+        let is = combined
+            .iter()
+            .position(|inst| matches!(inst, Instruction::IsEntityType(name) if name == "User"))
+            .unwrap();
+        assert!(matches!(
+            combined[is + 1..],
+            [
+                Instruction::If(Some(WasmType::Bool)),
+                Instruction::LoadVar(RequestVar::Principal),
+                Instruction::LoadConstEntity(0),
+                Instruction::In,
+                Instruction::Else,
+                Instruction::PushBool(false),
+                Instruction::End,
+                ..
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_scope_constraints() {
+        use Instruction::*;
+
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(
+                principal in Group::"admins",
+                action in [Action::"read", Action::"list"],
+                resource is Doc
+            );"#,
+        )
+        .unwrap();
+        let lowered = LoweredModule::from_template(&template).unwrap();
+
+        assert!(matches!(
+            lowered.policies[0].instructions.as_slice(),
+            [
+                NoDecision,
+                Permit,
+                Swap,
+                LoadVar(RequestVar::Principal),
+                LoadConstEntity(0),
+                In,
+                LoadVar(RequestVar::Action),
+                LoadConstEntity(1),
+                In,
+                If(Some(WasmType::Bool)),
+                PushBool(true),
+                Else,
+                LoadVar(RequestVar::Action),
+                LoadConstEntity(2),
+                In,
+                End,
+                LoadVar(RequestVar::Resource),
+                IsEntityType(name),
+                And,
+                And,
+                IfThenElse,
+                Return,
+            ] if name == "Doc"
+        ));
+    }
+
+    #[test]
+    fn test_template_slots() {
+        use Instruction::*;
+
+        let template = crate::ast::adapter::parse_template(
+            "permit(principal in ?principal, action, resource == ?resource);",
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        assert!(module.slots);
+        assert!(module.entities.is_empty());
+        assert!(matches!(
+            module.policies[0].instructions.as_slice(),
+            [
+                NoDecision,
+                Permit,
+                Swap,
+                LoadVar(RequestVar::Principal),
+                SlotPrincipal,
+                In,
+                LoadVar(RequestVar::Resource),
+                SlotResource,
+                Equal,
+                And,
+                IfThenElse,
+                Return,
+            ]
+        ));
+
+        let policy =
+            crate::ast::adapter::parse_template("permit(principal, action, resource);").unwrap();
+        assert!(!LoweredModule::from_template(&policy).unwrap().slots);
+    }
+
+    #[test]
+    fn test_schema_narrows_attributes() {
+        use Instruction::*;
+
+        let schema = Schema::from_cedarschema_str(
+            "entity User { level: Long, name: String }; entity Doc { owner: User };",
+        )
+        .unwrap();
+        let lower = |source: &str| {
+            let template = crate::ast::adapter::parse_template(source).unwrap();
+            let mut module = LoweredModule::from_template(&template).unwrap();
+            module.apply_schema(&schema).map(|()| module)
+        };
+
+        let module = lower(
+            r#"permit(principal, action, resource) when { resource.owner.level > 2 && principal is User };"#,
+        )
+        .unwrap();
+        let instructions = &module.policies[0].instructions;
+        let owner = instructions
+            .iter()
+            .position(|inst| matches!(inst, GetAttribute(attr) if attr == "owner"))
+            .unwrap();
+        assert!(matches!(
+            &instructions[owner..owner + 4],
+            [
+                GetAttribute(_),
+                Narrow(WasmType::Entity),
+                GetAttribute(_),
+                Narrow(WasmType::Int),
+            ]
+        ));
+
+        let err = lower(r#"permit(principal, action, resource) when { principal.age > 2 };"#)
+            .unwrap_err();
+        assert_eq!(err.message, "Attribute `age` is not declared in the schema");
+        let err = lower(r#"permit(principal, action, resource) when { principal is Admin };"#)
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "Entity type `Admin` is not declared in the schema"
+        );
+        let err = lower(r#"permit(principal == Group::"a", action, resource);"#).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Entity type `Group` is not declared in the schema"
+        );
+    }
+
+    #[test]
+    fn test_schema_attributes_belong_to_their_type() {
+        let schema = Schema::from_cedarschema_str(
+            r#"
+            entity User { level: Long, name: String };
+            entity Doc { owner: User, name: Long };
+            action read appliesTo { principal: User, resource: Doc, context: { level: String } };
+            "#,
+        )
+        .unwrap();
+        let lower = |source: &str| {
+            let template = crate::ast::adapter::parse_template(source).unwrap();
+            let mut module = LoweredModule::from_template(&template).unwrap();
+            module.apply_schema(&schema).map(|()| module)
+        };
+        let narrowed = |module: &LoweredModule| {
+            module.policies[0]
+                .instructions
+                .iter()
+                .filter_map(|inst| match inst {
+                    Instruction::Narrow(ty) => Some(*ty),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Only Doc declares `owner`, and principals are Users
+        let err = lower(
+            r#"permit(principal, action, resource) when { principal.owner == resource.owner };"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.message,
+            "Attribute `owner` is not declared in the schema"
+        );
+        // Whatever the actions apply to, `is` pins the type
+        let err = lower(
+            r#"permit(principal, action, resource is User) when { resource.owner == principal };"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.message,
+            "Attribute `owner` is not declared in the schema"
+        );
+        let err = lower(r#"permit(principal, action, resource) when { context has owner };"#)
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "Attribute `owner` is not declared in the schema"
+        );
+
+        // Same-named attributes keep the types their owners declare
+        let module = lower(
+            r#"permit(principal, action, resource) when { principal.name == "a" && resource.name > 1 };"#,
+        )
+        .unwrap();
+        assert_eq!(narrowed(&module), [WasmType::String, WasmType::Int]);
+        let module = lower(
+            r#"permit(principal, action, resource) when { context.level == "a" && resource.owner.level > 1 };"#,
+        )
+        .unwrap();
+        assert_eq!(
+            narrowed(&module),
+            [WasmType::String, WasmType::Entity, WasmType::Int]
+        );
+    }
+
+    #[test]
+    fn test_schema_type_errors_lower_to_error() {
+        use Instruction::*;
+
+        let schema =
+            Schema::from_cedarschema_str("entity User { level: Long, name: String };").unwrap();
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(principal, action, resource) when { principal.level > 2 && principal.name < 3 };"#,
+        )
+        .unwrap();
+        let mut module = LoweredModule::from_template(&template).unwrap();
+        module.apply_schema(&schema).unwrap();
+
+        // The comparison and its operands become one Error, which only runs
+        // if the left operand of `&&` holds
+        let instructions = &module.policies[0].instructions;
+        let error = instructions
+            .iter()
+            .position(|inst| matches!(inst, Error(_)))
+            .unwrap();
+        assert!(matches!(
+            &instructions[error - 1..error + 4],
+            [
+                If(Some(WasmType::Bool)),
+                Error(WasmType::Bool),
+                Else,
+                PushBool(false),
+                End,
+            ]
+        ));
+        assert!(!instructions.iter().any(|inst| matches!(inst, LessThan)));
+        assert_eq!(
+            instructions
+                .iter()
+                .filter(|inst| matches!(inst, GetAttribute(_)))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_like_keeps_escaped_asterisks() {
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(principal, action, resource) when { resource.name like "doc_\*_*" };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        let pattern = module.policies[0]
+            .instructions
+            .iter()
+            .find_map(|inst| match inst {
+                Instruction::Like(pattern) => Some(pattern.as_str()),
+                _ => None,
+            });
+        assert_eq!(pattern, Some(r"doc_\*_*"));
+    }
+
+    #[test]
+    fn test_unknown_is_rejected() {
+        use cedar_policy_core::ast::Unknown;
+
+        let parts = PolicyParts {
+            id: "policy0".to_string(),
+            effect: PolicyEffect::Permit,
+            scope: Scope {
+                principal: ScopeConstraint::Any,
+                action: ActionScope::Any,
+                resource: ScopeConstraint::Any,
+            },
+            clauses: vec![Clause::When(Expr::unknown(Unknown::new_untyped("owner")))],
+            sensitive: false,
+        };
+        let err = LoweredModule::from_parts(&[parts]).unwrap_err();
+        assert_eq!(
+            err.message,
+            "partial evaluation (unknown `owner`) is not supported"
+        );
+        assert_eq!(
+            err.feature.as_deref(),
+            Some("partial evaluation (unknown `owner`)")
+        );
+    }
+
+    #[test]
+    fn test_references() {
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(principal is User in Group::"staff", action, resource)
+            when {
+                principal.manager.level > 3 &&
+                Config::"global" has enabled &&
+                context.mfa &&
+                resource is Doc &&
+                principal.manager.level < 9
+            };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        assert_eq!(
+            module.referenced_attributes(),
+            [
+                (EntityRef::Var(RequestVar::Principal), "manager".to_string()),
+                (EntityRef::Computed, "level".to_string()),
+                (
+                    EntityRef::Entity(r#"Config::"global""#.to_string()),
+                    "enabled".to_string()
+                ),
+                (EntityRef::Var(RequestVar::Context), "mfa".to_string()),
+            ]
+        );
+        assert_eq!(
+            module.referenced_entity_types(),
+            ["User", "Group", "Config", "Doc"]
+        );
+    }
+}
@@ -1,5 +1,11 @@
 //! AST lowering and intermediate representation
 
+#[cfg(feature = "std")]
+pub mod adapter;
+#[cfg(feature = "std")]
+pub mod analysis;
 pub mod lowering;
 
+#[cfg(feature = "std")]
+pub use analysis::{LiteralKind, LiteralValue};
 pub use lowering::LoweredModule;
@@ -1,58 +1,320 @@
 //! CLI for Cedar policy compiler
 
-use cedar_policy_compiler::{Compiler, CompilerResult};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
-fn main() -> CompilerResult<()> {
-    let args: Vec<String> = std::env::args().collect();
+use cedar_policy_compiler::Compiler;
+use clap::{Parser, ValueEnum};
+use miette::IntoDiagnostic;
 
-    if args.len() < 2 {
-        print_usage(&args[0]);
-        std::process::exit(1);
+/// Cedar Policy Compiler
+#[derive(Debug, Parser)]
+#[command(
+    name = "cedar-compile",
+    version,
+    about = "Compile Cedar policies to WebAssembly",
+    after_help = "EXAMPLES:\n    \
+        cedar-compile policy.cedar\n    \
+        cedar-compile policy.cedar -o compiled.wasm --opt-level 2\n    \
+        cedar-compile policy.cedar --emit wat -o compiled.wat\n    \
+        cedar-compile policy.json\n    \
+        cedar-compile policies/ -o compiled.wasm\n    \
+        cedar-compile policy.cedar --validate\n    \
+        cedar-compile policy.cedar --opt-level 2 --opt-report\n    \
+        cedar-compile policy.cedar --validate --dump-ir\n    \
+        cat policy.cedar | cedar-compile -o - > compiled.wasm"
+)]
+struct Cli {
+    /// Cedar policy file to compile, in Cedar's JSON format if it ends in
+    /// `.json`, or a directory whose `.cedar` files are compiled together
+    /// as one policy set; `-` or omitted reads Cedar text from standard input
+    input: Option<PathBuf>,
+
+    /// Output file, `-` for standard output [default: output.wasm, or
+    /// output.wat / tests.json with --emit wat / testvectors]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Optimization level
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    opt_level: u8,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Emit::Wasm)]
+    emit: Emit,
+
+    /// Print what each optimization pass did to standard error
+    #[arg(long)]
+    opt_report: bool,
+
+    /// Print the lowered IR, after optimization, to standard error
+    #[arg(long)]
+    dump_ir: bool,
+
+    /// Only check that the policy compiles; nothing is written
+    #[arg(long, conflicts_with_all = ["output", "emit", "opt_report"])]
+    validate: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// WebAssembly binary
+    Wasm,
+    /// WebAssembly text format
+    Wat,
+    /// WebAssembly component importing the runtime as a WIT interface
+    Component,
+    /// JSON skeleton of test requests for the policies
+    #[value(name = "testvectors")]
+    TestVectors,
+}
+
+impl Cli {
+    /// Input path, or `None` for standard input
+    fn input_file(&self) -> Option<&Path> {
+        self.input.as_deref().filter(|path| *path != Path::new("-"))
     }
 
-    let input_file = &args[1];
-    let output_file = if args.len() >= 4 && args[2] == "-o" {
-        &args[3]
-    } else {
-        "output.wasm"
+    /// Input path if it is a directory of policy files
+    fn input_dir(&self) -> Option<&Path> {
+        self.input_file()
+            .filter(|path| path.metadata().is_ok_and(|metadata| metadata.is_dir()))
+    }
+
+    /// Whether the input is a policy in Cedar's JSON format
+    fn json_input(&self) -> bool {
+        self.input_file()
+            .and_then(Path::extension)
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+    }
+
+    fn output_file(&self) -> PathBuf {
+        self.output.clone().unwrap_or_else(|| match self.emit {
+            Emit::Wasm | Emit::Component => PathBuf::from("output.wasm"),
+            Emit::Wat => PathBuf::from("output.wat"),
+            Emit::TestVectors => PathBuf::from("tests.json"),
+        })
+    }
+}
+
+/// Errors are reported through miette, which prints the offending source
+/// snippet for errors that carry a location
+fn main() -> miette::Result<()> {
+    let cli = Cli::parse();
+    if cli.validate {
+        return validate(&cli);
+    }
+    let output_file = cli.output_file();
+    let to_stdout = output_file == Path::new("-");
+
+    // Progress goes to stderr so it never mixes with output on stdout
+    match cli.input_file() {
+        Some(path) => eprintln!("Compiling Cedar policy: {}", path.display()),
+        None => eprintln!("Compiling Cedar policy from standard input"),
+    }
+    eprintln!("Output: {}", output_file.display());
+    eprintln!("Optimization level: {}", cli.opt_level);
+
+    let compiler = Compiler::new().with_opt_level(cli.opt_level);
+    let output = match cli.input_dir() {
+        Some(dir) => compile_dir(&cli, &compiler, dir)?,
+        None => compile_source(&cli, &compiler)?,
     };
 
-    let opt_level = if args.contains(&"--opt".to_string()) {
-        2
+    if to_stdout {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&output).into_diagnostic()?;
+        stdout.flush().into_diagnostic()?;
     } else {
-        1
+        std::fs::write(&output_file, &output).into_diagnostic()?;
+    }
+
+    eprintln!("✓ Compilation successful!");
+    match cli.emit {
+        Emit::Wasm | Emit::Wat | Emit::Component => {
+            eprintln!("Generated {} bytes of WebAssembly", output.len())
+        }
+        Emit::TestVectors => eprintln!("Generated {} bytes of test vectors", output.len()),
+    }
+
+    Ok(())
+}
+
+/// Compile the policy read from the input file or standard input
+fn compile_source(cli: &Cli, compiler: &Compiler) -> miette::Result<Vec<u8>> {
+    let source = read_source(cli)?;
+    if cli.dump_ir {
+        dump_ir(cli, compiler, &source)?;
+    }
+    let output = match (cli.emit, cli.json_input()) {
+        (Emit::Wasm, false) => compiler.compile_str(&source)?,
+        (Emit::Wasm, true) => compiler.compile_json_str(&source)?,
+        (Emit::Wat, false) => compiler.compile_str_to_wat(&source)?.into_bytes(),
+        (Emit::Wat, true) => compiler.compile_json_str_to_wat(&source)?.into_bytes(),
+        (Emit::Component, false) => compiler.compile_str_to_component(&source)?,
+        (Emit::Component, true) => compiler.compile_json_str_to_component(&source)?,
+        (Emit::TestVectors, false) => compiler.test_vectors(&source)?.into_bytes(),
+        (Emit::TestVectors, true) => {
+            miette::bail!("--emit testvectors requires a Cedar text policy")
+        }
     };
 
-    println!("Compiling Cedar policy: {}", input_file);
-    println!("Output: {}", output_file);
-    println!("Optimization level: {}", opt_level);
+    if cli.opt_report {
+        if cli.json_input() {
+            miette::bail!("--opt-report requires a Cedar text policy");
+        }
+        eprint!("{}", compiler.optimization_report(&source)?);
+    }
+    Ok(output)
+}
 
-    let compiler = Compiler::new().with_opt_level(opt_level);
-    let wasm_bytes = compiler.compile_file(input_file)?;
+/// Compile the `.cedar` files in a directory into one module
+fn compile_dir(cli: &Cli, compiler: &Compiler, dir: &Path) -> miette::Result<Vec<u8>> {
+    if cli.dump_ir || cli.opt_report {
+        miette::bail!("--dump-ir and --opt-report require a single policy file");
+    }
+    Ok(match cli.emit {
+        Emit::Wasm => compiler.compile_dir(dir)?,
+        Emit::Wat => compiler.compile_dir_to_wat(dir)?.into_bytes(),
+        Emit::Component | Emit::TestVectors => {
+            miette::bail!("a directory of policies compiles only to wasm or wat")
+        }
+    })
+}
 
-    std::fs::write(output_file, wasm_bytes)?;
+/// Parse and lower the policy, reporting errors like a compilation would
+fn validate(cli: &Cli) -> miette::Result<()> {
+    match cli.input_file() {
+        Some(path) => eprintln!("Validating Cedar policy: {}", path.display()),
+        None => eprintln!("Validating Cedar policy from standard input"),
+    }
 
-    println!("✓ Compilation successful!");
-    println!("Generated {} bytes of WebAssembly", std::fs::metadata(output_file)?.len());
+    let compiler = Compiler::new().with_opt_level(cli.opt_level);
+    if let Some(dir) = cli.input_dir() {
+        if cli.dump_ir {
+            miette::bail!("--dump-ir requires a single policy file");
+        }
+        compiler.compile_dir(dir)?;
+        eprintln!("✓ Validation successful!");
+        return Ok(());
+    }
+    let source = read_source(cli)?;
+    if cli.dump_ir {
+        dump_ir(cli, &compiler, &source)?;
+    }
+    if cli.json_input() {
+        compiler.validate_json_str(&source)?;
+    } else {
+        compiler.validate_str(&source)?;
+    }
 
+    eprintln!("✓ Validation successful!");
     Ok(())
 }
 
-fn print_usage(program: &str) {
-    println!("Cedar Policy Compiler");
-    println!();
-    println!("USAGE:");
-    println!("    {} <input.cedar> [-o <output.wasm>] [--opt]", program);
-    println!();
-    println!("ARGS:");
-    println!("    <input.cedar>       Cedar policy file to compile");
-    println!();
-    println!("OPTIONS:");
-    println!("    -o <output.wasm>    Output file (default: output.wasm)");
-    println!("    --opt               Enable aggressive optimizations");
-    println!();
-    println!("EXAMPLES:");
-    println!("    {} policy.cedar", program);
-    println!("    {} policy.cedar -o compiled.wasm", program);
-    println!("    {} policy.cedar -o compiled.wasm --opt", program);
+/// Print the IR code generation receives, one instruction per line
+fn dump_ir(cli: &Cli, compiler: &Compiler, source: &str) -> miette::Result<()> {
+    let ir = if cli.json_input() {
+        compiler.lower_json_str(source)?
+    } else {
+        compiler.lower_str(source)?
+    };
+    eprint!("{}", compiler.optimize_ir(ir)?);
+    Ok(())
+}
+
+/// Read the policy from the input file or standard input
+fn read_source(cli: &Cli) -> miette::Result<String> {
+    match cli.input_file() {
+        Some(path) => std::fs::read_to_string(path).into_diagnostic(),
+        None => {
+            let mut source = String::new();
+            std::io::stdin()
+                .read_to_string(&mut source)
+                .into_diagnostic()?;
+            Ok(source)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_in_any_order() {
+        let cli = Cli::try_parse_from([
+            "cedar-compile",
+            "--opt-level",
+            "2",
+            "-o",
+            "out.wasm",
+            "policy.cedar",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.input_file(), Some(Path::new("policy.cedar")));
+        assert_eq!(cli.output_file(), PathBuf::from("out.wasm"));
+        assert_eq!(cli.opt_level, 2);
+        assert_eq!(cli.emit, Emit::Wasm);
+
+        let cli =
+            Cli::try_parse_from(["cedar-compile", "policy.cedar", "--emit=wat", "-o", "out.wat"])
+                .unwrap();
+        assert_eq!(cli.output_file(), PathBuf::from("out.wat"));
+        assert_eq!(cli.emit, Emit::Wat);
+    }
+
+    #[test]
+    fn test_default_output() {
+        let cli = Cli::try_parse_from(["cedar-compile", "policy.cedar"]).unwrap();
+        assert_eq!(cli.output_file(), PathBuf::from("output.wasm"));
+        assert_eq!(cli.opt_level, 1);
+
+        let cli = Cli::try_parse_from(["cedar-compile", "policy.cedar", "--emit", "wat"]).unwrap();
+        assert_eq!(cli.output_file(), PathBuf::from("output.wat"));
+
+        let cli =
+            Cli::try_parse_from(["cedar-compile", "policy.cedar", "--emit", "testvectors"]).unwrap();
+        assert_eq!(cli.output_file(), PathBuf::from("tests.json"));
+    }
+
+    #[test]
+    fn test_json_input_by_extension() {
+        let cli = Cli::try_parse_from(["cedar-compile", "policy.json"]).unwrap();
+        assert!(cli.json_input());
+
+        let cli = Cli::try_parse_from(["cedar-compile", "policy.cedar"]).unwrap();
+        assert!(!cli.json_input());
+
+        let cli = Cli::try_parse_from(["cedar-compile"]).unwrap();
+        assert!(!cli.json_input());
+    }
+
+    #[test]
+    fn test_stdin_input() {
+        let cli = Cli::try_parse_from(["cedar-compile"]).unwrap();
+        assert_eq!(cli.input_file(), None);
+
+        let cli = Cli::try_parse_from(["cedar-compile", "-", "-o", "-"]).unwrap();
+        assert_eq!(cli.input_file(), None);
+        assert_eq!(cli.output_file(), PathBuf::from("-"));
+    }
+
+    #[test]
+    fn test_validate_writes_no_output() {
+        let cli = Cli::try_parse_from(["cedar-compile", "policy.cedar", "--validate"]).unwrap();
+        assert!(cli.validate);
+
+        let err = Cli::try_parse_from(["cedar-compile", "policy.cedar", "--validate", "-o", "x"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_invalid_opt_level_is_rejected() {
+        let err = Cli::try_parse_from(["cedar-compile", "policy.cedar", "--opt-level", "3"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+    }
 }
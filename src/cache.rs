@@ -0,0 +1,120 @@
+//! In-memory cache of compiled modules, for services that compile the same
+//! policies again and again
+
+use std::collections::HashMap;
+
+/// Counters of a [`ModuleCache`], for monitoring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups that returned a cached module
+    pub hits: u64,
+    /// Lookups that found nothing, so the module was compiled
+    pub misses: u64,
+    /// Modules dropped to make room for newer ones
+    pub evictions: u64,
+    /// Modules currently cached
+    pub entries: usize,
+    /// Most modules the cache holds at once
+    pub capacity: usize,
+}
+
+/// Least-recently-used cache of compiled modules, by the source they were
+/// compiled from. It holds modules of one set of compiler settings: the
+/// compiler clears it when they change. Finding the entry to evict scans
+/// the cache, which is cheap next to a compilation at the capacities a
+/// service keeps.
+#[derive(Debug)]
+pub(crate) struct ModuleCache {
+    /// Module and the tick it was last used at
+    entries: HashMap<String, (Vec<u8>, u64)>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl ModuleCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            tick: 0,
+            stats: CacheStats {
+                capacity,
+                ..CacheStats::default()
+            },
+        }
+    }
+
+    /// The module cached for `source`, counting a hit or a miss
+    pub fn get(&mut self, source: &str) -> Option<Vec<u8>> {
+        self.tick += 1;
+        match self.entries.get_mut(source) {
+            Some((wasm, last_used)) => {
+                *last_used = self.tick;
+                self.stats.hits += 1;
+                Some(wasm.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache a module, evicting the least recently used one if full
+    pub fn insert(&mut self, source: String, wasm: Vec<u8>) {
+        if self.stats.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.stats.capacity && !self.entries.contains_key(&source) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(source, _)| source.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+                self.stats.evictions += 1;
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(source, (wasm, self.tick));
+    }
+
+    /// Drop every cached module, keeping the counters
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(source: &str) -> String {
+        source.to_string()
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let mut cache = ModuleCache::new(2);
+        cache.insert(key("a"), vec![1]);
+        cache.insert(key("b"), vec![2]);
+        assert_eq!(cache.get(&key("a")), Some(vec![1]));
+
+        // `b` is older than `a` now
+        cache.insert(key("c"), vec![3]);
+        assert_eq!(cache.get(&key("b")), None);
+        assert_eq!(cache.get(&key("a")), Some(vec![1]));
+        assert_eq!(cache.get(&key("c")), Some(vec![3]));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (3, 1, 1));
+        assert_eq!(stats.entries, 2);
+    }
+}
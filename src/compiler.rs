@@ -1,78 +1,685 @@
 //! Main compiler orchestration
 
-use cedar_policy_core::ast::{Policy, Template};
-use cedar_policy_core::parser::parse_policy_or_template;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use miette::{Diagnostic, SourceSpan};
+use rayon::prelude::*;
 use thiserror::Error;
 
-use crate::ast::lowering::LoweredModule;
-use crate::wasm::codegen::WasmCodeGen;
+use crate::ast::adapter::{
+    self, JsonPolicyError, ParseErrors, Policy, PolicyID, PolicySet, Schema, Template,
+};
+use crate::ast::analysis::{self, LiteralValue};
+use crate::ast::lowering::{LoweredModule, LoweringError, TypeError};
+use crate::cache::{CacheStats, ModuleCache};
+use crate::optimization::{self, diagnostics, OptimizationReport, Pass, PassManager};
+use crate::wasm::codegen::{CodegenStats, Overflow, WasmCodeGen};
+use crate::wasm::component;
+use crate::wasm::runtime::memory;
+#[cfg(feature = "wasm-opt")]
+use crate::wasm::wasm_opt::{self, WasmOptSettings};
 
 pub type CompilerResult<T> = Result<T, CompilerError>;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, Diagnostic)]
 pub enum CompilerError {
+    /// Cedar text that doesn't parse, rendered by miette with the parser's
+    /// labels on the source
+    #[error("Parse error{}: {}", at_line_column(&parse_location(.0)), .0)]
+    #[diagnostic(transparent)]
+    ParseError(Box<ParseErrors>),
+
+    /// A policy in Cedar's JSON format that doesn't parse
     #[error("Parse error: {0}")]
-    ParseError(String),
+    JsonParseError(JsonPolicyError),
 
     #[error("Compilation error: {0}")]
     CompilationError(String),
 
+    /// A compilation error tied to a location in the policy source
+    #[error("Compilation error at line {line}, column {column}: {message}")]
+    #[diagnostic(code(cedar_policy_compiler::compile))]
+    SourceError {
+        message: String,
+        line: usize,
+        column: usize,
+        #[label("{message}")]
+        span: SourceSpan,
+        #[source_code]
+        source_code: String,
+    },
+
+    /// A construct the compiler doesn't support yet, named in Cedar terms
+    #[error("Unsupported {feature}{}", at_line_column(.location))]
+    #[diagnostic(code(cedar_policy_compiler::unsupported))]
+    Unsupported {
+        feature: String,
+        /// 1-based line and column, if the parser recorded a location
+        location: Option<(usize, usize)>,
+        #[label("not supported")]
+        span: Option<SourceSpan>,
+        #[source_code]
+        source_code: Option<String>,
+    },
+
+    /// An expression the schema shows to be mistyped
+    #[error("Type error in policy {}", .0)]
+    #[diagnostic(code(cedar_policy_compiler::type_error))]
+    TypeError(TypeError),
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
     #[error("WASM encoding error: {0}")]
     WasmError(String),
+
+    /// An error in one file of a policy directory, with its spans into that
+    /// file
+    #[error("In {}", .path.display())]
+    InFile {
+        path: PathBuf,
+        #[source]
+        #[diagnostic_source]
+        error: Box<CompilerError>,
+    },
+}
+
+// Lets `InFile` hand its boxed error to miette as the diagnostic source
+impl std::borrow::Borrow<dyn Diagnostic> for Box<CompilerError> {
+    fn borrow(&self) -> &(dyn Diagnostic + 'static) {
+        self.as_ref()
+    }
+}
+
+impl From<TypeError> for CompilerError {
+    fn from(err: TypeError) -> Self {
+        CompilerError::TypeError(err)
+    }
+}
+
+fn at_line_column(location: &Option<(usize, usize)>) -> String {
+    match location {
+        Some((line, column)) => format!(" at line {}, column {}", line, column),
+        None => String::new(),
+    }
+}
+
+/// 1-based line and column of the first label of the first parse error
+fn parse_location(errors: &ParseErrors) -> Option<(usize, usize)> {
+    let label = errors.labels()?.next()?;
+    let contents = errors.source_code()?.read_span(label.inner(), 0, 0).ok()?;
+    Some((contents.line() + 1, contents.column() + 1))
+}
+
+impl From<LoweringError> for CompilerError {
+    fn from(err: LoweringError) -> Self {
+        let location = err.line_column();
+        if let Some(feature) = err.feature {
+            return CompilerError::Unsupported {
+                feature,
+                location,
+                span: err.loc.as_ref().map(|loc| loc.span),
+                source_code: err.loc.map(|loc| loc.src.to_string()),
+            };
+        }
+        match (location, err.loc) {
+            (Some((line, column)), Some(loc)) => CompilerError::SourceError {
+                message: err.message,
+                line,
+                column,
+                span: loc.span,
+                source_code: loc.src.to_string(),
+            },
+            _ => CompilerError::CompilationError(err.message),
+        }
+    }
+}
+
+/// Output format of [`Compiler::emit_str`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EmitFormat {
+    /// WebAssembly binary
+    #[default]
+    Wasm,
+    /// WebAssembly text format
+    Wat,
+    /// WebAssembly component wrapping the module; see
+    /// [`crate::wasm::component`]
+    Component,
+}
+
+/// How the host passes a request to `evaluate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Abi {
+    /// `evaluate(principal, action, resource, context)`, each an entity
+    /// handle the host assigned
+    #[default]
+    Handles,
+    /// `evaluate(json, len)`: the request as JSON in linear memory, parsed by
+    /// the host's `parse_request`; see [`crate::wasm::runtime`] for the
+    /// format. Not available with template slots, batch evaluation or
+    /// error codes.
+    JsonRequest,
+}
+
+/// Compiler settings, applied with [`Compiler::with_options`]. Fields not
+/// set explicitly can be filled in from [`CompilerOptions::default`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CompilerOptions {
+    /// Optimization level (0 = none, 1 = basic, 2 = aggressive); higher
+    /// levels are clamped to 2
+    pub opt_level: u8,
+    /// Output format of [`Compiler::emit_str`]
+    pub emit: EmitFormat,
+    /// Emit a `name` section naming the functions, for debuggers and
+    /// disassemblers
+    pub debug_names: bool,
+    /// Maximum size of linear memory in WASM pages (64KB each); policies
+    /// whose literals don't fit are rejected
+    pub max_memory_pages: u32,
+    /// Validate every generated module, reporting codegen bugs as errors
+    /// rather than returning a module a runtime would reject
+    pub strict: bool,
+    /// Declare a 64-bit linear memory (the `memory64` proposal), for hosts
+    /// whose memories are 64-bit; not available with [`Compiler::target_mvp`]
+    pub memory64: bool,
+    /// Run binaryen's `wasm-opt` over every module at this level (0-4);
+    /// if it fails, or the crate was built without the `wasm-opt` feature,
+    /// the module is returned unoptimized, with a warning.
+    pub wasm_opt_level: Option<u8>,
+    /// Export `_initialize` to load the string pool, for WASI-style hosts
+    /// that call it before `evaluate`, instead of loading it when the
+    /// module is instantiated; not available with [`Compiler::target_mvp`]
+    pub initialize: bool,
+    /// How `evaluate` takes its request
+    pub abi: Abi,
+    /// Emit a `cedar.sourcemap` section mapping each policy's code back to
+    /// the Cedar source it came from. See
+    /// [`crate::wasm::codegen::SOURCE_MAP_SECTION`]. Its offsets no longer
+    /// match the code once `wasm-opt` has rewritten it.
+    pub source_map: bool,
+    /// What arithmetic does when its result overflows a Long. Cedar makes
+    /// it an evaluation error, the default.
+    pub overflow: Overflow,
+}
+
+impl Default for CompilerOptions {
+    fn default() -> Self {
+        Self {
+            opt_level: 1,
+            emit: EmitFormat::Wasm,
+            debug_names: false,
+            max_memory_pages: memory::MAX_PAGES,
+            strict: false,
+            memory64: false,
+            wasm_opt_level: None,
+            initialize: false,
+            abi: Abi::Handles,
+            source_map: false,
+            overflow: Overflow::Error,
+        }
+    }
 }
 
 /// Cedar to WebAssembly compiler
+///
+/// Output is deterministic: the entity table, attribute names and string
+/// pool are numbered in order of first use, never by hash, so the same
+/// policies and settings compile to the same bytes on every run and
+/// platform, and modules can be cached by content.
 pub struct Compiler {
-    /// Optimization level (0 = none, 1 = basic, 2 = aggressive)
-    opt_level: u8,
+    options: CompilerOptions,
+    /// Restrict output to the WASM 1.0 MVP feature set
+    mvp_only: bool,
+    /// Export `evaluate_batch` with attribute reads memoized across requests
+    batch_evaluation: bool,
+    /// Record the branches taken in a coverage bitmap
+    coverage: bool,
+    /// Export each policy as `evaluate_<id>` next to the combined `evaluate`
+    policy_exports: bool,
+    /// Return an error code from `evaluate` next to the decision
+    error_codes: bool,
+    /// String literals matching this pattern are redacted from the output
+    sensitive_pattern: Option<String>,
+    /// Schema the policies are checked against and typed by
+    schema: Option<Schema>,
+    /// Compile mistyped expressions to runtime errors instead of rejecting
+    /// them
+    runtime_type_errors: bool,
+    /// User passes, run in order after the built-in optimizations
+    custom_passes: PassManager,
+    /// Modules already compiled by [`Compiler::compile_str`]
+    cache: Option<Mutex<ModuleCache>>,
+}
+
+/// A compiled module whose sensitive strings were redacted
+#[derive(Debug, Clone)]
+pub struct RedactedModule {
+    /// The compiled module, which does not contain the redacted strings
+    pub wasm: Vec<u8>,
+    /// Redacted strings, indexed by the id the module passes to the host's
+    /// `resolve_secret`
+    pub secrets: Vec<String>,
+}
+
+/// A template compiled once by [`Compiler::compile_template_linked`], ready
+/// to be specialized for each link without being lowered or optimized
+/// again
+pub struct TemplateModule<'a> {
+    compiler: &'a Compiler,
+    /// Optimized IR of the template
+    ir: LoweredModule,
+    /// The compiled template; with slots, its `evaluate` takes the entities
+    /// bound to them after the request (see [`crate::wasm::runtime`])
+    pub wasm: Vec<u8>,
+}
+
+impl TemplateModule<'_> {
+    /// Module evaluating the template linked to the given entities
+    /// (`Type::"id"`), whose `evaluate` takes just the request. The UIDs
+    /// join the module's entity table, so the host must give them the
+    /// handles it lists. A template without slots is returned as is.
+    pub fn instantiate(&self, principal_uid: &str, resource_uid: &str) -> CompilerResult<Vec<u8>> {
+        if !self.ir.slots {
+            return Ok(self.wasm.clone());
+        }
+        let mut ir = self.ir.clone();
+        let mut intern = |uid: &str| match ir.entities.iter().position(|e| e == uid) {
+            Some(idx) => idx as u32,
+            None => {
+                ir.entities.push(uid.to_string());
+                ir.entities.len() as u32 - 1
+            }
+        };
+        let slots = (intern(principal_uid), intern(resource_uid));
+        self.compiler
+            .generate(&ir, Some(slots))
+            .map(|module| module.wasm)
+    }
 }
 
 impl Compiler {
     /// Create a new compiler with default settings
     pub fn new() -> Self {
-        Self { opt_level: 1 }
+        Self::with_options(CompilerOptions::default())
+    }
+
+    /// Create a compiler with the given settings
+    pub fn with_options(options: CompilerOptions) -> Self {
+        Self {
+            options: CompilerOptions {
+                opt_level: options.opt_level.min(2),
+                ..options
+            },
+            mvp_only: false,
+            batch_evaluation: false,
+            coverage: false,
+            policy_exports: false,
+            error_codes: false,
+            sensitive_pattern: None,
+            schema: None,
+            runtime_type_errors: false,
+            custom_passes: PassManager::new(),
+            cache: None,
+        }
+    }
+
+    /// Create a compiler whose output only uses WASM 1.0 MVP features
+    /// (no multi-value, reference types, SIMD, or bulk memory), for
+    /// legacy or restricted runtimes
+    pub fn target_mvp() -> Self {
+        Self {
+            mvp_only: true,
+            ..Self::new()
+        }
     }
 
     /// Set optimization level (0-2)
     pub fn with_opt_level(mut self, level: u8) -> Self {
-        self.opt_level = level.min(2);
+        self.options.opt_level = level.min(2);
+        self.clear_cache();
+        self
+    }
+
+    /// Also export `evaluate_batch`, which evaluates many requests in one
+    /// call and fetches each attribute once per entity across the batch
+    pub fn with_batch_evaluation(mut self, enabled: bool) -> Self {
+        self.batch_evaluation = enabled;
+        self.clear_cache();
+        self
+    }
+
+    /// Instrument conditions to record which way each one went in a bitmap
+    /// in linear memory, for measuring branch coverage across test runs.
+    /// See [`crate::wasm::runtime`] for the layout.
+    pub fn with_coverage(mut self, enabled: bool) -> Self {
+        self.coverage = enabled;
+        self.clear_cache();
+        self
+    }
+
+    /// Also export every policy on its own as `evaluate_<id>`, named by its
+    /// `@id` annotation or else its policy ID, with the signature of
+    /// `evaluate`. [`Compiler::compile_str`] then accepts several policies
+    /// and compiles them into one module. Not available with batch
+    /// evaluation.
+    pub fn with_policy_exports(mut self, enabled: bool) -> Self {
+        self.policy_exports = enabled;
+        self.clear_cache();
+        self
+    }
+
+    /// Have `evaluate` return `(decision, error_code)`, where the error code
+    /// is nonzero only alongside [`Decision::Error`](crate::wasm::runtime::Decision::Error).
+    /// Takes the multi-value feature, so not available with
+    /// [`Compiler::target_mvp`]. See [`crate::wasm::runtime`] for the ABI.
+    pub fn with_error_codes(mut self, enabled: bool) -> Self {
+        self.error_codes = enabled;
+        self.clear_cache();
+        self
+    }
+
+    /// Redact string literals and `like` patterns matching `pattern`, where
+    /// `*` matches any run of characters, as if their policy were annotated
+    /// `@sensitive`. A matching string in a set or record literal fails
+    /// compilation, since those can't be redacted.
+    pub fn with_sensitive_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.sensitive_pattern = Some(pattern.into());
+        self.clear_cache();
+        self
+    }
+
+    /// Check policies against `schema`, rejecting attributes and entity
+    /// types it doesn't declare, and use the attribute types it declares to
+    /// pick their WASM types instead of treating every value as an i64.
+    /// Comparisons, set operations and arithmetic on attributes of the
+    /// wrong type are rejected with [`CompilerError::TypeError`].
+    pub fn with_schema(mut self, schema: Schema) -> Self {
+        self.schema = Some(schema);
+        self.clear_cache();
+        self
+    }
+
+    /// Compile expressions the schema shows to be mistyped into code that
+    /// returns [`Decision::Error`](crate::wasm::runtime::Decision::Error)
+    /// when evaluated, as Cedar's evaluator would, instead of rejecting the
+    /// policies
+    pub fn with_runtime_type_errors(mut self, enabled: bool) -> Self {
+        self.runtime_type_errors = enabled;
+        self.clear_cache();
+        self
+    }
+
+    /// Register a pass to run after the built-in optimization pipeline, at
+    /// every optimization level. Passes run in registration order. Modules
+    /// cached before are dropped.
+    pub fn add_pass(&mut self, pass: Box<dyn Pass>) {
+        self.custom_passes.register(pass);
+        self.clear_cache();
+    }
+
+    /// Keep the last `capacity` modules [`Compiler::compile_str`] returned,
+    /// and return them again for the same source instead of compiling it.
+    /// Changing a setting afterwards, with a builder method or
+    /// [`Compiler::add_pass`], drops every cached module, so a module
+    /// compiled with other settings is never returned.
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache = Some(Mutex::new(ModuleCache::new(capacity)));
         self
     }
 
+    /// Hits, misses and evictions of the module cache, if there is one
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| lock(cache).stats())
+    }
+
     /// Compile a Cedar policy from a string
     pub fn compile_str(&self, source: &str) -> CompilerResult<Vec<u8>> {
-        // Parse the policy (in cedar 4.4+, requires an optional PolicyID)
-        let template = parse_policy_or_template(None, source)
-            .map_err(|e| CompilerError::ParseError(format!("{:?}", e)))?;
+        let Some(cache) = &self.cache else {
+            return self.compile_source(source);
+        };
+        if let Some(wasm) = lock(cache).get(source) {
+            return Ok(wasm);
+        }
+        let wasm = self.compile_source(source)?;
+        lock(cache).insert(source.to_string(), wasm.clone());
+        Ok(wasm)
+    }
+
+    /// Drop the cached modules, after a change to the settings they were
+    /// compiled with
+    fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            lock(cache).clear();
+        }
+    }
+
+    /// Compile a single Cedar policy from a string under the given ID, or
+    /// `policy0` without one. The ID names the policy in type errors, in
+    /// the `cedar.policies` section and, with policy exports, in its
+    /// `evaluate_<id>` export. The module cache is not consulted: it is
+    /// keyed by source alone, and the same source compiles to a different
+    /// module under each ID.
+    pub fn compile_str_with_id(
+        &self,
+        source: &str,
+        id: Option<PolicyID>,
+    ) -> CompilerResult<Vec<u8>> {
+        let template =
+            adapter::parse_template_with_id(id, source).map_err(CompilerError::ParseError)?;
+
+        self.compile_template(&template)
+    }
+
+    /// [`Compiler::compile_str`] without the cache
+    fn compile_source(&self, source: &str) -> CompilerResult<Vec<u8>> {
+        if self.policy_exports {
+            let policy_set =
+                adapter::parse_policy_set(source).map_err(CompilerError::ParseError)?;
+            return self.compile_policy_set(&policy_set);
+        }
+        let template = adapter::parse_template(source).map_err(CompilerError::ParseError)?;
+
+        self.compile_template(&template)
+    }
+
+    /// Compile independent policies in parallel, one result per source in
+    /// the same order; a policy that fails doesn't affect the others
+    pub fn compile_str_many(&self, sources: &[&str]) -> Vec<CompilerResult<Vec<u8>>> {
+        sources
+            .par_iter()
+            .map(|source| self.compile_str(source))
+            .collect()
+    }
+
+    /// Compile a policy in Cedar's JSON format (the EST that tools emit)
+    /// through the same pipeline as [`Compiler::compile_str`]
+    pub fn compile_json_str(&self, source: &str) -> CompilerResult<Vec<u8>> {
+        let template =
+            adapter::parse_json_template(source).map_err(CompilerError::JsonParseError)?;
 
         self.compile_template(&template)
     }
 
+    /// Lower a Cedar policy to the intermediate representation, before
+    /// redaction, schema checks and optimization, which [`Compiler::compile_ir`]
+    /// applies. With the `serde` feature the IR can be cached and compiled
+    /// again later, e.g. at another optimization level.
+    pub fn lower_str(&self, source: &str) -> CompilerResult<LoweredModule> {
+        let template = adapter::parse_template(source).map_err(CompilerError::ParseError)?;
+
+        Ok(LoweredModule::from_template(&template)?)
+    }
+
+    /// Lower a policy in Cedar's JSON format, like [`Compiler::lower_str`]
+    pub fn lower_json_str(&self, source: &str) -> CompilerResult<LoweredModule> {
+        let template =
+            adapter::parse_json_template(source).map_err(CompilerError::JsonParseError)?;
+
+        Ok(LoweredModule::from_template(&template)?)
+    }
+
+    /// Compile lowered policies, as returned by [`Compiler::lower_str`], with
+    /// this compiler's settings
+    pub fn compile_ir(&self, ir: &LoweredModule) -> CompilerResult<Vec<u8>> {
+        self.optimize_and_generate(ir.clone())
+    }
+
+    /// Redact, schema-check and optimize lowered policies with this
+    /// compiler's settings, returning the IR code generation would receive
+    pub fn optimize_ir(&self, ir: LoweredModule) -> CompilerResult<LoweredModule> {
+        Ok(self.optimize(self.prepare(ir)?))
+    }
+
+    /// Report what each optimization pass, built-in then custom, does to a
+    /// Cedar policy at this compiler's optimization level
+    pub fn optimization_report(&self, source: &str) -> CompilerResult<OptimizationReport> {
+        let ir = self.prepare(self.lower_str(source)?)?;
+        Ok(self.optimize_with_report(ir).1)
+    }
+
+    /// Compile a Cedar policy like [`Compiler::compile_str`] and measure the
+    /// module, so a test can hold a policy to a size budget
+    pub fn codegen_stats(&self, source: &str) -> CompilerResult<CodegenStats> {
+        let wasm_bytes = self.compile_str(source)?;
+        CodegenStats::of(&wasm_bytes).map_err(CompilerError::WasmError)
+    }
+
+    /// Warnings about Cedar policies that compile but are likely mistakes,
+    /// such as a condition that is always false; see
+    /// [`diagnostics::check`]. An empty list means nothing looks wrong.
+    pub fn diagnostics(&self, source: &str) -> CompilerResult<Vec<diagnostics::Diagnostic>> {
+        let policy_set = adapter::parse_policy_set(source).map_err(CompilerError::ParseError)?;
+        let ir = self.prepare(LoweredModule::from_policy_set(&policy_set)?)?;
+
+        Ok(diagnostics::check(&ir))
+    }
+
+    /// Check that a Cedar policy compiles, without generating code: parse
+    /// it, lower it, redact sensitive strings and check it against the
+    /// schema if there is one, as compiling does. Only limits of code
+    /// generation itself, such as [`CompilerOptions::max_memory_pages`], are
+    /// left unchecked.
+    pub fn validate_str(&self, source: &str) -> CompilerResult<()> {
+        let template = adapter::parse_template(source).map_err(CompilerError::ParseError)?;
+
+        self.validate(LoweredModule::from_template(&template)?)
+    }
+
+    /// Check that a policy in Cedar's JSON format compiles, like
+    /// [`Compiler::validate_str`]
+    pub fn validate_json_str(&self, source: &str) -> CompilerResult<()> {
+        let template =
+            adapter::parse_json_template(source).map_err(CompilerError::JsonParseError)?;
+
+        self.validate(LoweredModule::from_template(&template)?)
+    }
+
+    /// Compile a Cedar policy from a string to the format selected by
+    /// [`CompilerOptions::emit`]
+    pub fn emit_str(&self, source: &str) -> CompilerResult<Vec<u8>> {
+        match self.options.emit {
+            EmitFormat::Wasm => self.compile_str(source),
+            EmitFormat::Wat => Ok(self.compile_str_to_wat(source)?.into_bytes()),
+            EmitFormat::Component => self.compile_str_to_component(source),
+        }
+    }
+
+    /// Compile a Cedar policy from a string, also returning the redacted
+    /// strings the host must resolve at runtime
+    pub fn compile_str_redacted(&self, source: &str) -> CompilerResult<RedactedModule> {
+        let template = adapter::parse_template(source).map_err(CompilerError::ParseError)?;
+        let ir = LoweredModule::from_template(&template)?;
+
+        self.build(ir)
+    }
+
     /// Compile a parsed Cedar template (which may be a policy)
     pub fn compile_template(&self, template: &Template) -> CompilerResult<Vec<u8>> {
-        // Convert template to policy for now
-        // In v3.3, templates are the main AST type
-        let ir = LoweredModule::from_template(template)
-            .map_err(|e| CompilerError::CompilationError(e))?;
+        let ir = LoweredModule::from_template(template)?;
 
-        // Step 2: Apply optimization passes
-        let optimized_ir = if self.opt_level > 0 {
-            crate::optimization::optimize(ir, self.opt_level)
-        } else {
-            ir
-        };
+        self.optimize_and_generate(ir)
+    }
 
-        // Step 3: Generate WebAssembly
-        let mut codegen = WasmCodeGen::new();
-        let wasm_bytes = codegen
-            .generate(&optimized_ir)
-            .map_err(|e| CompilerError::WasmError(e))?;
+    /// Compile a parsed Cedar template once, for linking to many entities
+    /// with [`TemplateModule::instantiate`]
+    pub fn compile_template_linked(
+        &self,
+        template: &Template,
+    ) -> CompilerResult<TemplateModule<'_>> {
+        let ir = LoweredModule::from_template(template)?;
+        let ir = self.optimize(self.prepare(ir)?);
+        let wasm = self.generate(&ir, None)?.wasm;
+
+        Ok(TemplateModule {
+            compiler: self,
+            ir,
+            wasm,
+        })
+    }
+
+    /// Compile every policy in a parsed Cedar policy set into one module whose
+    /// `evaluate` export combines the individual decisions
+    pub fn compile_policy_set(&self, policy_set: &PolicySet) -> CompilerResult<Vec<u8>> {
+        let ir = LoweredModule::from_policy_set(policy_set)?;
+
+        self.optimize_and_generate(ir)
+    }
+
+    /// Compile a Cedar policy set from a string into one module, like
+    /// [`Compiler::compile_policy_set`]. The module lists each policy's ID
+    /// and effect in its `cedar.policies` section, so a host can report
+    /// which policy a decision came from.
+    pub fn compile_policy_set_to_bytes(&self, source: &str) -> CompilerResult<Vec<u8>> {
+        let policy_set = adapter::parse_policy_set(source).map_err(CompilerError::ParseError)?;
+
+        self.compile_policy_set(&policy_set)
+    }
+
+    /// Compile every template in a parsed Cedar policy set once, rather than
+    /// each of its links. The module's `evaluate` takes the entities bound to
+    /// `?principal` and `?resource` after the request parameters, so one
+    /// module serves every link.
+    pub fn compile_templates(&self, policy_set: &PolicySet) -> CompilerResult<Vec<u8>> {
+        let ir = LoweredModule::from_templates(policy_set)?;
 
-        Ok(wasm_bytes)
+        self.optimize_and_generate(ir)
+    }
+
+    /// List the literal values (longs, strings, entities, extension
+    /// constructors) a policy compares against, with their source spans
+    pub fn literals(&self, source: &str) -> CompilerResult<Vec<LiteralValue>> {
+        let template = adapter::parse_template(source).map_err(CompilerError::ParseError)?;
+
+        Ok(analysis::literals(&template))
+    }
+
+    /// List the entity UIDs of the module's entity table, so that position
+    /// `i` holds the UID the host passes to `evaluate` as handle `i`
+    pub fn entity_table(&self, source: &str) -> CompilerResult<Vec<String>> {
+        let policy_set = adapter::parse_policy_set(source).map_err(CompilerError::ParseError)?;
+
+        Ok(analysis::entity_table(&policy_set)?)
+    }
+
+    /// Emit a JSON test vector skeleton for Cedar policies; see
+    /// [`analysis::test_vector_template`]
+    pub fn test_vectors(&self, source: &str) -> CompilerResult<String> {
+        let policy_set = adapter::parse_policy_set(source).map_err(CompilerError::ParseError)?;
+        let template = analysis::test_vector_template(&policy_set)?;
+
+        Ok(serde_json::to_string_pretty(&template).expect("JSON values always serialize"))
+    }
+
+    /// Translate Cedar policies into an SMT-LIB script that asserts the
+    /// combined decision is Permit, for checking properties with an SMT
+    /// solver. See [`crate::smt`] for how requests are modelled.
+    pub fn to_smtlib(&self, source: &str) -> CompilerResult<String> {
+        let policy_set = adapter::parse_policy_set(source).map_err(CompilerError::ParseError)?;
+        let ir = LoweredModule::from_policy_set(&policy_set)?;
+
+        crate::smt::to_smtlib(&self.optimize(ir)).map_err(CompilerError::CompilationError)
     }
 
     /// Compile a Cedar policy from a file
@@ -81,29 +688,331 @@ impl Compiler {
         self.compile_str(&source)
     }
 
+    /// Compile every `.cedar` file in a directory, in file name order, into
+    /// one module evaluating them as a policy set, so a forbid in any file
+    /// overrides a permit in another. Policies are numbered across the
+    /// files as if they were one.
+    pub fn compile_dir(&self, path: impl AsRef<Path>) -> CompilerResult<Vec<u8>> {
+        let dir = PolicyDir::read(path.as_ref())?;
+        self.compile_policy_set_to_bytes(&dir.source)
+            .map_err(|err| dir.locate(err))
+    }
+
+    /// Compile a directory of Cedar policies to the WebAssembly text format,
+    /// like [`Compiler::compile_dir`]
+    pub fn compile_dir_to_wat(&self, path: impl AsRef<Path>) -> CompilerResult<String> {
+        let wasm_bytes = self.compile_dir(path)?;
+        to_wat(&wasm_bytes)
+    }
+
+    /// Compile a Cedar policy from a string to the WebAssembly text format
+    pub fn compile_str_to_wat(&self, source: &str) -> CompilerResult<String> {
+        let wasm_bytes = self.compile_str(source)?;
+        to_wat(&wasm_bytes)
+    }
+
+    /// Compile a Cedar policy from a string to a WebAssembly component that
+    /// imports the runtime functions as a WIT interface and exports
+    /// `evaluate`
+    pub fn compile_str_to_component(&self, source: &str) -> CompilerResult<Vec<u8>> {
+        let wasm_bytes = self.compile_str(source)?;
+        to_component(&wasm_bytes)
+    }
+
+    /// Compile a policy in Cedar's JSON format to a WebAssembly component,
+    /// like [`Compiler::compile_str_to_component`]
+    pub fn compile_json_str_to_component(&self, source: &str) -> CompilerResult<Vec<u8>> {
+        let wasm_bytes = self.compile_json_str(source)?;
+        to_component(&wasm_bytes)
+    }
+
+    /// Compile a policy in Cedar's JSON format to the WebAssembly text format
+    pub fn compile_json_str_to_wat(&self, source: &str) -> CompilerResult<String> {
+        let wasm_bytes = self.compile_json_str(source)?;
+        to_wat(&wasm_bytes)
+    }
+
+    /// Compile a Cedar policy from a file to the WebAssembly text format
+    pub fn compile_file_to_wat(&self, path: impl AsRef<Path>) -> CompilerResult<String> {
+        let wasm_bytes = self.compile_file(path)?;
+        to_wat(&wasm_bytes)
+    }
+
     /// Compile a parsed Cedar policy
     pub fn compile_policy(&self, policy: &Policy) -> CompilerResult<Vec<u8>> {
         // Step 1: Lower Cedar AST to intermediate representation
-        let ir = LoweredModule::from_policy(policy)
-            .map_err(|e| CompilerError::CompilationError(e))?;
+        let ir = LoweredModule::from_policy(policy)?;
+
+        self.optimize_and_generate(ir)
+    }
+
+    /// Check lowered IR the way compiling it would before generating code
+    fn validate(&self, ir: LoweredModule) -> CompilerResult<()> {
+        self.prepare(ir).map(drop)
+    }
+
+    /// Type-check unless mistyped code should fail at runtime, then apply
+    /// the schema
+    fn check_schema(&self, ir: &mut LoweredModule, schema: &Schema) -> CompilerResult<()> {
+        if !self.runtime_type_errors {
+            ir.type_check(schema)?;
+        }
+        ir.apply_schema(schema)?;
+        Ok(())
+    }
+
+    /// Run the optimization passes selected by the optimization level
+    fn optimize(&self, ir: LoweredModule) -> LoweredModule {
+        self.optimize_with_report(ir).0
+    }
+
+    /// Run the optimization passes, reporting the built-in ones followed by
+    /// the custom ones
+    fn optimize_with_report(&self, ir: LoweredModule) -> (LoweredModule, OptimizationReport) {
+        let (ir, mut report) = optimization::optimize_with_report(ir, self.options.opt_level);
+        let (ir, custom) = self.custom_passes.run_with_report(ir);
+        report.passes.extend(custom.passes);
+        (ir, report)
+    }
+
+    /// Run the optimization passes and WASM codegen over lowered IR
+    fn optimize_and_generate(&self, ir: LoweredModule) -> CompilerResult<Vec<u8>> {
+        self.build(ir).map(|module| module.wasm)
+    }
+
+    /// Redact sensitive strings and apply the schema, if any
+    fn prepare(&self, mut ir: LoweredModule) -> CompilerResult<LoweredModule> {
+        if let Some(pattern) = &self.sensitive_pattern {
+            ir.redact_strings(|s| matches_pattern(pattern, s))?;
+        }
+        if let Some(schema) = &self.schema {
+            self.check_schema(&mut ir, schema)?;
+        }
+        Ok(ir)
+    }
+
+    /// Redact, optimize, and generate WASM for lowered IR
+    fn build(&self, ir: LoweredModule) -> CompilerResult<RedactedModule> {
+        let ir = self.prepare(ir)?;
 
         // Step 2: Apply optimization passes
-        let optimized_ir = if self.opt_level > 0 {
-            crate::optimization::optimize(ir, self.opt_level)
-        } else {
-            ir
-        };
+        let optimized_ir = self.optimize(ir);
+
+        self.generate(&optimized_ir, None)
+    }
 
+    /// Generate WASM for optimized IR, with the template's slots bound to
+    /// these entity handles if given
+    fn generate(
+        &self,
+        optimized_ir: &LoweredModule,
+        bound_slots: Option<(u32, u32)>,
+    ) -> CompilerResult<RedactedModule> {
         // Step 3: Generate WebAssembly
-        let mut codegen = WasmCodeGen::new();
+        let mut codegen = WasmCodeGen::new()
+            .with_mvp_only(self.mvp_only)
+            .with_batch_evaluation(self.batch_evaluation)
+            .with_coverage(self.coverage)
+            .with_policy_exports(self.policy_exports)
+            .with_error_codes(self.error_codes)
+            .with_names(self.options.debug_names)
+            .with_max_pages(self.options.max_memory_pages)
+            .with_memory64(self.options.memory64)
+            .with_initialize(self.options.initialize)
+            .with_json_requests(self.options.abi == Abi::JsonRequest)
+            .with_bound_slots(bound_slots)
+            .with_source_map(self.options.source_map)
+            .with_overflow(self.options.overflow)
+            .with_validation(self.options.strict);
         let wasm_bytes = codegen
-            .generate(&optimized_ir)
-            .map_err(|e| CompilerError::WasmError(e))?;
+            .generate(optimized_ir)
+            .map_err(CompilerError::CompilationError)?;
+
+        // Step 4: Optionally hand the module to binaryen
+        let wasm_bytes = match self.options.wasm_opt_level {
+            #[cfg(feature = "wasm-opt")]
+            Some(level) => wasm_opt::optimize(
+                &wasm_bytes,
+                WasmOptSettings {
+                    level,
+                    mvp_only: self.mvp_only,
+                    memory64: self.options.memory64,
+                    debug_names: self.options.debug_names,
+                },
+            ),
+            #[cfg(not(feature = "wasm-opt"))]
+            Some(_) => {
+                tracing::warn!("skipping wasm-opt: built without the `wasm-opt` feature");
+                wasm_bytes
+            }
+            None => wasm_bytes,
+        };
+
+        Ok(RedactedModule {
+            wasm: wasm_bytes,
+            secrets: codegen.secrets().to_vec(),
+        })
+    }
+}
+
+/// Match `text` against a pattern in which `*` matches any run of characters
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it was tried at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, start)) = backtrack {
+            // Let the last `*` absorb one more character
+            p = star + 1;
+            t = start + 1;
+            backtrack = Some((star, start + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Lock the module cache; a panic while it was held cannot leave an entry
+/// half-written, so a poisoned lock is still usable
+fn lock(cache: &Mutex<ModuleCache>) -> MutexGuard<'_, ModuleCache> {
+    cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Render a compiled module as WAT
+fn to_wat(wasm_bytes: &[u8]) -> CompilerResult<String> {
+    wasmprinter::print_bytes(wasm_bytes).map_err(|e| CompilerError::WasmError(e.to_string()))
+}
+
+/// The `.cedar` files in a directory joined into one policy set source
+struct PolicyDir {
+    source: String,
+    /// Each file, in order, with where its text starts in `source`
+    files: Vec<(PathBuf, usize)>,
+}
+
+impl PolicyDir {
+    /// Read the files in name order. Each is parsed on its own first, so a
+    /// parse error points into the file it is in.
+    fn read(dir: &Path) -> CompilerResult<Self> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_cedar = path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("cedar"));
+            if is_cedar && path.is_file() {
+                files.push(path);
+            }
+        }
+        if files.is_empty() {
+            return Err(CompilerError::CompilationError(format!(
+                "no .cedar files in {}",
+                dir.display()
+            )));
+        }
+        files.sort();
+
+        let mut joined = PolicyDir {
+            source: String::new(),
+            files: Vec::with_capacity(files.len()),
+        };
+        for path in files {
+            let policies = std::fs::read_to_string(&path)?;
+            if let Err(errors) = adapter::parse_policy_set(&policies) {
+                return Err(CompilerError::InFile {
+                    path,
+                    error: Box::new(CompilerError::ParseError(errors)),
+                });
+            }
+            joined.files.push((path, joined.source.len()));
+            joined.source.push_str(&policies);
+            joined.source.push('\n');
+        }
+        Ok(joined)
+    }
+
+    /// Point an error from compiling the joined source into the file it
+    /// comes from: spans are moved into that file, with its line, column
+    /// and source text, and a type error is placed by its policy
+    fn locate(&self, err: CompilerError) -> CompilerError {
+        let offset = match &err {
+            CompilerError::SourceError { span, .. } => Some(span.offset()),
+            CompilerError::Unsupported {
+                span: Some(span), ..
+            } => Some(span.offset()),
+            CompilerError::TypeError(type_error) => self.policy_offset(&type_error.policy),
+            _ => None,
+        };
+        let Some(file) =
+            offset.and_then(|offset| self.files.iter().rposition(|(_, start)| *start <= offset))
+        else {
+            return err;
+        };
+        let (path, start) = &self.files[file];
+        // Less the newline that joins it to the next file
+        let end = self
+            .files
+            .get(file + 1)
+            .map_or(self.source.len(), |(_, next)| *next)
+            - 1;
+        let text = &self.source[*start..end];
+        let in_file =
+            |span: SourceSpan| SourceSpan::new((span.offset() - start).into(), span.len());
 
-        Ok(wasm_bytes)
+        let error = match err {
+            CompilerError::SourceError { message, span, .. } => {
+                let (line, column) = adapter::line_column(text, span.offset() - start);
+                CompilerError::SourceError {
+                    message,
+                    line,
+                    column,
+                    span: in_file(span),
+                    source_code: text.to_string(),
+                }
+            }
+            CompilerError::Unsupported {
+                feature,
+                span: Some(span),
+                ..
+            } => CompilerError::Unsupported {
+                feature,
+                location: Some(adapter::line_column(text, span.offset() - start)),
+                span: Some(in_file(span)),
+                source_code: Some(text.to_string()),
+            },
+            err => err,
+        };
+        CompilerError::InFile {
+            path: path.clone(),
+            error: Box::new(error),
+        }
+    }
+
+    /// Where the policy named `id` starts in the joined source
+    fn policy_offset(&self, id: &str) -> Option<usize> {
+        let policies = adapter::parse_policy_set(&self.source).ok()?;
+        let policy = policies
+            .policies()
+            .find(|policy| adapter::PolicyParts::from_policy(policy).id == id)?;
+        policy.loc().map(|loc| loc.start())
     }
 }
 
+/// Wrap a compiled module in a component
+fn to_component(wasm_bytes: &[u8]) -> CompilerResult<Vec<u8>> {
+    component::wrap(wasm_bytes).map_err(CompilerError::WasmError)
+}
+
 impl Default for Compiler {
     fn default() -> Self {
         Self::new()
@@ -113,16 +1022,2049 @@ impl Default for Compiler {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::wasm::runtime::Decision;
 
     #[test]
     fn test_compiler_creation() {
         let compiler = Compiler::new();
-        assert_eq!(compiler.opt_level, 1);
+        assert_eq!(compiler.options, CompilerOptions::default());
+        assert_eq!(compiler.options.opt_level, 1);
 
         let compiler = Compiler::new().with_opt_level(2);
-        assert_eq!(compiler.opt_level, 2);
+        assert_eq!(compiler.options.opt_level, 2);
 
         let compiler = Compiler::new().with_opt_level(10);
-        assert_eq!(compiler.opt_level, 2); // Clamped to max
+        assert_eq!(compiler.options.opt_level, 2); // Clamped to max
+    }
+
+    #[test]
+    fn test_with_options() {
+        let options = CompilerOptions {
+            opt_level: 0,
+            emit: EmitFormat::Wat,
+            debug_names: true,
+            max_memory_pages: 4,
+            strict: true,
+            memory64: false,
+            wasm_opt_level: None,
+            initialize: false,
+            abi: Abi::Handles,
+            source_map: false,
+            overflow: Overflow::Error,
+        };
+        let compiler = Compiler::with_options(options.clone());
+        assert_eq!(compiler.options, options);
+
+        let wat = compiler
+            .emit_str("permit(principal, action, resource) when { 1 < 2 };")
+            .unwrap();
+        let wat = String::from_utf8(wat).unwrap();
+        // Unoptimized, so the comparison is still there
+        assert!(wat.contains("i64.lt_s"));
+        assert!(wat.contains("(func $evaluate"));
+        assert!(wat.contains("(memory (;0;) 1 4)"));
+
+        let too_small = Compiler::with_options(CompilerOptions {
+            max_memory_pages: 0,
+            ..options
+        });
+        assert!(too_small
+            .compile_str("permit(principal, action, resource);")
+            .is_err());
+
+        let clamped = Compiler::with_options(CompilerOptions {
+            opt_level: 7,
+            ..CompilerOptions::default()
+        });
+        assert_eq!(clamped.options.opt_level, 2);
+    }
+
+    #[test]
+    fn test_compile_str_many_keeps_results_per_policy() {
+        fn assert_send<T: Send>() {}
+        assert_send::<WasmCodeGen>();
+
+        let sources = [
+            r#"permit(principal == User::"alice", action, resource);"#,
+            "permit(principal, action",
+            "forbid(principal, action, resource);",
+            "permit(principal, action, resource) when { principal.tags.map() };",
+        ];
+        let results = Compiler::new().compile_str_many(&sources);
+
+        assert_eq!(results.len(), 4);
+        assert_eq!(
+            results[0].as_ref().unwrap(),
+            &Compiler::new().compile_str(sources[0]).unwrap()
+        );
+        assert!(matches!(results[1], Err(CompilerError::ParseError(_))));
+        assert_eq!(
+            run_evaluate(results[2].as_ref().unwrap(), 0, 0, 0),
+            Decision::Deny as i32
+        );
+        assert!(results[3].is_err());
+    }
+
+    #[test]
+    fn test_string_pool_respects_memory_limit() {
+        use wasmparser::{Parser, Payload};
+
+        let policy = format!(
+            r#"permit(principal, action, resource) when {{ principal.name == "{}" }};"#,
+            "x".repeat(100_000)
+        );
+
+        let tiny = Compiler::with_options(CompilerOptions {
+            max_memory_pages: 1,
+            ..CompilerOptions::default()
+        });
+        match tiny.compile_str(&policy) {
+            Err(CompilerError::CompilationError(message)) => {
+                assert!(message.contains("over the limit of 1"), "{}", message)
+            }
+            other => panic!("expected a compilation error, got {:?}", other),
+        }
+
+        // Within the default limit, memory starts out large enough
+        let wasm_bytes = Compiler::new().compile_str(&policy).unwrap();
+        let memory = Parser::new(0)
+            .parse_all(&wasm_bytes)
+            .find_map(|payload| match payload.unwrap() {
+                Payload::MemorySection(memories) => memories.into_iter().next(),
+                _ => None,
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(memory.initial, 2);
+    }
+
+    #[test]
+    fn test_memory64() {
+        use wasmparser::{Parser, Payload, Validator, WasmFeatures};
+
+        let options = CompilerOptions {
+            strict: true,
+            memory64: true,
+            ..CompilerOptions::default()
+        };
+        // Batch evaluation, coverage and the entity table all address memory
+        let wasm_bytes = Compiler::with_options(options.clone())
+            .with_batch_evaluation(true)
+            .with_coverage(true)
+            .compile_str(
+                r#"permit(principal == User::"alice", action, resource)
+                when { principal.name == "alice" };"#,
+            )
+            .unwrap();
+        let memory = Parser::new(0)
+            .parse_all(&wasm_bytes)
+            .find_map(|payload| match payload.unwrap() {
+                Payload::MemorySection(memories) => memories.into_iter().next(),
+                _ => None,
+            })
+            .unwrap()
+            .unwrap();
+        assert!(memory.memory64);
+        assert_eq!(memory.maximum, Some(memory::MAX_PAGES.into()));
+
+        let mut features = WasmFeatures::default();
+        features.set(WasmFeatures::MEMORY64, true);
+        Validator::new_with_features(features)
+            .validate_all(&wasm_bytes)
+            .unwrap();
+        features.set(WasmFeatures::MEMORY64, false);
+        assert!(Validator::new_with_features(features)
+            .validate_all(&wasm_bytes)
+            .is_err());
+
+        let mvp = Compiler {
+            mvp_only: true,
+            ..Compiler::with_options(options)
+        };
+        assert!(mvp
+            .compile_str("permit(principal, action, resource);")
+            .is_err());
+    }
+
+    #[test]
+    fn test_initialize() {
+        use wasmparser::{DataKind, Parser, Payload};
+
+        let options = CompilerOptions {
+            strict: true,
+            initialize: true,
+            ..CompilerOptions::default()
+        };
+        let wasm_bytes = Compiler::with_options(options.clone())
+            .compile_str(
+                r#"permit(principal, action, resource) when { principal.name == "alice" };"#,
+            )
+            .unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+
+        let mut exports = Vec::new();
+        let mut passive = false;
+        for payload in Parser::new(0).parse_all(&wasm_bytes) {
+            match payload.unwrap() {
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        exports.push(export.unwrap().name.to_string());
+                    }
+                }
+                Payload::DataSection(reader) => {
+                    for data in reader {
+                        passive |= matches!(data.unwrap().kind, DataKind::Passive);
+                    }
+                }
+                _ => {}
+            }
+        }
+        assert!(exports.contains(&"_initialize".to_string()));
+        assert!(passive);
+
+        // Without literals there is nothing to load, but the export remains
+        let wasm_bytes = Compiler::with_options(options.clone())
+            .compile_str("permit(principal, action, resource);")
+            .unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+
+        let mvp = Compiler {
+            mvp_only: true,
+            ..Compiler::with_options(options)
+        };
+        assert!(mvp
+            .compile_str("permit(principal, action, resource);")
+            .is_err());
+    }
+
+    #[test]
+    fn test_source_map() {
+        use crate::wasm::codegen::SOURCE_MAP_SECTION;
+        use wasmparser::{BinaryReader, Parser, Payload};
+
+        let source = r#"permit(principal, action, resource)
+            when { context.level > 3 }
+            unless { context.locked };"#;
+        let options = CompilerOptions {
+            strict: true,
+            source_map: true,
+            ..CompilerOptions::default()
+        };
+        let wasm_bytes = Compiler::with_options(options).compile_str(source).unwrap();
+
+        let data = Parser::new(0)
+            .parse_all(&wasm_bytes)
+            .find_map(|payload| match payload.unwrap() {
+                Payload::CustomSection(section) if section.name() == SOURCE_MAP_SECTION => {
+                    Some(section.data().to_vec())
+                }
+                _ => None,
+            })
+            .expect("source map section");
+        let mut reader = BinaryReader::new(&data, 0);
+        assert_eq!(reader.read_var_u32().unwrap(), 1);
+        reader.read_var_u32().unwrap();
+        let mut spans = Vec::new();
+        let mut last_offset = 0;
+        for _ in 0..reader.read_var_u32().unwrap() {
+            let offset = reader.read_var_u32().unwrap();
+            assert!(offset >= last_offset);
+            last_offset = offset;
+            let start = reader.read_var_u32().unwrap() as usize;
+            let end = reader.read_var_u32().unwrap() as usize;
+            spans.push(&source[start..end]);
+        }
+        assert!(spans.contains(&"context.level > 3"), "{:?}", spans);
+        assert!(spans.contains(&"context.locked"), "{:?}", spans);
+
+        // Without the option there is no section
+        let wasm_bytes = Compiler::new().compile_str(source).unwrap();
+        assert!(!Parser::new(0).parse_all(&wasm_bytes).any(|payload| {
+            matches!(payload, Ok(Payload::CustomSection(section)) if section.name() == SOURCE_MAP_SECTION)
+        }));
+    }
+
+    #[test]
+    fn test_json_request_abi() {
+        use wasmparser::{ExternalKind, FuncType, Parser, Payload, TypeRef, ValType};
+
+        let options = CompilerOptions {
+            strict: true,
+            abi: Abi::JsonRequest,
+            ..CompilerOptions::default()
+        };
+        let wasm_bytes = Compiler::with_options(options.clone())
+            .compile_str(r#"permit(principal == User::"alice", action, resource);"#)
+            .unwrap();
+
+        let mut types: Vec<FuncType> = Vec::new();
+        let mut functions = Vec::new();
+        let mut evaluate = None;
+        for payload in Parser::new(0).parse_all(&wasm_bytes) {
+            match payload.unwrap() {
+                Payload::TypeSection(reader) => {
+                    types.extend(reader.into_iter_err_on_gc_types().map(Result::unwrap));
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        if let TypeRef::Func(ty) = import.unwrap().ty {
+                            functions.push(ty);
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    functions.extend(reader.into_iter().map(Result::unwrap));
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export.unwrap();
+                        if export.name == "evaluate" && export.kind == ExternalKind::Func {
+                            evaluate = Some(export.index);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let ty = &types[functions[evaluate.unwrap() as usize] as usize];
+        assert_eq!(ty.params(), [ValType::I32, ValType::I32]);
+        assert_eq!(ty.results(), [ValType::I32]);
+
+        // The handle ABI is kept for features that extend its signature
+        assert!(Compiler::with_options(options)
+            .with_batch_evaluation(true)
+            .compile_str("permit(principal, action, resource);")
+            .is_err());
+    }
+
+    /// Instantiate a compiled module and run `evaluate` for one request
+    fn run_evaluate(wasm_bytes: &[u8], principal: i32, action: i32, resource: i32) -> i32 {
+        use wasmtime::{Engine, Instance, Module, Store};
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm_bytes).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+            .unwrap();
+
+        evaluate
+            .call(&mut store, (principal, action, resource, 0))
+            .unwrap()
+    }
+
+    /// A linker whose runtime functions all trap; tests shadow the ones the
+    /// module under test is expected to call
+    fn stub_linker<T>(engine: &wasmtime::Engine) -> wasmtime::Linker<T> {
+        let mut linker = wasmtime::Linker::new(engine);
+        linker.allow_shadowing(true);
+        linker
+            .func_wrap("cedar", "string_eq", |_: i32, _: i32| -> i32 { unreachable!() })
+            .unwrap()
+            .func_wrap("cedar", "get_attribute", |_: i32, _: i32| -> i64 {
+                unreachable!()
+            })
+            .unwrap()
+            .func_wrap("cedar", "has_attribute", |_: i32, _: i32| -> i32 {
+                unreachable!()
+            })
+            .unwrap()
+            .func_wrap("cedar", "entity_in", |_: i32, _: i32| -> i32 { unreachable!() })
+            .unwrap()
+            .func_wrap("cedar", "resolve_secret", |_: i32| -> i32 { unreachable!() })
+            .unwrap()
+            .func_wrap("cedar", "string_like", |_: i32, _: i32| -> i32 { unreachable!() })
+            .unwrap()
+            .func_wrap("cedar", "set_contains", |_: i32, _: i64| -> i32 { unreachable!() })
+            .unwrap()
+            .func_wrap("cedar", "set_contains_all", |_: i32, _: i32| -> i32 {
+                unreachable!()
+            })
+            .unwrap()
+            .func_wrap("cedar", "set_contains_any", |_: i32, _: i32| -> i32 {
+                unreachable!()
+            })
+            .unwrap()
+            .func_wrap("cedar", "entity_is", |_: i32, _: i32| -> i32 { unreachable!() })
+            .unwrap()
+            .func_wrap("cedar", "ip", |_: i32| -> i32 { unreachable!() })
+            .unwrap()
+            .func_wrap("cedar", "ip_is_in_range", |_: i32, _: i32| -> i32 {
+                unreachable!()
+            })
+            .unwrap()
+            .func_wrap("cedar", "set_equal", |_: i32, _: i32| -> i32 {
+                unreachable!()
+            })
+            .unwrap()
+            .func_wrap("cedar", "parse_request", |_: i32, _: i32, _: i32| -> i32 {
+                unreachable!()
+            })
+            .unwrap();
+        for name in [
+            "ip_is_ipv4",
+            "ip_is_ipv6",
+            "ip_is_loopback",
+            "ip_is_multicast",
+        ] {
+            linker
+                .func_wrap("cedar", name, |_: i32| -> i32 { unreachable!() })
+                .unwrap();
+        }
+        linker
+    }
+
+    #[test]
+    fn test_policy_set_forbid_overrides_permit() {
+        let policy_set = adapter::parse_policy_set(
+            r#"
+            permit(principal, action, resource);
+            forbid(principal, action, resource);
+            "#,
+        )
+        .unwrap();
+        let wasm_bytes = Compiler::new().compile_policy_set(&policy_set).unwrap();
+
+        assert_eq!(run_evaluate(&wasm_bytes, 0, 0, 0), Decision::Deny as i32);
+    }
+
+    #[test]
+    fn test_compile_dir() {
+        let dir = std::env::temp_dir().join(format!("cedar-compile-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.cedar"), "permit(principal, action, resource);").unwrap();
+        std::fs::write(
+            dir.join("b.cedar"),
+            r#"forbid(principal == User::"banned", action, resource);"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a policy").unwrap();
+
+        let result = Compiler::new().compile_dir(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let wasm_bytes = result.unwrap();
+
+        // User::"banned" is the only interned entity, index 0
+        assert_eq!(run_evaluate(&wasm_bytes, 0, 1, 2), Decision::Deny as i32);
+        assert_eq!(run_evaluate(&wasm_bytes, 7, 1, 2), Decision::Permit as i32);
+    }
+
+    #[test]
+    fn test_compile_dir_errors_name_their_file() {
+        let dir =
+            std::env::temp_dir().join(format!("cedar-compile-dir-errors-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let permit = "permit(principal, action, resource);";
+        let multiply = "permit(principal, action, resource)\nwhen { principal.level * 2 > 2 };";
+        let mistyped = r#"permit(principal, action, resource) when { principal.age == "x" };"#;
+        let schema =
+            Schema::from_cedarschema_str("entity User { age: Long, level: Long };").unwrap();
+        let compile = |files: &[(&str, &str)], compiler: Compiler| {
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                std::fs::remove_file(entry.unwrap().path()).unwrap();
+            }
+            for (name, text) in files {
+                std::fs::write(dir.join(name), text).unwrap();
+            }
+            match compiler.compile_dir(&dir) {
+                Err(CompilerError::InFile { path, error }) => (path, *error),
+                other => panic!("{:?} compiled to {:?}", files, other.map(|_| ())),
+            }
+        };
+
+        let parse = compile(
+            &[("a.cedar", permit), ("b.cedar", "permit(")],
+            Compiler::new(),
+        );
+        let unsupported = compile(
+            &[("a.cedar", permit), ("b.cedar", multiply)],
+            Compiler::new(),
+        );
+        let type_error = compile(
+            &[("a.cedar", mistyped), ("b.cedar", permit)],
+            Compiler::new().with_schema(schema),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(parse.0, dir.join("b.cedar"));
+        assert!(matches!(parse.1, CompilerError::ParseError(_)));
+
+        // The span, line and source are b.cedar's, not the joined text's
+        assert_eq!(unsupported.0, dir.join("b.cedar"));
+        match unsupported.1 {
+            CompilerError::Unsupported {
+                location: Some((line, _)),
+                span: Some(span),
+                source_code: Some(source_code),
+                ..
+            } => {
+                assert_eq!(line, 2);
+                assert_eq!(source_code, multiply);
+                assert!(multiply[span.offset()..].starts_with("principal.level * 2"));
+            }
+            other => panic!("expected an unsupported feature, got {:?}", other),
+        }
+
+        assert_eq!(type_error.0, dir.join("a.cedar"));
+        assert!(matches!(type_error.1, CompilerError::TypeError(_)));
+    }
+
+    #[test]
+    fn test_entity_denylist() {
+        // User::"banned" is the first (and only) interned entity, index 0
+        let compiler = Compiler::new();
+        let banned = 0;
+        let other = 7;
+
+        let forbid = compiler
+            .compile_str(
+                r#"forbid(principal, action, resource) when { principal == User::"banned" };"#,
+            )
+            .unwrap();
+        assert_eq!(run_evaluate(&forbid, banned, 1, 2), Decision::Deny as i32);
+        assert_eq!(
+            run_evaluate(&forbid, other, 1, 2),
+            Decision::NoDecision as i32
+        );
+
+        let permit = compiler
+            .compile_str(
+                r#"permit(principal, action, resource) when { principal != User::"banned" };"#,
+            )
+            .unwrap();
+        assert_eq!(
+            run_evaluate(&permit, banned, 1, 2),
+            Decision::NoDecision as i32
+        );
+        assert_eq!(run_evaluate(&permit, other, 1, 2), Decision::Permit as i32);
+    }
+
+    #[test]
+    fn test_scoped_permit_matches_principal() {
+        // User::"alice" is interned first, Action::"read" second
+        let wasm_bytes = Compiler::new()
+            .compile_str(
+                r#"permit(principal == User::"alice", action == Action::"read", resource);"#,
+            )
+            .unwrap();
+
+        assert_eq!(run_evaluate(&wasm_bytes, 0, 1, 5), Decision::Permit as i32);
+        assert_eq!(
+            run_evaluate(&wasm_bytes, 2, 1, 5),
+            Decision::NoDecision as i32
+        );
+        assert_eq!(
+            run_evaluate(&wasm_bytes, 0, 3, 5),
+            Decision::NoDecision as i32
+        );
+    }
+
+    #[test]
+    fn test_repeated_attribute_read_is_fetched_once() {
+        use crate::wasm::runtime::runtime_functions;
+        use wasmparser::{Operator, Parser, Payload};
+
+        let source = r#"permit(principal, action, resource) when { principal.role == "a" || principal.role == "b" };"#;
+        let get_attribute_calls = |opt_level| {
+            let wasm_bytes = Compiler::new()
+                .with_opt_level(opt_level)
+                .compile_str(source)
+                .unwrap();
+            wasmparser::validate(&wasm_bytes).unwrap();
+            let mut calls = 0;
+            for payload in Parser::new(0).parse_all(&wasm_bytes) {
+                if let Payload::CodeSectionEntry(body) = payload.unwrap() {
+                    for op in body.get_operators_reader().unwrap() {
+                        if let Operator::Call { function_index } = op.unwrap() {
+                            calls += (function_index == runtime_functions::GET_ATTRIBUTE) as usize;
+                        }
+                    }
+                }
+            }
+            calls
+        };
+
+        assert_eq!(get_attribute_calls(1), 2);
+        assert_eq!(get_attribute_calls(2), 1);
+    }
+
+    #[test]
+    fn test_guarded_read_skips_the_error_check() {
+        use crate::wasm::runtime::runtime_functions;
+        use wasmparser::{Operator, Parser, Payload};
+
+        let source = r#"permit(principal, action, resource) when { principal has role && principal.role == "admin" };"#;
+        let wasm_bytes = Compiler::new()
+            .with_opt_level(2)
+            .compile_str(source)
+            .unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+
+        let mut operators = Vec::new();
+        for payload in Parser::new(0).parse_all(&wasm_bytes) {
+            if let Payload::CodeSectionEntry(body) = payload.unwrap() {
+                for op in body.get_operators_reader().unwrap() {
+                    operators.push(op.unwrap());
+                }
+            }
+        }
+        let calls = |func: u32| {
+            operators
+                .iter()
+                .filter(
+                    |op| matches!(op, Operator::Call { function_index } if *function_index == func),
+                )
+                .count()
+        };
+        assert_eq!(calls(runtime_functions::HAS_ATTRIBUTE), 1);
+        assert_eq!(calls(runtime_functions::GET_ATTRIBUTE), 1);
+        // The error flag is only cleared on entry, never checked
+        assert!(!operators
+            .iter()
+            .any(|op| matches!(op, Operator::GlobalGet { .. })));
+    }
+
+    #[test]
+    fn test_templates_take_slot_parameters() {
+        use wasmparser::{Parser, Payload};
+        use wasmtime::{Engine, Module, Store};
+
+        let policy_set = adapter::parse_policy_set(
+            r#"
+            permit(principal == ?principal, action, resource);
+            forbid(principal, action, resource in ?resource);
+            "#,
+        )
+        .unwrap();
+        let wasm_bytes = Compiler::new().compile_templates(&policy_set).unwrap();
+
+        // The policy entry type gains the two slot parameters
+        let params = Parser::new(0)
+            .parse_all(&wasm_bytes)
+            .find_map(|payload| match payload.unwrap() {
+                Payload::TypeSection(types) => Some(types.into_iter_err_on_gc_types().next()),
+                _ => None,
+            })
+            .flatten()
+            .unwrap()
+            .unwrap()
+            .params()
+            .len();
+        assert_eq!(params, 6);
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm_bytes).unwrap();
+        let mut store = Store::new(&engine, ());
+        let mut linker = stub_linker(&engine);
+        linker
+            .func_wrap("cedar", "entity_in", |child: i32, parent: i32| {
+                (child == parent) as i32
+            })
+            .unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32, i32, i32), i32>(&mut store, "evaluate")
+            .unwrap();
+
+        // Linked to principal 3 and resource 9
+        let mut run = |principal, resource| {
+            evaluate
+                .call(&mut store, (principal, 0, resource, 0, 3, 9))
+                .unwrap()
+        };
+        assert_eq!(run(3, 5), Decision::Permit as i32);
+        assert_eq!(run(4, 5), Decision::NoDecision as i32);
+        assert_eq!(run(3, 9), Decision::Deny as i32);
+
+        let batch = Compiler::new().with_batch_evaluation(true);
+        assert!(batch.compile_templates(&policy_set).is_err());
+    }
+
+    #[test]
+    fn test_json_policy_compiles_like_text() {
+        let text = r#"permit(principal == User::"alice", action, resource)
+            when { resource.owner == principal && context.level >= 3 };"#;
+        let json = r#"{
+            "effect": "permit",
+            "principal": { "op": "==", "entity": { "type": "User", "id": "alice" } },
+            "action": { "op": "All" },
+            "resource": { "op": "All" },
+            "conditions": [{
+                "kind": "when",
+                "body": { "&&": {
+                    "left": { "==": {
+                        "left": { ".": { "left": { "Var": "resource" }, "attr": "owner" } },
+                        "right": { "Var": "principal" }
+                    } },
+                    "right": { ">=": {
+                        "left": { ".": { "left": { "Var": "context" }, "attr": "level" } },
+                        "right": { "Value": 3 }
+                    } }
+                } }
+            }]
+        }"#;
+
+        let compiler = Compiler::new().with_opt_level(2);
+        assert_eq!(
+            compiler.compile_json_str(json).unwrap(),
+            compiler.compile_str(text).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_logic_mixing_comparisons_and_attributes_validates() {
+        for condition in [
+            "principal.age > 18 && resource.public",
+            "!resource.public || principal.age > 18",
+            "!(principal.age > 18 && !resource.public)",
+        ] {
+            let source = format!(
+                "permit(principal, action, resource) when {{ {} }};",
+                condition
+            );
+            for opt_level in 0..=2 {
+                let wasm_bytes = Compiler::new()
+                    .with_opt_level(opt_level)
+                    .compile_str(&source)
+                    .unwrap();
+                wasmparser::validate(&wasm_bytes).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_target_mvp_validates_under_mvp_features() {
+        use wasmparser::{Validator, WasmFeatures};
+
+        let policy_set = adapter::parse_policy_set(
+            r#"
+            permit(principal, action, resource);
+            forbid(principal, action, resource) when { false };
+            "#,
+        )
+        .unwrap();
+        let wasm_bytes = Compiler::target_mvp()
+            .compile_policy_set(&policy_set)
+            .unwrap();
+
+        Validator::new_with_features(WasmFeatures::MVP)
+            .validate_all(&wasm_bytes)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_batch_fetches_shared_principal_attribute_once() {
+        use crate::wasm::runtime::memory::BATCH_REQUEST_SIZE;
+        use wasmtime::{Caller, Engine, Module, Store};
+
+        let wasm_bytes = Compiler::new()
+            .with_batch_evaluation(true)
+            .compile_str("permit(principal, action, resource) when { principal.level > 3 };")
+            .unwrap();
+
+        // The store data counts get_attribute calls; principal 0 has level 5
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm_bytes).unwrap();
+        let mut store = Store::new(&engine, 0u32);
+        let mut linker = stub_linker(&engine);
+        linker
+            .func_wrap(
+                "cedar",
+                "get_attribute",
+                |mut caller: Caller<'_, u32>, entity: i32, _attr: i32| -> i64 {
+                    *caller.data_mut() += 1;
+                    if entity == 0 {
+                        5
+                    } else {
+                        1
+                    }
+                },
+            )
+            .unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+
+        // Three requests from principal 0 against different resources
+        let requests: Vec<u8> = (0..3i32)
+            .flat_map(|resource| [0i32, 1, resource, 0])
+            .flat_map(i32::to_le_bytes)
+            .collect();
+        let results_ptr = requests.len();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        memory.write(&mut store, 0, &requests).unwrap();
+
+        let evaluate_batch = instance
+            .get_typed_func::<(i32, i32, i32), ()>(&mut store, "evaluate_batch")
+            .unwrap();
+        evaluate_batch
+            .call(&mut store, (0, 3, results_ptr as i32))
+            .unwrap();
+
+        assert_eq!(*store.data(), 1);
+        assert_eq!(requests.len(), 3 * BATCH_REQUEST_SIZE as usize);
+        let mut results = [0u8; 12];
+        memory.read(&store, results_ptr, &mut results).unwrap();
+        for decision in results.chunks(4) {
+            let decision = i32::from_le_bytes(decision.try_into().unwrap());
+            assert_eq!(decision, Decision::Permit as i32);
+        }
+
+        // A new batch starts from an empty memo
+        evaluate_batch
+            .call(&mut store, (0, 3, results_ptr as i32))
+            .unwrap();
+        assert_eq!(*store.data(), 2);
+    }
+
+    #[test]
+    fn test_compile_to_wat() {
+        let wat = Compiler::new()
+            .compile_str_to_wat("permit(principal, action, resource);")
+            .unwrap();
+
+        assert!(wat.contains("(func"));
+        assert!(wat.contains("(export \"evaluate\""));
+    }
+
+    #[test]
+    fn test_cache_returns_identical_modules() {
+        let source = r#"permit(principal, action, resource) when { resource.public };"#;
+        let compiler = Compiler::new().with_cache(8);
+
+        let first = compiler.compile_str(source).unwrap();
+        let second = compiler.compile_str(source).unwrap();
+        assert_eq!(first, second);
+        let stats = compiler.cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+        // Changing a setting drops the modules compiled with the old ones
+        let mut compiler = compiler.with_opt_level(0).with_coverage(true);
+        assert_eq!(compiler.cache_stats().unwrap().entries, 0);
+        let instrumented = compiler.compile_str(source).unwrap();
+        assert_ne!(instrumented, first);
+        assert_eq!(compiler.cache_stats().unwrap().misses, 2);
+        assert_eq!(Compiler::new().cache_stats(), None);
+
+        // So does adding a pass, whatever it is called
+        struct Forbid;
+        impl Pass for Forbid {
+            fn name(&self) -> &str {
+                "constant-folding"
+            }
+
+            fn run(&self, mut module: LoweredModule) -> LoweredModule {
+                for policy in &mut module.policies {
+                    policy.effect = crate::ast::adapter::PolicyEffect::Forbid;
+                }
+                module
+            }
+        }
+        compiler.add_pass(Box::new(Forbid));
+        assert_eq!(compiler.cache_stats().unwrap().entries, 0);
+        assert_ne!(compiler.compile_str(source).unwrap(), instrumented);
+    }
+
+    #[test]
+    fn test_component_output() {
+        use wasmparser::{Parser, Payload};
+        use wasmtime::component::{Component, Linker};
+        use wasmtime::{Engine, Store};
+
+        let compiler = Compiler::with_options(CompilerOptions {
+            emit: EmitFormat::Component,
+            ..CompilerOptions::default()
+        });
+        let component = compiler
+            .emit_str(r#"permit(principal, action, resource) when { principal.level > 2 };"#)
+            .unwrap();
+        wasmparser::validate(&component).unwrap();
+
+        let (mut imports, mut exports, mut wit) = (vec![], vec![], None);
+        for payload in Parser::new(0).parse_all(&component) {
+            match payload.unwrap() {
+                Payload::ComponentImportSection(reader) => {
+                    for import in reader {
+                        imports.push(import.unwrap().name.0.to_string());
+                    }
+                }
+                Payload::ComponentExportSection(reader) => {
+                    for export in reader {
+                        exports.push(export.unwrap().name.0.to_string());
+                    }
+                }
+                Payload::CustomSection(section) if section.name() == component::WIT_SECTION => {
+                    wit = Some(String::from_utf8(section.data().to_vec()).unwrap());
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(imports, ["cedar:policy/runtime"]);
+        assert_eq!(exports, ["evaluate"]);
+        let wit = wit.unwrap();
+        assert!(wit.contains("get-attribute: func(entity: s32, attr: s32) -> s64;"));
+        assert!(wit.contains(
+            "export evaluate: func(principal: s32, action: s32, resource: s32, context: s32) -> s32;"
+        ));
+
+        // A policy that never calls the host imports nothing, and runs as is
+        let component = compiler
+            .compile_str_to_component(r#"permit(principal == User::"alice", action, resource);"#)
+            .unwrap();
+        let engine = Engine::default();
+        let component = Component::new(&engine, &component).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &component)
+            .unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), (i32,)>(&mut store, "evaluate")
+            .unwrap();
+        let (decision,) = evaluate.call(&mut store, (0, 0, 0, 0)).unwrap();
+        assert_eq!(decision, Decision::Permit as i32);
+
+        let core = compiler
+            .compile_str("permit(principal, action, resource);")
+            .unwrap();
+        assert!(!component::wit(&core).unwrap().contains("import"));
+    }
+
+    #[test]
+    fn test_component_rejects_calls_reading_memory() {
+        use crate::wasm::runtime::runtime_functions;
+        use wasmtime::component::{Component, Linker, Val};
+        use wasmtime::{Engine, Store};
+
+        // The host could not read the string pool through the component
+        let compiler = Compiler::new();
+        for (source, function) in [
+            (
+                r#"permit(principal, action, resource) when { principal.name == "alice" };"#,
+                "string_eq",
+            ),
+            (
+                r#"permit(principal is User, action, resource);"#,
+                "entity_is",
+            ),
+        ] {
+            match compiler.compile_str_to_component(source) {
+                Err(CompilerError::WasmError(message)) => {
+                    assert!(message.contains(function), "{}", message)
+                }
+                other => panic!("expected a WasmError, got {:?}", other.map(|_| ())),
+            }
+        }
+
+        // Handles and integers go through the interface as they are
+        let component = compiler
+            .compile_str_to_component(
+                "permit(principal, action, resource) when { principal.level > 2 };",
+            )
+            .unwrap();
+        let engine = Engine::default();
+        let component = Component::new(&engine, &component).unwrap();
+        let mut linker = Linker::new(&engine);
+        let mut runtime = linker.instance("cedar:policy/runtime").unwrap();
+        for name in runtime_functions::NAMES {
+            let name = name.replace('_', "-");
+            let result = match name.as_str() {
+                "get-attribute" => Val::S64(3),
+                _ => Val::S32(0),
+            };
+            runtime
+                .func_new(&name, move |_, _, results| {
+                    results[0] = result.clone();
+                    Ok(())
+                })
+                .unwrap();
+        }
+        let mut store = Store::new(&engine, ());
+        let instance = linker.instantiate(&mut store, &component).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), (i32,)>(&mut store, "evaluate")
+            .unwrap();
+        let (decision,) = evaluate.call(&mut store, (0, 0, 0, 0)).unwrap();
+        assert_eq!(decision, Decision::Permit as i32);
+    }
+
+    #[test]
+    fn test_to_smtlib() {
+        let script = Compiler::new()
+            .to_smtlib(
+                r#"
+                permit(principal, action, resource) when { principal.level > 3 };
+                forbid(principal, action, resource) when { principal == User::"banned" };
+                "#,
+            )
+            .unwrap();
+
+        assert!(script.contains("(define-fun policy0 () Int"));
+        assert!(script.contains("(define-fun policy1 () Int"));
+        assert!(script.contains("(assert (= decision 1))"));
+    }
+
+    #[test]
+    fn test_sensitive_string_is_resolved_by_host() {
+        use wasmtime::{Caller, Engine, Module, Store};
+
+        let secret = "s3cr3t-value";
+        let redacted = Compiler::new()
+            .compile_str_redacted(
+                r#"
+                @sensitive
+                permit(principal, action, resource) when { principal.token == "s3cr3t-value" };
+                "#,
+            )
+            .unwrap();
+        assert_eq!(redacted.secrets, vec![secret.to_string()]);
+        assert!(!redacted
+            .wasm
+            .windows(secret.len())
+            .any(|w| w == secret.as_bytes()));
+
+        /// Write a length-prefixed string into the instance's memory
+        fn write_str(caller: &mut Caller<'_, u32>, ptr: i32, s: &str) -> i32 {
+            let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
+            let mut bytes = (s.len() as u32).to_le_bytes().to_vec();
+            bytes.extend_from_slice(s.as_bytes());
+            memory.write(caller, ptr as usize, &bytes).unwrap();
+            ptr
+        }
+        fn read_str(caller: &mut Caller<'_, u32>, ptr: i32) -> Vec<u8> {
+            let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
+            let data = memory.data(caller);
+            let ptr = ptr as usize;
+            let len = u32::from_le_bytes(data[ptr..ptr + 4].try_into().unwrap()) as usize;
+            data[ptr + 4..ptr + 4 + len].to_vec()
+        }
+
+        // The store data counts resolve_secret calls; principal 0's token is
+        // the secret, everyone else's is not
+        let engine = Engine::default();
+        let module = Module::new(&engine, &redacted.wasm).unwrap();
+        let mut linker = stub_linker(&engine);
+        linker
+            .func_wrap(
+                "cedar",
+                "resolve_secret",
+                move |mut caller: Caller<'_, u32>, id: i32| -> i32 {
+                    *caller.data_mut() += 1;
+                    assert_eq!(id, 0);
+                    write_str(&mut caller, 0x100, secret)
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap(
+                "cedar",
+                "get_attribute",
+                move |mut caller: Caller<'_, u32>, entity: i32, _attr: i32| -> i64 {
+                    let token = if entity == 0 { secret } else { "guess" };
+                    write_str(&mut caller, 0x200, token) as i64
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap(
+                "cedar",
+                "string_eq",
+                |mut caller: Caller<'_, u32>, a: i32, b: i32| -> i32 {
+                    (read_str(&mut caller, a) == read_str(&mut caller, b)) as i32
+                },
+            )
+            .unwrap();
+
+        let mut store = Store::new(&engine, 0u32);
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+            .unwrap();
+
+        assert_eq!(
+            evaluate.call(&mut store, (0, 1, 2, 0)).unwrap(),
+            Decision::Permit as i32
+        );
+        assert_eq!(
+            evaluate.call(&mut store, (5, 1, 2, 0)).unwrap(),
+            Decision::NoDecision as i32
+        );
+        assert_eq!(*store.data(), 2);
+    }
+
+    #[test]
+    fn test_sensitive_like_pattern_is_resolved_by_host() {
+        use wasmtime::{Caller, Engine, Module, Store};
+
+        let redacted = Compiler::new()
+            .compile_str_redacted(
+                r#"
+                @sensitive
+                permit(principal, action, resource)
+                when { principal.name like "topsecret*" && principal.k == "hunter2" };
+                "#,
+            )
+            .unwrap();
+        for secret in ["topsecret", "hunter2"] {
+            assert!(!redacted
+                .wasm
+                .windows(secret.len())
+                .any(|w| w == secret.as_bytes()));
+        }
+        assert_eq!(redacted.secrets, ["topsecret*", "hunter2"]);
+
+        // The host matches against the pattern it resolved; the name never
+        // matches, so the `==` is not reached
+        let engine = Engine::default();
+        let module = Module::new(&engine, &redacted.wasm).unwrap();
+        let mut linker = stub_linker(&engine);
+        linker
+            .func_wrap("cedar", "resolve_secret", |id: i32| -> i32 { 0x100 + id })
+            .unwrap()
+            .func_wrap("cedar", "get_attribute", |_: i32, _: i32| -> i64 { 0x200 })
+            .unwrap()
+            .func_wrap(
+                "cedar",
+                "string_like",
+                |mut caller: Caller<'_, Vec<(i32, i32)>>, value: i32, pattern: i32| -> i32 {
+                    caller.data_mut().push((value, pattern));
+                    0
+                },
+            )
+            .unwrap();
+        let mut store = Store::new(&engine, Vec::new());
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+            .unwrap();
+        assert_eq!(
+            evaluate.call(&mut store, (0, 1, 2, 0)).unwrap(),
+            Decision::NoDecision as i32
+        );
+        assert_eq!(store.data().as_slice(), [(0x200, 0x100)]);
+    }
+
+    #[test]
+    fn test_sensitive_pattern() {
+        let contains = |wasm: &[u8], s: &str| wasm.windows(s.len()).any(|w| w == s.as_bytes());
+        let source = r#"
+            permit(principal, action, resource)
+            when { principal.key == "sk_live_123" && principal.name == "alice" };
+        "#;
+
+        let plain = Compiler::new().compile_str(source).unwrap();
+        assert!(contains(&plain, "sk_live_123"));
+
+        let redacted = Compiler::new()
+            .with_sensitive_pattern("sk_*")
+            .compile_str_redacted(source)
+            .unwrap();
+        assert!(!contains(&redacted.wasm, "sk_live_123"));
+        assert!(contains(&redacted.wasm, "alice"));
+        assert_eq!(redacted.secrets, vec!["sk_live_123".to_string()]);
+
+        // `like` patterns are redacted too
+        let compiler = Compiler::new().with_sensitive_pattern("topsecret*");
+        let redacted = compiler
+            .compile_str_redacted(
+                r#"permit(principal, action, resource) when { principal.name like "topsecret*" };"#,
+            )
+            .unwrap();
+        assert!(!contains(&redacted.wasm, "topsecret"));
+        assert_eq!(redacted.secrets, ["topsecret*"]);
+
+        // Set and record literals can't be, so they are rejected
+        for condition in [
+            r#"["topsecretX"].contains(principal.name)"#,
+            r#"{ key: "topsecretX" }.key == principal.name"#,
+        ] {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            let err = compiler.compile_str_redacted(&source).unwrap_err();
+            assert!(err.to_string().contains("cannot be redacted"), "{}", err);
+        }
+
+        assert!(matches_pattern("sk_*", "sk_"));
+        assert!(matches_pattern("*-token-*", "a-token-b"));
+        assert!(!matches_pattern("sk_*", "pk_sk_1"));
+    }
+
+    #[test]
+    fn test_unsupported_expression_reports_line() {
+        let source = "permit(principal, action, resource)\nwhen {\n  principal.tags.hasTag(\"x\")\n};";
+        let err = Compiler::new().compile_str(source).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Unsupported entity tag method `hasTag` at line 3, column 3"
+        );
+        match err {
+            CompilerError::Unsupported {
+                span: Some(span), ..
+            } => {
+                let text = &source[span.offset()..span.offset() + span.len()];
+                assert_eq!(text, "principal.tags.hasTag(\"x\")");
+            }
+            other => panic!("expected an unsupported error, got {:?}", other),
+        }
+
+        // Columns count characters, not bytes
+        let source = "permit(principal, action, resource)\nwhen { \"é\" == \"é\" && principal.tags.hasTag(\"x\") };";
+        let err = Compiler::new().compile_str(source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unsupported entity tag method `hasTag` at line 2, column 22"
+        );
+    }
+
+    #[test]
+    fn test_unsupported_features_are_named() {
+        let feature = |condition: &str| {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            match Compiler::new().compile_str(&source).unwrap_err() {
+                CompilerError::Unsupported { feature, .. } => feature,
+                other => panic!("expected an unsupported error, got {:?}", other),
+            }
+        };
+
+        assert_eq!(feature("principal.level * 2 > 10"), "multiplication");
+        assert_eq!(
+            feature(r#"context.now.toDate() == datetime("2024-01-01")"#),
+            "extension call `toDate`"
+        );
+        assert_eq!(
+            feature("principal.groups.isEmpty()"),
+            "set method `isEmpty`"
+        );
+        assert_eq!(
+            feature(r#"[1, "one"].contains(principal.level)"#),
+            "set literal with elements of different types"
+        );
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let err = Compiler::new()
+            .compile_str("permit(principal, action, resource)\nwhen { principal.x == };")
+            .unwrap_err();
+        assert!(matches!(err, CompilerError::ParseError(_)));
+        assert!(
+            err.to_string()
+                .starts_with("Parse error at line 2, column 23: "),
+            "{}",
+            err
+        );
+        // The parser's labels point at the offending token
+        let label = err.labels().unwrap().next().unwrap();
+        assert_eq!(label.offset(), 58);
+        assert!(err.source_code().is_some());
+    }
+
+    #[test]
+    fn test_output_is_deterministic() {
+        let source = r#"
+            permit(principal == User::"alice", action in [Action::"read", Action::"edit"], resource)
+            when {
+                resource.owner == "alice" &&
+                ["a", "b"].contains(principal.tag) &&
+                { level: 3, team: "x" }.level > context.min &&
+                principal in Group::"admins"
+            };
+        "#;
+        let compile = || {
+            Compiler::with_options(CompilerOptions {
+                debug_names: true,
+                ..Default::default()
+            })
+            .with_batch_evaluation(true)
+            .with_coverage(true)
+            .with_sensitive_pattern("ali*")
+            .compile_str(source)
+            .unwrap()
+        };
+
+        let first = compile();
+        assert_eq!(first, compile());
+        let many = Compiler::new().compile_str_many(&[source, source]);
+        let [Ok(a), Ok(b)] = many.as_slice() else {
+            panic!("expected two modules, got {:?}", many);
+        };
+        assert_eq!(a, b);
+        assert_eq!(*a, Compiler::new().compile_str(source).unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_lowered_module_round_trip() {
+        let source = r#"permit(principal in Group::"admins", action, resource)
+            when { resource.tags.contains("x") && { level: 3 }.level > context.min };"#;
+        let ir = Compiler::new().lower_str(source).unwrap();
+        let json = serde_json::to_string(&ir).unwrap();
+        let cached: LoweredModule = serde_json::from_str(&json).unwrap();
+
+        for level in 0..=2 {
+            let compiler = Compiler::new().with_opt_level(level);
+            assert_eq!(
+                compiler.compile_ir(&cached).unwrap(),
+                compiler.compile_str(source).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_policy_exports() {
+        use wasmparser::{Parser, Payload};
+
+        let source = r#"
+            @id("admins")
+            permit(principal == User::"admin", action, resource);
+            forbid(principal, action, resource) when { 1 > 2 };
+        "#;
+        let wasm_bytes = Compiler::new()
+            .with_policy_exports(true)
+            .compile_str(source)
+            .unwrap();
+
+        let exports: Vec<String> = Parser::new(0)
+            .parse_all(&wasm_bytes)
+            .filter_map(|payload| match payload.unwrap() {
+                Payload::ExportSection(exports) => Some(exports),
+                _ => None,
+            })
+            .flatten()
+            .map(|export| export.unwrap().name.to_string())
+            .collect();
+        for name in ["evaluate", "evaluate_admins", "evaluate_policy1"] {
+            assert!(exports.iter().any(|e| e == name), "{:?}", exports);
+        }
+
+        // Without the option, several policies are a parse error
+        assert!(matches!(
+            Compiler::new().compile_str(source),
+            Err(CompilerError::ParseError(_))
+        ));
+        assert!(Compiler::new()
+            .with_policy_exports(true)
+            .with_batch_evaluation(true)
+            .compile_str(source)
+            .is_err());
+    }
+
+    #[test]
+    fn test_compile_str_with_id() {
+        use crate::wasm::codegen::POLICIES_SECTION;
+        use wasmparser::{Parser, Payload};
+
+        let schema = Schema::from_cedarschema_str("entity User { age: Long };").unwrap();
+        let compiler = Compiler::new().with_schema(schema);
+        let id = || Some(PolicyID::from_string("adults"));
+
+        let err = compiler
+            .compile_str_with_id(
+                r#"permit(principal, action, resource) when { principal.age == "x" };"#,
+                id(),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("adults"), "{}", err);
+
+        let wasm_bytes = compiler
+            .compile_str_with_id(
+                "permit(principal, action, resource) when { principal.age >= 18 };",
+                id(),
+            )
+            .unwrap();
+        let policies = Parser::new(0)
+            .parse_all(&wasm_bytes)
+            .find_map(|payload| match payload.unwrap() {
+                Payload::CustomSection(section) if section.name() == POLICIES_SECTION => {
+                    Some(section.data().to_vec())
+                }
+                _ => None,
+            })
+            .unwrap();
+        // One policy: its length-prefixed ID, then its effect
+        assert_eq!(&policies[..8], b"\x01\x06adults");
+    }
+
+    #[test]
+    fn test_optimization_report() {
+        let source = "permit(principal, action, resource) when { true && false };";
+        let report = Compiler::new().optimization_report(source).unwrap();
+
+        // The effect is swapped into place, and `&&` lowers to a branch on
+        // `true`, which folds to its arm; the constant condition then
+        // selects `NoDecision`
+        let folding = &report.passes[0];
+        assert_eq!(folding.name, "constant-folding");
+        assert_eq!(folding.constants_folded, 3);
+        assert_eq!(folding.instructions_removed(), 9);
+        assert_eq!(report.constants_folded(), 3);
+        assert_eq!(report.instructions_removed(), 9);
+        assert!(report
+            .to_string()
+            .starts_with("constant-folding: 11 -> 2 instructions, 3 constants folded"));
+
+        let report = Compiler::new()
+            .with_opt_level(0)
+            .optimization_report(source)
+            .unwrap();
+        assert!(report.passes.is_empty());
+    }
+
+    #[test]
+    fn test_codegen_stats() {
+        use crate::wasm::runtime::runtime_functions;
+
+        let source = r#"permit(principal, action, resource) when { principal.name == "alice" };"#;
+        let stats = Compiler::new().codegen_stats(source).unwrap();
+
+        assert_eq!(
+            stats.wasm_bytes_len,
+            Compiler::new().compile_str(source).unwrap().len()
+        );
+        // Clearing the error global, the attribute read and its error check,
+        // the string comparison and the select, then `end`
+        assert_eq!(stats.instruction_count, 20);
+        assert_eq!(stats.import_count, runtime_functions::COUNT as usize);
+        // "alice" with its length prefix, padded to a multiple of 4
+        assert_eq!(stats.data_bytes, 12);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let compiler = Compiler::new();
+
+        let warnings = compiler
+            .diagnostics("permit(principal, action, resource) when { 1 > 2 };")
+            .unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            "warning: policy0: policy condition is always false; policy will never apply"
+        );
+
+        let warnings = compiler
+            .diagnostics(
+                r#"
+                permit(principal, action, resource) when { principal.age > 18 };
+                forbid(principal, action, resource) unless { 2 > 1 };
+                permit(principal, action, resource);
+                "#,
+            )
+            .unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].policy, "policy1");
+    }
+
+    #[test]
+    fn test_unconditional_policy_folds_to_its_effect() {
+        use crate::ast::lowering::Instruction;
+
+        let compiler = Compiler::new();
+        let ir = compiler
+            .optimize_ir(
+                compiler
+                    .lower_str("permit(principal, action, resource);")
+                    .unwrap(),
+            )
+            .unwrap();
+        assert!(matches!(
+            ir.policies[0].instructions(),
+            [Instruction::Permit, Instruction::Return]
+        ));
+
+        let wasm_bytes = compiler
+            .compile_str("forbid(principal, action, resource) when { true };")
+            .unwrap();
+        assert_eq!(run_evaluate(&wasm_bytes, 0, 0, 0), Decision::Deny as i32);
+    }
+
+    #[test]
+    fn test_validate_str() {
+        let compiler = Compiler::new();
+        assert!(compiler
+            .validate_str("permit(principal, action, resource) when { principal.level > 2 };")
+            .is_ok());
+        assert!(matches!(
+            compiler.validate_str(
+                "permit(principal, action, resource) when { principal.level * 2 > 2 };"
+            ),
+            Err(CompilerError::Unsupported { .. })
+        ));
+        assert!(matches!(
+            compiler.validate_str("permit(principal, action, resource) when {"),
+            Err(CompilerError::ParseError(_))
+        ));
+
+        let schema = Schema::from_cedarschema_str("entity User { level: Long };").unwrap();
+        let err = Compiler::new()
+            .with_schema(schema)
+            .validate_str("permit(principal, action, resource) when { principal.age > 2 };")
+            .unwrap_err();
+        assert!(err.to_string().contains("Attribute `age` is not declared"));
+
+        // Strings that cannot be redacted fail validation as they fail compiling
+        let compiler = Compiler::new().with_sensitive_pattern("secret*");
+        let source = r#"permit(principal, action, resource) when { ["secret-a"].contains(principal.name) };"#;
+        assert!(compiler.compile_str(source).is_err());
+        let err = compiler.validate_str(source).unwrap_err();
+        assert!(err.to_string().contains("cannot be redacted"), "{}", err);
+    }
+
+    #[test]
+    fn test_schema_rejects_undeclared_attributes() {
+        let schema = Schema::from_cedarschema_str(
+            "entity User { level: Long }; action view appliesTo { principal: User, resource: User };",
+        )
+        .unwrap();
+        let compiler = Compiler::new().with_schema(schema);
+
+        let err = compiler
+            .compile_str("permit(principal, action, resource) when { principal.age > 18 };")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Compilation error: Attribute `age` is not declared in the schema"
+        );
+        assert!(compiler
+            .compile_str(
+                r#"permit(principal is User, action == Action::"view", resource) when { principal.level > 2 };"#
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_schema_rejects_mistyped_expressions() {
+        let schema = Schema::from_cedarschema_str(
+            "entity User { age: Long, level: Long, tags: Set<String> };",
+        )
+        .unwrap();
+        let compiler = Compiler::new().with_schema(schema.clone());
+        let type_error = |condition: &str| {
+            let source = format!(
+                "permit(principal, action, resource) when {{ {} }};",
+                condition
+            );
+            match compiler.compile_str(&source) {
+                Err(CompilerError::TypeError(err)) => err.message,
+                other => panic!("{} compiled to {:?}", condition, other.map(|_| ())),
+            }
+        };
+
+        assert_eq!(
+            type_error(r#"principal.age == "x""#),
+            "`==` compares Long with String"
+        );
+        assert_eq!(
+            type_error("principal.level.contains(1)"),
+            "`contains` expects Set, found Long"
+        );
+        assert_eq!(
+            type_error(r#"principal.tags.containsAll("x")"#),
+            "`containsAll` expects Set, found Set and String"
+        );
+        assert_eq!(
+            type_error(r#"principal.age < "x""#),
+            "`<` expects two Longs, two Decimals or two DateTimes, found Long and String"
+        );
+        assert_eq!(
+            type_error("-principal.tags > 2"),
+            "`-` expects Long, found Set"
+        );
+        // Entity literals are entities, never integers
+        assert_eq!(
+            type_error(r#"principal.age == User::"alice""#),
+            "`==` compares Long with Entity"
+        );
+
+        let err = compiler
+            .validate_str(r#"permit(principal, action, resource) when { principal.age == "x" };"#)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Type error in policy policy0: `==` compares Long with String"
+        );
+        assert!(compiler
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when {
+                    principal.age >= 18 &&
+                    principal.tags.contains("admin") &&
+                    principal != User::"alice"
+                };"#
+            )
+            .is_ok());
+        assert!(Compiler::new()
+            .with_schema(schema)
+            .with_runtime_type_errors(true)
+            .compile_str(r#"permit(principal, action, resource) when { principal.age == "x" };"#)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_custom_pass_runs() {
+        use crate::ast::lowering::Instruction;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        /// Turns every permit into a forbid
+        struct InvertPermits(Arc<AtomicUsize>);
+
+        impl Pass for InvertPermits {
+            fn name(&self) -> &str {
+                "invert-permits"
+            }
+
+            fn run(&self, mut module: LoweredModule) -> LoweredModule {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                for inst in module.policies.iter_mut().flat_map(|p| &mut p.instructions) {
+                    if matches!(inst, Instruction::Permit) {
+                        *inst = Instruction::Forbid;
+                    }
+                }
+                module
+            }
+        }
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut compiler = Compiler::new().with_opt_level(0);
+        compiler.add_pass(Box::new(InvertPermits(runs.clone())));
+        let wasm_bytes = compiler
+            .compile_str("permit(principal, action, resource);")
+            .unwrap();
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(run_evaluate(&wasm_bytes, 0, 0, 0), Decision::Deny as i32);
+    }
+
+    #[test]
+    fn test_bare_boolean_attribute_condition() {
+        use wasmtime::{Engine, Module, Store};
+
+        let wasm_bytes = Compiler::new()
+            .compile_str("permit(principal, action, resource) when { principal.active };")
+            .unwrap();
+
+        // Principal 0 is active, everyone else is not
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm_bytes).unwrap();
+        let mut linker = stub_linker(&engine);
+        linker
+            .func_wrap("cedar", "get_attribute", |entity: i32, _attr: i32| -> i64 {
+                (entity == 0) as i64
+            })
+            .unwrap();
+
+        let mut store = Store::new(&engine, ());
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+            .unwrap();
+
+        assert_eq!(
+            evaluate.call(&mut store, (0, 1, 2, 0)).unwrap(),
+            Decision::Permit as i32
+        );
+        assert_eq!(
+            evaluate.call(&mut store, (3, 1, 2, 0)).unwrap(),
+            Decision::NoDecision as i32
+        );
+    }
+
+    #[test]
+    fn test_boolean_literal_with_host_condition() {
+        use wasmtime::{Engine, Module, Store};
+
+        let source = "permit(principal, action, resource) when { true && principal has role };";
+        for opt_level in 0..=2 {
+            let wasm_bytes = Compiler::with_options(CompilerOptions {
+                opt_level,
+                strict: true,
+                ..CompilerOptions::default()
+            })
+            .compile_str(source)
+            .unwrap();
+
+            let engine = Engine::default();
+            let module = Module::new(&engine, &wasm_bytes).unwrap();
+            let mut linker = stub_linker(&engine);
+            linker
+                .func_wrap("cedar", "has_attribute", |entity: i32, _attr: i32| -> i32 {
+                    (entity == 0) as i32
+                })
+                .unwrap();
+            let mut store = Store::new(&engine, ());
+            let instance = linker.instantiate(&mut store, &module).unwrap();
+            let evaluate = instance
+                .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+                .unwrap();
+
+            assert_eq!(
+                evaluate.call(&mut store, (0, 1, 2, 0)).unwrap(),
+                Decision::Permit as i32
+            );
+            assert_eq!(
+                evaluate.call(&mut store, (3, 1, 2, 0)).unwrap(),
+                Decision::NoDecision as i32
+            );
+        }
+    }
+
+    #[test]
+    fn test_entity_table_in_data_section() {
+        use wasmtime::{Engine, Instance, Module, Store};
+
+        let policy_set = adapter::parse_policy_set(
+            r#"
+            permit(principal, action, resource) when { principal == User::"alice" };
+            forbid(principal, action, resource) when { principal == User::"bob" };
+            permit(principal, action, resource) when { resource == User::"alice" };
+            "#,
+        )
+        .unwrap();
+        let wasm_bytes = Compiler::new().compile_policy_set(&policy_set).unwrap();
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm_bytes).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let global = |store: &mut Store<()>, name| {
+            let global = instance.get_global(&mut *store, name).unwrap();
+            global.get(&mut *store).unwrap_i32() as usize
+        };
+        let table = global(&mut store, "entity_table");
+        let count = global(&mut store, "entity_count");
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let data = memory.data(&store);
+        let read_u32 = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+        let uids: Vec<_> = (0..count)
+            .map(|idx| {
+                let ptr = read_u32(table + idx * 4);
+                std::str::from_utf8(&data[ptr + 4..ptr + 4 + read_u32(ptr)]).unwrap()
+            })
+            .collect();
+
+        assert_eq!(uids, vec![r#"User::"alice""#, r#"User::"bob""#]);
+    }
+
+    #[test]
+    fn test_entity_table_follows_source_order() {
+        let entities = Compiler::new()
+            .entity_table(
+                r#"
+                permit(principal == User::"carol", action, resource)
+                when { resource in Folder::"shared" || principal == User::"alice" };
+                forbid(principal, action == Action::"delete", resource)
+                when { principal == User::"carol" || resource == Doc::"readme" };
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            entities,
+            vec![
+                r#"User::"carol""#,
+                r#"Folder::"shared""#,
+                r#"User::"alice""#,
+                r#"Action::"delete""#,
+                r#"Doc::"readme""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_fuel_scales_with_set_size() {
+        use wasmtime::{Config, Engine, Instance, Module, Store};
+
+        // Membership in a set of `size` users, as a chain of `==`
+        let policy = |size: usize| {
+            let members = (0..size)
+                .map(|i| format!(r#"principal == User::"u{i}""#))
+                .collect::<Vec<_>>()
+                .join(" || ");
+            format!("permit(principal, action, resource) when {{ {members} }};")
+        };
+        let max_fuel = |source: &str| {
+            let policy_set = adapter::parse_policy_set(source).unwrap();
+            LoweredModule::from_policy_set(&policy_set).unwrap().max_fuel()
+        };
+
+        let small = max_fuel(&policy(2));
+        let large = max_fuel(&policy(8));
+        assert!(large > small);
+        assert_eq!(large - small, 6 * (max_fuel(&policy(3)) - small));
+
+        // The estimate bounds what a metered runtime actually charges: the
+        // call would trap if it ran out of fuel
+        let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+        let wasm_bytes = Compiler::new().with_opt_level(0).compile_str(&policy(8)).unwrap();
+        let module = Module::new(&engine, &wasm_bytes).unwrap();
+        let mut store = Store::new(&engine, ());
+        store.set_fuel(large).unwrap();
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+            .unwrap();
+        assert_eq!(
+            evaluate.call(&mut store, (7, 8, 9, 0)).unwrap(),
+            Decision::Permit as i32
+        );
+    }
+
+    #[test]
+    fn test_referenced_attributes() {
+        use crate::ast::lowering::{EntityRef, RequestVar};
+
+        let ir = Compiler::new()
+            .lower_str(
+                r#"permit(principal, action, resource)
+                when { principal.role == "admin" || resource.owner == principal };"#,
+            )
+            .unwrap();
+        assert_eq!(
+            ir.referenced_attributes(),
+            [
+                (EntityRef::Var(RequestVar::Principal), "role".to_string()),
+                (EntityRef::Var(RequestVar::Resource), "owner".to_string()),
+            ]
+        );
+        assert!(ir.referenced_entity_types().is_empty());
+    }
+
+    #[test]
+    fn test_shared_subexpression_becomes_helper() {
+        use crate::ast::lowering::Instruction;
+        use wasmparser::{Name, Parser, Payload};
+        use wasmtime::{Engine, Module, Store};
+
+        let source = r#"
+            permit(principal, action == Action::"view", resource) when { resource.owner == principal };
+            permit(principal, action == Action::"edit", resource) when { resource.owner == principal };
+        "#;
+        let compiler = Compiler::with_options(CompilerOptions {
+            opt_level: 2,
+            debug_names: true,
+            ..Default::default()
+        });
+        let policy_set = adapter::parse_policy_set(source).unwrap();
+        let lowered = LoweredModule::from_policy_set(&policy_set).unwrap();
+        let ir = compiler.optimize_ir(lowered.clone()).unwrap();
+        assert_eq!(ir.helpers.len(), 1);
+        for policy in ir.policies() {
+            assert!(policy.instructions().contains(&Instruction::Call(0)));
+        }
+
+        let wasm_bytes = compiler.compile_ir(&lowered).unwrap();
+        let mut helpers = Vec::new();
+        for payload in Parser::new(0).parse_all(&wasm_bytes) {
+            if let Payload::CustomSection(section) = payload.unwrap() {
+                if let wasmparser::KnownCustom::Name(reader) = section.as_known() {
+                    for name in reader {
+                        if let Name::Function(names) = name.unwrap() {
+                            for naming in names {
+                                let naming = naming.unwrap();
+                                if naming.name.starts_with("helper") {
+                                    helpers.push(naming.name.to_string());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        assert_eq!(helpers, ["helper0"]);
+
+        // Resource 6 is owned by principal 5; actions are entity table
+        // indices, `view` first
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm_bytes).unwrap();
+        let mut store = Store::new(&engine, ());
+        let mut linker = stub_linker(&engine);
+        linker
+            .func_wrap("cedar", "get_attribute", |entity: i32, _attr: i32| -> i64 {
+                if entity == 6 {
+                    5
+                } else {
+                    7
+                }
+            })
+            .unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+            .unwrap();
+        let mut decide = |principal, action| {
+            evaluate
+                .call(&mut store, (principal, action, 6, 0))
+                .unwrap()
+        };
+        assert_eq!(decide(5, 0), Decision::Permit as i32);
+        assert_eq!(decide(5, 1), Decision::Permit as i32);
+        assert_ne!(decide(8, 1), Decision::Permit as i32);
+    }
+
+    #[test]
+    fn test_test_vectors_list_referenced_attributes() {
+        let json = Compiler::new()
+            .test_vectors(
+                r#"
+                permit(principal, action, resource == Doc::"readme")
+                when { principal.level > 3 && context has mfa };
+                forbid(principal, action, resource)
+                unless { resource.owner == principal.manager };
+                "#,
+            )
+            .unwrap();
+        let vectors: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(vectors["entities"], serde_json::json!([r#"Doc::"readme""#]));
+        let request = &vectors["requests"][0];
+        let attrs = |value: &serde_json::Value| {
+            let mut keys: Vec<_> = value.as_object().unwrap().keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(attrs(&request["principal"]["attrs"]), ["level", "manager"]);
+        assert_eq!(attrs(&request["resource"]["attrs"]), ["owner"]);
+        assert!(attrs(&request["action"]["attrs"]).is_empty());
+        assert_eq!(attrs(&request["context"]), ["mfa"]);
+        assert!(request["decision"].is_null());
+    }
+
+    #[test]
+    fn test_coverage_records_both_sides_of_if() {
+        use wasmtime::{Engine, Instance, Module, Store};
+
+        // Joining the clause to the scope owns bits 0 and 1, the `if` inside
+        // the clause bits 2 and 3, and the policy condition the rest
+        let wasm_bytes = Compiler::new()
+            .with_opt_level(0)
+            .with_coverage(true)
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when { if principal == User::"a" then resource == User::"b" else true };"#,
+            )
+            .unwrap();
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm_bytes).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+            .unwrap();
+        let coverage = instance.get_global(&mut store, "coverage").unwrap();
+        let coverage = coverage.get(&mut store).unwrap_i32() as usize;
+        let bits = instance.get_global(&mut store, "coverage_bits").unwrap();
+        assert_eq!(bits.get(&mut store).unwrap_i32(), 6);
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let first_byte = |store: &Store<()>| memory.data(store)[coverage];
+
+        let if_bits = |store: &Store<()>| (first_byte(store) >> 2) & 0b11;
+
+        assert_eq!(if_bits(&store), 0);
+        evaluate.call(&mut store, (0, 0, 1, 0)).unwrap();
+        assert_eq!(if_bits(&store), 0b01);
+        evaluate.call(&mut store, (2, 0, 1, 0)).unwrap();
+        assert_eq!(if_bits(&store), 0b11);
+    }
+
+    #[test]
+    fn test_is_entity_type_asks_host() {
+        use wasmtime::{Caller, Engine, Module, Store};
+
+        let wasm_bytes = Compiler::new()
+            .compile_str("permit(principal, action, resource) when { principal is User };")
+            .unwrap();
+
+        // Principal 0 is a User, anything else is not
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm_bytes).unwrap();
+        let mut store = Store::new(&engine, ());
+        let mut linker = stub_linker(&engine);
+        linker
+            .func_wrap(
+                "cedar",
+                "entity_is",
+                |mut caller: Caller<'_, ()>, entity: i32, type_name: i32| -> i32 {
+                    let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
+                    let data = memory.data(&caller);
+                    let ptr = type_name as usize;
+                    let len = u32::from_le_bytes(data[ptr..ptr + 4].try_into().unwrap()) as usize;
+                    let name = std::str::from_utf8(&data[ptr + 4..ptr + 4 + len]).unwrap();
+                    (entity == 0 && name == "User") as i32
+                },
+            )
+            .unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let evaluate = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+            .unwrap();
+
+        assert_eq!(
+            evaluate.call(&mut store, (0, 1, 2, 0)).unwrap(),
+            Decision::Permit as i32
+        );
+        assert_eq!(
+            evaluate.call(&mut store, (3, 1, 2, 0)).unwrap(),
+            Decision::NoDecision as i32
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        use crate::ast::LiteralKind;
+
+        let source = r#"
+            permit(principal, action, resource)
+            when { principal.role == "admin" && principal.level > 5 };
+        "#;
+        let literals = Compiler::new().literals(source).unwrap();
+        let kinds: Vec<_> = literals.iter().map(|l| l.kind.clone()).collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                LiteralKind::String("admin".to_string()),
+                LiteralKind::Long(5)
+            ]
+        );
+
+        // Spans point back at the literal text
+        let span = literals[0].span.unwrap();
+        assert_eq!(&source[span.start..span.end], r#""admin""#);
+    }
+
+    #[test]
+    fn test_compile_has_no_filesystem_side_effects() {
+        // The compile runs in a child process whose temp and working
+        // directories are a fresh scratch dir, which must stay empty
+        if std::env::var_os("CEDAR_COMPILE_SCRATCH").is_some() {
+            Compiler::new()
+                .compile_str("permit(principal, action, resource) when { 1 < 2 };")
+                .unwrap();
+            return;
+        }
+
+        let scratch =
+            std::env::temp_dir().join(format!("cedar-compile-scratch-{}", std::process::id()));
+        std::fs::create_dir(&scratch).unwrap();
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "compiler::tests::test_compile_has_no_filesystem_side_effects",
+            ])
+            .env("CEDAR_COMPILE_SCRATCH", "1")
+            .env("TMPDIR", &scratch)
+            .env("TMP", &scratch)
+            .env("TEMP", &scratch)
+            .current_dir(&scratch)
+            .status()
+            .unwrap();
+        let written: Vec<_> = std::fs::read_dir(&scratch)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        std::fs::remove_dir_all(&scratch).unwrap();
+
+        assert!(status.success());
+        assert!(written.is_empty(), "compiling wrote {:?}", written);
     }
 }
@@ -1,13 +1,33 @@
 //! Cedar Policy Compiler
 //!
 //! Compiles Cedar authorization policies to WebAssembly for optimized execution.
+//!
+//! Without the default `std` feature only the IR ([`ast::lowering`]) and the
+//! [`optimization`] passes are built, on `core` and `alloc`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
 pub mod ast;
+#[cfg(feature = "std")]
+pub mod cache;
+#[cfg(feature = "std")]
 pub mod compiler;
 pub mod optimization;
+#[cfg(feature = "std")]
+pub mod smt;
 pub mod wasm;
 
-pub use compiler::{Compiler, CompilerError, CompilerResult};
+#[cfg(feature = "std")]
+pub use ast::adapter::Schema;
+#[cfg(feature = "std")]
+pub use compiler::{
+    Abi, Compiler, CompilerError, CompilerOptions, CompilerResult, EmitFormat, RedactedModule,
+    TemplateModule,
+};
+#[cfg(feature = "std")]
+pub use wasm::codegen::Overflow;
 
 #[cfg(test)]
 mod tests {
@@ -20,8 +40,7 @@ mod tests {
             permit(principal, action, resource);
         "#;
 
-        // This will fail until we implement the compiler
-        // let result = compiler.compile_str(policy);
-        // assert!(result.is_ok());
+        let result = compiler.compile_str(policy);
+        assert!(result.is_ok());
     }
 }
@@ -0,0 +1,66 @@
+//! Warnings about policies that compile but are likely mistakes
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::passes::{constant_folding, dead_code_elimination};
+use crate::ast::lowering::{Instruction, LoweredFunction, LoweredModule};
+
+/// A warning about one policy. Unlike an error, it doesn't stop the policy
+/// from compiling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// ID of the policy the warning is about
+    pub policy: String,
+    pub message: String,
+}
+
+impl core::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "warning: {}: {}", self.policy, self.message)
+    }
+}
+
+/// Warn about policies whose condition constant folding reduces to `true`
+/// or `false`. A policy without a condition is meant to always apply, so
+/// it gets no warning.
+pub fn check(module: &LoweredModule) -> Vec<Diagnostic> {
+    let folded = dead_code_elimination(constant_folding(module.clone()));
+
+    let mut diagnostics = Vec::new();
+    for (policy, folded) in module.policies.iter().zip(&folded.policies) {
+        if is_unconditional(policy) {
+            continue;
+        }
+        let message = match folded.instructions.as_slice() {
+            [Instruction::NoDecision, Instruction::Return] => {
+                "policy condition is always false; policy will never apply"
+            }
+            [Instruction::Permit | Instruction::Forbid, Instruction::Return] => {
+                "policy condition is always true; policy always applies"
+            }
+            _ => continue,
+        };
+        diagnostics.push(Diagnostic {
+            policy: policy.id.clone(),
+            message: message.to_string(),
+        });
+    }
+    diagnostics
+}
+
+/// Whether the policy was lowered without a condition: its effect selected
+/// by the literal `true`
+fn is_unconditional(policy: &LoweredFunction) -> bool {
+    matches!(
+        policy.instructions.as_slice(),
+        [
+            Instruction::NoDecision,
+            Instruction::Permit | Instruction::Forbid,
+            Instruction::Swap,
+            Instruction::PushBool(true),
+            Instruction::IfThenElse,
+            Instruction::Return,
+        ]
+    )
+}
@@ -1,23 +1,297 @@
 //! Optimization passes for the intermediate representation
 
+pub mod diagnostics;
 pub mod passes;
 
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
 use crate::ast::lowering::LoweredModule;
 
-/// Apply optimization passes to the IR
+/// An IR-to-IR transformation that can be plugged into the compiler with
+/// [`crate::Compiler::add_pass`]
+pub trait Pass: Send + Sync {
+    /// Short name identifying the pass in logs
+    fn name(&self) -> &str;
+
+    /// Transform the module. The result must still be well-formed IR: every
+    /// policy function leaves a single decision on the stack.
+    fn run(&self, module: LoweredModule) -> LoweredModule;
+
+    /// Transform the module like [`Pass::run`] and count the rewrites made.
+    /// The [`PassManager`] fills in the name and instruction counts, so the
+    /// default, which counts nothing, suits passes with nothing more to say.
+    fn run_with_stats(&self, module: LoweredModule) -> (LoweredModule, PassStats) {
+        (self.run(module), PassStats::default())
+    }
+}
+
+/// What one pass did to the module
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PassStats {
+    pub name: String,
+    /// Instructions across all policies before the pass
+    pub instructions_before: usize,
+    /// Instructions across all policies after the pass
+    pub instructions_after: usize,
+    /// Constant expressions evaluated, and branches on a constant condition
+    /// replaced by their arm
+    pub constants_folded: usize,
+    /// Peephole rewrites applied
+    pub peepholes_applied: usize,
+}
+
+impl PassStats {
+    /// Net number of instructions the pass removed
+    pub fn instructions_removed(&self) -> usize {
+        self.instructions_before
+            .saturating_sub(self.instructions_after)
+    }
+}
+
+/// What each pass of an optimization pipeline did, in the order they ran
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OptimizationReport {
+    pub passes: Vec<PassStats>,
+}
+
+impl OptimizationReport {
+    /// Net number of instructions removed by all passes
+    pub fn instructions_removed(&self) -> usize {
+        self.passes
+            .iter()
+            .map(PassStats::instructions_removed)
+            .sum()
+    }
+
+    /// Constants folded by all passes
+    pub fn constants_folded(&self) -> usize {
+        self.passes.iter().map(|pass| pass.constants_folded).sum()
+    }
+
+    /// Peepholes applied by all passes
+    pub fn peepholes_applied(&self) -> usize {
+        self.passes.iter().map(|pass| pass.peepholes_applied).sum()
+    }
+}
+
+impl core::fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for pass in &self.passes {
+            writeln!(
+                f,
+                "{}: {} -> {} instructions, {} constants folded, {} peepholes applied",
+                pass.name,
+                pass.instructions_before,
+                pass.instructions_after,
+                pass.constants_folded,
+                pass.peepholes_applied
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An ordered list of passes, run one after another
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    /// A manager with no passes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The built-in pipeline for an optimization level: none at 0, constant
+    /// folding and dead code elimination from 1, set membership, instruction
+    /// combining, attribute caching, guarded reads and common subexpressions
+    /// at 2
+    pub fn for_opt_level(opt_level: u8) -> Self {
+        let mut manager = Self::new();
+        if opt_level >= 1 {
+            // Basic optimizations
+            manager.register(Box::new(passes::ConstantFolding));
+            manager.register(Box::new(passes::DeadCodeElimination));
+        }
+        if opt_level >= 2 {
+            // Aggressive optimizations
+            manager.register(Box::new(passes::SetMembership));
+            manager.register(Box::new(passes::InstructionCombining));
+            manager.register(Box::new(passes::AttributeCaching));
+            // After caching, which only reuses reads it recognizes
+            manager.register(Box::new(passes::GuardedReads));
+            manager.register(Box::new(passes::CommonSubexpressions));
+        }
+        manager
+    }
+
+    /// Append a pass; passes run in registration order
+    pub fn register(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    /// Names of the passes, in the order they run
+    pub fn names(&self) -> Vec<&str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    /// Run every pass over the module
+    pub fn run(&self, module: LoweredModule) -> LoweredModule {
+        self.run_with_report(module).0
+    }
+
+    /// Run every pass over the module, reporting what each one did
+    pub fn run_with_report(
+        &self,
+        mut module: LoweredModule,
+    ) -> (LoweredModule, OptimizationReport) {
+        let mut report = OptimizationReport::default();
+        for pass in &self.passes {
+            tracing::debug!(pass = pass.name(), "running pass");
+            let instructions_before = instruction_count(&module);
+            let before: Vec<_> = module
+                .policies
+                .iter()
+                .map(|policy| policy.instructions.clone())
+                .collect();
+            let (optimized, stats) = pass.run_with_stats(module);
+            module = optimized;
+            // Passes rewrite instructions without their spans
+            for (policy, before) in module.policies.iter_mut().zip(&before) {
+                if policy.instructions != *before {
+                    policy.realign_spans(before);
+                }
+            }
+            report.passes.push(PassStats {
+                name: pass.name().to_string(),
+                instructions_before,
+                instructions_after: instruction_count(&module),
+                ..stats
+            });
+        }
+        (module, report)
+    }
+}
+
+fn instruction_count(module: &LoweredModule) -> usize {
+    module
+        .policies
+        .iter()
+        .chain(&module.helpers)
+        .map(|p| p.instructions.len())
+        .sum()
+}
+
+/// Apply the built-in optimization passes for `opt_level` to the IR
 pub fn optimize(module: LoweredModule, opt_level: u8) -> LoweredModule {
-    let mut optimized = module;
+    PassManager::for_opt_level(opt_level).run(module)
+}
+
+/// [`optimize`], also reporting what each pass did
+pub fn optimize_with_report(
+    module: LoweredModule,
+    opt_level: u8,
+) -> (LoweredModule, OptimizationReport) {
+    PassManager::for_opt_level(opt_level).run_with_report(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::lowering::{Instruction, LoweredFunction};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts its runs and leaves the module alone
+    struct CountRuns(Arc<AtomicUsize>);
+
+    impl Pass for CountRuns {
+        fn name(&self) -> &str {
+            "count-runs"
+        }
 
-    if opt_level >= 1 {
-        // Basic optimizations
-        optimized = passes::constant_folding(optimized);
-        optimized = passes::dead_code_elimination(optimized);
+        fn run(&self, module: LoweredModule) -> LoweredModule {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            module
+        }
     }
 
-    if opt_level >= 2 {
-        // Aggressive optimizations
-        optimized = passes::instruction_combining(optimized);
+    #[test]
+    fn test_registered_pass_runs() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut manager = PassManager::for_opt_level(1);
+        manager.register(Box::new(CountRuns(runs.clone())));
+        assert_eq!(
+            manager.names(),
+            ["constant-folding", "dead-code-elimination", "count-runs"]
+        );
+
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions: vec![
+                    Instruction::PushBool(true),
+                    Instruction::Not,
+                    Instruction::Return,
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let optimized = manager.run(module);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        // The built-in passes still ran before it
+        assert!(matches!(
+            optimized.policies[0].instructions.as_slice(),
+            [Instruction::PushBool(false), Instruction::Return]
+        ));
+    }
+
+    #[test]
+    fn test_report_counts_peepholes() {
+        use crate::ast::lowering::RequestVar;
+        use Instruction::*;
+
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions: vec![
+                    Permit,
+                    NoDecision,
+                    LoadVar(RequestVar::Principal),
+                    HasAttribute("admin".to_string()),
+                    Not,
+                    Not,
+                    IfThenElse,
+                    Return,
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let (_, report) = optimize_with_report(module, 2);
+
+        assert_eq!(report.passes.len(), 7);
+        assert_eq!(report.constants_folded(), 0);
+        assert_eq!(report.peepholes_applied(), 1);
+        assert_eq!(report.passes[3].instructions_removed(), 2);
     }
 
-    optimized
+    #[test]
+    fn test_pipeline_per_opt_level() {
+        assert!(PassManager::for_opt_level(0).names().is_empty());
+        assert_eq!(
+            PassManager::for_opt_level(2).names(),
+            [
+                "constant-folding",
+                "dead-code-elimination",
+                "set-membership",
+                "instruction-combining",
+                "attribute-caching",
+                "guarded-reads",
+                "common-subexpressions"
+            ]
+        );
+    }
 }
@@ -1,54 +1,1548 @@
 //! Individual optimization passes
 
-use crate::ast::lowering::{Instruction, LoweredModule};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+use super::{Pass, PassStats};
+use crate::ast::lowering::{Instruction, LoweredFunction, LoweredModule, SetElement};
+use crate::wasm::types::WasmType;
+
+/// [`constant_folding`] as a [`Pass`]
+pub struct ConstantFolding;
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &str {
+        "constant-folding"
+    }
+
+    fn run(&self, module: LoweredModule) -> LoweredModule {
+        constant_folding(module)
+    }
+
+    fn run_with_stats(&self, module: LoweredModule) -> (LoweredModule, PassStats) {
+        let (module, folds) = fold_constants(module);
+        let stats = PassStats {
+            constants_folded: folds,
+            ..Default::default()
+        };
+        (module, stats)
+    }
+}
+
+/// [`dead_code_elimination`] as a [`Pass`]
+pub struct DeadCodeElimination;
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &str {
+        "dead-code-elimination"
+    }
+
+    fn run(&self, module: LoweredModule) -> LoweredModule {
+        dead_code_elimination(module)
+    }
+}
+
+/// [`instruction_combining`] as a [`Pass`]
+pub struct InstructionCombining;
+
+impl Pass for InstructionCombining {
+    fn name(&self) -> &str {
+        "instruction-combining"
+    }
+
+    fn run(&self, module: LoweredModule) -> LoweredModule {
+        instruction_combining(module)
+    }
+
+    fn run_with_stats(&self, module: LoweredModule) -> (LoweredModule, PassStats) {
+        let (module, peepholes) = combine_instructions(module);
+        let stats = PassStats {
+            peepholes_applied: peepholes,
+            ..Default::default()
+        };
+        (module, stats)
+    }
+}
+
+/// [`set_membership`] as a [`Pass`]
+pub struct SetMembership;
+
+impl Pass for SetMembership {
+    fn name(&self) -> &str {
+        "set-membership"
+    }
+
+    fn run(&self, module: LoweredModule) -> LoweredModule {
+        set_membership(module)
+    }
+
+    fn run_with_stats(&self, module: LoweredModule) -> (LoweredModule, PassStats) {
+        let (module, peepholes) = collapse_disjunctions(module);
+        let stats = PassStats {
+            peepholes_applied: peepholes,
+            ..Default::default()
+        };
+        (module, stats)
+    }
+}
+
+/// [`attribute_caching`] as a [`Pass`]
+pub struct AttributeCaching;
+
+impl Pass for AttributeCaching {
+    fn name(&self) -> &str {
+        "attribute-caching"
+    }
+
+    fn run(&self, module: LoweredModule) -> LoweredModule {
+        attribute_caching(module)
+    }
+}
+
+/// [`guarded_reads`] as a [`Pass`]
+pub struct GuardedReads;
+
+impl Pass for GuardedReads {
+    fn name(&self) -> &str {
+        "guarded-reads"
+    }
+
+    fn run(&self, module: LoweredModule) -> LoweredModule {
+        guarded_reads(module)
+    }
+
+    fn run_with_stats(&self, module: LoweredModule) -> (LoweredModule, PassStats) {
+        let (module, peepholes) = fuse_presence_checks(module);
+        let stats = PassStats {
+            peepholes_applied: peepholes,
+            ..Default::default()
+        };
+        (module, stats)
+    }
+}
+
+/// [`common_subexpressions`] as a [`Pass`]
+pub struct CommonSubexpressions;
+
+impl Pass for CommonSubexpressions {
+    fn name(&self) -> &str {
+        "common-subexpressions"
+    }
+
+    fn run(&self, module: LoweredModule) -> LoweredModule {
+        common_subexpressions(module)
+    }
+}
 
 /// Constant folding: evaluate constant expressions at compile time
+/// For example: PushBool(true), PushBool(false), And -> PushBool(false)
 pub fn constant_folding(module: LoweredModule) -> LoweredModule {
-    // TODO: Implement constant folding
-    // For example: PushBool(true), PushBool(false), And -> PushBool(false)
-    module
+    fold_constants(module).0
+}
+
+/// [`constant_folding`], also returning the number of folds
+fn fold_constants(mut module: LoweredModule) -> (LoweredModule, usize) {
+    let mut folds = 0;
+    for func in &mut module.policies {
+        func.instructions = fold_instructions(core::mem::take(&mut func.instructions), &mut folds);
+    }
+
+    (module, folds)
+}
+
+/// Fold in a single pass: each operator is checked against the already-folded
+/// output, so nested constant expressions collapse as soon as they complete.
+/// An `If` on a constant is replaced by the arm it would run, and an
+/// `IfThenElse` of constants by the value it selects. Each fold and each
+/// replaced `If` is counted in `folds`.
+fn fold_instructions(instructions: Vec<Instruction>, folds: &mut usize) -> Vec<Instruction> {
+    let mut out: Vec<Instruction> = Vec::with_capacity(instructions.len());
+    // For each open If, its constant condition if it was folded away
+    let mut branches: Vec<Option<bool>> = Vec::new();
+    // Nesting depth inside an arm that is being dropped, if any
+    let mut dropping: Option<usize> = None;
+
+    for inst in instructions {
+        if let Some(depth) = dropping {
+            dropping = match (&inst, depth) {
+                (Instruction::If(_), _) => Some(depth + 1),
+                // The else arm of a false condition is kept
+                (Instruction::Else, 0) => None,
+                (Instruction::End, 0) => {
+                    branches.pop();
+                    None
+                }
+                (Instruction::End, _) => Some(depth - 1),
+                _ => Some(depth),
+            };
+            continue;
+        }
+
+        match inst {
+            Instruction::If(_) => {
+                if let Some(Instruction::PushBool(condition)) = out.last() {
+                    let condition = *condition;
+                    out.pop();
+                    *folds += 1;
+                    branches.push(Some(condition));
+                    if !condition {
+                        dropping = Some(0);
+                    }
+                    continue;
+                }
+                branches.push(None);
+            }
+            Instruction::Else if branches.last() == Some(&Some(true)) => {
+                dropping = Some(0);
+                continue;
+            }
+            Instruction::End if branches.last().copied().flatten().is_some() => {
+                branches.pop();
+                continue;
+            }
+            Instruction::End => {
+                branches.pop();
+            }
+            _ => {}
+        }
+
+        // Constants are reordered or discarded in place
+        match (out.as_slice(), &inst) {
+            ([.., a, b], Instruction::Swap) if is_constant(a) && is_constant(b) => {
+                let len = out.len();
+                out.swap(len - 2, len - 1);
+                *folds += 1;
+                continue;
+            }
+            ([.., a], Instruction::Drop) if is_constant(a) => {
+                out.pop();
+                *folds += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        let folded = match out.as_slice() {
+            [.., a, b, condition] => fold_select(a, b, condition, &inst),
+            _ => None,
+        };
+        if let Some(result) = folded {
+            out.truncate(out.len() - 3);
+            out.push(result);
+            *folds += 1;
+            continue;
+        }
+
+        let folded = match out.as_slice() {
+            [.., a, b] => fold_binary(a, b, &inst),
+            _ => None,
+        };
+        if let Some(result) = folded {
+            out.truncate(out.len() - 2);
+            out.push(result);
+            *folds += 1;
+            continue;
+        }
+
+        let folded = match out.as_slice() {
+            [.., a] => fold_unary(a, &inst),
+            _ => None,
+        };
+        if let Some(result) = folded {
+            out.pop();
+            out.push(result);
+            *folds += 1;
+            continue;
+        }
+
+        out.push(inst);
+    }
+
+    out
+}
+
+/// Fold `a b condition IfThenElse` when all three are constants, such as a
+/// policy's effect and `NoDecision` selected by a condition that always holds
+fn fold_select(
+    a: &Instruction,
+    b: &Instruction,
+    condition: &Instruction,
+    op: &Instruction,
+) -> Option<Instruction> {
+    use Instruction::*;
+
+    match (condition, op) {
+        (PushBool(c), IfThenElse) if is_constant(a) && is_constant(b) => {
+            Some(if *c { a.clone() } else { b.clone() })
+        }
+        _ => None,
+    }
+}
+
+/// Whether an instruction pushes a constant, with no other effect
+fn is_constant(inst: &Instruction) -> bool {
+    use Instruction::*;
+
+    matches!(
+        inst,
+        Permit
+            | Forbid
+            | NoDecision
+            | PushBool(_)
+            | PushInt(_)
+            | PushDecimal(_)
+            | PushDateTime(_)
+            | PushString(_)
+            | LoadConstEntity(_)
+            | PushSet(_)
+            | PushRecord(_)
+    )
+}
+
+/// Fold `a b op` when both operands are constants
+fn fold_binary(a: &Instruction, b: &Instruction, op: &Instruction) -> Option<Instruction> {
+    use Instruction::*;
+
+    match (a, b, op) {
+        (PushBool(x), PushBool(y), And) => Some(PushBool(*x && *y)),
+        (PushBool(x), PushBool(y), Or) => Some(PushBool(*x || *y)),
+        (PushBool(x), PushBool(y), Equal) => Some(PushBool(x == y)),
+        (PushBool(x), PushBool(y), NotEqual) => Some(PushBool(x != y)),
+        (LoadConstEntity(x), LoadConstEntity(y), Equal) => Some(PushBool(x == y)),
+        (LoadConstEntity(x), LoadConstEntity(y), NotEqual) => Some(PushBool(x != y)),
+        (PushInt(x), PushInt(y), Equal) => Some(PushBool(x == y)),
+        (PushInt(x), PushInt(y), NotEqual) => Some(PushBool(x != y)),
+        (PushInt(x), PushInt(y), LessThan) => Some(PushBool(x < y)),
+        (PushInt(x), PushInt(y), LessThanOrEqual) => Some(PushBool(x <= y)),
+        (PushInt(x), PushInt(y), GreaterThan) => Some(PushBool(x > y)),
+        (PushInt(x), PushInt(y), GreaterThanOrEqual) => Some(PushBool(x >= y)),
+        (PushDecimal(x), PushDecimal(y), Equal) => Some(PushBool(x == y)),
+        (PushDecimal(x), PushDecimal(y), NotEqual) => Some(PushBool(x != y)),
+        (PushDecimal(x), PushDecimal(y), LessThan) => Some(PushBool(x < y)),
+        (PushDecimal(x), PushDecimal(y), LessThanOrEqual) => Some(PushBool(x <= y)),
+        (PushDecimal(x), PushDecimal(y), GreaterThan) => Some(PushBool(x > y)),
+        (PushDecimal(x), PushDecimal(y), GreaterThanOrEqual) => Some(PushBool(x >= y)),
+        (PushDateTime(x), PushDateTime(y), Equal) => Some(PushBool(x == y)),
+        (PushDateTime(x), PushDateTime(y), NotEqual) => Some(PushBool(x != y)),
+        (PushDateTime(x), PushDateTime(y), LessThan) => Some(PushBool(x < y)),
+        (PushDateTime(x), PushDateTime(y), LessThanOrEqual) => Some(PushBool(x <= y)),
+        (PushDateTime(x), PushDateTime(y), GreaterThan) => Some(PushBool(x > y)),
+        (PushDateTime(x), PushDateTime(y), GreaterThanOrEqual) => Some(PushBool(x >= y)),
+        (PushSet(x), PushSet(y), Equal) => Some(PushBool(same_elements(x, y))),
+        (PushSet(x), PushSet(y), NotEqual) => Some(PushBool(!same_elements(x, y))),
+        _ => None,
+    }
+}
+
+/// Whether two set literals are equal sets, ignoring order and duplicates
+fn same_elements(a: &[SetElement], b: &[SetElement]) -> bool {
+    a.iter().all(|elem| b.contains(elem)) && b.iter().all(|elem| a.contains(elem))
+}
+
+/// Fold `a op` when the operand is a constant
+fn fold_unary(a: &Instruction, op: &Instruction) -> Option<Instruction> {
+    match (a, op) {
+        (Instruction::PushBool(x), Instruction::Not) => Some(Instruction::PushBool(!x)),
+        // Overflow is left to fail at runtime
+        (Instruction::PushInt(x), Instruction::Neg) => x.checked_neg().map(Instruction::PushInt),
+        (Instruction::PushRecord(fields), Instruction::HasAttribute(attr)) => Some(
+            Instruction::PushBool(fields.iter().any(|(name, _)| name == attr)),
+        ),
+        // A missing field is left for the host to report as an error
+        (Instruction::PushRecord(fields), Instruction::GetAttribute(attr)) => {
+            let (_, value) = fields.iter().find(|(name, _)| name == attr)?;
+            Some(match value {
+                SetElement::Bool(b) => Instruction::PushBool(*b),
+                SetElement::Int(i) => Instruction::PushInt(*i),
+                SetElement::String(s) => Instruction::PushString(s.clone()),
+                SetElement::Entity(idx) => Instruction::LoadConstEntity(*idx),
+            })
+        }
+        _ => None,
+    }
 }
 
 /// Dead code elimination: remove unreachable code
+///
+/// Works over the structured `If`/`Else`/`End` view of each function: within
+/// any branch, instructions after a `Return` are dropped up to the branch's
+/// `Else`/`End`, which are kept as merge points. Code after a merge point is
+/// always kept, since WASM validation needs it even when every arm returns.
 pub fn dead_code_elimination(mut module: LoweredModule) -> LoweredModule {
-    // Remove instructions after Return
-    if let Some(return_idx) = module
-        .entry
-        .instructions
-        .iter()
-        .position(|inst| matches!(inst, Instruction::Return))
-    {
-        module.entry.instructions.truncate(return_idx + 1);
+    for func in &mut module.policies {
+        func.instructions = eliminate_dead_code(core::mem::take(&mut func.instructions));
     }
 
     module
 }
 
+fn eliminate_dead_code(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut out = Vec::with_capacity(instructions.len());
+    // Whether the current branch has already returned
+    let mut dead = false;
+    // Nesting depth of Ifs opened inside dead code, which are dropped whole
+    let mut dead_depth = 0;
+
+    for inst in instructions {
+        if dead_depth > 0 {
+            match inst {
+                Instruction::If(_) => dead_depth += 1,
+                Instruction::End => dead_depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+
+        match inst {
+            Instruction::If(_) if dead => dead_depth = 1,
+            // Merge points of live branches: the next arm, or the code after
+            // the If, is reachable again
+            Instruction::Else | Instruction::End => {
+                dead = false;
+                out.push(inst);
+            }
+            _ if dead => {}
+            Instruction::Return => {
+                dead = true;
+                out.push(inst);
+            }
+            _ => out.push(inst),
+        }
+    }
+
+    out
+}
+
 /// Instruction combining: combine multiple instructions into more efficient forms
+///
+/// Peepholes, applied until nothing changes (so the pass is idempotent):
+/// - `Not, Not` -> removed
+/// - `Equal, Not` -> `NotEqual` (and `NotEqual, Not` -> `Equal`)
+/// - `PushBool(true)` as either operand of `And` -> just the other operand
+/// - a value computed by two or more instructions, then computed again right
+///   away -> computed once and `Dup`ed
 pub fn instruction_combining(module: LoweredModule) -> LoweredModule {
-    // TODO: Implement peephole optimizations
-    // For example: Not, Not -> remove both
+    combine_instructions(module).0
+}
+
+/// [`instruction_combining`], also returning the number of peepholes applied
+fn combine_instructions(mut module: LoweredModule) -> (LoweredModule, usize) {
+    let mut peepholes = 0;
+    for func in &mut module.policies {
+        while combine_once(&mut func.instructions) {
+            peepholes += 1;
+        }
+    }
+
+    (module, peepholes)
+}
+
+/// Apply the first matching peephole; returns whether anything changed
+fn combine_once(instructions: &mut Vec<Instruction>) -> bool {
+    for i in 0..instructions.len() {
+        match (&instructions[i], instructions.get(i + 1)) {
+            (Instruction::Not, Some(Instruction::Not)) => {
+                instructions.drain(i..i + 2);
+                return true;
+            }
+            (Instruction::Equal, Some(Instruction::Not)) => {
+                instructions.splice(i..i + 2, [Instruction::NotEqual]);
+                return true;
+            }
+            (Instruction::NotEqual, Some(Instruction::Not)) => {
+                instructions.splice(i..i + 2, [Instruction::Equal]);
+                return true;
+            }
+            (Instruction::PushBool(true), _) => {
+                if let Some(and_idx) = consuming_and(instructions, i) {
+                    instructions.remove(and_idx);
+                    instructions.remove(i);
+                    return true;
+                }
+            }
+            _ => {}
+        }
+        if let Some(start) = expression_start(instructions, i) {
+            let len = i - start;
+            if len >= 2 && instructions.get(i..i + len) == Some(&instructions[start..i]) {
+                instructions.splice(i..i + len, [Instruction::Dup]);
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+/// Where the branch-free instructions computing the value on top of the
+/// stack just before `end` start, if that value is computed on its own
+fn expression_start(instructions: &[Instruction], end: usize) -> Option<usize> {
+    // Number of values still to be accounted for
+    let mut needed = 1;
+
+    for (idx, inst) in instructions[..end].iter().enumerate().rev() {
+        if inst.is_control() || matches!(inst, Instruction::Return) {
+            return None;
+        }
+        let (pops, pushes) = inst.stack_effect();
+        // The value was pushed alongside others, as by a `Dup`
+        if pushes > needed {
+            return None;
+        }
+        needed = needed - pushes + pops;
+        if needed == 0 {
+            return Some(idx);
+        }
+    }
+
+    None
+}
+
+/// If the value pushed at `idx` is consumed as an operand of an `And`, return
+/// that `And`'s index
+fn consuming_and(instructions: &[Instruction], idx: usize) -> Option<usize> {
+    // Number of values stacked above the one pushed at `idx`
+    let mut depth = 0;
+
+    for (offset, inst) in instructions[idx + 1..].iter().enumerate() {
+        // Values flowing through branches aren't tracked
+        if inst.is_control() {
+            return None;
+        }
+        let (pops, pushes) = inst.stack_effect();
+        if pops > depth {
+            // This instruction consumes our value: as the right operand of an
+            // And (depth 0) or the left one with its partner above (depth 1)
+            return match inst {
+                Instruction::And if depth <= 1 => Some(idx + 1 + offset),
+                _ => None,
+            };
+        }
+        depth = depth - pops + pushes;
+    }
+
+    None
+}
+
+/// Set membership: `x == a || x == b || x == c` -> `[a, b, c].contains(x)`
+///
+/// A disjunction of two or more equality checks of the same branch-free
+/// operand against constants of one kind (strings, longs, booleans or entity
+/// literals) computes the operand once and makes one `Contains` host call
+/// instead of one comparison and one branch per check. Errors are kept: the
+/// operand is the first thing either form computes. Redacted strings are
+/// left alone, since the set would put them in the module.
+pub fn set_membership(module: LoweredModule) -> LoweredModule {
+    collapse_disjunctions(module).0
+}
+
+/// [`set_membership`], also returning the number of disjunctions collapsed
+fn collapse_disjunctions(mut module: LoweredModule) -> (LoweredModule, usize) {
+    let mut collapsed = 0;
+    for func in &mut module.policies {
+        let mut i = 0;
+        while i < func.instructions.len() {
+            if collapse_disjunction(&mut func.instructions, i) {
+                collapsed += 1;
+            }
+            i += 1;
+        }
+    }
+
+    (module, collapsed)
+}
+
+/// Collapse the disjunction whose first equality check is the `Equal` at
+/// `equal`, if there is one; returns whether it did
+fn collapse_disjunction(instructions: &mut Vec<Instruction>, equal: usize) -> bool {
+    if instructions[equal] != Instruction::Equal || equal == 0 {
+        return false;
+    }
+    let Some(first) = set_element(&instructions[equal - 1]) else {
+        return false;
+    };
+    let Some(start) = expression_start(instructions, equal - 1) else {
+        return false;
+    };
+    let operand = instructions[start..equal - 1].to_vec();
+    let kind = core::mem::discriminant(&first);
+    let mut elements = vec![first];
+
+    // Each further check is the `else` arm of an `If` whose `then` arm is true:
+    // If, PushBool(true), Else, <operand>, <constant>, Equal, End
+    let mut end = equal + 1;
+    loop {
+        let check = end + 3 + operand.len();
+        let matches = matches!(
+            instructions.get(end..end + 3),
+            Some([
+                Instruction::If(None | Some(WasmType::Bool)),
+                Instruction::PushBool(true),
+                Instruction::Else
+            ])
+        ) && instructions.get(end + 3..check) == Some(&operand[..])
+            && matches!(
+                instructions.get(check + 1..check + 3),
+                Some([Instruction::Equal, Instruction::End])
+            );
+        let element = matches
+            .then(|| set_element(&instructions[check]))
+            .flatten()
+            .filter(|element| core::mem::discriminant(element) == kind);
+        let Some(element) = element else {
+            break;
+        };
+        if !elements.contains(&element) {
+            elements.push(element);
+        }
+        end = check + 3;
+    }
+    if end == equal + 1 {
+        return false;
+    }
+
+    let collapsed = core::iter::once(Instruction::PushSet(elements))
+        .chain(operand)
+        .chain([Instruction::Contains]);
+    instructions.splice(start..end, collapsed);
+    true
+}
+
+/// The set element a constant instruction pushes, if it can be one
+fn set_element(inst: &Instruction) -> Option<SetElement> {
+    match inst {
+        Instruction::PushBool(b) => Some(SetElement::Bool(*b)),
+        Instruction::PushInt(i) => Some(SetElement::Int(*i)),
+        Instruction::PushString(s) => Some(SetElement::String(s.clone())),
+        Instruction::LoadConstEntity(idx) => Some(SetElement::Entity(*idx)),
+        _ => None,
+    }
+}
+
+/// Guarded reads: `x has a && x.a == ...` reads `x.a` knowing it is there
+///
+/// Within the branch a `has` check of a request variable's attribute
+/// selects when it holds, reads of that attribute of the same variable
+/// become `GetPresentAttribute`, which skips the error check after the host
+/// call: the attribute can't be missing.
+pub fn guarded_reads(module: LoweredModule) -> LoweredModule {
+    fuse_presence_checks(module).0
+}
+
+/// [`guarded_reads`], also returning the number of reads fused with a check
+fn fuse_presence_checks(mut module: LoweredModule) -> (LoweredModule, usize) {
+    use Instruction::*;
+
+    let mut fused = 0;
+    for func in &mut module.policies {
+        let instructions = &mut func.instructions;
+        for i in 0..instructions.len().saturating_sub(2) {
+            let (LoadVar(var), HasAttribute(attr), If(_)) =
+                (&instructions[i], &instructions[i + 1], &instructions[i + 2])
+            else {
+                continue;
+            };
+            let (var, attr) = (*var, attr.clone());
+
+            // The arm taken when the attribute is present, up to its `Else`
+            // or `End`
+            let mut depth = 0;
+            for j in i + 3..instructions.len() {
+                match &instructions[j] {
+                    If(_) => depth += 1,
+                    Else | End if depth == 0 => break,
+                    End => depth -= 1,
+                    GetAttribute(read) if *read == attr && instructions[j - 1] == LoadVar(var) => {
+                        instructions[j] = GetPresentAttribute(attr.clone());
+                        fused += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    (module, fused)
+}
+
+/// Attribute caching: fetch each attribute once per policy
+///
+/// An attribute path read more than once, such as `principal.role` or
+/// `resource.owner.team` starting from a request variable, slot or entity
+/// literal, is saved in a local (`TeeLocal`) when first read and reloaded
+/// (`GetLocal`) by later reads that the first one is known to precede: those
+/// after it in the same branch arm, including arms nested there. A read that
+/// errors returns from the policy, so a saved value is always valid.
+pub fn attribute_caching(mut module: LoweredModule) -> LoweredModule {
+    for func in &mut module.policies {
+        func.instructions = cache_attributes(core::mem::take(&mut func.instructions));
+    }
+
     module
 }
 
+/// An attribute path: the entity it starts from, then the attributes read
+type AttributePath = Vec<String>;
+
+/// Tracks the attribute path of the value on top of the stack, if it is one
+#[derive(Default)]
+struct PathTracker {
+    /// The path, and where in the output its instructions start
+    top: Option<(usize, AttributePath)>,
+}
+
+impl PathTracker {
+    /// Account for `inst`, about to be pushed at output position `at`;
+    /// returns the path `inst` reads if it is a `GetAttribute` on one
+    fn step(&mut self, inst: &Instruction, at: usize) -> Option<AttributePath> {
+        use Instruction::*;
+
+        match inst {
+            LoadVar(_) | SlotPrincipal | SlotResource | LoadConstEntity(_) => {
+                self.top = Some((at, vec![format!("{:?}", inst)]));
+                None
+            }
+            GetAttribute(attr) => {
+                let (_, path) = self.top.as_mut()?;
+                path.push(attr.clone());
+                Some(path.clone())
+            }
+            // The schema's view of a value doesn't change what it is
+            Narrow(_) => None,
+            _ => {
+                self.top = None;
+                None
+            }
+        }
+    }
+}
+
+fn cache_attributes(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    // How often each path is read
+    let mut reads: BTreeMap<AttributePath, usize> = BTreeMap::new();
+    let mut tracker = PathTracker::default();
+    for (at, inst) in instructions.iter().enumerate() {
+        if let Some(path) = tracker.step(inst, at) {
+            *reads.entry(path).or_default() += 1;
+        }
+    }
+    if reads.values().all(|count| *count < 2) {
+        return instructions;
+    }
+
+    let mut out = Vec::with_capacity(instructions.len());
+    let mut tracker = PathTracker::default();
+    // Local of each cached path, numbered in order of first read
+    let mut locals: BTreeMap<AttributePath, u32> = BTreeMap::new();
+    // Paths saved so far in each open branch arm, outermost first
+    let mut saved: Vec<Vec<AttributePath>> = vec![Vec::new()];
+
+    for inst in instructions {
+        match inst {
+            Instruction::If(_) => saved.push(Vec::new()),
+            // Values saved in an arm are gone once it ends
+            Instruction::Else => {
+                if let Some(arm) = saved.last_mut() {
+                    arm.clear();
+                }
+            }
+            Instruction::End if saved.len() > 1 => {
+                saved.pop();
+            }
+            _ => {}
+        }
+
+        let Some(path) = tracker.step(&inst, out.len()) else {
+            out.push(inst);
+            continue;
+        };
+        if reads[&path] < 2 {
+            out.push(inst);
+            continue;
+        }
+        let next_local = locals.len() as u32;
+        let local = *locals.entry(path.clone()).or_insert(next_local);
+        if saved.iter().flatten().any(|p| *p == path) {
+            // Replace the whole path with the saved value
+            let start = tracker.top.as_ref().map_or(out.len(), |(start, _)| *start);
+            out.truncate(start);
+            out.push(Instruction::GetLocal(local));
+        } else {
+            out.push(inst);
+            out.push(Instruction::TeeLocal(local));
+            if let Some(arm) = saved.last_mut() {
+                arm.push(path);
+            }
+        }
+    }
+
+    out
+}
+
+/// Common subexpressions: compute a repeated expression in one helper
+///
+/// A branch-free expression of four or more instructions that reads the
+/// request and occurs twice or more across the policies, such as
+/// `resource.owner == principal`, moves to a helper of
+/// [`LoweredModule::helpers`], and every occurrence becomes a
+/// [`Instruction::Call`] to it. The expression saving the most instructions
+/// goes first, so later helpers may call earlier ones. Conditions (`&&`,
+/// `||`, `if`) stay in the policies, each keeping its own coverage bits, and
+/// so do reads cached in locals.
+pub fn common_subexpressions(mut module: LoweredModule) -> LoweredModule {
+    while let Some((body, occurrences)) = best_subexpression(&module) {
+        let helper = module.helpers.len();
+        let (policy, start) = occurrences[0];
+        let first = &module.policies[policy];
+        let spans = if first.spans.len() == first.instructions.len() {
+            first.spans[start..start + body.len()].to_vec()
+        } else {
+            Vec::new()
+        };
+        // Right to left, so the starts still ahead stay valid
+        for &(policy, start) in occurrences.iter().rev() {
+            let instructions = &mut module.policies[policy].instructions;
+            instructions.splice(start..start + body.len(), [Instruction::Call(helper)]);
+        }
+        module.helpers.push(LoweredFunction {
+            id: format!("helper{}", helper),
+            instructions: body,
+            spans,
+            ..Default::default()
+        });
+    }
+
+    module
+}
+
+/// An expression and where it occurs, as (policy, start), in order
+type Occurrences = (Vec<Instruction>, Vec<(usize, usize)>);
+
+/// The repeated expression whose extraction saves the most instructions
+fn best_subexpression(module: &LoweredModule) -> Option<Occurrences> {
+    const MIN_LEN: usize = 4;
+
+    // Each expression, keyed by its debug form, and where it occurs.
+    // Expressions of the same length never overlap.
+    let mut found: BTreeMap<String, Occurrences> = BTreeMap::new();
+    for (policy, func) in module.policies.iter().enumerate() {
+        let instructions = &func.instructions;
+        for end in 1..=instructions.len() {
+            let Some(start) = expression_start(instructions, end) else {
+                continue;
+            };
+            let body = &instructions[start..end];
+            if body.len() < MIN_LEN || !is_shareable(body) {
+                continue;
+            }
+            let (_, occurrences) = found
+                .entry(format!("{:?}", body))
+                .or_insert_with(|| (body.to_vec(), Vec::new()));
+            occurrences.push((policy, start));
+        }
+    }
+
+    // Each occurrence shrinks to one call, and the body is kept once
+    found
+        .into_values()
+        .filter(|(_, occurrences)| occurrences.len() >= 2)
+        .max_by_key(|(body, occurrences)| occurrences.len() * (body.len() - 1) - body.len())
+}
+
+/// Whether an expression reads the request and can run as a helper
+fn is_shareable(body: &[Instruction]) -> bool {
+    use Instruction::*;
+
+    let reads_request = body
+        .iter()
+        .any(|inst| matches!(inst, LoadVar(_) | SlotPrincipal | SlotResource | Call(_)));
+    reads_request
+        && !body.iter().any(|inst| {
+            matches!(
+                inst,
+                And | Or
+                    | IfThenElse
+                    | Error(_)
+                    | Permit
+                    | Forbid
+                    | NoDecision
+                    | TeeLocal(_)
+                    | GetLocal(_)
+            )
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ast::lowering::LoweredFunction;
+    use crate::ast::lowering::RequestVar;
+
+    fn fold(instructions: Vec<Instruction>) -> Vec<Instruction> {
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        constant_folding(module).policies.remove(0).instructions
+    }
+
+    #[test]
+    fn test_constant_folding_boolean_and() {
+        let folded = fold(vec![
+            Instruction::PushBool(true),
+            Instruction::PushBool(false),
+            Instruction::And,
+        ]);
+        assert!(matches!(folded.as_slice(), [Instruction::PushBool(false)]));
+    }
+
+    #[test]
+    fn test_constant_folding_not() {
+        let folded = fold(vec![Instruction::PushBool(true), Instruction::Not]);
+        assert!(matches!(folded.as_slice(), [Instruction::PushBool(false)]));
+    }
+
+    #[test]
+    fn test_constant_folding_neg() {
+        let folded = fold(vec![Instruction::PushInt(5), Instruction::Neg]);
+        assert!(matches!(folded.as_slice(), [Instruction::PushInt(-5)]));
+
+        // Overflow is left to the runtime
+        let folded = fold(vec![Instruction::PushInt(i64::MIN), Instruction::Neg]);
+        assert!(matches!(
+            folded.as_slice(),
+            [Instruction::PushInt(i64::MIN), Instruction::Neg]
+        ));
+    }
+
+    #[test]
+    fn test_constant_folding_comparison() {
+        let folded = fold(vec![
+            Instruction::PushInt(1),
+            Instruction::PushInt(2),
+            Instruction::LessThan,
+        ]);
+        assert!(matches!(folded.as_slice(), [Instruction::PushBool(true)]));
+    }
+
+    #[test]
+    fn test_constant_folding_set_equality() {
+        let strings = |elems: &[&str]| {
+            Instruction::PushSet(
+                elems
+                    .iter()
+                    .map(|s| SetElement::String(s.to_string()))
+                    .collect(),
+            )
+        };
+        for (a, b, equal) in [
+            (&["a", "b"][..], &["b", "a", "a"][..], true),
+            (&["a", "b"], &["a"], false),
+        ] {
+            let folded = fold(vec![strings(a), strings(b), Instruction::Equal]);
+            assert_eq!(folded, [Instruction::PushBool(equal)]);
+            let folded = fold(vec![strings(a), strings(b), Instruction::NotEqual]);
+            assert_eq!(folded, [Instruction::PushBool(!equal)]);
+        }
+    }
+
+    #[test]
+    fn test_constant_folding_record_fields() {
+        let record = || {
+            Instruction::PushRecord(vec![
+                ("a".to_string(), SetElement::Int(1)),
+                ("b".to_string(), SetElement::Bool(true)),
+            ])
+        };
+        let folded = fold(vec![
+            record(),
+            Instruction::GetAttribute("a".to_string()),
+            Instruction::PushInt(0),
+            Instruction::GreaterThan,
+            record(),
+            Instruction::HasAttribute("c".to_string()),
+            Instruction::Or,
+        ]);
+        assert!(matches!(folded.as_slice(), [Instruction::PushBool(true)]));
+
+        // Reading a missing field is a runtime error, so it stays
+        let folded = fold(vec![record(), Instruction::GetAttribute("c".to_string())]);
+        assert!(matches!(
+            folded.as_slice(),
+            [Instruction::PushRecord(_), Instruction::GetAttribute(_)]
+        ));
+    }
+
+    #[test]
+    fn test_constant_folding_nested() {
+        // !(1 < 2) || false
+        let folded = fold(vec![
+            Instruction::PushInt(1),
+            Instruction::PushInt(2),
+            Instruction::LessThan,
+            Instruction::Not,
+            Instruction::PushBool(false),
+            Instruction::Or,
+        ]);
+        assert!(matches!(folded.as_slice(), [Instruction::PushBool(false)]));
+    }
+
+    #[test]
+    fn test_folding_lowers_estimates() {
+        let template = crate::ast::adapter::parse_template(
+            r#"permit(principal, action, resource)
+            when { 1 < 2 && "admin" == "admin" && principal.level > 3 };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+        let (size, cost) = (module.estimated_wasm_size(), module.estimated_eval_cost());
+
+        // `1 < 2` and the branch on it are gone; the strings are still
+        // compared by the host
+        let folded = constant_folding(module);
+        assert!(folded.estimated_wasm_size() < size);
+        assert!(folded.estimated_eval_cost() < cost);
+    }
+
+    #[test]
+    fn test_constant_folding_select() {
+        use Instruction::*;
+
+        // An unconditional policy returns its effect
+        let folded = fold(vec![Forbid, NoDecision, PushBool(true), IfThenElse, Return]);
+        assert_eq!(folded, [Forbid, Return]);
+        let folded = fold(vec![
+            Permit,
+            NoDecision,
+            PushBool(false),
+            IfThenElse,
+            Return,
+        ]);
+        assert_eq!(folded, [NoDecision, Return]);
+
+        // A value computed at runtime is left to select
+        let instructions = vec![
+            LoadVar(RequestVar::Principal),
+            GetAttribute("level".to_string()),
+            PushInt(0),
+            PushBool(true),
+            IfThenElse,
+        ];
+        assert_eq!(fold(instructions.clone()), instructions);
+    }
+
+    #[test]
+    fn test_constant_folding_swap_and_drop() {
+        use Instruction::*;
+
+        // A policy's effect swapped into place, then selected
+        let folded = fold(vec![
+            NoDecision,
+            Forbid,
+            Swap,
+            PushBool(true),
+            IfThenElse,
+            Return,
+        ]);
+        assert_eq!(folded, [Forbid, Return]);
+        let folded = fold(vec![PushBool(false), PushInt(1), Drop, Return]);
+        assert_eq!(folded, [PushBool(false), Return]);
+
+        // Values computed at runtime stay where they are
+        let instructions = vec![LoadVar(RequestVar::Principal), PushInt(1), Swap, Drop];
+        assert_eq!(fold(instructions.clone()), instructions);
+    }
+
+    #[test]
+    fn test_constant_folding_if() {
+        use crate::wasm::types::WasmType;
+
+        // true && (principal == 0)
+        let folded = fold(vec![
+            Instruction::PushBool(true),
+            Instruction::If(Some(WasmType::Bool)),
+            Instruction::LoadVar(RequestVar::Principal),
+            Instruction::LoadConstEntity(0),
+            Instruction::Equal,
+            Instruction::Else,
+            Instruction::PushBool(false),
+            Instruction::End,
+        ]);
+        assert!(matches!(
+            folded.as_slice(),
+            [
+                Instruction::LoadVar(RequestVar::Principal),
+                Instruction::LoadConstEntity(0),
+                Instruction::Equal,
+            ]
+        ));
+
+        // false && (true && principal == 0), then the folded result negated
+        let folded = fold(vec![
+            Instruction::PushBool(false),
+            Instruction::If(Some(WasmType::Bool)),
+            Instruction::PushBool(true),
+            Instruction::If(Some(WasmType::Bool)),
+            Instruction::LoadVar(RequestVar::Principal),
+            Instruction::LoadConstEntity(0),
+            Instruction::Equal,
+            Instruction::Else,
+            Instruction::PushBool(false),
+            Instruction::End,
+            Instruction::Else,
+            Instruction::PushBool(false),
+            Instruction::End,
+            Instruction::Not,
+        ]);
+        assert!(matches!(folded.as_slice(), [Instruction::PushBool(true)]));
+    }
+
+    #[test]
+    fn test_constant_folding_leaves_variables() {
+        let folded = fold(vec![
+            Instruction::LoadVar(RequestVar::Principal),
+            Instruction::PushInt(0),
+            Instruction::Equal,
+        ]);
+        assert!(matches!(
+            folded.as_slice(),
+            [
+                Instruction::LoadVar(RequestVar::Principal),
+                Instruction::PushInt(0),
+                Instruction::Equal
+            ]
+        ));
+    }
+
+    fn combine(instructions: Vec<Instruction>) -> Vec<Instruction> {
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        instruction_combining(module)
+            .policies
+            .remove(0)
+            .instructions
+    }
+
+    #[test]
+    fn test_combining_double_negation() {
+        // The attribute read around the double negation must survive
+        let combined = combine(vec![
+            Instruction::Permit,
+            Instruction::NoDecision,
+            Instruction::LoadVar(RequestVar::Principal),
+            Instruction::GetAttribute("active".to_string()),
+            Instruction::Not,
+            Instruction::Not,
+            Instruction::IfThenElse,
+            Instruction::Return,
+        ]);
+        assert!(matches!(
+            combined.as_slice(),
+            [
+                Instruction::Permit,
+                Instruction::NoDecision,
+                Instruction::LoadVar(RequestVar::Principal),
+                Instruction::GetAttribute(attr),
+                Instruction::IfThenElse,
+                Instruction::Return,
+            ] if attr == "active"
+        ));
+    }
+
+    #[test]
+    fn test_combining_not_equal() {
+        let combined = combine(vec![
+            Instruction::LoadVar(RequestVar::Principal),
+            Instruction::PushInt(0),
+            Instruction::Equal,
+            Instruction::Not,
+        ]);
+        assert!(matches!(combined.last(), Some(Instruction::NotEqual)));
+        assert_eq!(combined.len(), 3);
+    }
+
+    #[test]
+    fn test_combining_true_and() {
+        // true && (principal == 0)
+        let combined = combine(vec![
+            Instruction::PushBool(true),
+            Instruction::LoadVar(RequestVar::Principal),
+            Instruction::PushInt(0),
+            Instruction::Equal,
+            Instruction::And,
+        ]);
+        assert!(matches!(
+            combined.as_slice(),
+            [
+                Instruction::LoadVar(RequestVar::Principal),
+                Instruction::PushInt(0),
+                Instruction::Equal
+            ]
+        ));
+
+        // (principal == 0) && true
+        let combined = combine(vec![
+            Instruction::LoadVar(RequestVar::Principal),
+            Instruction::PushInt(0),
+            Instruction::Equal,
+            Instruction::PushBool(true),
+            Instruction::And,
+        ]);
+        assert_eq!(combined.len(), 3);
+    }
+
+    #[test]
+    fn test_combining_pattern_at_end() {
+        // A trailing `true` or `Not` has nothing to combine with
+        let instructions = vec![
+            Instruction::PushBool(false),
+            Instruction::Not,
+            Instruction::PushBool(true),
+        ];
+        assert_eq!(combine(instructions).len(), 3);
+    }
+
+    #[test]
+    fn test_combining_duplicated_operand() {
+        use Instruction::*;
+
+        // principal.level == principal.level
+        let read = [
+            LoadVar(RequestVar::Principal),
+            GetAttribute("level".to_string()),
+        ];
+        let combined = combine([&read[..], &read, &[Equal]].concat());
+        assert_eq!(combined, [&read[..], &[Dup, Equal]].concat());
+
+        // A lone request variable is cheaper to load again
+        let instructions = vec![
+            LoadVar(RequestVar::Principal),
+            LoadVar(RequestVar::Principal),
+            Equal,
+        ];
+        assert_eq!(combine(instructions.clone()), instructions);
+
+        // Nor is a value reused across a branch
+        let instructions = [&read[..], &[If(None)], &read, &[Else, PushInt(0), End]].concat();
+        assert_eq!(combine(instructions.clone()), instructions);
+    }
+
+    #[test]
+    fn test_combining_idempotent() {
+        let instructions = vec![
+            Instruction::PushBool(true),
+            Instruction::PushBool(true),
+            Instruction::LoadVar(RequestVar::Principal),
+            Instruction::GetAttribute("active".to_string()),
+            Instruction::Not,
+            Instruction::Not,
+            Instruction::And,
+            Instruction::And,
+        ];
+        let once = combine(instructions);
+        let twice = combine(once.clone());
+        assert_eq!(format!("{:?}", once), format!("{:?}", twice));
+        assert_eq!(once.len(), 2);
+    }
 
     #[test]
     fn test_dead_code_elimination() {
         let module = LoweredModule {
-            entry: LoweredFunction {
+            policies: vec![LoweredFunction {
                 instructions: vec![
                     Instruction::PushBool(true),
                     Instruction::Return,
                     Instruction::PushBool(false), // Dead code
                 ],
-            },
+                ..Default::default()
+            }],
+            ..Default::default()
         };
 
         let optimized = dead_code_elimination(module);
-        assert_eq!(optimized.entry.instructions.len(), 2);
+        assert_eq!(optimized.policies[0].instructions.len(), 2);
+    }
+
+    fn eliminate(instructions: Vec<Instruction>) -> Vec<Instruction> {
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        dead_code_elimination(module)
+            .policies
+            .remove(0)
+            .instructions
+    }
+
+    #[test]
+    fn test_dead_code_in_then_branch() {
+        let optimized = eliminate(vec![
+            Instruction::LoadVar(RequestVar::Principal),
+            Instruction::PushInt(0),
+            Instruction::Equal,
+            Instruction::If(None),
+            Instruction::Forbid,
+            Instruction::Return,
+            Instruction::PushBool(true), // Dead code
+            Instruction::Not,            // Dead code
+            Instruction::Else,
+            Instruction::End,
+            Instruction::Permit,
+            Instruction::Return,
+        ]);
+
+        // Only the then-arm is trimmed; the merge point and the code after it
+        // (reachable through the else arm) survive
+        assert!(matches!(
+            optimized.as_slice(),
+            [
+                Instruction::LoadVar(RequestVar::Principal),
+                Instruction::PushInt(0),
+                Instruction::Equal,
+                Instruction::If(None),
+                Instruction::Forbid,
+                Instruction::Return,
+                Instruction::Else,
+                Instruction::End,
+                Instruction::Permit,
+                Instruction::Return,
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_dead_code_after_inner_return() {
+        let optimized = eliminate(vec![
+            Instruction::PushBool(true),
+            Instruction::If(None),
+            Instruction::Forbid,
+            Instruction::Return,
+            Instruction::Else,
+            Instruction::Permit,
+            Instruction::Return,
+            Instruction::PushBool(false), // Dead code
+            Instruction::If(None),        // Dead code, dropped with its body
+            Instruction::NoDecision,
+            Instruction::Return,
+            Instruction::End,
+            Instruction::End,
+            Instruction::NoDecision,
+            Instruction::Return,
+        ]);
+
+        assert!(matches!(
+            optimized.as_slice(),
+            [
+                Instruction::PushBool(true),
+                Instruction::If(None),
+                Instruction::Forbid,
+                Instruction::Return,
+                Instruction::Else,
+                Instruction::Permit,
+                Instruction::Return,
+                Instruction::End,
+                Instruction::NoDecision,
+                Instruction::Return,
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_set_membership() {
+        let lower = |condition: &str| {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            let template = crate::ast::adapter::parse_template(&source).unwrap();
+            let module = LoweredModule::from_template(&template).unwrap();
+            set_membership(module).policies.remove(0).instructions
+        };
+        let count = |instructions: &[Instruction], f: fn(&Instruction) -> bool| {
+            instructions.iter().filter(|inst| f(inst)).count()
+        };
+
+        let collapsed = lower(
+            r#"principal.role == "a" || principal.role == "b" || principal.role == "c" ||
+            principal.role == "d" || principal.role == "e""#,
+        );
+        let contains = collapsed
+            .iter()
+            .position(|inst| matches!(inst, Instruction::Contains))
+            .unwrap();
+        let strings = ["a", "b", "c", "d", "e"].map(|s| SetElement::String(s.to_string()));
+        assert_eq!(
+            collapsed[contains - 3..=contains],
+            [
+                Instruction::PushSet(strings.to_vec()),
+                Instruction::LoadVar(RequestVar::Principal),
+                Instruction::GetAttribute("role".to_string()),
+                Instruction::Contains,
+            ]
+        );
+        assert_eq!(count(&collapsed, |i| matches!(i, Instruction::Equal)), 0);
+        assert_eq!(count(&collapsed, |i| matches!(i, Instruction::Contains)), 1);
+
+        // Entity literals collapse too, and repeated ones are kept once
+        let collapsed =
+            lower(r#"principal == User::"a" || principal == User::"b" || principal == User::"a""#);
+        assert!(collapsed.contains(&Instruction::PushSet(vec![
+            SetElement::Entity(0),
+            SetElement::Entity(1)
+        ])));
+
+        // Mixed kinds and different operands stay comparisons
+        for condition in [
+            r#"principal.role == "a" || principal.role == 1"#,
+            r#"principal.role == "a" || resource.role == "b""#,
+            r#"principal.role == "a" && principal.role == "b""#,
+        ] {
+            let kept = lower(condition);
+            assert_eq!(count(&kept, |i| matches!(i, Instruction::Equal)), 2);
+            assert_eq!(count(&kept, |i| matches!(i, Instruction::Contains)), 0);
+        }
+    }
+
+    #[test]
+    fn test_guarded_reads() {
+        let lower = |condition: &str| {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            let template = crate::ast::adapter::parse_template(&source).unwrap();
+            let module = LoweredModule::from_template(&template).unwrap();
+            guarded_reads(module).policies.remove(0).instructions
+        };
+        let reads = |instructions: &[Instruction]| {
+            let checked = instructions
+                .iter()
+                .filter(|inst| matches!(inst, Instruction::GetAttribute(_)))
+                .count();
+            let present = instructions
+                .iter()
+                .filter(|inst| matches!(inst, Instruction::GetPresentAttribute(_)))
+                .count();
+            (checked, present)
+        };
+
+        assert_eq!(
+            reads(&lower(
+                r#"principal has role && (principal.role == "a" || principal.role == "b")"#
+            )),
+            (0, 2)
+        );
+        // Another attribute, another variable, or a read outside the guarded
+        // arm may still be missing
+        for condition in [
+            r#"principal has role && principal.team == "a""#,
+            r#"principal has role && resource.role == "a""#,
+            r#"(principal has role || true) && principal.role == "a""#,
+        ] {
+            assert_eq!(reads(&lower(condition)), (1, 0), "{}", condition);
+        }
+    }
+
+    #[test]
+    fn test_attribute_caching() {
+        let lower = |condition: &str| {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            let template = crate::ast::adapter::parse_template(&source).unwrap();
+            let module = LoweredModule::from_template(&template).unwrap();
+            attribute_caching(module).policies.remove(0).instructions
+        };
+        let count = |instructions: &[Instruction], f: fn(&Instruction) -> bool| {
+            instructions.iter().filter(|inst| f(inst)).count()
+        };
+
+        // The second read is in the `else` arm of the `||`, after the first
+        let cached = lower(r#"principal.role == "a" || principal.role == "b""#);
+        assert_eq!(
+            count(&cached, |i| matches!(i, Instruction::GetAttribute(_))),
+            1
+        );
+        let read = cached
+            .iter()
+            .position(|inst| matches!(inst, Instruction::GetAttribute(_)))
+            .unwrap();
+        assert!(matches!(cached[read + 1], Instruction::TeeLocal(0)));
+        let reuse = cached
+            .iter()
+            .position(|inst| matches!(inst, Instruction::GetLocal(0)))
+            .unwrap();
+        // `LoadVar(Principal)` is dropped along with the read
+        assert!(matches!(cached[reuse - 1], Instruction::Else));
+
+        // Nested paths are cached whole
+        let cached = lower("resource.owner.level > 1 && resource.owner.level < 5");
+        assert_eq!(
+            count(&cached, |i| matches!(i, Instruction::GetAttribute(_))),
+            2
+        );
+        assert_eq!(count(&cached, |i| matches!(i, Instruction::GetLocal(_))), 1);
+
+        // A read in the `then` arm of `&&` may not have run by the `||`
+        let cached = lower(r#"(context.admin && principal.role == "a") || principal.role == "b""#);
+        assert_eq!(
+            count(&cached, |i| matches!(i, Instruction::GetAttribute(_))),
+            3
+        );
+        assert_eq!(count(&cached, |i| matches!(i, Instruction::GetLocal(_))), 0);
+
+        // Attributes read once are left alone
+        let cached = lower(r#"principal.role == "a" && resource.role == "b""#);
+        assert_eq!(count(&cached, |i| matches!(i, Instruction::TeeLocal(_))), 0);
+    }
+
+    #[test]
+    fn test_common_subexpressions() {
+        use Instruction::*;
+
+        let lower = |conditions: &[&str]| {
+            let source: String = conditions
+                .iter()
+                .map(|condition| {
+                    format!("permit(principal, action, resource) when {{ {condition} }};")
+                })
+                .collect();
+            let policy_set = crate::ast::adapter::parse_policy_set(&source).unwrap();
+            common_subexpressions(LoweredModule::from_policy_set(&policy_set).unwrap())
+        };
+        let calls = |module: &LoweredModule| {
+            let calls = module.instructions().filter(|inst| matches!(inst, Call(_)));
+            calls.count()
+        };
+
+        let module = lower(&[
+            "resource.owner == principal",
+            r#"resource.owner == principal && principal.role == "a""#,
+        ]);
+        assert_eq!(module.helpers.len(), 1);
+        assert_eq!(
+            module.helpers[0].instructions,
+            [
+                LoadVar(RequestVar::Resource),
+                GetAttribute("owner".to_string()),
+                LoadVar(RequestVar::Principal),
+                Equal,
+            ]
+        );
+        assert_eq!(module.helpers[0].id, "helper0");
+        assert_eq!(calls(&module), 2);
+
+        // Too short to be worth a call, and conditions stay in the policies
+        let module = lower(&["principal.level > 1", "principal.level > 2"]);
+        assert!(module.helpers.is_empty());
+        let module = lower(&["context.a || context.b", "context.a || context.b"]);
+        assert!(module
+            .helpers
+            .iter()
+            .all(|helper| !helper.instructions.contains(&Or)));
     }
 }
@@ -0,0 +1,812 @@
+//! Export lowered policies as SMT-LIB for formal verification
+//!
+//! The request is modelled symbolically: `principal`, `action`, and
+//! `resource` are integer entity handles (literals use their index in
+//! [`LoweredModule::entities`]), and `context` is an integer record handle.
+//! Each entity attribute is an uninterpreted function of an entity
+//! (`|attr.x|`), kept apart from the record field of the same name
+//! (`|field.x|`); `has`/`in`/`is` are uninterpreted predicates. An attribute
+//! read from a value narrowed to a record is a field, any other is an entity
+//! attribute.
+//!
+//! Reading an attribute the value doesn't have is an error, and as in Cedar
+//! a policy that errors is skipped: its decision is NoDecision whenever the
+//! error condition of what it evaluated holds. Type errors the lowering
+//! leaves to runtime coercions and integer overflow are not modelled, since
+//! integers are unbounded here.
+//!
+//! The script defines one decision per policy plus the combined `decision`,
+//! asserts that it is Permit, and ends with `(check-sat)`; adding constraints
+//! that describe a forbidden request and getting `unsat` proves the policies
+//! never permit it.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::ast::lowering::{Instruction, LoweredFunction, LoweredModule, RequestVar};
+use crate::wasm::runtime::Decision;
+use crate::wasm::types::WasmType;
+
+/// SMT sort of a symbolic value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sort {
+    Bool,
+    Int,
+    String,
+}
+
+impl Sort {
+    fn name(self) -> &'static str {
+        match self {
+            Sort::Bool => "Bool",
+            Sort::Int => "Int",
+            Sort::String => "String",
+        }
+    }
+}
+
+/// A symbolic stack value
+#[derive(Debug, Clone)]
+struct Term {
+    text: String,
+    /// `None` for attribute reads whose sort is not yet known
+    sort: Option<Sort>,
+    /// Function of the attribute this term reads, so its sort can be
+    /// inferred from use
+    attr: Option<String>,
+    /// Whether this is a record handle, whose attributes are fields
+    record: bool,
+    /// Condition under which computing this term errors, `None` if it can't
+    error: Option<String>,
+}
+
+impl Term {
+    fn new(text: impl Into<String>, sort: Sort) -> Self {
+        Self {
+            text: text.into(),
+            sort: Some(sort),
+            attr: None,
+            record: false,
+            error: None,
+        }
+    }
+
+    /// This term, erroring if any of `operands` does
+    fn computed_from(self, operands: &[&Term]) -> Self {
+        Self {
+            error: any_error(operands.iter().map(|term| term.error.clone())),
+            ..self
+        }
+    }
+}
+
+/// Disjunction of the error conditions that are present
+fn any_error(errors: impl IntoIterator<Item = Option<String>>) -> Option<String> {
+    let errors: Vec<String> = errors.into_iter().flatten().collect();
+    match errors.len() {
+        0 => None,
+        1 => errors.into_iter().next(),
+        _ => Some(format!("(or {})", errors.join(" "))),
+    }
+}
+
+/// Symbols referenced by the translated policies
+#[derive(Debug, Default)]
+struct Declarations {
+    /// Attribute function to the attribute's name and its result sort, once
+    /// inferred
+    attributes: BTreeMap<String, (String, Option<Sort>)>,
+    /// `has` predicates, by symbol
+    has_attributes: Vec<String>,
+    entity_types: Vec<String>,
+    secrets: Vec<String>,
+    uses_in: bool,
+}
+
+impl Declarations {
+    /// Record that `term` is used where a value of `sort` is expected
+    fn constrain(&mut self, term: &Term, sort: Sort) -> Result<(), String> {
+        if let Some((attr, known)) = term.attr.as_ref().and_then(|f| self.attributes.get_mut(f)) {
+            match known {
+                Some(existing) if *existing != sort => {
+                    return Err(format!(
+                        "Attribute `{}` is used both as {} and {}",
+                        attr,
+                        existing.name(),
+                        sort.name()
+                    ));
+                }
+                _ => *known = Some(sort),
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a `has` predicate, returning its symbol
+    fn has(&mut self, attr: &str, record: bool) -> String {
+        let symbol = has_symbol(attr, record);
+        if !self.has_attributes.contains(&symbol) {
+            self.has_attributes.push(symbol.clone());
+        }
+        symbol
+    }
+}
+
+/// Translate a lowered module into an SMT-LIB script
+pub fn to_smtlib(module: &LoweredModule) -> Result<String, String> {
+    let mut decls = Declarations::default();
+    // A call stands for the value its helper computes, in terms of the
+    // request; each helper only calls the ones before it
+    let mut helpers = Vec::with_capacity(module.helpers.len());
+    for helper in &module.helpers {
+        let value = translate(&helper.instructions, &helpers, &mut decls)?;
+        helpers.push(value);
+    }
+    let decisions = module
+        .policies
+        .iter()
+        .map(|policy| translate_policy(policy, &helpers, &mut decls))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut out = String::new();
+    writeln!(out, "(set-logic ALL)").unwrap();
+    for (idx, entity) in module.entities.iter().enumerate() {
+        writeln!(out, "; entity {}: {}", idx, entity).unwrap();
+    }
+    for var in ["principal", "action", "resource", "context"] {
+        writeln!(out, "(declare-const {} Int)", var).unwrap();
+    }
+    if module.slots {
+        for slot in ["principal_slot", "resource_slot"] {
+            writeln!(out, "(declare-const {} Int)", slot).unwrap();
+        }
+    }
+    for (symbol, (_, sort)) in &decls.attributes {
+        let sort = sort.unwrap_or(Sort::Int);
+        writeln!(out, "(declare-fun {} (Int) {})", symbol, sort.name()).unwrap();
+    }
+    for symbol in &decls.has_attributes {
+        writeln!(out, "(declare-fun {} (Int) Bool)", symbol).unwrap();
+    }
+    for type_name in &decls.entity_types {
+        writeln!(out, "(declare-fun {} (Int) Bool)", is_symbol(type_name)).unwrap();
+    }
+    for idx in 0..decls.secrets.len() {
+        writeln!(out, "(declare-const {} String)", secret_symbol(idx)).unwrap();
+    }
+    if decls.uses_in {
+        writeln!(out, "(declare-fun in (Int Int) Bool)").unwrap();
+    }
+
+    for (idx, decision) in decisions.iter().enumerate() {
+        writeln!(out, "(define-fun policy{} () Int {})", idx, decision).unwrap();
+    }
+    writeln!(out, "(define-fun decision () Int {})", combine(decisions.len())).unwrap();
+    writeln!(out, "(assert (= decision {}))", int(Decision::Permit as i64)).unwrap();
+    writeln!(out, "(check-sat)").unwrap();
+
+    Ok(out)
+}
+
+/// Forbid overrides permit; with no matching policy the result is NoDecision
+fn combine(policy_count: usize) -> String {
+    let any = |decision: Decision| {
+        let decision = int(decision as i64);
+        match policy_count {
+            0 => "false".to_string(),
+            1 => format!("(= policy0 {})", decision),
+            _ => format!(
+                "(or {})",
+                (0..policy_count)
+                    .map(|idx| format!("(= policy{} {})", idx, decision))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        }
+    };
+    format!(
+        "(ite {} {} (ite {} {} {}))",
+        any(Decision::Deny),
+        int(Decision::Deny as i64),
+        any(Decision::Permit),
+        int(Decision::Permit as i64),
+        int(Decision::NoDecision as i64)
+    )
+}
+
+/// Symbolically execute a policy function, returning its decision term. A
+/// policy that errors is skipped, so its decision is then NoDecision.
+fn translate_policy(
+    policy: &LoweredFunction,
+    helpers: &[Term],
+    decls: &mut Declarations,
+) -> Result<String, String> {
+    let decision = translate(&policy.instructions, helpers, decls)?;
+    Ok(match decision.error {
+        Some(error) => format!(
+            "(ite {} {} {})",
+            error,
+            int(Decision::NoDecision as i64),
+            decision.text
+        ),
+        None => decision.text,
+    })
+}
+
+/// Symbolically execute a function body, returning the term it returns or
+/// leaves on the stack. `helpers` are the values of the helpers it can call.
+fn translate(
+    instructions: &[Instruction],
+    helpers: &[Term],
+    decls: &mut Declarations,
+) -> Result<Term, String> {
+    use Instruction::*;
+
+    let mut stack: Vec<Term> = Vec::new();
+    // Open branches: condition, stack height at entry, then-branch result
+    let mut branches: Vec<(Term, usize, Option<Term>)> = Vec::new();
+    // Attribute values saved in locals
+    let mut locals: BTreeMap<u32, Term> = BTreeMap::new();
+    // Errors of dropped values, which still fail the function. The optimizer
+    // only drops constants, so treating these as unconditional is exact.
+    let mut dropped: Vec<Option<String>> = Vec::new();
+
+    let pop = |stack: &mut Vec<Term>, inst: &Instruction| {
+        stack
+            .pop()
+            .ok_or_else(|| format!("{:?} expects an operand on the stack", inst))
+    };
+
+    for inst in instructions {
+        match inst {
+            PushBool(b) => stack.push(Term::new(b.to_string(), Sort::Bool)),
+            PushInt(i) | PushDecimal(i) | PushDateTime(i) => {
+                stack.push(Term::new(int(*i), Sort::Int))
+            }
+            PushString(s) => stack.push(Term::new(string(s), Sort::String)),
+            // Secrets stay symbolic so the script doesn't leak them either
+            PushSecret(s) => {
+                let idx = match decls.secrets.iter().position(|existing| existing == s) {
+                    Some(idx) => idx,
+                    None => {
+                        decls.secrets.push(s.clone());
+                        decls.secrets.len() - 1
+                    }
+                };
+                stack.push(Term::new(secret_symbol(idx), Sort::String));
+            }
+            LoadConstEntity(idx) => stack.push(Term::new(int(*idx as i64), Sort::Int)),
+            LoadVar(var) => {
+                let name = match var {
+                    RequestVar::Principal => "principal",
+                    RequestVar::Action => "action",
+                    RequestVar::Resource => "resource",
+                    RequestVar::Context => "context",
+                };
+                stack.push(Term {
+                    record: matches!(var, RequestVar::Context),
+                    ..Term::new(name, Sort::Int)
+                });
+            }
+            SlotPrincipal => stack.push(Term::new("principal_slot", Sort::Int)),
+            SlotResource => stack.push(Term::new("resource_slot", Sort::Int)),
+
+            Equal | NotEqual => {
+                let right = pop(&mut stack, inst)?;
+                let left = pop(&mut stack, inst)?;
+                // An attribute compared with a known value takes its sort
+                if let Some(sort) = left.sort {
+                    decls.constrain(&right, sort)?;
+                }
+                if let Some(sort) = right.sort {
+                    decls.constrain(&left, sort)?;
+                }
+                let eq = format!("(= {} {})", left.text, right.text);
+                let text = if matches!(inst, NotEqual) {
+                    format!("(not {})", eq)
+                } else {
+                    eq
+                };
+                stack.push(Term::new(text, Sort::Bool).computed_from(&[&left, &right]));
+            }
+            LessThan | LessThanOrEqual | GreaterThan | GreaterThanOrEqual => {
+                let op = match inst {
+                    LessThan => "<",
+                    LessThanOrEqual => "<=",
+                    GreaterThan => ">",
+                    _ => ">=",
+                };
+                let right = pop(&mut stack, inst)?;
+                let left = pop(&mut stack, inst)?;
+                decls.constrain(&left, Sort::Int)?;
+                decls.constrain(&right, Sort::Int)?;
+                stack.push(
+                    Term::new(format!("({} {} {})", op, left.text, right.text), Sort::Bool)
+                        .computed_from(&[&left, &right]),
+                );
+            }
+
+            And | Or => {
+                let right = pop(&mut stack, inst)?;
+                let left = pop(&mut stack, inst)?;
+                decls.constrain(&left, Sort::Bool)?;
+                decls.constrain(&right, Sort::Bool)?;
+                let op = if matches!(inst, And) { "and" } else { "or" };
+                stack.push(
+                    Term::new(format!("({} {} {})", op, left.text, right.text), Sort::Bool)
+                        .computed_from(&[&left, &right]),
+                );
+            }
+            Not => {
+                let arg = pop(&mut stack, inst)?;
+                decls.constrain(&arg, Sort::Bool)?;
+                stack.push(
+                    Term::new(format!("(not {})", arg.text), Sort::Bool).computed_from(&[&arg]),
+                );
+            }
+            // Integers are unbounded here, so negation cannot overflow
+            Neg => {
+                let arg = pop(&mut stack, inst)?;
+                decls.constrain(&arg, Sort::Int)?;
+                stack
+                    .push(Term::new(format!("(- {})", arg.text), Sort::Int).computed_from(&[&arg]));
+            }
+
+            GetAttribute(attr) | GetPresentAttribute(attr) => {
+                let value = pop(&mut stack, inst)?;
+                let symbol = attr_symbol(attr, value.record);
+                let sort = decls
+                    .attributes
+                    .entry(symbol.clone())
+                    .or_insert_with(|| (attr.clone(), None))
+                    .1;
+                // Reading a missing attribute errors; a `has` check already
+                // guards a present one
+                let missing = match inst {
+                    GetAttribute(_) => Some(format!(
+                        "(not ({} {}))",
+                        decls.has(attr, value.record),
+                        value.text
+                    )),
+                    _ => None,
+                };
+                stack.push(Term {
+                    text: format!("({} {})", symbol, value.text),
+                    sort,
+                    attr: Some(symbol),
+                    record: false,
+                    error: any_error([value.error, missing]),
+                });
+            }
+            TeeLocal(idx) => {
+                let value = stack
+                    .last()
+                    .ok_or_else(|| format!("{:?} expects an operand on the stack", inst))?;
+                locals.insert(*idx, value.clone());
+            }
+            Dup => {
+                let value = pop(&mut stack, inst)?;
+                stack.push(value.clone());
+                stack.push(value);
+            }
+            Swap => {
+                let top = pop(&mut stack, inst)?;
+                let lower = pop(&mut stack, inst)?;
+                stack.push(top);
+                stack.push(lower);
+            }
+            Drop => {
+                dropped.push(pop(&mut stack, inst)?.error);
+            }
+            GetLocal(idx) => {
+                let value = locals
+                    .get(idx)
+                    .ok_or_else(|| format!("{:?} reads a local that was never set", inst))?;
+                stack.push(value.clone());
+            }
+            // Entities and records are integers here; other types leave the
+            // sort open
+            Narrow(ty) => {
+                let value = pop(&mut stack, inst)?;
+                let sort = match ty {
+                    WasmType::Bool => Some(Sort::Bool),
+                    WasmType::Int | WasmType::Entity | WasmType::Record => Some(Sort::Int),
+                    WasmType::String => Some(Sort::String),
+                    _ => None,
+                };
+                if let Some(sort) = sort {
+                    decls.constrain(&value, sort)?;
+                }
+                stack.push(Term {
+                    sort: sort.or(value.sort),
+                    record: match ty {
+                        WasmType::Record => true,
+                        WasmType::Entity => false,
+                        _ => value.record,
+                    },
+                    ..value
+                });
+            }
+            HasAttribute(attr) => {
+                let value = pop(&mut stack, inst)?;
+                let symbol = decls.has(attr, value.record);
+                stack.push(
+                    Term::new(format!("({} {})", symbol, value.text), Sort::Bool)
+                        .computed_from(&[&value]),
+                );
+            }
+            IsEntityType(type_name) => {
+                let entity = pop(&mut stack, inst)?;
+                if !decls.entity_types.contains(type_name) {
+                    decls.entity_types.push(type_name.clone());
+                }
+                stack.push(
+                    Term::new(
+                        format!("({} {})", is_symbol(type_name), entity.text),
+                        Sort::Bool,
+                    )
+                    .computed_from(&[&entity]),
+                );
+            }
+            In => {
+                let parent = pop(&mut stack, inst)?;
+                let child = pop(&mut stack, inst)?;
+                decls.uses_in = true;
+                stack.push(
+                    Term::new(format!("(in {} {})", child.text, parent.text), Sort::Bool)
+                        .computed_from(&[&child, &parent]),
+                );
+            }
+
+            PushSet(_) | Contains | ContainsAll | ContainsAny => {
+                return Err("Sets are not supported in SMT export".to_string());
+            }
+            PushRecord(_) => {
+                return Err("Record literals are not supported in SMT export".to_string());
+            }
+            Ip | IsInRange | IsIpv4 | IsIpv6 | IsLoopback | IsMulticast => {
+                return Err("IP addresses are not supported in SMT export".to_string());
+            }
+            LikeSecret(_) => {
+                return Err("Redacted `like` patterns are not supported in SMT export".to_string());
+            }
+            Like(pattern) => {
+                let arg = pop(&mut stack, inst)?;
+                decls.constrain(&arg, Sort::String)?;
+                stack.push(
+                    Term::new(
+                        format!("(str.in_re {} {})", arg.text, like_regex(pattern)),
+                        Sort::Bool,
+                    )
+                    .computed_from(&[&arg]),
+                );
+            }
+
+            IfThenElse => {
+                let cond = pop(&mut stack, inst)?;
+                let else_val = pop(&mut stack, inst)?;
+                let then_val = pop(&mut stack, inst)?;
+                decls.constrain(&cond, Sort::Bool)?;
+                let error = any_error([
+                    cond.error.clone(),
+                    then_val.error.clone(),
+                    else_val.error.clone(),
+                ]);
+                stack.push(Term {
+                    text: format!("(ite {} {} {})", cond.text, then_val.text, else_val.text),
+                    error,
+                    ..then_val
+                });
+            }
+            // Branches must each leave one value, which becomes an `ite`
+            If(_) => {
+                let cond = pop(&mut stack, inst)?;
+                decls.constrain(&cond, Sort::Bool)?;
+                branches.push((cond, stack.len(), None));
+            }
+            Else => {
+                let (_, height, then_val) = branches
+                    .last_mut()
+                    .ok_or("Else without a matching If")?;
+                if stack.len() != *height + 1 {
+                    return Err("Branch must leave exactly one value".to_string());
+                }
+                *then_val = stack.pop();
+            }
+            End => {
+                let (cond, height, then_val) = branches.pop().ok_or("End without a matching If")?;
+                let then_val = then_val.ok_or("Branch without an Else arm")?;
+                if stack.len() != height + 1 {
+                    return Err("Branch must leave exactly one value".to_string());
+                }
+                let else_val = pop(&mut stack, inst)?;
+                // Only the arm taken is evaluated, so only its errors count
+                let arms = match (&then_val.error, &else_val.error) {
+                    (None, None) => None,
+                    (then_err, else_err) => Some(format!(
+                        "(ite {} {} {})",
+                        cond.text,
+                        then_err.as_deref().unwrap_or("false"),
+                        else_err.as_deref().unwrap_or("false")
+                    )),
+                };
+                stack.push(Term {
+                    text: format!("(ite {} {} {})", cond.text, then_val.text, else_val.text),
+                    error: any_error([cond.error, arms]),
+                    ..then_val
+                });
+            }
+            // Any value of the sort will do, since it is never used
+            Error(ty) => {
+                let value = match ty {
+                    WasmType::Bool => Term::new("false", Sort::Bool),
+                    WasmType::String => Term::new(string(""), Sort::String),
+                    _ => Term::new("0", Sort::Int),
+                };
+                stack.push(Term {
+                    error: Some("true".to_string()),
+                    ..value
+                });
+            }
+            Return => break,
+            Call(idx) => {
+                let value = helpers.get(*idx).ok_or_else(|| {
+                    format!("{:?} calls a helper that isn't defined before it", inst)
+                })?;
+                stack.push(value.clone());
+            }
+
+            Permit => stack.push(Term::new(int(Decision::Permit as i64), Sort::Int)),
+            Forbid => stack.push(Term::new(int(Decision::Deny as i64), Sort::Int)),
+            NoDecision => stack.push(Term::new(int(Decision::NoDecision as i64), Sort::Int)),
+        }
+    }
+
+    let value = stack
+        .pop()
+        .ok_or_else(|| "Function leaves no value on the stack".to_string())?;
+    dropped.push(value.error.clone());
+    Ok(Term {
+        error: any_error(dropped),
+        ..value
+    })
+}
+
+/// SMT-LIB integer literal (negative numbers are written as a negation)
+fn int(value: i64) -> String {
+    if value < 0 {
+        format!("(- {})", value.unsigned_abs())
+    } else {
+        value.to_string()
+    }
+}
+
+/// SMT-LIB string literal, where `"` is escaped by doubling it
+fn string(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// SMT-LIB regular expression for a `like` pattern: literal runs separated
+/// by `re.all` wildcards
+fn like_regex(pattern: &str) -> String {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => literal.extend(chars.next()),
+            '*' => {
+                if !literal.is_empty() {
+                    parts.push(format!("(str.to_re {})", string(&literal)));
+                    literal.clear();
+                }
+                parts.push("re.all".to_string());
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() || parts.is_empty() {
+        parts.push(format!("(str.to_re {})", string(&literal)));
+    }
+    match parts.as_slice() {
+        [part] => part.clone(),
+        _ => format!("(re.++ {})", parts.join(" ")),
+    }
+}
+
+/// Quoted symbol for an entity attribute or record field function. `|` and
+/// `\` can't appear in quoted symbols, so they are replaced.
+fn attr_symbol(attr: &str, record: bool) -> String {
+    let prefix = if record { "field" } else { "attr" };
+    format!("|{}.{}|", prefix, attr.replace(['|', '\\'], "_"))
+}
+
+fn has_symbol(attr: &str, record: bool) -> String {
+    let prefix = if record { "has_field" } else { "has" };
+    format!("|{}.{}|", prefix, attr.replace(['|', '\\'], "_"))
+}
+
+fn is_symbol(type_name: &str) -> String {
+    format!("|is.{}|", type_name.replace(['|', '\\'], "_"))
+}
+
+fn secret_symbol(idx: usize) -> String {
+    format!("|secret.{}|", idx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::adapter;
+
+    /// Check that a script is a sequence of balanced s-expressions
+    fn assert_balanced(script: &str) {
+        let mut depth = 0i32;
+        let mut in_string = false;
+        let mut in_symbol = false;
+        for c in script.chars() {
+            match c {
+                '"' if !in_symbol => in_string = !in_string,
+                '|' if !in_string => in_symbol = !in_symbol,
+                '(' if !in_string && !in_symbol => depth += 1,
+                ')' if !in_string && !in_symbol => depth -= 1,
+                _ => {}
+            }
+            assert!(depth >= 0, "unbalanced parentheses in:\n{}", script);
+        }
+        assert_eq!(depth, 0, "unbalanced parentheses in:\n{}", script);
+        assert!(!in_string && !in_symbol);
+    }
+
+    /// Parse a script as SMT-LIB and check that every symbol it uses is a
+    /// theory builtin or declared before use, as a solver requires
+    fn assert_well_formed(script: &str) {
+        use smt2parser::concrete::{Command, QualIdentifier, SyntaxBuilder, Term};
+        use smt2parser::visitors::Identifier;
+
+        fn check(term: &Term, declared: &[String]) {
+            const BUILTINS: &str = "true false and or not = ite < <= > >= - \
+                                    str.in_re str.to_re re.all re.++";
+            let symbol = |qual: &QualIdentifier| match qual {
+                QualIdentifier::Simple { identifier }
+                | QualIdentifier::Sorted { identifier, .. } => match identifier {
+                    Identifier::Simple { symbol } | Identifier::Indexed { symbol, .. } => {
+                        symbol.0.clone()
+                    }
+                },
+            };
+            let mut used = None;
+            match term {
+                Term::Constant(_) => {}
+                Term::QualIdentifier(qual) => used = Some(symbol(qual)),
+                Term::Application {
+                    qual_identifier,
+                    arguments,
+                } => {
+                    used = Some(symbol(qual_identifier));
+                    for argument in arguments {
+                        check(argument, declared);
+                    }
+                }
+                other => panic!("unexpected term {:?}", other),
+            }
+            if let Some(used) = used {
+                assert!(
+                    BUILTINS.split_whitespace().any(|builtin| builtin == used)
+                        || declared.contains(&used),
+                    "`{}` is used but not declared",
+                    used
+                );
+            }
+        }
+
+        let mut declared = Vec::new();
+        let commands = smt2parser::CommandStream::new(script.as_bytes(), SyntaxBuilder, None);
+        for command in commands {
+            match command.unwrap_or_else(|e| panic!("invalid SMT-LIB: {}\n{}", e, script)) {
+                Command::DeclareConst { symbol, .. } | Command::DeclareFun { symbol, .. } => {
+                    declared.push(symbol.0)
+                }
+                Command::DefineFun { sig, term } => {
+                    check(&term, &declared);
+                    declared.push(sig.name.0);
+                }
+                Command::Assert { term } => check(&term, &declared),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_simple_policy_asserts_permit_condition() {
+        let template = adapter::parse_template(
+            r#"permit(principal, action, resource) when { principal.role == "admin" };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+        let script = to_smtlib(&module).unwrap();
+
+        assert_balanced(&script);
+        assert!(script.contains("(declare-const principal Int)"));
+        // The attribute's sort is inferred from the string it is compared to
+        assert!(script.contains("(declare-fun |attr.role| (Int) String)"));
+        assert!(script.contains(r#"(= (|attr.role| principal) "admin")"#));
+        assert!(script.contains("(define-fun decision () Int"));
+        assert!(script.contains("(assert (= decision 1))"));
+        assert!(script.trim_end().ends_with("(check-sat)"));
+    }
+
+    #[test]
+    fn test_template_slots_are_constants() {
+        let template =
+            adapter::parse_template("permit(principal == ?principal, action, resource);").unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+        let script = to_smtlib(&module).unwrap();
+
+        assert_balanced(&script);
+        assert!(script.contains("(declare-const principal_slot Int)"));
+        assert!(script.contains("(= principal principal_slot)"));
+    }
+
+    #[test]
+    fn test_conflicting_attribute_sorts_are_rejected() {
+        let template = adapter::parse_template(
+            r#"permit(principal, action, resource) when { principal.x == "a" && principal.x < 3 };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+
+        assert!(to_smtlib(&module).unwrap_err().contains("`x`"));
+    }
+
+    #[test]
+    fn test_context_is_a_declared_record() {
+        let template = adapter::parse_template(
+            "permit(principal, action, resource) when { context.level > 3 && principal.level > 3 };",
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+        let script = to_smtlib(&module).unwrap();
+
+        assert_well_formed(&script);
+        assert!(script.contains("(declare-const context Int)"));
+        // The context's field and the principal's attribute are unrelated
+        assert!(script.contains("(declare-fun |field.level| (Int) Int)"));
+        assert!(script.contains("(declare-fun |attr.level| (Int) Int)"));
+        assert!(script.contains("(|field.level| context)"));
+        assert!(script.contains("(|attr.level| principal)"));
+    }
+
+    #[test]
+    fn test_policy_that_errors_is_skipped() {
+        let template = adapter::parse_template(
+            "forbid(principal, action, resource) when { principal.level > 3 };",
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+        let script = to_smtlib(&module).unwrap();
+
+        assert_well_formed(&script);
+        // Without the attribute the forbid errors, so it decides nothing
+        assert!(script.contains("(declare-fun |has.level| (Int) Bool)"));
+        assert!(script.contains(
+            "(define-fun policy0 () Int (ite (ite true (not (|has.level| principal)) false) (- 1) "
+        ));
+    }
+
+    #[test]
+    fn test_has_guard_prevents_the_error() {
+        let template = adapter::parse_template(
+            "forbid(principal, action, resource) when { principal has level && principal.level > 3 };",
+        )
+        .unwrap();
+        let module = LoweredModule::from_template(&template).unwrap();
+        let script = to_smtlib(&module).unwrap();
+
+        assert_well_formed(&script);
+        // The read only runs once `has` held, so its error can't happen
+        assert!(
+            script.contains("(ite (|has.level| principal) (not (|has.level| principal)) false)")
+        );
+    }
+}
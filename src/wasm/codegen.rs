@@ -1,227 +1,3324 @@
 //! WebAssembly code generation from lowered IR
 
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
 use wasm_encoder::{
-    CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction as WasmInst,
-    MemorySection, MemoryType, Module, TypeSection, ValType,
+    BlockType, CodeSection, ConstExpr, CustomSection, DataCountSection, DataSection, Encode,
+    EntityType, ExportKind, ExportSection, Function, FunctionSection, GlobalSection, GlobalType,
+    ImportSection, IndirectNameMap, Instruction as WasmInst, MemArg, MemorySection, MemoryType,
+    Module, NameMap, NameSection, TypeSection, ValType,
+};
+use wasmparser::{Validator, WasmFeatures};
+
+use crate::ast::adapter::PolicyEffect;
+use crate::ast::lowering::{
+    Instruction, LoweredFunction, LoweredModule, RequestVar, SetElement, Span,
 };
+use crate::wasm::runtime::{memory, runtime_functions, Decision, ValueKind};
+use crate::wasm::types::{FunctionSignature, WasmType};
+
+/// Name of the custom section listing attribute names, in the order host
+/// calls refer to them: a count followed by length-prefixed UTF-8 names
+pub const ATTRIBUTES_SECTION: &str = "cedar.attributes";
+
+/// Name of the custom section listing the policies in policy-set order, so
+/// hosts can tell which policy decided: a count followed by each policy's
+/// length-prefixed ID and a byte holding its effect as a [`Decision`]
+/// (`Permit` or `Deny`)
+pub const POLICIES_SECTION: &str = "cedar.policies";
+
+/// Name of the custom section mapping policy code back to the Cedar source:
+/// a count followed by, for each policy function, its function index and a
+/// count of `(offset, start, end)` triples: the offset into the function's
+/// body where the code lowered from source bytes `start..end` begins. All
+/// are LEB128 u32s.
+pub const SOURCE_MAP_SECTION: &str = "cedar.sourcemap";
+
+/// What integer arithmetic does when its result doesn't fit in a Long
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Overflow {
+    /// An evaluation error, as in Cedar: the policy yields NoDecision and
+    /// sets the error flag
+    #[default]
+    Error,
+    /// Trap with `unreachable`, aborting the whole evaluation
+    Trap,
+    /// Clamp the result to the nearest Long, without a branch
+    Saturate,
+}
+
+/// Size of a WASM page in bytes
+const PAGE_SIZE: u32 = 0x10000;
+
+/// Parameters holding the entities bound to `?principal` and `?resource`,
+/// in modules compiled from templates with slots
+const PRINCIPAL_SLOT_PARAM: u32 = 4;
+const RESOURCE_SLOT_PARAM: u32 = 5;
+
+/// Locals after the slot parameters that single instructions use to shuffle
+/// an operand (i32 and i64). Functions without slot parameters declare two
+/// unused locals in their place, so the indices are the same either way.
+const SCRATCH_LOCAL: u32 = 6;
+const SCRATCH_I64_LOCAL: u32 = 7;
+/// Local holding the condition a coverage probe records (i32)
+const COVERAGE_LOCAL: u32 = 8;
+/// First of the i64 locals backing the IR's `TeeLocal`/`GetLocal`
+const CACHE_LOCAL_START: u32 = 9;
+
+/// Mutable global a runtime function sets to report an evaluation error;
+/// memo slots follow it
+const ERROR_GLOBAL: u32 = 0;
+
+/// Names of the request parameters in the `name` section
+pub(crate) const PARAM_NAMES: [&str; 4] = ["principal", "action", "resource", "context"];
+pub(crate) const SLOT_PARAM_NAMES: [&str; 2] = ["principal_slot", "resource_slot"];
+
+/// Where the functions of a generated module ended up in the function
+/// index space
+struct FunctionIndices {
+    policies: std::ops::Range<u32>,
+    /// Functions the policies share, after the policies
+    helpers: std::ops::Range<u32>,
+    /// Function combining the policy decisions, for policy sets
+    combinator: Option<u32>,
+    /// The exported `evaluate`
+    evaluate: u32,
+    /// The exported `evaluate_batch`
+    batch: Option<u32>,
+    /// The exported `_initialize`
+    initialize: Option<u32>,
+}
+
+/// Parameter and result types of one function type
+type FuncType = (Vec<ValType>, Vec<ValType>);
 
-use crate::ast::lowering::{Instruction, LoweredModule};
-use crate::wasm::runtime::{memory, Decision};
-use crate::wasm::types::FunctionSignature;
+/// Size figures of a compiled module, for keeping policies within a budget
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodegenStats {
+    /// Size of the module in bytes
+    pub wasm_bytes_len: usize,
+    /// WASM instructions across all function bodies, counting the `end`
+    /// closing each body
+    pub instruction_count: usize,
+    /// Imported runtime functions
+    pub import_count: usize,
+    /// Bytes of static data, such as the string pool
+    pub data_bytes: usize,
+}
+
+impl CodegenStats {
+    /// Measure a compiled module
+    pub fn of(wasm: &[u8]) -> Result<Self, String> {
+        use wasmparser::{Parser, Payload};
+
+        let mut stats = CodegenStats {
+            wasm_bytes_len: wasm.len(),
+            ..Self::default()
+        };
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.map_err(|e| e.to_string())? {
+                Payload::ImportSection(imports) => stats.import_count += imports.count() as usize,
+                Payload::DataSection(data) => {
+                    for segment in data {
+                        stats.data_bytes += segment.map_err(|e| e.to_string())?.data.len();
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    let mut operators = body.get_operators_reader().map_err(|e| e.to_string())?;
+                    while !operators.eof() {
+                        operators.read().map_err(|e| e.to_string())?;
+                        stats.instruction_count += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+}
 
 /// WebAssembly code generator
 pub struct WasmCodeGen {
-    module: Module,
+    /// Reject output that needs anything beyond the WASM 1.0 MVP
+    mvp_only: bool,
+    /// Validate every generated module
+    validate: bool,
+    /// Emit a `name` section
+    names: bool,
+    /// Maximum size of linear memory in WASM pages
+    max_pages: u32,
+    /// Memoize attribute reads across requests and export `evaluate_batch`
+    batch_evaluation: bool,
+    /// Record the outcome of every condition in the coverage bitmap
+    coverage: bool,
+    /// Address of the coverage bitmap
+    coverage_start: u32,
+    /// Number of coverage probes emitted; probe `k` owns bits `2k` (true)
+    /// and `2k + 1` (false)
+    coverage_probes: u32,
+    /// Attribute names referenced by host calls, indexed by their `attr`
+    /// argument
+    attributes: Vec<String>,
+    /// Number of memoized attribute reads emitted (batch evaluation only);
+    /// each owns three globals: key, value, valid
+    memo_sites: u32,
+    /// Types of the values on the WASM operand stack while compiling a
+    /// function, used to pick type-specific instructions
+    stack: Vec<WasmType>,
+    /// Open structured branches: stack height at entry, result type, and
+    /// whether the result type was inferred from the branches
+    branches: Vec<(usize, Option<WasmType>, bool)>,
+    /// Result types inferred for the untyped `If`s of the function being
+    /// compiled, the last one first
+    inferred: Vec<Option<WasmType>>,
+    /// First of the two locals (i32, then i64) `Swap` sets the lower value
+    /// aside in, after the cache locals of the function being compiled
+    swap_locals: u32,
+    /// Strings in the pool and their addresses in linear memory
+    strings: Vec<(String, u32)>,
+    /// Set literals in the pool and their addresses in linear memory
+    sets: Vec<(Vec<SetElement>, u32)>,
+    /// Record literals in the pool and their addresses in linear memory
+    records: Vec<(Vec<(String, SetElement)>, u32)>,
+    /// Contents of the string pool, loaded at `memory::STRING_POOL_START`
+    pool: Vec<u8>,
+    /// Redacted string literals, indexed by their `resolve_secret` id
+    secrets: Vec<String>,
+    /// Whether any emitted code calls a runtime function
+    uses_host: bool,
+    /// Whether the module takes template slot parameters
+    slots: bool,
+    /// Export each policy function as `evaluate_<id>`
+    policy_exports: bool,
+    /// Declare a 64-bit linear memory, addressed with i64s
+    memory64: bool,
+    /// Return an error code from `evaluate` next to the decision
+    error_codes: bool,
+    /// Load the string pool from `_initialize` instead of at instantiation
+    initialize: bool,
+    /// Export an `evaluate` taking the request as JSON
+    json_requests: bool,
+    /// Address of the four i32s `parse_request` writes the request to
+    request_start: u32,
+    /// Entity handles bound to `?principal` and `?resource`, for modules
+    /// whose `evaluate` takes just the request
+    bound_slots: Option<(u32, u32)>,
+    /// Emit the source map section
+    source_map: bool,
+    /// How arithmetic handles overflow
+    overflow: Overflow,
+    /// Body offsets where each compiled policy's code for a new span
+    /// starts, in policy order
+    mappings: Vec<Vec<(u32, Span)>>,
+    /// Function index of the first helper
+    helper_base: u32,
+    /// Result types of the helpers compiled so far
+    helper_types: Vec<WasmType>,
+    /// Whether each helper compiled so far can fail, leaving the error flag
+    /// set for its callers to check
+    helper_fails: Vec<bool>,
+    /// Result type of the helper being compiled, `None` for a policy
+    helper_result: Option<WasmType>,
+    /// Whether the function being compiled can exit through a failure
+    may_fail: bool,
 }
 
 impl WasmCodeGen {
     pub fn new() -> Self {
         Self {
-            module: Module::new(),
+            mvp_only: false,
+            validate: false,
+            names: false,
+            max_pages: memory::MAX_PAGES,
+            batch_evaluation: false,
+            coverage: false,
+            coverage_start: 0,
+            coverage_probes: 0,
+            attributes: Vec::new(),
+            memo_sites: 0,
+            stack: Vec::new(),
+            branches: Vec::new(),
+            inferred: Vec::new(),
+            swap_locals: 0,
+            strings: Vec::new(),
+            sets: Vec::new(),
+            records: Vec::new(),
+            pool: Vec::new(),
+            secrets: Vec::new(),
+            uses_host: false,
+            slots: false,
+            policy_exports: false,
+            memory64: false,
+            error_codes: false,
+            initialize: false,
+            json_requests: false,
+            request_start: 0,
+            bound_slots: None,
+            source_map: false,
+            mappings: Vec::new(),
+            overflow: Overflow::Error,
+            helper_base: 0,
+            helper_types: Vec::new(),
+            helper_fails: Vec::new(),
+            helper_result: None,
+            may_fail: false,
         }
     }
 
-    /// Generate a complete WebAssembly module from the lowered IR
+    /// Only emit WASM 1.0 MVP features: single-result blocks and functions,
+    /// active data segments, untyped `select`
+    pub fn with_mvp_only(mut self, mvp_only: bool) -> Self {
+        self.mvp_only = mvp_only;
+        self
+    }
+
+    /// Check each generated module with `wasmparser` before returning it, so
+    /// a codegen bug is reported as an error instead of an invalid module
+    pub fn with_validation(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    /// Emit a `name` custom section naming the functions, for debuggers and
+    /// disassemblers
+    pub fn with_names(mut self, names: bool) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Declare a 64-bit (`memory64`) linear memory. The module then loads and
+    /// stores through i64 addresses, and the addresses it exchanges with the
+    /// host as such are i64s; see [`crate::wasm::runtime`].
+    pub fn with_memory64(mut self, memory64: bool) -> Self {
+        self.memory64 = memory64;
+        self
+    }
+
+    /// Cap linear memory at `pages` WASM pages (64KB each) instead of
+    /// [`memory::MAX_PAGES`]
+    pub fn with_max_pages(mut self, pages: u32) -> Self {
+        self.max_pages = pages;
+        self
+    }
+
+    /// Export `evaluate_batch` and remember each attribute read's last result,
+    /// so requests in a batch that share an entity fetch its attributes once.
+    /// See [`crate::wasm::runtime`] for the batch ABI.
+    pub fn with_batch_evaluation(mut self, batch_evaluation: bool) -> Self {
+        self.batch_evaluation = batch_evaluation;
+        self
+    }
+
+    /// Also export every policy function on its own as `evaluate_<id>`, with
+    /// the signature of `evaluate`
+    pub fn with_policy_exports(mut self, policy_exports: bool) -> Self {
+        self.policy_exports = policy_exports;
+        self
+    }
+
+    /// Have `evaluate` return an error code after the decision, which takes
+    /// the multi-value feature. See [`crate::wasm::runtime`] for the codes.
+    pub fn with_error_codes(mut self, error_codes: bool) -> Self {
+        self.error_codes = error_codes;
+        self
+    }
+
+    /// Export `_initialize`, which copies the string pool into memory from a
+    /// passive data segment, for hosts that set modules up explicitly as
+    /// WASI reactors. Takes the bulk-memory feature. See
+    /// [`crate::wasm::runtime`].
+    pub fn with_initialize(mut self, initialize: bool) -> Self {
+        self.initialize = initialize;
+        self
+    }
+
+    /// Export `evaluate(json, len)`, which has the host parse a JSON request
+    /// and evaluates the policies on it. See [`crate::wasm::runtime`] for
+    /// the request format.
+    pub fn with_json_requests(mut self, json_requests: bool) -> Self {
+        self.json_requests = json_requests;
+        self
+    }
+
+    /// Bind the slots of a template to these entity handles: the exported
+    /// `evaluate` takes just the request and passes them on to the
+    /// template's evaluation function
+    pub fn with_bound_slots(mut self, slots: Option<(u32, u32)>) -> Self {
+        self.bound_slots = slots;
+        self
+    }
+
+    /// Map the code of every policy back to the spans of Cedar source it
+    /// was lowered from, in a custom section. See [`SOURCE_MAP_SECTION`].
+    pub fn with_source_map(mut self, source_map: bool) -> Self {
+        self.source_map = source_map;
+        self
+    }
+
+    /// Handle integer overflow this way instead of as an evaluation error
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Record which way every condition went in a bitmap exported as
+    /// `coverage`. See [`crate::wasm::runtime`] for the layout.
+    pub fn with_coverage(mut self, coverage: bool) -> Self {
+        self.coverage = coverage;
+        self
+    }
+
+    /// Strings redacted from the last generated module, indexed by the id
+    /// the module passes to `resolve_secret`. The host must supply these at
+    /// runtime; they are not embedded in the module.
+    pub fn secrets(&self) -> &[String] {
+        &self.secrets
+    }
+
+    /// Generate a complete WebAssembly module from the lowered IR. The pool
+    /// and interning tables start afresh on every call, so one generator
+    /// can compile any number of modules.
     pub fn generate(&mut self, lowered: &LoweredModule) -> Result<Vec<u8>, String> {
+        self.attributes.clear();
+        self.memo_sites = 0;
+        self.strings.clear();
+        self.sets.clear();
+        self.records.clear();
+        self.pool.clear();
+        self.secrets.clear();
+        self.uses_host = false;
+        self.coverage_probes = 0;
+        self.mappings.clear();
+        self.helper_types.clear();
+        self.helper_fails.clear();
+        self.slots = lowered.slots;
+        if self.slots && self.batch_evaluation {
+            return Err("Batch evaluation does not support template slots".to_string());
+        }
+        if self.bound_slots.is_some() && !self.slots {
+            return Err("Only templates with slots can have them bound".to_string());
+        }
+        if self.bound_slots.is_some() && self.error_codes {
+            return Err("Templates with bound slots do not support error codes".to_string());
+        }
+        if self.memory64 && self.mvp_only {
+            return Err("memory64 is not part of the WASM MVP".to_string());
+        }
+        if self.error_codes && self.mvp_only {
+            return Err(
+                "Error codes need multi-value, which is not part of the WASM MVP".to_string(),
+            );
+        }
+        if self.initialize && self.mvp_only {
+            return Err(
+                "`_initialize` needs bulk memory, which is not part of the WASM MVP".to_string(),
+            );
+        }
+        if self.max_pages < memory::INITIAL_PAGES {
+            return Err(format!(
+                "Memory limit of {} pages is below the {} the module starts with",
+                self.max_pages,
+                memory::INITIAL_PAGES
+            ));
+        }
+        // Only `evaluate` resets the memo, so calling a policy directly could
+        // see another request's attributes
+        if self.policy_exports && self.batch_evaluation {
+            return Err("Batch evaluation does not support per-policy exports".to_string());
+        }
+        if self.json_requests && (self.slots || self.batch_evaluation || self.error_codes) {
+            return Err(
+                "JSON requests do not support template slots, batch evaluation or error codes"
+                    .to_string(),
+            );
+        }
+
+        // The entity table opens the pool: one u32 pointer per interned
+        // entity UID, in index order
+        self.reserve(lowered.entities.len() * 4)?;
+        self.pool.resize(lowered.entities.len() * 4, 0);
+        for (idx, uid) in lowered.entities.iter().enumerate() {
+            let ptr = self.intern_string(uid)?;
+            self.pool[idx * 4..idx * 4 + 4].copy_from_slice(&ptr.to_le_bytes());
+        }
+
+        // The coverage bitmap follows, zeroed, with two bits per condition
+        let coverage_bits = if self.coverage {
+            let probes = lowered
+                .instructions()
+                .filter(|inst| is_coverage_probe(inst))
+                .count() as u32;
+            let bytes = (probes * 2).div_ceil(8).next_multiple_of(4);
+            self.coverage_start = self.reserve(bytes as usize)?;
+            self.pool.resize(self.pool.len() + bytes as usize, 0);
+            probes * 2
+        } else {
+            0
+        };
+
+        // Then room for the request `parse_request` parses
+        if self.json_requests {
+            self.request_start = self.reserve(16)?;
+            self.pool.resize(self.pool.len() + 16, 0);
+            self.uses_host = true;
+        }
+
+        // Calls to helpers need the number of imports before the bodies
+        // are compiled, so a module with helpers always imports the runtime
+        if !lowered.helpers.is_empty() {
+            self.uses_host = true;
+        }
+        self.helper_base = runtime_functions::COUNT + lowered.policies.len() as u32;
+        let helper_bodies = lowered
+            .helpers
+            .iter()
+            .map(|helper| self.compile_helper(helper))
+            .collect::<Result<Vec<_>, _>>()?;
+        let helper_mappings = std::mem::take(&mut self.mappings);
+
+        // Policy bodies only refer to imports and helpers, whose indices are
+        // fixed, so they are compiled first to find out whether the host is
+        // needed
+        let policy_bodies = lowered
+            .policies
+            .iter()
+            .map(|policy| self.compile_function(policy))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.mappings.extend(helper_mappings);
+
         // 1. Type section: Define function signatures
-        let mut types = TypeSection::new();
-        let sig = FunctionSignature::policy_entry();
-        // In wasm-encoder 0.220+, use ty() instead of function()
-        types.ty().function(
-            sig.params.iter().map(|t| t.to_val_type()).collect::<Vec<_>>(),
-            sig.results.iter().map(|t| t.to_val_type()).collect::<Vec<_>>(),
-        );
+        // Type 0 is the policy entry signature; others are added as needed
+        let sig = self.entry_signature();
+        let mut types: Vec<FuncType> = vec![(
+            sig.params.iter().map(|t| t.to_val_type()).collect(),
+            sig.results.iter().map(|t| t.to_val_type()).collect(),
+        )];
+
+        // 2. Import section: runtime functions, only if a policy calls the host
+        let mut imports = ImportSection::new();
+        let import_count = if self.uses_host {
+            // Imported in index order, so they occupy function indices 0..COUNT
+            for (func, name) in (0..).zip(runtime_functions::NAMES) {
+                let (params, results) = runtime_signature(func);
+                let ty = type_index(&mut types, params, results);
+                imports.import(runtime_functions::MODULE, name, EntityType::Function(ty));
+            }
+            runtime_functions::COUNT
+        } else {
+            0
+        };
 
-        // 2. Function section: Declare functions
+        // 3. Function section: Declare functions
+        // A single policy is the evaluation function itself; a policy set gets
+        // one function per policy followed by a combining `evaluate`
+        let combined = lowered.policies.len() != 1;
         let mut functions = FunctionSection::new();
-        functions.function(0); // Main evaluation function uses type 0
+        for _ in &lowered.policies {
+            functions.function(0); // Policy functions use type 0
+        }
+        // Helpers take the same parameters and return their value
+        for ty in &self.helper_types {
+            let params = types[0].0.clone();
+            functions.function(type_index(&mut types, params, vec![ty.to_val_type()]));
+        }
+        if combined {
+            functions.function(0);
+        }
+        let mut next_idx = import_count + functions.len();
+        let inner_evaluate_idx = if combined { next_idx - 1 } else { import_count };
+
+        // Batch evaluation wraps the inner `evaluate` to reset the memo first,
+        // and error codes to return the error flag with the decision
+        let mut evaluate_idx = inner_evaluate_idx;
+        let mut batch_idx = None;
+        if self.wraps_evaluate() {
+            let ty = if self.error_codes {
+                let params = types[0].0.clone();
+                type_index(&mut types, params, vec![ValType::I32, ValType::I32])
+            } else {
+                0
+            };
+            functions.function(ty);
+            evaluate_idx = next_idx;
+            next_idx += 1;
+        }
+        if self.json_requests {
+            functions.function(type_index(
+                &mut types,
+                vec![ValType::I32, ValType::I32],
+                vec![ValType::I32],
+            ));
+            evaluate_idx = next_idx;
+            next_idx += 1;
+        }
+        if self.bound_slots.is_some() {
+            let sig = FunctionSignature::policy_entry();
+            functions.function(type_index(
+                &mut types,
+                sig.params.iter().map(|t| t.to_val_type()).collect(),
+                sig.results.iter().map(|t| t.to_val_type()).collect(),
+            ));
+            evaluate_idx = next_idx;
+            next_idx += 1;
+        }
+        if self.batch_evaluation {
+            let address = self.address_type();
+            let batch_ty = type_index(&mut types, vec![address, ValType::I32, address], vec![]);
+            functions.function(batch_ty);
+            batch_idx = Some(next_idx);
+            next_idx += 1;
+        }
+        let mut initialize_idx = None;
+        if self.initialize {
+            functions.function(type_index(&mut types, vec![], vec![]));
+            initialize_idx = Some(next_idx);
+            next_idx += 1;
+        }
+        debug_assert_eq!(next_idx, import_count + functions.len());
 
-        // 3. Memory section: Linear memory for string storage
+        // 4. Memory section: Linear memory for string storage, starting out
+        // large enough for the pool
+        let pool_end = u64::from(memory::STRING_POOL_START) + self.pool.len() as u64;
+        let min_pages = pool_end
+            .div_ceil(u64::from(PAGE_SIZE))
+            .max(u64::from(memory::INITIAL_PAGES));
+        if min_pages > u64::from(self.max_pages) {
+            return Err(format!(
+                "String pool needs {} bytes, which takes {} pages of memory, over the limit of {}",
+                self.pool.len(),
+                min_pages,
+                self.max_pages
+            ));
+        }
         let mut memories = MemorySection::new();
         memories.memory(MemoryType {
-            minimum: memory::INITIAL_PAGES.into(),
-            maximum: Some(memory::MAX_PAGES.into()),
-            memory64: false,
+            minimum: min_pages,
+            maximum: Some(self.max_pages.into()),
+            memory64: self.memory64,
             shared: false,
             page_size_log2: None,
         });
 
-        // 4. Export section: Export the main function and memory
+        // 5. Export section: Export the main function and memory
         let mut exports = ExportSection::new();
-        exports.export("evaluate", ExportKind::Func, 0);
+        exports.export("evaluate", ExportKind::Func, evaluate_idx);
+        if let Some(batch_idx) = batch_idx {
+            exports.export("evaluate_batch", ExportKind::Func, batch_idx);
+        }
+        if let Some(initialize_idx) = initialize_idx {
+            exports.export("_initialize", ExportKind::Func, initialize_idx);
+        }
+        if self.policy_exports {
+            let mut names = BTreeSet::new();
+            for (func, policy) in (import_count..).zip(&lowered.policies) {
+                let name = format!("evaluate_{}", policy.id);
+                if !names.insert(name.clone()) {
+                    return Err(format!("Two policies are exported as `{}`", name));
+                }
+                exports.export(&name, ExportKind::Func, func);
+            }
+        }
         exports.export("memory", ExportKind::Memory, 0);
+        if self.reports_errors() {
+            exports.export("error", ExportKind::Global, ERROR_GLOBAL);
+        }
+        // Entity table globals follow the memo slots
+        let entity_table_global = ERROR_GLOBAL + 1 + self.memo_sites * 3;
+        let has_entities = !lowered.entities.is_empty();
+        if has_entities {
+            exports.export("entity_table", ExportKind::Global, entity_table_global);
+            exports.export("entity_count", ExportKind::Global, entity_table_global + 1);
+        }
+        let coverage_global = entity_table_global + if has_entities { 2 } else { 0 };
+        if self.coverage {
+            exports.export("coverage", ExportKind::Global, coverage_global);
+            exports.export("coverage_bits", ExportKind::Global, coverage_global + 1);
+        }
 
-        // 5. Code section: Implement the function bodies
+        // 6. Code section: Implement the function bodies
         let mut codes = CodeSection::new();
-        let func_body = self.compile_function(&lowered.entry)?;
-        codes.function(&func_body);
+        for func_body in policy_bodies.iter().chain(&helper_bodies) {
+            codes.function(func_body);
+        }
+        if combined {
+            codes.function(&self.compile_combinator(import_count, lowered.policies.len() as u32));
+        }
+        if self.wraps_evaluate() {
+            codes.function(&self.compile_evaluate_wrapper(inner_evaluate_idx));
+        }
+        if self.json_requests {
+            codes.function(&self.compile_json_evaluate(inner_evaluate_idx));
+        }
+        if let Some(slots) = self.bound_slots {
+            codes.function(&compile_bound_evaluate(inner_evaluate_idx, slots));
+        }
+        if self.batch_evaluation {
+            codes.function(&self.compile_evaluate_batch(inner_evaluate_idx));
+        }
+        if self.initialize {
+            codes.function(&self.compile_initialize());
+        }
 
-        // Assemble the module
-        self.module.section(&types);
-        self.module.section(&functions);
-        self.module.section(&memories);
-        self.module.section(&exports);
-        self.module.section(&codes);
+        // 7. Global section: the error flag, then memo slots, known once the
+        // bodies are compiled
+        let mut globals = GlobalSection::new();
+        let flag = GlobalType {
+            val_type: ValType::I32,
+            mutable: true,
+            shared: false,
+        };
+        globals.global(flag, &ConstExpr::i32_const(0));
+        for _ in 0..self.memo_sites {
+            let slot = |val_type| GlobalType {
+                val_type,
+                mutable: true,
+                shared: false,
+            };
+            globals.global(slot(ValType::I32), &ConstExpr::i32_const(0));
+            globals.global(slot(ValType::I64), &ConstExpr::i64_const(0));
+            globals.global(slot(ValType::I32), &ConstExpr::i32_const(0));
+        }
+        let constant = |val_type| GlobalType {
+            val_type,
+            mutable: false,
+            shared: false,
+        };
+        if has_entities {
+            globals.global(
+                constant(self.address_type()),
+                &self.address_const(memory::STRING_POOL_START),
+            );
+            globals.global(
+                constant(ValType::I32),
+                &ConstExpr::i32_const(lowered.entities.len() as i32),
+            );
+        }
+        if self.coverage {
+            globals.global(
+                constant(self.address_type()),
+                &self.address_const(self.coverage_start),
+            );
+            globals.global(
+                constant(ValType::I32),
+                &ConstExpr::i32_const(coverage_bits as i32),
+            );
+        }
 
-        // Clone to avoid move issue (acceptable for now)
-        Ok(self.module.clone().finish())
-    }
+        // 8. Data section: the string pool, passive if `_initialize` loads it
+        let mut data = DataSection::new();
+        if self.initialize && !self.pool.is_empty() {
+            data.passive(self.pool.iter().copied());
+        } else if !self.pool.is_empty() {
+            data.active(
+                0,
+                &self.address_const(memory::STRING_POOL_START),
+                self.pool.iter().copied(),
+            );
+        }
 
-    /// Compile a function from the IR instructions
-    fn compile_function(&self, func: &crate::ast::lowering::LoweredFunction) -> Result<Function, String> {
-        let mut f = Function::new(vec![]); // No locals for now
+        let mut type_section = TypeSection::new();
+        for (params, results) in &types {
+            // In wasm-encoder 0.220+, use ty() instead of function()
+            type_section
+                .ty()
+                .function(params.iter().copied(), results.iter().copied());
+        }
 
-        for inst in &func.instructions {
-            self.compile_instruction(inst, &mut f)?;
+        // Assemble the module
+        let mut module = Module::new();
+        module.section(&type_section);
+        if self.uses_host {
+            module.section(&imports);
+        }
+        module.section(&functions);
+        module.section(&memories);
+        module.section(&globals);
+        module.section(&exports);
+        if self.initialize && !data.is_empty() {
+            // `memory.init` needs the segment count ahead of the code
+            module.section(&DataCountSection { count: data.len() });
+        }
+        module.section(&codes);
+        if !data.is_empty() {
+            module.section(&data);
+        }
+        if !self.attributes.is_empty() {
+            let mut data = Vec::new();
+            self.attributes.len().encode(&mut data);
+            for name in &self.attributes {
+                name.as_str().encode(&mut data);
+            }
+            module.section(&CustomSection {
+                name: Cow::Borrowed(ATTRIBUTES_SECTION),
+                data: Cow::Owned(data),
+            });
+        }
+        let mut data = Vec::new();
+        lowered.policies.len().encode(&mut data);
+        for policy in &lowered.policies {
+            policy.id.as_str().encode(&mut data);
+            data.push(match policy.effect {
+                PolicyEffect::Permit => Decision::Permit as u8,
+                PolicyEffect::Forbid => Decision::Deny as u8,
+            });
+        }
+        module.section(&CustomSection {
+            name: Cow::Borrowed(POLICIES_SECTION),
+            data: Cow::Owned(data),
+        });
+        if self.source_map {
+            let mut data = Vec::new();
+            self.mappings.len().encode(&mut data);
+            for (func, mappings) in (import_count..).zip(&self.mappings) {
+                func.encode(&mut data);
+                mappings.len().encode(&mut data);
+                for (offset, span) in mappings {
+                    offset.encode(&mut data);
+                    (span.start as u32).encode(&mut data);
+                    (span.end as u32).encode(&mut data);
+                }
+            }
+            module.section(&CustomSection {
+                name: Cow::Borrowed(SOURCE_MAP_SECTION),
+                data: Cow::Owned(data),
+            });
         }
 
-        // Every WASM function body must end with an End instruction
-        f.instruction(&WasmInst::End);
+        if self.names {
+            let functions = FunctionIndices {
+                policies: import_count..import_count + lowered.policies.len() as u32,
+                helpers: self.helper_base..self.helper_base + lowered.helpers.len() as u32,
+                combinator: combined.then_some(inner_evaluate_idx),
+                evaluate: evaluate_idx,
+                batch: batch_idx,
+                initialize: initialize_idx,
+            };
+            module.section(&self.name_section(&functions));
+        }
 
-        Ok(f)
+        let wasm_bytes = module.finish();
+
+        if self.mvp_only {
+            Validator::new_with_features(WasmFeatures::MVP)
+                .validate_all(&wasm_bytes)
+                .map_err(|e| {
+                    format!("Module is not valid under the WASM MVP feature set: {}", e)
+                })?;
+        } else if self.validate {
+            let mut features = WasmFeatures::default();
+            features.set(WasmFeatures::MEMORY64, self.memory64);
+            Validator::new_with_features(features)
+                .validate_all(&wasm_bytes)
+                .map_err(|e| format!("Generated module is invalid: {}", e))?;
+        }
+
+        Ok(wasm_bytes)
     }
 
-    /// Compile a single IR instruction to WASM instructions
-    fn compile_instruction(&self, inst: &Instruction, f: &mut Function) -> Result<(), String> {
-        match inst {
-            // Literals
-            Instruction::PushBool(b) => {
-                f.instruction(&WasmInst::I32Const(if *b { 1 } else { 0 }));
+    /// Name the runtime imports, the functions, and their locals, so
+    /// disassemblers and debuggers can show them
+    fn name_section(&self, functions: &FunctionIndices) -> NameSection {
+        let mut function_names = NameMap::new();
+        let mut local_names = IndirectNameMap::new();
+        let locals = |extra: &[(u32, &str)]| {
+            let mut names = NameMap::new();
+            for (idx, name) in (0..).zip(PARAM_NAMES) {
+                names.append(idx, name);
             }
-            Instruction::PushInt(i) => {
-                f.instruction(&WasmInst::I64Const(*i));
+            if self.slots {
+                for (idx, name) in (PRINCIPAL_SLOT_PARAM..).zip(SLOT_PARAM_NAMES) {
+                    names.append(idx, name);
+                }
             }
-            Instruction::PushString(_s) => {
-                // TODO: Implement string storage in linear memory
-                // For now, push a placeholder pointer
-                f.instruction(&WasmInst::I32Const(0));
+            for (idx, name) in extra {
+                names.append(*idx, name);
             }
+            names
+        };
+        let policy_locals = locals(&[
+            (SCRATCH_LOCAL, "scratch"),
+            (SCRATCH_I64_LOCAL, "scratch_i64"),
+            (COVERAGE_LOCAL, "condition"),
+        ]);
 
-            // Comparison operations (for i64)
-            Instruction::Equal => {
-                f.instruction(&WasmInst::I64Eq);
-            }
-            Instruction::NotEqual => {
-                f.instruction(&WasmInst::I64Ne);
-            }
-            Instruction::LessThan => {
-                f.instruction(&WasmInst::I64LtS); // Signed less than
-            }
-            Instruction::LessThanOrEqual => {
-                f.instruction(&WasmInst::I64LeS);
-            }
-            Instruction::GreaterThan => {
-                f.instruction(&WasmInst::I64GtS);
+        if self.uses_host {
+            for (func, name) in (0..).zip(runtime_functions::NAMES) {
+                function_names.append(func, name);
             }
-            Instruction::GreaterThanOrEqual => {
-                f.instruction(&WasmInst::I64GeS);
+        }
+        for (i, func) in functions.policies.clone().enumerate() {
+            if func == functions.evaluate {
+                function_names.append(func, "evaluate");
+            } else {
+                function_names.append(func, &format!("policy{}", i));
             }
-
-            // Logical operations (on i64)
-            Instruction::And => {
-                f.instruction(&WasmInst::I64And);
+            local_names.append(func, &policy_locals);
+        }
+        for (i, func) in functions.helpers.clone().enumerate() {
+            function_names.append(func, &format!("helper{}", i));
+            local_names.append(func, &policy_locals);
+        }
+        if let Some(func) = functions.combinator {
+            if func == functions.evaluate {
+                function_names.append(func, "evaluate");
+            } else {
+                function_names.append(func, "evaluate_policies");
             }
-            Instruction::Or => {
-                f.instruction(&WasmInst::I64Or);
+            let params = self.entry_signature().params.len() as u32;
+            local_names.append(
+                func,
+                &locals(&[
+                    (params, "decision"),
+                    (params + 1, "permitted"),
+                    (params + 2, "errored"),
+                ]),
+            );
+        }
+        if self.wraps_evaluate() {
+            let params = self.entry_signature().params.len() as u32;
+            let decision = [(params, "decision")];
+            let extra: &[(u32, &str)] = if self.error_codes { &decision } else { &[] };
+            function_names.append(functions.evaluate, "evaluate");
+            local_names.append(functions.evaluate, &locals(extra));
+        }
+        if self.bound_slots.is_some() {
+            function_names.append(functions.evaluate, "evaluate");
+            let mut request_locals = NameMap::new();
+            for (idx, name) in (0..).zip(PARAM_NAMES) {
+                request_locals.append(idx, name);
             }
-            Instruction::Not => {
-                f.instruction(&WasmInst::I64Eqz); // Logical not: x == 0
+            local_names.append(functions.evaluate, &request_locals);
+        }
+        if self.json_requests {
+            function_names.append(functions.evaluate, "evaluate");
+            let mut json_locals = NameMap::new();
+            json_locals.append(0, "json");
+            json_locals.append(1, "len");
+            local_names.append(functions.evaluate, &json_locals);
+        }
+        if let Some(func) = functions.batch {
+            function_names.append(func, "evaluate_batch");
+            let mut batch_locals = NameMap::new();
+            for (idx, name) in (0..).zip(["requests", "count", "results", "i", "request"]) {
+                batch_locals.append(idx, name);
             }
+            local_names.append(func, &batch_locals);
+        }
+        if let Some(func) = functions.initialize {
+            function_names.append(func, "_initialize");
+        }
 
-            // Control flow
-            Instruction::IfThenElse => {
-                // Stack at IfThenElse: [else_value (i32), then_value (i32), condition (i64)]
-                // WASM select requires: [else_value, then_value, condition (i32)]
-                // So we need to convert the i64 condition to i32
-                // We can use I32WrapI64 which takes the low 32 bits
-                f.instruction(&WasmInst::I32WrapI64);
-                // Now stack is: [else_value (i32), then_value (i32), condition (i32)]
-                f.instruction(&WasmInst::Select);
-            }
+        let mut names = NameSection::new();
+        names.functions(&function_names);
+        names.locals(&local_names);
+        names
+    }
 
-            Instruction::Return => {
-                f.instruction(&WasmInst::Return);
-            }
+    /// Compile a function from the IR instructions
+    fn compile_function(&mut self, func: &LoweredFunction) -> Result<Function, String> {
+        let slot_padding = if self.slots { 0 } else { 2 };
+        let cache_locals = func
+            .instructions
+            .iter()
+            .filter_map(|inst| match inst {
+                Instruction::TeeLocal(idx) | Instruction::GetLocal(idx) => Some(idx + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let swaps = func.instructions.contains(&Instruction::Swap);
+        self.swap_locals = CACHE_LOCAL_START + cache_locals;
+        let mut f = Function::new(vec![
+            (slot_padding, ValType::I32),
+            (1, ValType::I32),
+            (1, ValType::I64),
+            (1, ValType::I32),
+            (cache_locals, ValType::I64),
+            (swaps as u32, ValType::I32),
+            (swaps as u32, ValType::I64),
+        ]);
+        self.stack.clear();
+        self.branches.clear();
+        self.inferred = infer_branch_types(&func.instructions, &self.helper_types)?.0;
+        self.inferred.reverse();
 
-            // Policy decisions
-            Instruction::Permit => {
-                f.instruction(&WasmInst::I32Const(Decision::Permit as i32));
-            }
-            Instruction::Forbid => {
-                f.instruction(&WasmInst::I32Const(Decision::Deny as i32));
-            }
-            Instruction::NoDecision => {
-                f.instruction(&WasmInst::I32Const(Decision::NoDecision as i32));
-            }
+        // Errors are reported per policy
+        if self.reports_errors() && self.helper_result.is_none() {
+            f.instruction(&WasmInst::I32Const(0));
+            f.instruction(&WasmInst::GlobalSet(ERROR_GLOBAL));
+        }
 
-            // Entity operations - these would require runtime support
-            Instruction::GetAttribute(_attr) => {
-                // TODO: Call runtime function to get attribute
-                return Err("GetAttribute not yet implemented".to_string());
+        // Spans only line up with instructions IR rewrites kept them for
+        let spans = (func.spans.len() == func.instructions.len()).then_some(&func.spans);
+        let mut mappings: Vec<(u32, Span)> = Vec::new();
+        for (idx, inst) in func.instructions.iter().enumerate() {
+            if let Some(span) = spans.and_then(|spans| spans[idx]) {
+                if mappings.last().map(|(_, last)| *last) != Some(span) {
+                    mappings.push((f.byte_len() as u32, span));
+                }
             }
-            Instruction::HasAttribute(_attr) => {
-                // TODO: Call runtime function to check attribute
-                return Err("HasAttribute not yet implemented".to_string());
-            }
-            Instruction::In => {
-                // TODO: Call runtime function for entity hierarchy check
-                return Err("In operator not yet implemented".to_string());
+            self.compile_instruction(inst, &mut f)?;
+        }
+        self.mappings.push(mappings);
+        if !self.branches.is_empty() {
+            return Err("If without a matching End".to_string());
+        }
+        if let Some(result) = self.helper_result {
+            if !matches!(self.stack.as_slice(), [ty] if ty.to_val_type() == result.to_val_type()) {
+                return Err(format!(
+                    "Helper {} must end with one value on the stack, found {}",
+                    func.id,
+                    describe_types(&self.stack)
+                ));
             }
+            f.instruction(&WasmInst::End);
+            return Ok(f);
+        }
+        // Falling off the end returns whatever is left, which must be the
+        // decision alone
+        let returns = matches!(func.instructions.last(), Some(Instruction::Return));
+        let decision_left =
+            matches!(self.stack.as_slice(), [ty] if ty.to_val_type() == ValType::I32);
+        if !returns && !decision_left {
+            return Err(format!(
+                "Function must end with one i32 decision on the stack, found {}",
+                describe_types(&self.stack)
+            ));
         }
 
-        Ok(())
+        // Every WASM function body must end with an End instruction
+        f.instruction(&WasmInst::End);
+
+        Ok(f)
     }
-}
 
-impl Default for WasmCodeGen {
-    fn default() -> Self {
-        Self::new()
+    /// Compile a helper, a function returning the one value its body
+    /// leaves, and record its type and whether it can fail for its callers
+    fn compile_helper(&mut self, helper: &LoweredFunction) -> Result<Function, String> {
+        let (_, left) = infer_branch_types(&helper.instructions, &self.helper_types)?;
+        let [result] = left[..] else {
+            return Err(format!(
+                "Helper {} must leave one value on the stack, found {}",
+                helper.id,
+                describe_types(&left)
+            ));
+        };
+        self.helper_result = Some(result);
+        self.may_fail = false;
+        let body = self.compile_function(helper);
+        self.helper_result = None;
+        self.helper_types.push(result);
+        self.helper_fails.push(self.may_fail);
+        body
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::ast::lowering::LoweredFunction;
+    /// Signature of `evaluate` and the policy functions
+    fn entry_signature(&self) -> FunctionSignature {
+        if self.slots {
+            FunctionSignature::template_entry()
+        } else {
+            FunctionSignature::policy_entry()
+        }
+    }
 
-    #[test]
-    fn test_simple_permit_codegen() {
-        let module = LoweredModule {
-            entry: LoweredFunction {
-                instructions: vec![
-                    Instruction::Permit,
-                    Instruction::Return,
-                ],
-            },
-        };
+    /// Build the `evaluate` function for a policy set: forbid overrides
+    /// permit, and with no matching policy the result is NoDecision
+    fn compile_combinator(&self, first_policy_idx: u32, policy_count: u32) -> Function {
+        // Locals after the request parameters: decision of the current
+        // policy, whether any policy permitted, and whether any errored
+        let param_count = self.entry_signature().params.len() as u32;
+        let mut f = Function::new(vec![(3, ValType::I32)]);
+        let decision = param_count;
+        let permitted = param_count + 1;
+        let errored = param_count + 2;
 
-        let mut codegen = WasmCodeGen::new();
-        let result = codegen.generate(&module);
+        for func_idx in first_policy_idx..first_policy_idx + policy_count {
+            // Every policy sees the same request
+            for param in 0..param_count {
+                f.instruction(&WasmInst::LocalGet(param));
+            }
+            f.instruction(&WasmInst::Call(func_idx));
+            f.instruction(&WasmInst::LocalSet(decision));
 
-        assert!(result.is_ok());
-        let wasm_bytes = result.unwrap();
-        assert!(!wasm_bytes.is_empty());
+            // Each policy clears the error flag, so collect it after each;
+            // without error reporting it stays 0
+            f.instruction(&WasmInst::GlobalGet(ERROR_GLOBAL));
+            f.instruction(&WasmInst::LocalGet(errored));
+            f.instruction(&WasmInst::I32Or);
+            f.instruction(&WasmInst::LocalSet(errored));
 
-        // Verify it's valid WASM (starts with magic number)
-        assert_eq!(&wasm_bytes[0..4], b"\0asm");
+            // A forbid short-circuits the whole set
+            f.instruction(&WasmInst::LocalGet(decision));
+            f.instruction(&WasmInst::I32Const(Decision::Deny as i32));
+            f.instruction(&WasmInst::I32Eq);
+            f.instruction(&WasmInst::If(BlockType::Empty));
+            f.instruction(&WasmInst::LocalGet(errored));
+            f.instruction(&WasmInst::GlobalSet(ERROR_GLOBAL));
+            f.instruction(&WasmInst::I32Const(Decision::Deny as i32));
+            f.instruction(&WasmInst::Return);
+            f.instruction(&WasmInst::End);
+
+            // permitted |= (decision == Permit)
+            f.instruction(&WasmInst::LocalGet(decision));
+            f.instruction(&WasmInst::I32Const(Decision::Permit as i32));
+            f.instruction(&WasmInst::I32Eq);
+            f.instruction(&WasmInst::LocalGet(permitted));
+            f.instruction(&WasmInst::I32Or);
+            f.instruction(&WasmInst::LocalSet(permitted));
+        }
+
+        f.instruction(&WasmInst::LocalGet(errored));
+        f.instruction(&WasmInst::GlobalSet(ERROR_GLOBAL));
+        f.instruction(&WasmInst::I32Const(Decision::Permit as i32));
+        f.instruction(&WasmInst::I32Const(Decision::NoDecision as i32));
+        f.instruction(&WasmInst::LocalGet(permitted));
+        f.instruction(&WasmInst::Select);
+        f.instruction(&WasmInst::End);
+        f
     }
 
-    #[test]
-    fn test_boolean_logic_codegen() {
-        let module = LoweredModule {
-            entry: LoweredFunction {
-                instructions: vec![
-                    Instruction::PushBool(true),
-                    Instruction::PushBool(false),
-                    Instruction::And,
-                    Instruction::Return,
-                ],
-            },
-        };
+    /// Whether the exported `evaluate` wraps the policies' own
+    fn wraps_evaluate(&self) -> bool {
+        self.batch_evaluation || self.error_codes
+    }
 
-        let mut codegen = WasmCodeGen::new();
-        let result = codegen.generate(&module);
+    /// Build the exported `evaluate` around the inner one. For batch
+    /// evaluation a single request is a batch of one, so it starts from an
+    /// empty memo; with error codes the error flag follows a
+    /// [`Decision::Error`].
+    fn compile_evaluate_wrapper(&self, inner_evaluate_idx: u32) -> Function {
+        let param_count = self.entry_signature().params.len() as u32;
+        let decision = param_count;
+        let locals = if self.error_codes { 1 } else { 0 };
+        let mut f = Function::new(vec![(locals, ValType::I32)]);
+        self.emit_memo_reset(&mut f);
+        for param in 0..param_count {
+            f.instruction(&WasmInst::LocalGet(param));
+        }
+        f.instruction(&WasmInst::Call(inner_evaluate_idx));
+        if self.error_codes {
+            // error code = decision == Error ? error : 0
+            f.instruction(&WasmInst::LocalTee(decision));
+            f.instruction(&WasmInst::GlobalGet(ERROR_GLOBAL));
+            f.instruction(&WasmInst::I32Const(0));
+            f.instruction(&WasmInst::LocalGet(decision));
+            f.instruction(&WasmInst::I32Const(Decision::Error as i32));
+            f.instruction(&WasmInst::I32Eq);
+            f.instruction(&WasmInst::Select);
+        }
+        f.instruction(&WasmInst::End);
+        f
+    }
 
-        assert!(result.is_ok());
+    /// Build `evaluate(json, len)`, which has the host parse the request into
+    /// its slot in the pool, then passes it to the inner `evaluate`
+    fn compile_json_evaluate(&self, inner_evaluate_idx: u32) -> Function {
+        let (json, len) = (0, 1);
+        let mut f = Function::new(vec![]);
+        f.instruction(&WasmInst::LocalGet(json));
+        f.instruction(&WasmInst::LocalGet(len));
+        f.instruction(&WasmInst::I32Const(self.request_start as i32));
+        f.instruction(&WasmInst::Call(runtime_functions::PARSE_REQUEST));
+
+        // A request that doesn't parse is an error
+        f.instruction(&WasmInst::I32Eqz);
+        f.instruction(&WasmInst::If(BlockType::Empty));
+        if self.reports_errors() {
+            f.instruction(&WasmInst::I32Const(1));
+            f.instruction(&WasmInst::GlobalSet(ERROR_GLOBAL));
+        }
+        f.instruction(&WasmInst::I32Const(Decision::Error as i32));
+        f.instruction(&WasmInst::Return);
+        f.instruction(&WasmInst::End);
+
+        // evaluate(principal, action, resource, context)
+        for offset in [0, 4, 8, 12] {
+            self.emit_address(self.request_start, &mut f);
+            f.instruction(&WasmInst::I32Load(MemArg {
+                offset,
+                align: 2,
+                memory_index: 0,
+            }));
+        }
+        f.instruction(&WasmInst::Call(inner_evaluate_idx));
+        f.instruction(&WasmInst::End);
+        f
+    }
+
+    /// Build `evaluate_batch(requests, count, results)`, which evaluates each
+    /// request in turn without resetting the memo in between
+    fn compile_evaluate_batch(&self, inner_evaluate_idx: u32) -> Function {
+        let (requests, count, results) = (0, 1, 2);
+        // Locals: loop counter and address of the current request
+        let (i, request) = (3, 4);
+        let mut f = Function::new(vec![(1, ValType::I32), (1, self.address_type())]);
+
+        self.emit_memo_reset(&mut f);
+
+        f.instruction(&WasmInst::Block(BlockType::Empty));
+        f.instruction(&WasmInst::Loop(BlockType::Empty));
+
+        // while i < count
+        f.instruction(&WasmInst::LocalGet(i));
+        f.instruction(&WasmInst::LocalGet(count));
+        f.instruction(&WasmInst::I32GeU);
+        f.instruction(&WasmInst::BrIf(1));
+
+        // request = requests + i * BATCH_REQUEST_SIZE
+        self.emit_element_address(requests, i, memory::BATCH_REQUEST_SIZE, &mut f);
+        f.instruction(&WasmInst::LocalSet(request));
+
+        // results[i] = evaluate(principal, action, resource, context)
+        self.emit_element_address(results, i, 4, &mut f);
+        for offset in [0, 4, 8, 12] {
+            f.instruction(&WasmInst::LocalGet(request));
+            f.instruction(&WasmInst::I32Load(MemArg {
+                offset,
+                align: 2,
+                memory_index: 0,
+            }));
+        }
+        f.instruction(&WasmInst::Call(inner_evaluate_idx));
+        f.instruction(&WasmInst::I32Store(MemArg {
+            offset: 0,
+            align: 2,
+            memory_index: 0,
+        }));
+
+        // i += 1
+        f.instruction(&WasmInst::LocalGet(i));
+        f.instruction(&WasmInst::I32Const(1));
+        f.instruction(&WasmInst::I32Add);
+        f.instruction(&WasmInst::LocalSet(i));
+        f.instruction(&WasmInst::Br(0));
+
+        f.instruction(&WasmInst::End); // loop
+        f.instruction(&WasmInst::End); // block
+        f.instruction(&WasmInst::End);
+        f
+    }
+
+    /// Build `_initialize`, which copies the string pool from its passive
+    /// segment to [`memory::STRING_POOL_START`]. Calling it again copies the
+    /// pool afresh.
+    fn compile_initialize(&self) -> Function {
+        let mut f = Function::new(vec![]);
+        if !self.pool.is_empty() {
+            self.emit_address(memory::STRING_POOL_START, &mut f);
+            f.instruction(&WasmInst::I32Const(0));
+            f.instruction(&WasmInst::I32Const(self.pool.len() as i32));
+            f.instruction(&WasmInst::MemoryInit {
+                mem: 0,
+                data_index: 0,
+            });
+        }
+        f.instruction(&WasmInst::End);
+        f
+    }
+
+    /// Value type of linear-memory addresses
+    fn address_type(&self) -> ValType {
+        if self.memory64 {
+            ValType::I64
+        } else {
+            ValType::I32
+        }
+    }
+
+    /// Constant expression for a linear-memory address
+    fn address_const(&self, addr: u32) -> ConstExpr {
+        if self.memory64 {
+            ConstExpr::i64_const(addr.into())
+        } else {
+            ConstExpr::i32_const(addr as i32)
+        }
+    }
+
+    /// Push a constant linear-memory address
+    fn emit_address(&self, addr: u32, f: &mut Function) {
+        if self.memory64 {
+            f.instruction(&WasmInst::I64Const(addr.into()));
+        } else {
+            f.instruction(&WasmInst::I32Const(addr as i32));
+        }
+    }
+
+    /// Push the address of element `index` (an i32 local) of an array of
+    /// `size`-byte elements starting at the address in local `base`
+    fn emit_element_address(&self, base: u32, index: u32, size: u32, f: &mut Function) {
+        f.instruction(&WasmInst::LocalGet(base));
+        f.instruction(&WasmInst::LocalGet(index));
+        if self.memory64 {
+            f.instruction(&WasmInst::I64ExtendI32U);
+            f.instruction(&WasmInst::I64Const(size.into()));
+            f.instruction(&WasmInst::I64Mul);
+            f.instruction(&WasmInst::I64Add);
+        } else {
+            f.instruction(&WasmInst::I32Const(size as i32));
+            f.instruction(&WasmInst::I32Mul);
+            f.instruction(&WasmInst::I32Add);
+        }
+    }
+
+    /// Whether the host can report errors through the `error` global. The
+    /// MVP cannot export mutable globals, so there errors must trap.
+    fn reports_errors(&self) -> bool {
+        !self.mvp_only
+    }
+
+    /// Invalidate every memo slot
+    fn emit_memo_reset(&self, f: &mut Function) {
+        for site in 0..self.memo_sites {
+            f.instruction(&WasmInst::I32Const(0));
+            f.instruction(&WasmInst::GlobalSet(memo_slot(site).2));
+        }
+    }
+
+    /// Index of an attribute name in the module's attribute table, interning
+    /// it on first use
+    fn intern_attribute(&mut self, attr: &str) -> Result<u32, String> {
+        let idx = match self.attributes.iter().position(|a| a == attr) {
+            Some(idx) => idx,
+            None => {
+                // Names are length-prefixed with a u32 in the custom section
+                if u32::try_from(attr.len()).is_err() {
+                    return Err(format!(
+                        "Attribute name of {} bytes is too long to store",
+                        attr.len()
+                    ));
+                }
+                self.attributes.push(attr.to_string());
+                self.attributes.len() - 1
+            }
+        };
+        Ok(idx as u32)
+    }
+
+    /// Read an attribute of the entity on top of the stack through the host,
+    /// reusing the last result if this site already fetched it for the same
+    /// entity in the current batch
+    fn emit_memoized_get_attribute(&mut self, attr: u32, checked: bool, f: &mut Function) {
+        let site = self.memo_sites;
+        self.memo_sites += 1;
+        let (key, value, valid) = memo_slot(site);
+        let entity = SCRATCH_LOCAL;
+
+        f.instruction(&WasmInst::LocalSet(entity));
+
+        // valid && key == entity
+        f.instruction(&WasmInst::GlobalGet(valid));
+        f.instruction(&WasmInst::GlobalGet(key));
+        f.instruction(&WasmInst::LocalGet(entity));
+        f.instruction(&WasmInst::I32Eq);
+        f.instruction(&WasmInst::I32And);
+
+        f.instruction(&WasmInst::If(BlockType::Result(ValType::I64)));
+        f.instruction(&WasmInst::GlobalGet(value));
+        f.instruction(&WasmInst::Else);
+        f.instruction(&WasmInst::LocalGet(entity));
+        f.instruction(&WasmInst::I32Const(attr as i32));
+        self.emit_get_attribute_call(checked, f);
+        f.instruction(&WasmInst::GlobalSet(value));
+        f.instruction(&WasmInst::LocalGet(entity));
+        f.instruction(&WasmInst::GlobalSet(key));
+        f.instruction(&WasmInst::I32Const(1));
+        f.instruction(&WasmInst::GlobalSet(valid));
+        f.instruction(&WasmInst::GlobalGet(value));
+        f.instruction(&WasmInst::End);
+    }
+
+    /// Record the i32 condition on top of the stack in the coverage bitmap,
+    /// leaving it in place
+    fn emit_coverage_probe(&mut self, f: &mut Function) {
+        if !self.coverage {
+            return;
+        }
+        let probe = self.coverage_probes;
+        self.coverage_probes += 1;
+
+        f.instruction(&WasmInst::LocalTee(COVERAGE_LOCAL));
+        f.instruction(&WasmInst::If(BlockType::Empty));
+        self.emit_coverage_bit(probe * 2, f);
+        f.instruction(&WasmInst::Else);
+        self.emit_coverage_bit(probe * 2 + 1, f);
+        f.instruction(&WasmInst::End);
+        f.instruction(&WasmInst::LocalGet(COVERAGE_LOCAL));
+    }
+
+    /// Probe the left operand of `&&`/`||`, which decides whether the right
+    /// one matters, from below the right operand on top
+    fn emit_left_operand_probe(&mut self, f: &mut Function) {
+        if self.coverage {
+            f.instruction(&WasmInst::LocalSet(SCRATCH_LOCAL));
+            self.emit_coverage_probe(f);
+            f.instruction(&WasmInst::LocalGet(SCRATCH_LOCAL));
+        }
+    }
+
+    /// Set one bit of the coverage bitmap
+    fn emit_coverage_bit(&self, bit: u32, f: &mut Function) {
+        let addr = self.coverage_start + bit / 8;
+        let byte = MemArg {
+            offset: 0,
+            align: 0,
+            memory_index: 0,
+        };
+        self.emit_address(addr, f);
+        self.emit_address(addr, f);
+        f.instruction(&WasmInst::I32Load8U(byte));
+        f.instruction(&WasmInst::I32Const(1 << (bit % 8)));
+        f.instruction(&WasmInst::I32Or);
+        f.instruction(&WasmInst::I32Store8(byte));
+    }
+
+    /// Report an integer overflow: like a failed host call, the policy
+    /// yields NoDecision, unless overflow is set to trap. A helper always
+    /// sets the error flag, which its caller checks.
+    fn emit_overflow(&mut self, f: &mut Function) {
+        if self.overflow == Overflow::Trap {
+            f.instruction(&WasmInst::Unreachable);
+            return;
+        }
+        if self.reports_errors() || self.helper_result.is_some() {
+            f.instruction(&WasmInst::I32Const(1));
+            f.instruction(&WasmInst::GlobalSet(ERROR_GLOBAL));
+        }
+        self.emit_error_exit(f);
+    }
+
+    /// Leave the function after a failure with the error flag set: a policy
+    /// yields NoDecision, and a helper any value of its type
+    fn emit_error_exit(&mut self, f: &mut Function) {
+        self.may_fail = true;
+        f.instruction(&match self.helper_result {
+            Some(WasmType::Int) => WasmInst::I64Const(0),
+            Some(_) => WasmInst::I32Const(0),
+            None => WasmInst::I32Const(Decision::NoDecision as i32),
+        });
+        f.instruction(&WasmInst::Return);
+    }
+
+    /// Call `get_attribute`, checking the error flag afterwards unless the
+    /// attribute is known to be present
+    fn emit_get_attribute_call(&mut self, checked: bool, f: &mut Function) {
+        if checked {
+            self.emit_host_call(runtime_functions::GET_ATTRIBUTE, f);
+        } else {
+            self.uses_host = true;
+            f.instruction(&WasmInst::Call(runtime_functions::GET_ATTRIBUTE));
+        }
+    }
+
+    /// Call an imported runtime function. If it may fail and sets the error
+    /// flag, the policy yields NoDecision.
+    fn emit_host_call(&mut self, func: u32, f: &mut Function) {
+        self.uses_host = true;
+        f.instruction(&WasmInst::Call(func));
+        if self.reports_errors() && runtime_functions::FALLIBLE.contains(&func) {
+            f.instruction(&WasmInst::GlobalGet(ERROR_GLOBAL));
+            f.instruction(&WasmInst::If(BlockType::Empty));
+            self.emit_error_exit(f);
+            f.instruction(&WasmInst::End);
+        }
+    }
+
+    /// Call a helper on the request this function was called with. If the
+    /// helper can fail, this function fails with it; without an exported
+    /// flag, a policy clears it again.
+    fn emit_helper_call(&mut self, helper: usize, f: &mut Function) {
+        for param in 0..self.entry_signature().params.len() as u32 {
+            f.instruction(&WasmInst::LocalGet(param));
+        }
+        f.instruction(&WasmInst::Call(self.helper_base + helper as u32));
+        if self.helper_fails[helper] {
+            f.instruction(&WasmInst::GlobalGet(ERROR_GLOBAL));
+            f.instruction(&WasmInst::If(BlockType::Empty));
+            if !self.reports_errors() && self.helper_result.is_none() {
+                f.instruction(&WasmInst::I32Const(0));
+                f.instruction(&WasmInst::GlobalSet(ERROR_GLOBAL));
+            }
+            self.emit_error_exit(f);
+            f.instruction(&WasmInst::End);
+        }
+    }
+
+    /// Address of a string literal in the string pool, adding it on first use.
+    /// Each entry is a little-endian u32 byte length followed by the UTF-8
+    /// bytes, aligned to 4 bytes.
+    fn intern_string(&mut self, s: &str) -> Result<u32, String> {
+        if let Some((_, offset)) = self.strings.iter().find(|(existing, _)| existing == s) {
+            return Ok(*offset);
+        }
+        let len = u32::try_from(s.len())
+            .map_err(|_| format!("String literal of {} bytes is too long to store", s.len()))?;
+        let offset = self.reserve((4 + s.len()).next_multiple_of(4))?;
+        self.strings.push((s.to_string(), offset));
+        self.pool.extend_from_slice(&len.to_le_bytes());
+        self.pool.extend_from_slice(s.as_bytes());
+        self.pool.resize(self.pool.len().next_multiple_of(4), 0);
+        Ok(offset)
+    }
+
+    /// Address at which `bytes` more bytes would go in the pool, checking
+    /// that they fit both 32-bit pointers and the memory limit
+    fn reserve(&self, bytes: usize) -> Result<u32, String> {
+        pool_address(self.pool.len(), bytes, self.max_pages)
+    }
+
+    /// Kind and `get_attribute` encoding of a set element or record field,
+    /// interning strings
+    fn encode_constant(&mut self, elem: &SetElement) -> Result<(ValueKind, i64), String> {
+        Ok(match elem {
+            SetElement::Bool(b) => (ValueKind::Bool, *b as i64),
+            SetElement::Int(i) => (ValueKind::Long, *i),
+            SetElement::String(s) => (ValueKind::String, self.intern_string(s)? as i64),
+            SetElement::Entity(idx) => (ValueKind::Entity, *idx as i64),
+        })
+    }
+
+    /// Address of a set literal in the pool, adding it on first use. See
+    /// [`crate::wasm::runtime`] for the layout.
+    fn intern_set(&mut self, elems: &[SetElement]) -> Result<u32, String> {
+        if let Some((_, offset)) = self.sets.iter().find(|(existing, _)| existing == elems) {
+            return Ok(*offset);
+        }
+        // Element strings go first so the set itself is contiguous
+        let encoded = elems
+            .iter()
+            .map(|elem| self.encode_constant(elem))
+            .collect::<Result<Vec<_>, _>>()?;
+        let kind = encoded.first().map_or(ValueKind::Bool, |(kind, _)| *kind);
+
+        let offset = self.reserve(8 + 8 * elems.len())?;
+        self.sets.push((elems.to_vec(), offset));
+        let len = elems.len() as u32;
+        self.pool.extend_from_slice(&len.to_le_bytes());
+        self.pool.extend_from_slice(&(kind as u32).to_le_bytes());
+        for (_, value) in encoded {
+            self.pool.extend_from_slice(&value.to_le_bytes());
+        }
+        Ok(offset)
+    }
+
+    /// Address of a record literal in the pool, adding it on first use. See
+    /// [`crate::wasm::runtime`] for the layout.
+    fn intern_record(&mut self, fields: &[(String, SetElement)]) -> Result<u32, String> {
+        if let Some((_, offset)) = self.records.iter().find(|(existing, _)| existing == fields) {
+            return Ok(*offset);
+        }
+        let encoded = fields
+            .iter()
+            .map(|(name, value)| {
+                let (kind, value) = self.encode_constant(value)?;
+                Ok((self.intern_attribute(name)?, kind, value))
+            })
+            .collect::<Result<Vec<(u32, ValueKind, i64)>, String>>()?;
+
+        let offset = self.reserve(4 + 16 * fields.len())?;
+        self.records.push((fields.to_vec(), offset));
+        let len = fields.len() as u32;
+        self.pool.extend_from_slice(&len.to_le_bytes());
+        for (attr, kind, value) in encoded {
+            self.pool.extend_from_slice(&attr.to_le_bytes());
+            self.pool.extend_from_slice(&(kind as u32).to_le_bytes());
+            self.pool.extend_from_slice(&value.to_le_bytes());
+        }
+        Ok(offset)
+    }
+
+    /// Id of a redacted string, adding it on first use
+    fn intern_secret(&mut self, s: &str) -> u32 {
+        let idx = match self.secrets.iter().position(|existing| existing == s) {
+            Some(idx) => idx,
+            None => {
+                self.secrets.push(s.to_string());
+                self.secrets.len() - 1
+            }
+        };
+        idx as u32
+    }
+
+    /// Compare the two strings on top of the stack with the host
+    fn emit_string_eq(&mut self, operands: &[WasmType], f: &mut Function) {
+        emit_pointer_operands(operands, f);
+        self.emit_host_call(runtime_functions::STRING_EQ, f);
+    }
+
+    /// Compare the two values on top of the stack by their type: sets and
+    /// strings by content with the host, longs (and attribute values of
+    /// unknown type) as i64s, and booleans and entity handles as i32s.
+    /// Handles index the entity table, so equal handles are equal UIDs.
+    fn emit_equal(&mut self, operands: &[WasmType], f: &mut Function) {
+        if operands.contains(&WasmType::Set) {
+            let pointers = |ty: &WasmType| matches!(ty, WasmType::Set | WasmType::Int);
+            if operands.iter().all(pointers) {
+                emit_pointer_operands(operands, f);
+                self.emit_host_call(runtime_functions::SET_EQUAL, f);
+            } else {
+                // A set never equals a value of another type
+                f.instruction(&WasmInst::Drop);
+                f.instruction(&WasmInst::Drop);
+                f.instruction(&WasmInst::I32Const(0));
+            }
+        } else if operands.contains(&WasmType::String) {
+            self.emit_string_eq(operands, f);
+        } else if operands.contains(&WasmType::Int) {
+            emit_wide_operands(operands, f);
+            f.instruction(&WasmInst::I64Eq);
+        } else {
+            f.instruction(&WasmInst::I32Eq);
+        }
+    }
+
+    /// Test whether the set below the top of the stack contains the value
+    /// on top, passed to the host as an i64
+    fn emit_set_contains(&mut self, operands: &[WasmType], f: &mut Function) {
+        if let [set, value] = operands {
+            if *value != WasmType::Int {
+                f.instruction(&WasmInst::I64ExtendI32U);
+            }
+            if *set == WasmType::Int {
+                f.instruction(&WasmInst::LocalSet(SCRATCH_I64_LOCAL));
+                f.instruction(&WasmInst::I32WrapI64);
+                f.instruction(&WasmInst::LocalGet(SCRATCH_I64_LOCAL));
+            }
+        }
+        self.emit_host_call(runtime_functions::SET_CONTAINS, f);
+    }
+
+    /// Pop the types of the operands `inst` consumes, in push order
+    fn pop_operands(&mut self, inst: &Instruction) -> Result<Vec<WasmType>, String> {
+        let (pops, _) = inst.stack_effect();
+        if self.stack.len() < pops {
+            return Err(format!(
+                "{:?} expects {} operands, found {}",
+                inst,
+                pops,
+                self.stack.len()
+            ));
+        }
+        Ok(self.stack.split_off(self.stack.len() - pops))
+    }
+
+    /// Check that a structured branch leaves exactly its declared result. A
+    /// boolean branch may end in an attribute value, which is coerced, and a
+    /// branch whose i64 result was inferred may end in an i32, which is
+    /// widened.
+    fn check_branch_result(&mut self, inst: &Instruction, f: &mut Function) -> Result<(), String> {
+        let Some(&(height, result, inferred)) = self.branches.last() else {
+            return Err(format!("{:?} without a matching If", inst));
+        };
+        if result == Some(WasmType::Bool) && self.stack.get(height..) == Some(&[WasmType::Int]) {
+            emit_to_bool(WasmType::Int, f);
+            self.stack[height] = WasmType::Bool;
+        }
+        if inferred
+            && result == Some(WasmType::Int)
+            && matches!(self.stack.get(height..), Some([ty]) if *ty != WasmType::Int)
+        {
+            f.instruction(&WasmInst::I64ExtendI32U);
+            self.stack[height] = WasmType::Int;
+        }
+        let produced = &self.stack[height.min(self.stack.len())..];
+        let matches = produced.len() == usize::from(result.is_some())
+            && produced
+                .iter()
+                .zip(&result)
+                .all(|(ty, expected)| ty.to_val_type() == expected.to_val_type());
+        if !matches {
+            return Err(format!(
+                "{:?} expects the branch to leave {}, found {}",
+                inst,
+                describe_types(result.as_slice()),
+                describe_types(produced)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Compile a single IR instruction to WASM instructions
+    fn compile_instruction(&mut self, inst: &Instruction, f: &mut Function) -> Result<(), String> {
+        if matches!(inst, Instruction::Else | Instruction::End) {
+            self.check_branch_result(inst, f)?;
+        }
+        // An untyped If takes the type inferred from its branches
+        let inferred = matches!(inst, Instruction::If(None));
+        let typed;
+        let inst = if inferred {
+            typed = Instruction::If(self.inferred.pop().flatten());
+            &typed
+        } else {
+            inst
+        };
+        let operands = self.pop_operands(inst)?;
+        check_operands(inst, &operands)?;
+        // Booleans and entities are i32; only integers need the i64 forms
+        let wide = operands.contains(&WasmType::Int);
+        let strings = operands.contains(&WasmType::String);
+        let sets = operands.contains(&WasmType::Set);
+
+        match inst {
+            // Literals
+            Instruction::PushBool(b) => {
+                f.instruction(&WasmInst::I32Const(if *b { 1 } else { 0 }));
+            }
+            Instruction::PushInt(i)
+            | Instruction::PushDecimal(i)
+            | Instruction::PushDateTime(i) => {
+                f.instruction(&WasmInst::I64Const(*i));
+            }
+            Instruction::PushString(s) => {
+                let ptr = self.intern_string(s)?;
+                f.instruction(&WasmInst::I32Const(ptr as i32));
+            }
+            Instruction::PushSecret(s) => {
+                let id = self.intern_secret(s);
+                f.instruction(&WasmInst::I32Const(id as i32));
+                self.emit_host_call(runtime_functions::RESOLVE_SECRET, f);
+            }
+            Instruction::LoadConstEntity(idx) => {
+                f.instruction(&WasmInst::I32Const(*idx as i32));
+            }
+            Instruction::PushSet(elems) => {
+                let ptr = self.intern_set(elems)?;
+                f.instruction(&WasmInst::I32Const(ptr as i32));
+            }
+            Instruction::PushRecord(fields) => {
+                let ptr = self.intern_record(fields)?;
+                f.instruction(&WasmInst::I32Const(ptr as i32));
+            }
+
+            // Request inputs are the leading function parameters
+            Instruction::LoadVar(var) => {
+                f.instruction(&WasmInst::LocalGet(*var as u32));
+            }
+            Instruction::SlotPrincipal | Instruction::SlotResource if !self.slots => {
+                return Err("Template slot read in a module without slots".to_string());
+            }
+            Instruction::SlotPrincipal => {
+                f.instruction(&WasmInst::LocalGet(PRINCIPAL_SLOT_PARAM));
+            }
+            Instruction::SlotResource => {
+                f.instruction(&WasmInst::LocalGet(RESOURCE_SLOT_PARAM));
+            }
+
+            // Comparison operations
+            Instruction::Equal => self.emit_equal(&operands, f),
+            Instruction::NotEqual if strings || sets => {
+                self.emit_equal(&operands, f);
+                f.instruction(&WasmInst::I32Eqz);
+            }
+            Instruction::NotEqual => {
+                emit_wide_operands(&operands, f);
+                f.instruction(&if wide { WasmInst::I64Ne } else { WasmInst::I32Ne });
+            }
+            // Ordering is only defined on integers (i64)
+            Instruction::LessThan => {
+                f.instruction(&WasmInst::I64LtS); // Signed less than
+            }
+            Instruction::LessThanOrEqual => {
+                f.instruction(&WasmInst::I64LeS);
+            }
+            Instruction::GreaterThan => {
+                f.instruction(&WasmInst::I64GtS);
+            }
+            Instruction::GreaterThanOrEqual => {
+                f.instruction(&WasmInst::I64GeS);
+            }
+
+            // Logical operations (on i32 booleans)
+            Instruction::And => {
+                emit_bool_operands(&operands, f);
+                self.emit_left_operand_probe(f);
+                f.instruction(&WasmInst::I32And);
+            }
+            Instruction::Or => {
+                emit_bool_operands(&operands, f);
+                self.emit_left_operand_probe(f);
+                f.instruction(&WasmInst::I32Or);
+            }
+            Instruction::Not => {
+                emit_bool_operands(&operands, f);
+                f.instruction(&WasmInst::I32Eqz); // Logical not: x == 0
+            }
+
+            // Arithmetic
+            Instruction::Neg if self.overflow == Overflow::Saturate => {
+                // -i64::MIN saturates to i64::MAX
+                f.instruction(&WasmInst::LocalSet(SCRATCH_I64_LOCAL));
+                f.instruction(&WasmInst::I64Const(i64::MAX));
+                f.instruction(&WasmInst::I64Const(0));
+                f.instruction(&WasmInst::LocalGet(SCRATCH_I64_LOCAL));
+                f.instruction(&WasmInst::I64Sub);
+                f.instruction(&WasmInst::LocalGet(SCRATCH_I64_LOCAL));
+                f.instruction(&WasmInst::I64Const(i64::MIN));
+                f.instruction(&WasmInst::I64Eq);
+                f.instruction(&WasmInst::Select);
+            }
+            Instruction::Neg => {
+                f.instruction(&WasmInst::LocalTee(SCRATCH_I64_LOCAL));
+                f.instruction(&WasmInst::I64Const(i64::MIN));
+                f.instruction(&WasmInst::I64Eq);
+                f.instruction(&WasmInst::If(BlockType::Empty));
+                self.emit_overflow(f);
+                f.instruction(&WasmInst::End);
+                f.instruction(&WasmInst::I64Const(0));
+                f.instruction(&WasmInst::LocalGet(SCRATCH_I64_LOCAL));
+                f.instruction(&WasmInst::I64Sub);
+            }
+
+            // Control flow
+            Instruction::IfThenElse => {
+                // Stack at IfThenElse: [then_value, else_value, condition (i32)],
+                // exactly the operands WASM select expects
+                emit_to_bool(operands[2], f);
+                self.emit_coverage_probe(f);
+                f.instruction(&WasmInst::Select);
+            }
+
+            Instruction::Return | Instruction::Error(_) if self.helper_result.is_some() => {
+                return Err(format!("{:?} in a helper, which returns a value", inst));
+            }
+            Instruction::Return => {
+                f.instruction(&WasmInst::Return);
+            }
+            Instruction::Call(helper) => self.emit_helper_call(*helper, f),
+            Instruction::Error(_) => {
+                if self.reports_errors() {
+                    f.instruction(&WasmInst::I32Const(1));
+                    f.instruction(&WasmInst::GlobalSet(ERROR_GLOBAL));
+                }
+                f.instruction(&WasmInst::I32Const(Decision::Error as i32));
+                f.instruction(&WasmInst::Return);
+            }
+            Instruction::If(result) => {
+                emit_to_bool(operands[0], f);
+                self.emit_coverage_probe(f);
+                let block = match result {
+                    Some(ty) => BlockType::Result(ty.to_val_type()),
+                    None => BlockType::Empty,
+                };
+                f.instruction(&WasmInst::If(block));
+            }
+            Instruction::Else => {
+                f.instruction(&WasmInst::Else);
+            }
+            Instruction::End => {
+                f.instruction(&WasmInst::End);
+            }
+
+            // Policy decisions
+            Instruction::Permit => {
+                f.instruction(&WasmInst::I32Const(Decision::Permit as i32));
+            }
+            Instruction::Forbid => {
+                f.instruction(&WasmInst::I32Const(Decision::Deny as i32));
+            }
+            Instruction::NoDecision => {
+                f.instruction(&WasmInst::I32Const(Decision::NoDecision as i32));
+            }
+
+            // Entity operations call into the host (imported runtime functions)
+            Instruction::GetAttribute(attr) | Instruction::GetPresentAttribute(attr) => {
+                // An entity read from an attribute is a handle in an i64
+                if wide {
+                    f.instruction(&WasmInst::I32WrapI64);
+                }
+                let checked = matches!(inst, Instruction::GetAttribute(_));
+                let attr = self.intern_attribute(attr)?;
+                if self.batch_evaluation {
+                    self.emit_memoized_get_attribute(attr, checked, f);
+                } else {
+                    f.instruction(&WasmInst::I32Const(attr as i32));
+                    self.emit_get_attribute_call(checked, f);
+                }
+            }
+            Instruction::HasAttribute(attr) => {
+                if wide {
+                    f.instruction(&WasmInst::I32WrapI64);
+                }
+                let attr = self.intern_attribute(attr)?;
+                f.instruction(&WasmInst::I32Const(attr as i32));
+                self.emit_host_call(runtime_functions::HAS_ATTRIBUTE, f);
+            }
+            Instruction::TeeLocal(idx) => {
+                f.instruction(&WasmInst::LocalTee(CACHE_LOCAL_START + idx));
+            }
+            Instruction::GetLocal(idx) => {
+                f.instruction(&WasmInst::LocalGet(CACHE_LOCAL_START + idx));
+            }
+            // The copy is taken through a scratch local of the value's type
+            Instruction::Dup => {
+                let scratch = if wide {
+                    SCRATCH_I64_LOCAL
+                } else {
+                    SCRATCH_LOCAL
+                };
+                f.instruction(&WasmInst::LocalTee(scratch));
+                f.instruction(&WasmInst::LocalGet(scratch));
+            }
+            // The top goes to a scratch local and the value below it to a
+            // swap local, each of its own type, and both are read back
+            Instruction::Swap => {
+                let top = match operands[1] {
+                    WasmType::Int => SCRATCH_I64_LOCAL,
+                    _ => SCRATCH_LOCAL,
+                };
+                let lower = match operands[0] {
+                    WasmType::Int => self.swap_locals + 1,
+                    _ => self.swap_locals,
+                };
+                f.instruction(&WasmInst::LocalSet(top));
+                f.instruction(&WasmInst::LocalSet(lower));
+                f.instruction(&WasmInst::LocalGet(top));
+                f.instruction(&WasmInst::LocalGet(lower));
+            }
+            Instruction::Drop => {
+                f.instruction(&WasmInst::Drop);
+            }
+            // Attribute values arrive as i64; the schema says what they hold
+            Instruction::Narrow(ty) => match (operands[0], *ty) {
+                (from, to) if from == to => {}
+                (WasmType::Int, WasmType::Bool) => emit_to_bool(WasmType::Int, f),
+                (WasmType::Int, _) => {
+                    f.instruction(&WasmInst::I32WrapI64);
+                }
+                (from, to) => return Err(format!("Cannot narrow {:?} to {:?}", from, to)),
+            },
+            Instruction::In => {
+                emit_pointer_operands(&operands, f);
+                self.emit_host_call(runtime_functions::ENTITY_IN, f);
+            }
+            Instruction::IsEntityType(type_name) => {
+                if wide {
+                    f.instruction(&WasmInst::I32WrapI64);
+                }
+                let ptr = self.intern_string(type_name)?;
+                f.instruction(&WasmInst::I32Const(ptr as i32));
+                self.emit_host_call(runtime_functions::ENTITY_IS, f);
+            }
+            Instruction::Like(pattern) => {
+                // An attribute value carries the string pointer in an i64
+                if wide {
+                    f.instruction(&WasmInst::I32WrapI64);
+                }
+                let ptr = self.intern_string(pattern)?;
+                f.instruction(&WasmInst::I32Const(ptr as i32));
+                self.emit_host_call(runtime_functions::STRING_LIKE, f);
+            }
+            Instruction::LikeSecret(pattern) => {
+                if wide {
+                    f.instruction(&WasmInst::I32WrapI64);
+                }
+                let id = self.intern_secret(pattern);
+                f.instruction(&WasmInst::I32Const(id as i32));
+                self.emit_host_call(runtime_functions::RESOLVE_SECRET, f);
+                self.emit_host_call(runtime_functions::STRING_LIKE, f);
+            }
+            Instruction::Contains => self.emit_set_contains(&operands, f),
+            Instruction::ContainsAll => {
+                emit_pointer_operands(&operands, f);
+                self.emit_host_call(runtime_functions::SET_CONTAINS_ALL, f);
+            }
+            Instruction::ContainsAny => {
+                emit_pointer_operands(&operands, f);
+                self.emit_host_call(runtime_functions::SET_CONTAINS_ANY, f);
+            }
+            Instruction::Ip => {
+                if wide {
+                    f.instruction(&WasmInst::I32WrapI64);
+                }
+                self.emit_host_call(runtime_functions::IP, f);
+            }
+            Instruction::IsInRange => {
+                emit_pointer_operands(&operands, f);
+                self.emit_host_call(runtime_functions::IP_IS_IN_RANGE, f);
+            }
+            Instruction::IsIpv4
+            | Instruction::IsIpv6
+            | Instruction::IsLoopback
+            | Instruction::IsMulticast => {
+                if wide {
+                    f.instruction(&WasmInst::I32WrapI64);
+                }
+                let func = match inst {
+                    Instruction::IsIpv4 => runtime_functions::IP_IS_IPV4,
+                    Instruction::IsIpv6 => runtime_functions::IP_IS_IPV6,
+                    Instruction::IsLoopback => runtime_functions::IP_IS_LOOPBACK,
+                    _ => runtime_functions::IP_IS_MULTICAST,
+                };
+                self.emit_host_call(func, f);
+            }
+        }
+
+        match inst {
+            Instruction::If(result) => {
+                self.branches.push((self.stack.len(), *result, inferred));
+            }
+            Instruction::Else => {
+                if let Some(&(height, ..)) = self.branches.last() {
+                    self.stack.truncate(height);
+                }
+            }
+            Instruction::End => {
+                if let Some((height, result, _)) = self.branches.pop() {
+                    self.stack.truncate(height);
+                    self.stack.extend(result);
+                }
+            }
+            Instruction::Call(helper) => self.stack.push(self.helper_types[*helper]),
+            _ => self.stack.extend(result_types(inst, &operands)),
+        }
+
+        Ok(())
+    }
+}
+
+/// Build `evaluate(principal, action, resource, context)` for a template
+/// whose slots are bound to the entity handles `(principal, resource)`
+fn compile_bound_evaluate(
+    template_evaluate_idx: u32,
+    (principal, resource): (u32, u32),
+) -> Function {
+    let mut f = Function::new(vec![]);
+    for param in 0..PARAM_NAMES.len() as u32 {
+        f.instruction(&WasmInst::LocalGet(param));
+    }
+    f.instruction(&WasmInst::I32Const(principal as i32));
+    f.instruction(&WasmInst::I32Const(resource as i32));
+    f.instruction(&WasmInst::Call(template_evaluate_idx));
+    f.instruction(&WasmInst::End);
+    f
+}
+
+/// Globals of a memoized attribute read: entity key, value, and whether
+/// the slot is valid
+fn memo_slot(site: u32) -> (u32, u32, u32) {
+    let key = ERROR_GLOBAL + 1 + site * 3;
+    (key, key + 1, key + 2)
+}
+
+/// Whether coverage instrumentation probes the condition of `inst`
+fn is_coverage_probe(inst: &Instruction) -> bool {
+    matches!(
+        inst,
+        Instruction::If(_) | Instruction::IfThenElse | Instruction::And | Instruction::Or
+    )
+}
+
+/// Address of `bytes` more bytes after a pool of `pool_len` bytes. Pool
+/// pointers are 32-bit even in 64-bit memory, and the whole pool must fit
+/// in `max_pages`.
+fn pool_address(pool_len: usize, bytes: usize, max_pages: u32) -> Result<u32, String> {
+    let offset = u64::from(memory::STRING_POOL_START) + pool_len as u64;
+    let end = offset + bytes as u64;
+    if end > 1 << 32 {
+        return Err(format!(
+            "String pool needs {} bytes, more than 32-bit pointers can address",
+            pool_len as u64 + bytes as u64
+        ));
+    }
+    let pages = end.div_ceil(u64::from(PAGE_SIZE));
+    if pages > u64::from(max_pages) {
+        return Err(format!(
+            "String pool needs {} bytes, which takes {} pages of memory, over the limit of {}",
+            pool_len as u64 + bytes as u64,
+            pages,
+            max_pages
+        ));
+    }
+    Ok(offset as u32)
+}
+
+/// Index of a function type, adding it if it is new
+fn type_index(types: &mut Vec<FuncType>, params: Vec<ValType>, results: Vec<ValType>) -> u32 {
+    let ty = (params, results);
+    match types.iter().position(|t| *t == ty) {
+        Some(idx) => idx as u32,
+        None => {
+            types.push(ty);
+            types.len() as u32 - 1
+        }
+    }
+}
+
+/// Normalize the value on top of the stack to an i32 boolean. Attribute
+/// values arrive as i64, where booleans are 0 or 1.
+fn emit_to_bool(ty: WasmType, f: &mut Function) {
+    if ty == WasmType::Int {
+        f.instruction(&WasmInst::I64Const(0));
+        f.instruction(&WasmInst::I64Ne);
+    }
+}
+
+/// Turn i64 operands into i32 pointers or handles. Attribute values arrive
+/// as i64, where strings, sets and entities are zero-extended.
+fn emit_pointer_operands(operands: &[WasmType], f: &mut Function) {
+    if let [lower, top] = operands {
+        if *top == WasmType::Int {
+            f.instruction(&WasmInst::I32WrapI64);
+        }
+        if *lower == WasmType::Int {
+            // Set the (now i32) top aside to reach the operand below it
+            f.instruction(&WasmInst::LocalSet(SCRATCH_LOCAL));
+            f.instruction(&WasmInst::I32WrapI64);
+            f.instruction(&WasmInst::LocalGet(SCRATCH_LOCAL));
+        }
+    }
+}
+
+/// Zero-extend the i32 operand of a comparison with an i64 attribute value,
+/// matching the `get_attribute` encoding
+fn emit_wide_operands(operands: &[WasmType], f: &mut Function) {
+    if let [lower, top] = operands {
+        match (*lower == WasmType::Int, *top == WasmType::Int) {
+            (true, false) => {
+                f.instruction(&WasmInst::I64ExtendI32U);
+            }
+            (false, true) => {
+                f.instruction(&WasmInst::LocalSet(SCRATCH_I64_LOCAL));
+                f.instruction(&WasmInst::I64ExtendI32U);
+                f.instruction(&WasmInst::LocalGet(SCRATCH_I64_LOCAL));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reject operands of a type no coercion can turn into what `inst` needs,
+/// so malformed IR fails here rather than when the module is validated
+pub(crate) fn check_operands(inst: &Instruction, operands: &[WasmType]) -> Result<(), String> {
+    use Instruction::*;
+    use WasmType::*;
+
+    // Attribute values are i64s that may hold any kind of value
+    let all = |kinds: &[WasmType]| operands.iter().all(|ty| *ty == Int || kinds.contains(ty));
+    let (ok, expected) = match inst {
+        LessThan | LessThanOrEqual | GreaterThan | GreaterThanOrEqual => {
+            (operands.iter().all(|ty| *ty == Int), "two i64 operands")
+        }
+        Neg => (operands == [Int], "an i64 operand"),
+        And | Or => (all(&[Bool]), "two boolean operands"),
+        Not | If(_) => (all(&[Bool]), "a boolean operand"),
+        IfThenElse => match operands {
+            [then_value, else_value, condition] => {
+                let same = then_value.to_val_type() == else_value.to_val_type();
+                let ok = same && matches!(condition, Bool | Int);
+                (ok, "two values of the same type and a boolean")
+            }
+            _ => (false, "three operands"),
+        },
+        Return => (operands == [Bool], "an i32 decision"),
+        In => (all(&[Entity]), "two entity operands"),
+        GetAttribute(_) | GetPresentAttribute(_) | HasAttribute(_) => {
+            (all(&[Entity, Record]), "an entity or record")
+        }
+        TeeLocal(_) => (operands == [Int], "an attribute value (i64)"),
+        IsEntityType(_) => (all(&[Entity]), "an entity operand"),
+        Like(_) | LikeSecret(_) => (all(&[String]), "a string operand"),
+        Contains => (
+            operands.first().is_some_and(|ty| matches!(ty, Set | Int)),
+            "a set and a value",
+        ),
+        ContainsAll | ContainsAny => (all(&[Set]), "two set operands"),
+        Instruction::Ip => (all(&[String]), "a string operand"),
+        IsInRange => (all(&[WasmType::Ip]), "two IP operands"),
+        IsIpv4 | IsIpv6 | IsLoopback | IsMulticast => (all(&[WasmType::Ip]), "an IP operand"),
+        _ => (true, ""),
+    };
+
+    if ok {
+        Ok(())
+    } else {
+        Err(format!(
+            "{:?} expects {}, found {}",
+            inst,
+            expected,
+            describe_types(operands)
+        ))
+    }
+}
+
+/// Infer the result type of every untyped `If` in `instructions`, in
+/// order, by following the operand types through its branches: the type
+/// both leave, unified by [`unify_branches`], or `None` if they leave
+/// nothing. Also returns the types left on the stack at the end. Calls
+/// push the type of the helper in `helpers`, which must be there; branches
+/// that don't match are left to the code generator to report.
+fn infer_branch_types(
+    instructions: &[Instruction],
+    helpers: &[WasmType],
+) -> Result<(Vec<Option<WasmType>>, Vec<WasmType>), String> {
+    /// An open branch while following the stack
+    struct Branch {
+        /// Stack height at entry
+        height: usize,
+        declared: Option<WasmType>,
+        /// Index of the inferred type, if untyped
+        slot: Option<usize>,
+        /// What the then arm left
+        then_arm: Vec<WasmType>,
+    }
+
+    let mut inferred = Vec::new();
+    let mut stack: Vec<WasmType> = Vec::new();
+    let mut branches: Vec<Branch> = Vec::new();
+
+    for inst in instructions {
+        match inst {
+            Instruction::If(result) => {
+                stack.pop();
+                let slot = result.is_none().then(|| {
+                    inferred.push(None);
+                    inferred.len() - 1
+                });
+                branches.push(Branch {
+                    height: stack.len(),
+                    declared: *result,
+                    slot,
+                    then_arm: Vec::new(),
+                });
+            }
+            Instruction::Else => {
+                if let Some(branch) = branches.last_mut() {
+                    branch.then_arm = stack.split_off(branch.height.min(stack.len()));
+                }
+            }
+            Instruction::End => {
+                let Some(branch) = branches.pop() else {
+                    break;
+                };
+                let else_arm = stack.split_off(branch.height.min(stack.len()));
+                let mut result = branch.declared;
+                if let Some(slot) = branch.slot {
+                    result = match (branch.then_arm.as_slice(), else_arm.as_slice()) {
+                        ([then_ty], [else_ty]) => Some(unify_branches(*then_ty, *else_ty)?),
+                        _ => None,
+                    };
+                    inferred[slot] = result;
+                }
+                stack.extend(result);
+            }
+            Instruction::Call(helper) => match helpers.get(*helper) {
+                Some(ty) => stack.push(*ty),
+                None => return Err(format!("Call to helper {} before it is defined", helper)),
+            },
+            _ => {
+                let (pops, _) = inst.stack_effect();
+                let operands = stack.split_off(stack.len().saturating_sub(pops));
+                stack.extend(result_types(inst, &operands));
+            }
+        }
+    }
+    Ok((inferred, stack))
+}
+
+/// Type of an `If` whose branches leave `then_ty` and `else_ty`: their
+/// common type, or an i64 if either is one, which holds any other value in
+/// the `get_attribute` encoding. Two different i32 types only meet in an
+/// ill-typed policy, or one whose attribute types aren't known from a schema.
+pub(crate) fn unify_branches(then_ty: WasmType, else_ty: WasmType) -> Result<WasmType, String> {
+    if then_ty == else_ty {
+        Ok(then_ty)
+    } else if then_ty == WasmType::Int || else_ty == WasmType::Int {
+        Ok(WasmType::Int)
+    } else {
+        Err(format!(
+            "If branches leave incompatible types {}",
+            describe_types(&[then_ty, else_ty])
+        ))
+    }
+}
+
+/// Operand types for error messages, e.g. `Entity (i32) and Int (i64)`
+fn describe_types(types: &[WasmType]) -> String {
+    if types.is_empty() {
+        return "nothing".to_string();
+    }
+    types
+        .iter()
+        .map(|ty| {
+            let width = if *ty == WasmType::Int { "i64" } else { "i32" };
+            format!("{:?} ({})", ty, width)
+        })
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
+
+/// Normalize the operands of a logical operator to i32 booleans
+fn emit_bool_operands(operands: &[WasmType], f: &mut Function) {
+    match operands {
+        [lower, top] => {
+            emit_to_bool(*top, f);
+            if *lower == WasmType::Int {
+                // Set the (now i32) top aside to reach the operand below it
+                f.instruction(&WasmInst::LocalSet(SCRATCH_LOCAL));
+                emit_to_bool(*lower, f);
+                f.instruction(&WasmInst::LocalGet(SCRATCH_LOCAL));
+            }
+        }
+        [arg] => emit_to_bool(*arg, f),
+        _ => {}
+    }
+}
+
+/// Types of the values a non-branching instruction leaves on the stack
+fn result_types(inst: &Instruction, operands: &[WasmType]) -> Vec<WasmType> {
+    match inst {
+        Instruction::Dup => operands.repeat(2),
+        Instruction::Swap => operands.iter().rev().copied().collect(),
+        _ => result_type(inst, operands).into_iter().collect(),
+    }
+}
+
+/// Type of the value a non-branching instruction leaves on the stack; for
+/// `Dup`, the type of either copy, and for `Swap`, the type of the new top
+pub(crate) fn result_type(inst: &Instruction, operands: &[WasmType]) -> Option<WasmType> {
+    use Instruction::*;
+
+    match inst {
+        PushInt(_)
+        | PushDecimal(_)
+        | PushDateTime(_)
+        | GetAttribute(_)
+        | GetPresentAttribute(_)
+        | Neg => Some(WasmType::Int),
+        Narrow(ty) => Some(*ty),
+        GetLocal(_) => Some(WasmType::Int),
+        Error(ty) => Some(*ty),
+        TeeLocal(_) | Dup | Swap => operands.first().copied(),
+        PushString(_) | PushSecret(_) => Some(WasmType::String),
+        PushSet(_) => Some(WasmType::Set),
+        PushRecord(_) => Some(WasmType::Record),
+        Ip => Some(WasmType::Ip),
+        LoadVar(RequestVar::Context) => Some(WasmType::Record),
+        LoadConstEntity(_) | LoadVar(_) | SlotPrincipal | SlotResource => Some(WasmType::Entity),
+        // Both select operands have the same type
+        IfThenElse => operands.first().copied(),
+        Return | Drop | If(_) | Else | End => None,
+        // Booleans and decisions
+        _ => Some(WasmType::Bool),
+    }
+}
+
+/// Signature of an imported runtime function, as documented in
+/// [`runtime_functions`]
+fn runtime_signature(func: u32) -> FuncType {
+    use runtime_functions::*;
+    use ValType::{I32, I64};
+
+    match func {
+        STRING_EQ => (vec![I32, I32], vec![I32]),
+        GET_ATTRIBUTE => (vec![I32, I32], vec![I64]),
+        HAS_ATTRIBUTE => (vec![I32, I32], vec![I32]),
+        ENTITY_IN => (vec![I32, I32], vec![I32]),
+        RESOLVE_SECRET => (vec![I32], vec![I32]),
+        STRING_LIKE => (vec![I32, I32], vec![I32]),
+        SET_CONTAINS => (vec![I32, I64], vec![I32]),
+        SET_CONTAINS_ALL => (vec![I32, I32], vec![I32]),
+        SET_CONTAINS_ANY => (vec![I32, I32], vec![I32]),
+        ENTITY_IS => (vec![I32, I32], vec![I32]),
+        IP => (vec![I32], vec![I32]),
+        IP_IS_IN_RANGE => (vec![I32, I32], vec![I32]),
+        IP_IS_IPV4 | IP_IS_IPV6 | IP_IS_LOOPBACK | IP_IS_MULTICAST => (vec![I32], vec![I32]),
+        SET_EQUAL => (vec![I32, I32], vec![I32]),
+        PARSE_REQUEST => (vec![I32, I32, I32], vec![I32]),
+        _ => unreachable!("unknown runtime function {func}"),
+    }
+}
+
+impl Default for WasmCodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::lowering::LoweredFunction;
+
+    #[test]
+    fn test_simple_permit_codegen() {
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions: vec![
+                    Instruction::Permit,
+                    Instruction::Return,
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut codegen = WasmCodeGen::new();
+        let result = codegen.generate(&module);
+
+        assert!(result.is_ok());
+        let wasm_bytes = result.unwrap();
+        assert!(!wasm_bytes.is_empty());
+
+        // Verify it's valid WASM (starts with magic number)
+        assert_eq!(&wasm_bytes[0..4], b"\0asm");
+    }
+
+    #[test]
+    fn test_generate_is_repeatable() {
+        let policy_set = crate::ast::adapter::parse_policy_set(
+            r#"permit(principal == User::"alice", action, resource) when { resource.owner == "alice" };
+            forbid(principal, action, resource) when { context.level < 3 };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_policy_set(&policy_set).unwrap();
+
+        let mut codegen = WasmCodeGen::new();
+        let first = codegen.generate(&module).unwrap();
+        let second = codegen.generate(&module).unwrap();
+
+        wasmparser::validate(&first).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_boolean_logic_codegen() {
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions: vec![
+                    Instruction::PushBool(true),
+                    Instruction::PushBool(false),
+                    Instruction::And,
+                    Instruction::Return,
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut codegen = WasmCodeGen::new();
+        let result = codegen.generate(&module);
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_logic_on_comparisons_and_attributes() {
+        use Instruction::*;
+
+        // `principal.age > 18 && resource.public` without the branches
+        // lowering adds: an i32 comparison next to an i64 attribute value
+        let comparison = [
+            LoadVar(RequestVar::Principal),
+            GetAttribute("age".to_string()),
+            PushInt(18),
+            GreaterThan,
+        ];
+        let attribute = [
+            LoadVar(RequestVar::Resource),
+            GetAttribute("public".to_string()),
+        ];
+        let cases = [
+            [&comparison[..], &attribute[..], &[And]].concat(),
+            [&attribute[..], &comparison[..], &[Or]].concat(),
+            [&attribute[..], &[Not], &comparison[..], &[And, Not]].concat(),
+        ];
+        for condition in cases {
+            let instructions =
+                [&[Permit, NoDecision], &condition[..], &[IfThenElse, Return]].concat();
+            let module = LoweredModule {
+                policies: vec![LoweredFunction {
+                    instructions,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+
+            let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+            wasmparser::validate(&wasm_bytes).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_swap_and_drop() {
+        use Instruction::*;
+
+        let evaluate = |instructions: Vec<Instruction>| {
+            let module = LoweredModule {
+                policies: vec![LoweredFunction {
+                    instructions,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+            wasmparser::validate(&wasm_bytes).unwrap();
+
+            let engine = wasmtime::Engine::default();
+            let module = wasmtime::Module::new(&engine, &wasm_bytes).unwrap();
+            let mut store = wasmtime::Store::new(&engine, ());
+            let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+            instance
+                .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+                .unwrap()
+                .call(&mut store, (0, 0, 0, 0))
+                .unwrap()
+        };
+        let select = |setup: &[Instruction]| {
+            let instructions = [&[Permit, NoDecision], setup, &[IfThenElse, Return]].concat();
+            evaluate(instructions)
+        };
+
+        // Swapped into select order
+        let decision = evaluate(vec![
+            NoDecision,
+            Permit,
+            Swap,
+            PushBool(true),
+            IfThenElse,
+            Return,
+        ]);
+        assert_eq!(decision, Decision::Permit as i32);
+
+        // Two i64s: 2 > 1 rather than 1 > 2
+        let decision = select(&[PushInt(1), PushInt(2), Swap, GreaterThan]);
+        assert_eq!(decision, Decision::Permit as i32);
+
+        // An i64 below an i32, and the i64 dropped once on top
+        let decision = select(&[PushInt(0), PushBool(true), Swap, Drop]);
+        assert_eq!(decision, Decision::Permit as i32);
+        let decision = select(&[PushBool(false), PushBool(true), Drop]);
+        assert_eq!(decision, Decision::NoDecision as i32);
+    }
+
+    #[test]
+    fn test_overflow_modes() {
+        use wasmparser::{Operator, Parser, Payload};
+        use Instruction::*;
+
+        // Permit if -i64::MIN == i64::MAX, which only saturation makes hold
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions: vec![
+                    Permit,
+                    NoDecision,
+                    PushInt(i64::MIN),
+                    Neg,
+                    PushInt(i64::MAX),
+                    Equal,
+                    IfThenElse,
+                    Return,
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let compile = |overflow: Overflow| {
+            let wasm_bytes = WasmCodeGen::new()
+                .with_overflow(overflow)
+                .generate(&module)
+                .unwrap();
+            wasmparser::validate(&wasm_bytes).unwrap();
+
+            let engine = wasmtime::Engine::default();
+            let module = wasmtime::Module::new(&engine, &wasm_bytes).unwrap();
+            let mut store = wasmtime::Store::new(&engine, ());
+            let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+            let decision = instance
+                .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+                .unwrap()
+                .call(&mut store, (0, 0, 0, 0));
+            (wasm_bytes, decision)
+        };
+        // The negation, after it sets its operand aside
+        fn negation(wasm_bytes: &[u8]) -> Vec<Operator<'_>> {
+            let ops = Parser::new(0)
+                .parse_all(wasm_bytes)
+                .find_map(|payload| match payload.unwrap() {
+                    Payload::CodeSectionEntry(body) => Some(body),
+                    _ => None,
+                })
+                .unwrap()
+                .get_operators_reader()
+                .unwrap()
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let start = ops
+                .iter()
+                .position(|op| matches!(op, Operator::LocalTee { .. } | Operator::LocalSet { .. }))
+                .unwrap();
+            ops[start + 1..].to_vec()
+        }
+
+        // An evaluation error: the policy yields NoDecision
+        let (wasm_bytes, decision) = compile(Overflow::Error);
+        assert!(matches!(
+            negation(&wasm_bytes)[..7],
+            [
+                Operator::I64Const { value: i64::MIN },
+                Operator::I64Eq,
+                Operator::If { .. },
+                Operator::I32Const { value: 1 },
+                Operator::GlobalSet { global_index: ERROR_GLOBAL },
+                Operator::I32Const { value },
+                Operator::Return,
+            ] if value == Decision::NoDecision as i32
+        ));
+        assert_eq!(decision.unwrap(), Decision::NoDecision as i32);
+
+        // The same check, trapping instead
+        let (wasm_bytes, decision) = compile(Overflow::Trap);
+        assert!(matches!(
+            negation(&wasm_bytes)[..5],
+            [
+                Operator::I64Const { value: i64::MIN },
+                Operator::I64Eq,
+                Operator::If { .. },
+                Operator::Unreachable,
+                Operator::End,
+            ]
+        ));
+        assert!(decision.is_err());
+
+        // Clamped with a select, without a branch
+        let (wasm_bytes, decision) = compile(Overflow::Saturate);
+        let ops = negation(&wasm_bytes);
+        assert!(matches!(
+            ops[..8],
+            [
+                Operator::I64Const { value: i64::MAX },
+                Operator::I64Const { value: 0 },
+                Operator::LocalGet { .. },
+                Operator::I64Sub,
+                Operator::LocalGet { .. },
+                Operator::I64Const { value: i64::MIN },
+                Operator::I64Eq,
+                Operator::Select,
+            ]
+        ));
+        assert!(!ops.iter().any(|op| matches!(op, Operator::If { .. })));
+        assert_eq!(decision.unwrap(), Decision::Permit as i32);
+    }
+
+    #[test]
+    fn test_helpers_are_called() {
+        use Instruction::*;
+
+        let function = |instructions: Vec<Instruction>| LoweredFunction {
+            instructions,
+            ..Default::default()
+        };
+        // The policy permits if helper 1 holds, which calls helper 0
+        let module = |helpers: Vec<Vec<Instruction>>| LoweredModule {
+            policies: vec![function(vec![
+                NoDecision,
+                Permit,
+                Swap,
+                Call(1),
+                IfThenElse,
+                Return,
+            ])],
+            helpers: helpers.into_iter().map(function).collect(),
+            ..Default::default()
+        };
+        // Helper 0 negates `value`, and helper 1 checks the result is positive
+        let negation = |value| {
+            module(vec![
+                vec![PushInt(value), Neg],
+                vec![Call(0), PushInt(0), GreaterThan],
+            ])
+        };
+
+        let run = |module: &LoweredModule, mvp_only| {
+            let wasm_bytes = WasmCodeGen::new()
+                .with_mvp_only(mvp_only)
+                .with_validation(true)
+                .generate(module)
+                .unwrap();
+            let engine = wasmtime::Engine::default();
+            let module = wasmtime::Module::new(&engine, &wasm_bytes).unwrap();
+            let mut store = wasmtime::Store::new(&engine, ());
+            let mut linker = wasmtime::Linker::new(&engine);
+            linker.define_unknown_imports_as_traps(&module).unwrap();
+            let instance = linker.instantiate(&mut store, &module).unwrap();
+            let decision = instance
+                .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "evaluate")
+                .unwrap()
+                .call(&mut store, (0, 0, 0, 0))
+                .unwrap();
+            let error = instance
+                .get_global(&mut store, "error")
+                .map(|error| error.get(&mut store).unwrap_i32());
+            (decision, error)
+        };
+
+        let permit = Decision::Permit as i32;
+        let no_decision = Decision::NoDecision as i32;
+        assert_eq!(run(&negation(-1), false), (permit, Some(0)));
+        assert_eq!(run(&negation(1), false), (no_decision, Some(0)));
+        // The overflow in helper 0 fails both helpers and the policy
+        assert_eq!(run(&negation(i64::MIN), false), (no_decision, Some(1)));
+        assert_eq!(run(&negation(-1), true), (permit, None));
+        assert_eq!(run(&negation(i64::MIN), true), (no_decision, None));
+
+        // Helpers only call the helpers before them, and return a value
+        let errors = [
+            module(vec![vec![Call(0)], vec![Call(0)]]),
+            module(vec![vec![PushBool(true)], vec![Call(2)]]),
+            module(vec![vec![PushBool(true)], vec![Error(WasmType::Bool)]]),
+        ]
+        .map(|module| WasmCodeGen::new().generate(&module).unwrap_err());
+        assert_eq!(errors[0], "Call to helper 0 before it is defined");
+        assert_eq!(errors[1], "Call to helper 2 before it is defined");
+        assert!(errors[2].contains("in a helper"), "{}", errors[2]);
+    }
+
+    #[test]
+    fn test_evaluate_is_indexed_after_imports() {
+        use wasmparser::{ExternalKind, Parser, Payload};
+
+        let exported_evaluate = |source: &str| {
+            let policy_set = crate::ast::adapter::parse_policy_set(source).unwrap();
+            let module = LoweredModule::from_policy_set(&policy_set).unwrap();
+            let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+            wasmparser::validate(&wasm_bytes).unwrap();
+
+            let mut imports = 0;
+            let mut evaluate = None;
+            for payload in Parser::new(0).parse_all(&wasm_bytes) {
+                match payload.unwrap() {
+                    Payload::ImportSection(reader) => imports = reader.count(),
+                    Payload::ExportSection(reader) => {
+                        for export in reader {
+                            let export = export.unwrap();
+                            if export.name == "evaluate" && export.kind == ExternalKind::Func {
+                                evaluate = Some(export.index);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            (imports, evaluate.unwrap())
+        };
+
+        // Without host calls there are no imports
+        let (imports, evaluate) = exported_evaluate("permit(principal, action, resource);");
+        assert_eq!((imports, evaluate), (0, 0));
+
+        // A single policy is the first function after the imports
+        let (imports, evaluate) =
+            exported_evaluate("permit(principal, action, resource) when { principal.admin };");
+        assert_eq!(imports, runtime_functions::COUNT);
+        assert_eq!(evaluate, imports);
+
+        // A policy set's combinator follows its policies
+        let (imports, evaluate) = exported_evaluate(
+            r#"
+            permit(principal, action, resource) when { principal.admin };
+            forbid(principal, action, resource) when { resource.private };
+            "#,
+        );
+        assert_eq!(imports, runtime_functions::COUNT);
+        assert_eq!(evaluate, imports + 2);
+    }
+
+    #[test]
+    fn test_structured_branch_codegen() {
+        use crate::wasm::types::WasmType;
+
+        // if (principal == <entity 0>) { Forbid } else { Permit }
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions: vec![
+                    Instruction::LoadVar(crate::ast::lowering::RequestVar::Principal),
+                    Instruction::LoadConstEntity(0),
+                    Instruction::Equal,
+                    Instruction::If(Some(WasmType::Bool)),
+                    Instruction::Forbid,
+                    Instruction::Else,
+                    Instruction::Permit,
+                    Instruction::End,
+                    Instruction::Return,
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+    }
+
+    #[test]
+    fn test_entity_literal_codegen() {
+        // principal == <entity 3>
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions: vec![
+                    Instruction::LoadVar(crate::ast::lowering::RequestVar::Principal),
+                    Instruction::LoadConstEntity(3),
+                    Instruction::Equal,
+                    Instruction::Return,
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+
+        // Entity handles are compared as i32, never as integers
+        let wat = wasmprinter::print_bytes(&wasm_bytes).unwrap();
+        assert!(wat.contains("i32.const 3"));
+        assert!(wat.contains("i32.eq"));
+        assert!(!wat.contains("i64."));
+    }
+
+    #[test]
+    fn test_like_pattern_is_interned() {
+        use crate::ast::lowering::RequestVar;
+
+        let like = |pattern: &str| LoweredFunction {
+            instructions: vec![
+                Instruction::Permit,
+                Instruction::NoDecision,
+                Instruction::LoadVar(RequestVar::Resource),
+                Instruction::GetAttribute("name".to_string()),
+                Instruction::Like(pattern.to_string()),
+                Instruction::IfThenElse,
+                Instruction::Return,
+            ],
+            ..Default::default()
+        };
+        let module = LoweredModule {
+            policies: vec![like(r"doc_\*_*"), like(r"doc_\*_*")],
+            ..Default::default()
+        };
+
+        let mut codegen = WasmCodeGen::new();
+        let wasm_bytes = codegen.generate(&module).unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+
+        // Both policies share one pool entry holding the pattern verbatim
+        let mut expected = 8u32.to_le_bytes().to_vec();
+        expected.extend_from_slice(br"doc_\*_*");
+        assert_eq!(codegen.pool, expected);
+
+        let wat = wasmprinter::print_bytes(&wasm_bytes).unwrap();
+        assert!(wat.contains(r#"(import "cedar" "string_like""#));
+        assert_eq!(wat.matches("i32.wrap_i64").count(), 2);
+    }
+
+    #[test]
+    fn test_oversized_literal_is_rejected() {
+        use crate::ast::lowering::RequestVar;
+
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions: vec![
+                    Instruction::Permit,
+                    Instruction::NoDecision,
+                    Instruction::LoadVar(RequestVar::Resource),
+                    Instruction::GetAttribute("name".to_string()),
+                    Instruction::Like("x".repeat(70_000)),
+                    Instruction::IfThenElse,
+                    Instruction::Return,
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        // The literal is refused before it is added to the pool
+        let mut codegen = WasmCodeGen::new().with_max_pages(1);
+        let err = codegen.generate(&module).unwrap_err();
+        assert!(err.contains("over the limit of 1"), "{}", err);
+        assert!(codegen.pool.is_empty());
+
+        // Offsets must fit in 32 bits whatever the memory limit
+        let err = pool_address(0, 5 << 30, u32::MAX).unwrap_err();
+        assert!(err.contains("32-bit pointers"), "{}", err);
+        let err = pool_address(u32::MAX as usize, 4, u32::MAX).unwrap_err();
+        assert!(err.contains("32-bit pointers"), "{}", err);
+        assert_eq!(pool_address(8, 4, 1), Ok(memory::STRING_POOL_START + 8));
+    }
+
+    #[test]
+    fn test_contains_all_calls_host_with_set_literal() {
+        use wasmparser::{Operator, Parser, Payload};
+
+        let policy_set = crate::ast::adapter::parse_policy_set(
+            r#"permit(principal, action, resource) when { resource.tags.containsAll(["a", "b"]) };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_policy_set(&policy_set).unwrap();
+        let mut codegen = WasmCodeGen::new();
+        let wasm_bytes = codegen.generate(&module).unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+
+        let ops = Parser::new(0)
+            .parse_all(&wasm_bytes)
+            .find_map(|payload| match payload.unwrap() {
+                Payload::CodeSectionEntry(body) => Some(body),
+                _ => None,
+            })
+            .unwrap()
+            .get_operators_reader()
+            .unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        // The set literal follows its element strings in the pool
+        let set = codegen.sets[0].1;
+        assert_eq!(set, memory::STRING_POOL_START + 16);
+        let get_attribute = Operator::Call {
+            function_index: runtime_functions::GET_ATTRIBUTE,
+        };
+        let get = ops.iter().position(|op| *op == get_attribute).unwrap();
+        assert!(matches!(
+            ops[get - 2..get + 11],
+            [
+                Operator::LocalGet { local_index: 2 },
+                Operator::I32Const { value: 0 },
+                Operator::Call { .. },
+                // A missing attribute skips the policy
+                Operator::GlobalGet { global_index: ERROR_GLOBAL },
+                Operator::If { .. },
+                Operator::I32Const { value: -1 },
+                Operator::Return,
+                Operator::End,
+                Operator::I32Const { value },
+                Operator::LocalSet { local_index: SCRATCH_LOCAL },
+                Operator::I32WrapI64,
+                Operator::LocalGet { local_index: SCRATCH_LOCAL },
+                Operator::Call { function_index: runtime_functions::SET_CONTAINS_ALL },
+            ] if value == set as i32
+        ));
+
+        let offset = (set - memory::STRING_POOL_START) as usize;
+        let header = &codegen.pool[offset..offset + 8];
+        assert_eq!(header[..4], 2u32.to_le_bytes());
+        assert_eq!(header[4..], (ValueKind::String as u32).to_le_bytes());
+    }
+
+    #[test]
+    fn test_is_in_range_calls_host() {
+        use wasmparser::{Operator, Parser, Payload};
+
+        let policy_set = crate::ast::adapter::parse_policy_set(
+            r#"permit(principal, action, resource) when { context.src_ip.isInRange(ip("10.0.0.0/8")) };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_policy_set(&policy_set).unwrap();
+        let mut codegen = WasmCodeGen::new();
+        let wasm_bytes = codegen.generate(&module).unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+
+        let ops = Parser::new(0)
+            .parse_all(&wasm_bytes)
+            .find_map(|payload| match payload.unwrap() {
+                Payload::CodeSectionEntry(body) => Some(body),
+                _ => None,
+            })
+            .unwrap()
+            .get_operators_reader()
+            .unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let call = |func| Operator::Call {
+            function_index: func,
+        };
+
+        // The range is an interned string, parsed by the host
+        assert_eq!(codegen.strings[0].0, "10.0.0.0/8");
+        let ip = ops
+            .iter()
+            .position(|op| *op == call(runtime_functions::IP))
+            .unwrap();
+        assert!(matches!(
+            ops[ip - 1],
+            Operator::I32Const { value } if value == codegen.strings[0].1 as i32
+        ));
+        // The address is an attribute value, unwrapped from under the range
+        let in_range = ops
+            .iter()
+            .position(|op| *op == call(runtime_functions::IP_IS_IN_RANGE))
+            .unwrap();
+        assert!(matches!(
+            ops[in_range - 3..in_range],
+            [
+                Operator::LocalSet {
+                    local_index: SCRATCH_LOCAL
+                },
+                Operator::I32WrapI64,
+                Operator::LocalGet {
+                    local_index: SCRATCH_LOCAL
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_nested_attributes_and_record_literals() {
+        use wasmparser::{Operator, Parser, Payload};
+
+        let policy_set = crate::ast::adapter::parse_policy_set(
+            r#"permit(principal, action, resource) when { context.auth.level > 2 && { level: 3 } has level };"#,
+        )
+        .unwrap();
+        let module = LoweredModule::from_policy_set(&policy_set).unwrap();
+        let mut codegen = WasmCodeGen::new();
+        let wasm_bytes = codegen.generate(&module).unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+
+        let ops = Parser::new(0)
+            .parse_all(&wasm_bytes)
+            .find_map(|payload| match payload.unwrap() {
+                Payload::CodeSectionEntry(body) => Some(body),
+                _ => None,
+            })
+            .unwrap()
+            .get_operators_reader()
+            .unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let get_attribute = Operator::Call {
+            function_index: runtime_functions::GET_ATTRIBUTE,
+        };
+
+        // `auth` is fetched from the context, then `level` from its value
+        let calls: Vec<usize> = ops
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| **op == get_attribute)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(calls.len(), 2);
+        assert!(matches!(
+            ops[calls[0] - 2..calls[0]],
+            [
+                Operator::LocalGet { local_index: 3 },
+                Operator::I32Const { value: 0 }
+            ]
+        ));
+        assert!(matches!(
+            ops[calls[1] - 2..calls[1]],
+            [Operator::I32WrapI64, Operator::I32Const { value: 1 }]
+        ));
+
+        // The record is a field count, then attribute, kind and value per field
+        assert_eq!(codegen.attributes, ["auth", "level"]);
+        let offset = (codegen.records[0].1 - memory::STRING_POOL_START) as usize;
+        let record = &codegen.pool[offset..offset + 20];
+        assert_eq!(record[..4], 1u32.to_le_bytes());
+        assert_eq!(record[4..8], 1u32.to_le_bytes());
+        assert_eq!(record[8..12], (ValueKind::Long as u32).to_le_bytes());
+        assert_eq!(record[12..], 3i64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_mistyped_ir_is_rejected() {
+        use Instruction::*;
+
+        let generate = |instructions: Vec<Instruction>| {
+            let module = LoweredModule {
+                policies: vec![LoweredFunction {
+                    instructions,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            WasmCodeGen::new().generate(&module)
+        };
+
+        let err = generate(vec![
+            PushInt(1),
+            LoadVar(RequestVar::Principal),
+            LessThan,
+            Return,
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "LessThan expects two i64 operands, found Int (i64) and Entity (i32)"
+        );
+
+        let err = generate(vec![PushString("a".into()), PushBool(true), And, Return]).unwrap_err();
+        assert!(err.starts_with("And expects two boolean operands"), "{err}");
+
+        let err =
+            generate(vec![Permit, PushInt(0), PushBool(true), IfThenElse, Return]).unwrap_err();
+        assert!(
+            err.starts_with("IfThenElse expects two values of the same type"),
+            "{err}"
+        );
+
+        let err = generate(vec![PushInt(1), Return]).unwrap_err();
+        assert_eq!(err, "Return expects an i32 decision, found Int (i64)");
+
+        let err = generate(vec![
+            PushBool(true),
+            If(Some(WasmType::Int)),
+            PushBool(true),
+            Else,
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Else expects the branch to leave Int (i64), found Bool (i32)"
+        );
+
+        let err = generate(vec![PushBool(true), If(None), Permit]).unwrap_err();
+        assert_eq!(err, "If without a matching End");
+
+        let err = generate(vec![
+            PushBool(true),
+            If(None),
+            PushString("a".into()),
+            Else,
+            PushBool(false),
+            End,
+            Return,
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "If branches leave incompatible types String (i32) and Bool (i32)"
+        );
+
+        let err = generate(vec![Permit, Permit]).unwrap_err();
+        assert!(
+            err.starts_with("Function must end with one i32 decision"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_attribute_values_are_coerced_to_operand_types() {
+        use Instruction::*;
+
+        // `resource.owner == principal && resource.owner in principal`
+        let module = LoweredModule {
+            policies: vec![LoweredFunction {
+                instructions: vec![
+                    Permit,
+                    NoDecision,
+                    LoadVar(RequestVar::Resource),
+                    GetAttribute("owner".to_string()),
+                    LoadVar(RequestVar::Principal),
+                    Equal,
+                    LoadVar(RequestVar::Resource),
+                    GetAttribute("owner".to_string()),
+                    LoadVar(RequestVar::Principal),
+                    In,
+                    And,
+                    IfThenElse,
+                    Return,
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+        Validator::new().validate_all(&wasm_bytes).unwrap();
+    }
+
+    #[test]
+    fn test_comparisons_follow_schema_types() {
+        use crate::ast::adapter::Schema;
+        use wasmparser::{Operator, Parser, Payload};
+
+        let schema = Schema::from_cedarschema_str(
+            "entity User { name: String, level: Long, active: Bool, manager: User };",
+        )
+        .unwrap();
+        // Operators of the policy function comparing two attributes
+        let compare = |attr: &str| {
+            let source = format!(
+                "permit(principal, action, resource) when {{ principal.{attr} == resource.{attr} }};"
+            );
+            let policy_set = crate::ast::adapter::parse_policy_set(&source).unwrap();
+            let mut module = LoweredModule::from_policy_set(&policy_set).unwrap();
+            module.apply_schema(&schema).unwrap();
+            let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+            wasmparser::validate(&wasm_bytes).unwrap();
+
+            let ops = Parser::new(0)
+                .parse_all(&wasm_bytes)
+                .find_map(|payload| match payload.unwrap() {
+                    Payload::CodeSectionEntry(body) => Some(body),
+                    _ => None,
+                })
+                .unwrap()
+                .get_operators_reader()
+                .unwrap()
+                .into_iter()
+                .map(|op| format!("{:?}", op.unwrap()))
+                .collect::<Vec<_>>();
+            ops
+        };
+        let string_eq = format!(
+            "{:?}",
+            Operator::Call {
+                function_index: runtime_functions::STRING_EQ
+            }
+        );
+
+        let ops = compare("name");
+        assert!(ops.contains(&string_eq));
+        assert!(!ops.iter().any(|op| op == "I64Eq" || op == "I32Eq"));
+
+        let ops = compare("level");
+        assert!(ops.contains(&"I64Eq".to_string()));
+        assert!(!ops.contains(&string_eq));
+
+        // Booleans and entity handles are i32s
+        for attr in ["active", "manager"] {
+            let ops = compare(attr);
+            assert!(ops.contains(&"I32Eq".to_string()), "{attr}: {ops:?}");
+            assert!(!ops.contains(&"I64Eq".to_string()), "{attr}: {ops:?}");
+            assert!(!ops.contains(&string_eq));
+        }
+    }
+
+    #[test]
+    fn test_if_branch_types_are_unified() {
+        use wasmparser::{BlockType, Operator, Parser, Payload, ValType};
+
+        // Result type of the `if` expression, nested in the `when` clause's
+        let branch_type = |condition: &str| {
+            let source = format!("permit(principal, action, resource) when {{ {condition} }};");
+            let policy_set = crate::ast::adapter::parse_policy_set(&source).unwrap();
+            let module = LoweredModule::from_policy_set(&policy_set).unwrap();
+            let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+            wasmparser::validate(&wasm_bytes).unwrap();
+
+            let body = Parser::new(0)
+                .parse_all(&wasm_bytes)
+                .find_map(|payload| match payload.unwrap() {
+                    Payload::CodeSectionEntry(body) => Some(body),
+                    _ => None,
+                })
+                .unwrap();
+            let ty = body
+                .get_operators_reader()
+                .unwrap()
+                .into_iter()
+                .filter_map(|op| match op.unwrap() {
+                    Operator::If {
+                        blockty: BlockType::Type(ty),
+                    } => Some(ty),
+                    _ => None,
+                })
+                .last();
+            ty
+        };
+
+        // The branches' common type, or i64 when one is an attribute value
+        let ty = branch_type("(if principal has x then 1 else 2) == 1");
+        assert_eq!(ty, Some(ValType::I64));
+        let ty = branch_type(r#"(if principal has x then "a" else "b") == "a""#);
+        assert_eq!(ty, Some(ValType::I32));
+        let ty = branch_type(r#"(if principal has x then principal.x else "b") == "a""#);
+        assert_eq!(ty, Some(ValType::I64));
+    }
+
+    #[test]
+    fn test_name_section() {
+        let has_name_section = |wasm_bytes: &[u8]| {
+            wasmparser::Parser::new(0)
+                .parse_all(wasm_bytes)
+                .any(|payload| {
+                    matches!(payload, Ok(wasmparser::Payload::CustomSection(section))
+                        if section.name() == "name")
+                })
+        };
+        let policy = || LoweredFunction {
+            instructions: vec![
+                Instruction::Permit,
+                Instruction::NoDecision,
+                Instruction::LoadVar(RequestVar::Principal),
+                Instruction::HasAttribute("admin".to_string()),
+                Instruction::IfThenElse,
+                Instruction::Return,
+            ],
+            ..Default::default()
+        };
+        let module = LoweredModule {
+            policies: vec![policy(), policy()],
+            ..Default::default()
+        };
+
+        let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+        assert!(!has_name_section(&wasm_bytes));
+
+        let wasm_bytes = WasmCodeGen::new()
+            .with_names(true)
+            .with_batch_evaluation(true)
+            .generate(&module)
+            .unwrap();
+        assert!(has_name_section(&wasm_bytes));
+        let wat = wasmprinter::print_bytes(&wasm_bytes).unwrap();
+        for name in [
+            "(import \"cedar\" \"has_attribute\" (func $has_attribute",
+            "(func $policy1",
+            "(func $evaluate_policies",
+            "(func $evaluate ",
+            "(func $evaluate_batch",
+            "(param $principal i32)",
+            "(local $condition i32)",
+            "(local $permitted i32)",
+        ] {
+            assert!(wat.contains(name), "missing {name} in {wat}");
+        }
+    }
+
+    #[test]
+    fn test_request_variables_are_parameters() {
+        use wasmparser::{Operator, Parser, Payload};
+
+        let policy_set = crate::ast::adapter::parse_policy_set(
+            "permit(principal, action, resource) when { principal == resource };",
+        )
+        .unwrap();
+        let module = LoweredModule::from_policy_set(&policy_set).unwrap();
+        let wasm_bytes = WasmCodeGen::new().generate(&module).unwrap();
+        wasmparser::validate(&wasm_bytes).unwrap();
+
+        let mut param_counts = Vec::new();
+        let mut policy_ops = None;
+        for payload in Parser::new(0).parse_all(&wasm_bytes) {
+            match payload.unwrap() {
+                Payload::TypeSection(reader) => {
+                    for ty in reader.into_iter_err_on_gc_types() {
+                        param_counts.push(ty.unwrap().params().len());
+                    }
+                }
+                // The first body is the policy function
+                Payload::CodeSectionEntry(body) if policy_ops.is_none() => {
+                    let ops = body.get_operators_reader().unwrap();
+                    policy_ops = Some(ops.into_iter().collect::<Result<Vec<_>, _>>().unwrap());
+                }
+                _ => {}
+            }
+        }
+
+        // Type 0 is the policy entry signature
+        assert_eq!(param_counts[0], 4);
+        let ops = policy_ops.unwrap();
+        let eq = ops.iter().position(|op| *op == Operator::I32Eq).unwrap();
+        assert!(matches!(
+            ops[eq - 2..eq],
+            [
+                Operator::LocalGet { local_index: 0 },
+                Operator::LocalGet { local_index: 2 }
+            ]
+        ));
     }
 }
@@ -0,0 +1,312 @@
+//! Wrapping compiled modules as WebAssembly components
+//!
+//! A component imports the runtime functions as the `cedar:policy/runtime`
+//! interface and exports `evaluate`, lifted from the core module, so that
+//! component-model runtimes can link it without wiring core imports by
+//! hand. The WIT world describing it is embedded in the [`WIT_SECTION`]
+//! custom section.
+//!
+//! The component does not export the module's memory, so its hosts cannot
+//! read the strings, sets and records runtime functions receive pointers
+//! to. Only policies whose host calls pass entity handles, attribute
+//! indices and values the host itself returned can be wrapped; [`wrap`]
+//! rejects a module that calls any of the [`MEMORY_FUNCTIONS`].
+
+use wasm_encoder::{
+    ComponentBuilder, ComponentExportKind, ComponentTypeRef, ComponentValType, CustomSection,
+    ExportKind, InstanceType, ModuleArg, PrimitiveValType,
+};
+use wasmparser::{ExternalKind, FuncType, Operator, Parser, Payload, TypeRef, ValType};
+
+use super::codegen::{PARAM_NAMES, SLOT_PARAM_NAMES};
+use super::runtime::runtime_functions;
+
+/// Custom section holding the component's WIT world
+pub const WIT_SECTION: &str = "cedar.wit";
+
+/// WIT package of the runtime interface and the policy world
+const PACKAGE: &str = "cedar:policy";
+
+/// Parameter names of the runtime functions, indexed like
+/// [`runtime_functions::NAMES`]
+const RUNTIME_PARAM_NAMES: [&[&str]; runtime_functions::COUNT as usize] = [
+    &["a", "b"],
+    &["entity", "attr"],
+    &["entity", "attr"],
+    &["child", "parent"],
+    &["id"],
+    &["s", "pattern"],
+    &["set", "value"],
+    &["set", "other"],
+    &["set", "other"],
+    &["entity", "type_name"],
+    &["s"],
+    &["ip", "range"],
+    &["ip"],
+    &["ip"],
+    &["ip"],
+    &["ip"],
+    &["a", "b"],
+    &["json", "len", "request"],
+];
+
+/// Runtime functions that take or return pointers into the module's memory
+pub const MEMORY_FUNCTIONS: [u32; 10] = [
+    runtime_functions::STRING_EQ,
+    runtime_functions::RESOLVE_SECRET,
+    runtime_functions::STRING_LIKE,
+    runtime_functions::SET_CONTAINS,
+    runtime_functions::SET_CONTAINS_ALL,
+    runtime_functions::SET_CONTAINS_ANY,
+    runtime_functions::ENTITY_IS,
+    runtime_functions::IP,
+    runtime_functions::SET_EQUAL,
+    runtime_functions::PARSE_REQUEST,
+];
+
+/// A function of the component's interface: its WIT name and its
+/// parameters and result as core types
+struct Signature {
+    name: String,
+    params: Vec<(String, ValType)>,
+    result: ValType,
+}
+
+impl Signature {
+    /// Name `ty`'s parameters after `names`, which may be longer
+    fn new(name: &str, names: &[&str], ty: &FuncType) -> Result<Self, String> {
+        let [result] = ty.results() else {
+            return Err(format!("`{}` does not return a single value", name));
+        };
+        Ok(Signature {
+            name: kebab_case(name),
+            params: names
+                .iter()
+                .map(|name| kebab_case(name))
+                .zip(ty.params().iter().copied())
+                .collect(),
+            result: *result,
+        })
+    }
+}
+
+/// The core module's runtime imports and its `evaluate` export
+struct CoreInterface {
+    imports: Vec<Signature>,
+    evaluate: Signature,
+}
+
+/// Wrap a compiled module in a component that imports the runtime
+/// interface and exports `evaluate`
+pub fn wrap(core: &[u8]) -> Result<Vec<u8>, String> {
+    let interface = read_interface(core)?;
+    let mut builder = ComponentBuilder::default();
+
+    let module = builder.core_module_raw(core);
+    let mut args = Vec::new();
+    if !interface.imports.is_empty() {
+        let mut ty = InstanceType::new();
+        for import in &interface.imports {
+            let func_ty = ty.type_count();
+            encode_function(&mut ty.ty().function(), import);
+            ty.export(&import.name, ComponentTypeRef::Func(func_ty));
+        }
+        let ty = builder.type_instance(&ty);
+        let instance = builder.import(
+            &format!("{}/runtime", PACKAGE),
+            ComponentTypeRef::Instance(ty),
+        );
+
+        let lowered: Vec<(u32, &str)> = interface
+            .imports
+            .iter()
+            .zip(runtime_functions::NAMES)
+            .map(|(import, core_name)| {
+                let func = builder.alias_export(instance, &import.name, ComponentExportKind::Func);
+                (builder.lower_func(func, []), core_name)
+            })
+            .collect();
+        let runtime = builder.core_instantiate_exports(
+            lowered
+                .iter()
+                .map(|(func, name)| (*name, ExportKind::Func, *func)),
+        );
+        args.push((runtime_functions::MODULE, ModuleArg::Instance(runtime)));
+    }
+    let instance = builder.core_instantiate(module, args);
+
+    let core_evaluate = builder.core_alias_export(instance, "evaluate", ExportKind::Func);
+    let (evaluate_ty, mut encoder) = builder.type_function();
+    encode_function(&mut encoder, &interface.evaluate);
+    let evaluate = builder.lift_func(core_evaluate, evaluate_ty, []);
+    builder.export("evaluate", ComponentExportKind::Func, evaluate, None);
+
+    builder.custom_section(&CustomSection {
+        name: WIT_SECTION.into(),
+        data: world(&interface).into_bytes().into(),
+    });
+    Ok(builder.finish())
+}
+
+/// The WIT world of the component [`wrap`] builds from a compiled module
+pub fn wit(core: &[u8]) -> Result<String, String> {
+    Ok(world(&read_interface(core)?))
+}
+
+/// Find the runtime functions a compiled module imports and the signature of
+/// its `evaluate`, failing if it calls one of the [`MEMORY_FUNCTIONS`].
+/// Runtime functions are imported in index order, so the i-th import is
+/// `runtime_functions::NAMES[i]`.
+fn read_interface(core: &[u8]) -> Result<CoreInterface, String> {
+    let mut types: Vec<FuncType> = Vec::new();
+    let mut functions: Vec<u32> = Vec::new();
+    let mut imports = 0;
+    let mut evaluate = None;
+    for payload in Parser::new(0).parse_all(core) {
+        match payload.map_err(|e| e.to_string())? {
+            Payload::TypeSection(reader) => {
+                for ty in reader.into_iter_err_on_gc_types() {
+                    types.push(ty.map_err(|e| e.to_string())?);
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|e| e.to_string())?;
+                    let expected = runtime_functions::NAMES.get(imports);
+                    match import.ty {
+                        TypeRef::Func(ty)
+                            if import.module == runtime_functions::MODULE
+                                && expected == Some(&import.name) =>
+                        {
+                            imports += 1;
+                            functions.push(ty);
+                        }
+                        _ => return Err(format!("Unexpected import `{}`", import.name)),
+                    }
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                for ty in reader {
+                    functions.push(ty.map_err(|e| e.to_string())?);
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                for op in body.get_operators_reader().map_err(|e| e.to_string())? {
+                    if let Operator::Call { function_index } = op.map_err(|e| e.to_string())? {
+                        if (function_index as usize) < imports
+                            && MEMORY_FUNCTIONS.contains(&function_index)
+                        {
+                            return Err(format!(
+                                "The policy calls `{}`, which passes pointers into the \
+                                 module's memory, and components don't export it",
+                                runtime_functions::NAMES[function_index as usize]
+                            ));
+                        }
+                    }
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export.map_err(|e| e.to_string())?;
+                    if export.name == "evaluate" && export.kind == ExternalKind::Func {
+                        evaluate = Some(export.index);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let func_type = |func: u32| {
+        functions
+            .get(func as usize)
+            .and_then(|ty| types.get(*ty as usize))
+            .ok_or_else(|| format!("Function {} has no type", func))
+    };
+    let imports = (0..imports)
+        .map(|func| {
+            Signature::new(
+                runtime_functions::NAMES[func],
+                RUNTIME_PARAM_NAMES[func],
+                func_type(func as u32)?,
+            )
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let evaluate = evaluate.ok_or("Module has no `evaluate` export")?;
+    let names: Vec<&str> = PARAM_NAMES
+        .iter()
+        .chain(&SLOT_PARAM_NAMES)
+        .copied()
+        .collect();
+    let evaluate = Signature::new("evaluate", &names, func_type(evaluate)?)?;
+    Ok(CoreInterface { imports, evaluate })
+}
+
+/// Add a function's component type, lifting i32 to s32 and i64 to s64
+fn encode_function(encoder: &mut wasm_encoder::ComponentFuncTypeEncoder<'_>, sig: &Signature) {
+    encoder.params(
+        sig.params
+            .iter()
+            .map(|(name, ty)| (name.as_str(), component_type(*ty))),
+    );
+    encoder.result(component_type(sig.result));
+}
+
+/// Component type of a core value
+fn component_type(ty: ValType) -> ComponentValType {
+    ComponentValType::Primitive(match ty {
+        ValType::I64 => PrimitiveValType::S64,
+        _ => PrimitiveValType::S32,
+    })
+}
+
+/// WIT name of a component value type
+fn wit_type(ty: ValType) -> &'static str {
+    match ty {
+        ValType::I64 => "s64",
+        _ => "s32",
+    }
+}
+
+/// Render a function as a WIT declaration
+fn wit_function(sig: &Signature) -> String {
+    let params = sig
+        .params
+        .iter()
+        .map(|(name, ty)| format!("{}: {}", name, wit_type(*ty)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "{}: func({}) -> {};",
+        sig.name,
+        params,
+        wit_type(sig.result)
+    )
+}
+
+/// The WIT package declaring the runtime interface and the policy world
+fn world(interface: &CoreInterface) -> String {
+    let mut wit = format!("package {};\n\n", PACKAGE);
+    if !interface.imports.is_empty() {
+        wit.push_str("interface runtime {\n");
+        for import in &interface.imports {
+            wit.push_str(&format!("    {}\n", wit_function(import)));
+        }
+        wit.push_str("}\n\n");
+    }
+    wit.push_str("world policy {\n");
+    if !interface.imports.is_empty() {
+        wit.push_str("    import runtime;\n");
+    }
+    wit.push_str(&format!(
+        "    export {}\n",
+        wit_function(&interface.evaluate)
+    ));
+    wit.push_str("}\n");
+    wit
+}
+
+/// WIT names are kebab-case
+fn kebab_case(name: &str) -> String {
+    name.replace('_', "-")
+}
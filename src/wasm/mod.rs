@@ -1,7 +1,14 @@
 //! WebAssembly code generation
 
+#[cfg(feature = "std")]
 pub mod codegen;
+#[cfg(feature = "std")]
+pub mod component;
+#[cfg(feature = "std")]
 pub mod runtime;
 pub mod types;
+#[cfg(feature = "wasm-opt")]
+pub mod wasm_opt;
 
+#[cfg(feature = "std")]
 pub use codegen::WasmCodeGen;
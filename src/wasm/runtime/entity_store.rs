@@ -0,0 +1,190 @@
+//! Reference entity data for hosts implementing the runtime imports
+
+use std::collections::{BTreeSet, HashMap};
+
+/// Attribute value of an entity, with entities as `Type::"id"` UIDs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Bool(bool),
+    Long(i64),
+    String(String),
+    Entity(String),
+    /// Elements all have the same kind
+    Set(Vec<Value>),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Entity {
+    attrs: HashMap<String, Value>,
+    parents: BTreeSet<String>,
+}
+
+/// Entities with attributes and a parent hierarchy, keyed by UID
+/// (`Type::"id"`)
+#[derive(Debug, Clone, Default)]
+pub struct EntityStore {
+    entities: HashMap<String, Entity>,
+}
+
+impl EntityStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an entity, replacing any earlier one with the same UID
+    pub fn add_entity(
+        &mut self,
+        uid: impl Into<String>,
+        attrs: impl IntoIterator<Item = (String, Value)>,
+        parents: impl IntoIterator<Item = String>,
+    ) -> &mut Self {
+        self.entities.insert(
+            uid.into(),
+            Entity {
+                attrs: attrs.into_iter().collect(),
+                parents: parents.into_iter().collect(),
+            },
+        );
+        self
+    }
+
+    /// Attribute of an entity, if both exist
+    pub fn attribute(&self, uid: &str, attr: &str) -> Option<&Value> {
+        self.entities.get(uid)?.attrs.get(attr)
+    }
+
+    /// Whether `child` is `ancestor` or one of its transitive descendants
+    pub fn is_in(&self, child: &str, ancestor: &str) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![child];
+        while let Some(uid) = pending.pop() {
+            if uid == ancestor {
+                return true;
+            }
+            if seen.insert(uid) {
+                if let Some(entity) = self.entities.get(uid) {
+                    pending.extend(entity.parents.iter().map(String::as_str));
+                }
+            }
+        }
+        false
+    }
+
+    /// `entity_in(child, parent)`
+    pub fn entity_in(&self, handles: &EntityHandles, child: i32, parent: i32) -> i32 {
+        match (handles.uid(child), handles.uid(parent)) {
+            (Some(child), Some(parent)) => self.is_in(child, parent) as i32,
+            _ => 0,
+        }
+    }
+
+    /// `has_attribute(entity, attr)`, where `attr` indexes the module's
+    /// attribute names
+    pub fn has_attribute(
+        &self,
+        handles: &EntityHandles,
+        attributes: &[String],
+        entity: i32,
+        attr: i32,
+    ) -> i32 {
+        self.lookup(handles, attributes, entity, attr).is_some() as i32
+    }
+
+    /// The value `get_attribute(entity, attr)` returns, before the host
+    /// encodes it as an i64; `None` if the attribute is missing
+    pub fn get_attribute(
+        &self,
+        handles: &EntityHandles,
+        attributes: &[String],
+        entity: i32,
+        attr: i32,
+    ) -> Option<&Value> {
+        self.lookup(handles, attributes, entity, attr)
+    }
+
+    fn lookup(
+        &self,
+        handles: &EntityHandles,
+        attributes: &[String],
+        entity: i32,
+        attr: i32,
+    ) -> Option<&Value> {
+        let attr = attributes.get(usize::try_from(attr).ok()?)?;
+        self.attribute(handles.uid(entity)?, attr)
+    }
+}
+
+/// Entity handles of one module: its entity table, then any other UIDs the
+/// host numbers from `entity_count` upwards
+#[derive(Debug, Clone, Default)]
+pub struct EntityHandles {
+    uids: Vec<String>,
+}
+
+impl EntityHandles {
+    /// Start from a module's entity table
+    pub fn new(entity_table: Vec<String>) -> Self {
+        Self { uids: entity_table }
+    }
+
+    /// Handle of a UID, numbering it on first use if the module's table
+    /// doesn't have it
+    pub fn handle(&mut self, uid: &str) -> i32 {
+        let idx = match self.uids.iter().position(|existing| existing == uid) {
+            Some(idx) => idx,
+            None => {
+                self.uids.push(uid.to_string());
+                self.uids.len() - 1
+            }
+        };
+        idx as i32
+    }
+
+    /// UID of a handle, if it was ever numbered
+    pub fn uid(&self, handle: i32) -> Option<&str> {
+        self.uids
+            .get(usize::try_from(handle).ok()?)
+            .map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entity_in_follows_parents_transitively() {
+        let mut store = EntityStore::new();
+        store
+            .add_entity(
+                r#"User::"alice""#,
+                [("level".to_string(), Value::Long(3))],
+                [r#"Group::"admins""#.to_string()],
+            )
+            .add_entity(r#"Group::"admins""#, [], [r#"Group::"staff""#.to_string()])
+            .add_entity(r#"User::"bob""#, [], []);
+
+        let mut handles = EntityHandles::new(vec![r#"Group::"admins""#.to_string()]);
+        let admins = 0;
+        let alice = handles.handle(r#"User::"alice""#);
+        let bob = handles.handle(r#"User::"bob""#);
+        let staff = handles.handle(r#"Group::"staff""#);
+        assert_eq!((alice, bob, staff), (1, 2, 3));
+
+        assert_eq!(store.entity_in(&handles, alice, admins), 1);
+        assert_eq!(store.entity_in(&handles, alice, staff), 1);
+        assert_eq!(store.entity_in(&handles, alice, alice), 1);
+        assert_eq!(store.entity_in(&handles, bob, admins), 0);
+        assert_eq!(store.entity_in(&handles, admins, alice), 0);
+        assert_eq!(store.entity_in(&handles, 7, admins), 0);
+
+        let attributes = ["level".to_string(), "name".to_string()];
+        assert_eq!(store.has_attribute(&handles, &attributes, alice, 0), 1);
+        assert_eq!(store.has_attribute(&handles, &attributes, alice, 1), 0);
+        assert_eq!(
+            store.get_attribute(&handles, &attributes, alice, 0),
+            Some(&Value::Long(3))
+        );
+        assert_eq!(store.get_attribute(&handles, &attributes, bob, 0), None);
+    }
+}
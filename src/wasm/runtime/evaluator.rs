@@ -0,0 +1,1878 @@
+//! In-process evaluation of compiled modules with wasmtime, for testing
+//! policies end to end
+
+use wasmtime::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, TypedFunc, Val};
+
+use super::entity_store::{EntityHandles, EntityStore, Value};
+use super::{memory, runtime_functions, Decision, ValueKind};
+use crate::ast::adapter::PolicyEffect;
+use crate::wasm::codegen::{ATTRIBUTES_SECTION, POLICIES_SECTION};
+
+/// A compiled module that failed to load or evaluate
+#[derive(Debug, thiserror::Error)]
+pub enum EvaluatorError {
+    #[error("WASM runtime error: {0}")]
+    Wasm(#[from] wasmtime::Error),
+
+    #[error("evaluate returned {0}, which is not a decision")]
+    InvalidDecision(i32),
+}
+
+/// Where values the host returns to the module start; the bytes below
+/// [`memory::STRING_POOL_START`] are free for them
+const RETURNED_VALUES_START: u32 = 16;
+
+/// State the runtime functions work on
+struct Host {
+    entities: EntityStore,
+    handles: EntityHandles,
+    /// The module's attribute names, indexed by the `attr` argument
+    attributes: Vec<String>,
+    /// Next free address for strings and sets returned to the module
+    next_value: u32,
+    /// Errors reported during the current request
+    errors: Vec<String>,
+}
+
+/// Runs `evaluate` of a compiled module, implementing the runtime functions
+/// against an [`EntityStore`]. Strings, `like` patterns, sets, entity types
+/// and IP addresses are evaluated from the module's own data; an IP value
+/// is a pointer to its string, so IP attributes are [`Value::String`]s. Reading a missing
+/// attribute or a redacted string is an evaluation error, which skips the
+/// policy; [`Evaluator::errors`] lists them.
+pub struct Evaluator {
+    store: Store<Host>,
+    evaluate: Entry,
+    policies: Vec<(String, PolicyEffect)>,
+    /// Error code returned with the last decision
+    error_code: i32,
+    /// Fuel the last request consumed
+    fuel: u64,
+}
+
+/// A module's `evaluate`, returning the decision alone or with an error
+/// code, or taking the request as JSON
+enum Entry {
+    Decision(TypedFunc<(i32, i32, i32, i32), i32>),
+    WithErrorCode(TypedFunc<(i32, i32, i32, i32), (i32, i32)>),
+    /// With the memory the host writes the request to
+    Json(TypedFunc<(i32, i32), i32>, Memory),
+}
+
+/// UID of the entity holding the context of a JSON request
+const JSON_CONTEXT: &str = r#"Context::"request""#;
+
+impl Evaluator {
+    /// Instantiate a compiled module whose entities have no attributes and
+    /// no parents
+    pub fn new(wasm: &[u8]) -> Result<Self, EvaluatorError> {
+        Self::with_entities(wasm, EntityStore::new())
+    }
+
+    /// Instantiate a compiled module with its entity data
+    pub fn with_entities(wasm: &[u8], entities: EntityStore) -> Result<Self, EvaluatorError> {
+        let mut config = Config::new();
+        config.wasm_memory64(true).consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, wasm)?;
+        let mut linker = Linker::new(&engine);
+        define_runtime_functions(&mut linker)?;
+
+        let host = Host {
+            entities,
+            handles: EntityHandles::default(),
+            attributes: attribute_names(wasm)?,
+            next_value: RETURNED_VALUES_START,
+            errors: Vec::new(),
+        };
+        let mut store = Store::new(&engine, host);
+        store.set_fuel(u64::MAX)?;
+        let instance = linker.instantiate(&mut store, &module)?;
+        if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            initialize.call(&mut store, ())?;
+        }
+        let evaluate = if let Ok(evaluate) = instance.get_typed_func(&mut store, "evaluate") {
+            Entry::Decision(evaluate)
+        } else if let Ok(evaluate) = instance.get_typed_func(&mut store, "evaluate") {
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| wasmtime::Error::msg("module does not export its memory"))?;
+            Entry::Json(evaluate, memory)
+        } else {
+            Entry::WithErrorCode(instance.get_typed_func(&mut store, "evaluate")?)
+        };
+
+        let mut evaluator = Self {
+            store,
+            evaluate,
+            policies: policy_table(wasm)?,
+            error_code: 0,
+            fuel: 0,
+        };
+        let table = evaluator.entity_table(&instance)?;
+        evaluator.store.data_mut().handles = EntityHandles::new(table);
+        Ok(evaluator)
+    }
+
+    /// Handle to pass to [`Evaluator::evaluate`] for an entity UID
+    /// (`Type::"id"`)
+    pub fn handle(&mut self, uid: &str) -> i32 {
+        self.store.data_mut().handles.handle(uid)
+    }
+
+    /// Evaluate one request: entity handles (see [`crate::wasm::runtime`])
+    /// and the address of the context, 0 for none
+    pub fn evaluate(
+        &mut self,
+        principal: i32,
+        action: i32,
+        resource: i32,
+        context: i32,
+    ) -> Result<Decision, EvaluatorError> {
+        let host = self.store.data_mut();
+        host.next_value = RETURNED_VALUES_START;
+        host.errors.clear();
+        let request = (principal, action, resource, context);
+        self.store.set_fuel(u64::MAX)?;
+        let (decision, error_code) = match &self.evaluate {
+            Entry::Decision(evaluate) => (evaluate.call(&mut self.store, request)?, 0),
+            Entry::WithErrorCode(evaluate) => evaluate.call(&mut self.store, request)?,
+            Entry::Json(..) => {
+                return Err(wasmtime::Error::msg("module takes JSON requests").into());
+            }
+        };
+        self.fuel = u64::MAX - self.store.get_fuel()?;
+        self.error_code = error_code;
+        Decision::from_i32(decision).ok_or(EvaluatorError::InvalidDecision(decision))
+    }
+
+    /// Evaluate one JSON request (see [`crate::wasm::runtime`]) with a module
+    /// compiled for them. The context's fields become the attributes of a
+    /// `Context::"request"` entity, replacing the last request's.
+    pub fn evaluate_json(&mut self, request: &str) -> Result<Decision, EvaluatorError> {
+        let Entry::Json(evaluate, memory) = &self.evaluate else {
+            return Err(wasmtime::Error::msg("module does not take JSON requests").into());
+        };
+        let (evaluate, memory) = (evaluate.clone(), *memory);
+        let host = self.store.data_mut();
+        host.next_value = RETURNED_VALUES_START;
+        host.errors.clear();
+        let (data, host) = memory.data_and_store_mut(&mut self.store);
+        let json = write(data, host, request.as_bytes())?;
+
+        self.store.set_fuel(u64::MAX)?;
+        let decision = evaluate.call(&mut self.store, (json as i32, request.len() as i32))?;
+        self.fuel = u64::MAX - self.store.get_fuel()?;
+        self.error_code = 0;
+        Decision::from_i32(decision).ok_or(EvaluatorError::InvalidDecision(decision))
+    }
+
+    /// Error code returned with the last decision by modules compiled with
+    /// error codes; 0 for other modules
+    pub fn error_code(&self) -> i32 {
+        self.error_code
+    }
+
+    /// Fuel the last request consumed, as wasmtime meters it: about one unit
+    /// per WASM operator, not counting the time spent in the runtime
+    /// functions. Modules compiled from Cedar text consume at most
+    /// [`LoweredModule::max_fuel`](crate::ast::lowering::LoweredModule::max_fuel).
+    pub fn fuel(&self) -> u64 {
+        self.fuel
+    }
+
+    /// Evaluation errors of the last request, in the order they occurred;
+    /// each skipped the policy it occurred in
+    pub fn errors(&self) -> &[String] {
+        &self.store.data().errors
+    }
+
+    /// IDs and effects of the module's policies, in policy-set order
+    pub fn policies(&self) -> &[(String, PolicyEffect)] {
+        &self.policies
+    }
+
+    /// UIDs of the module's entity table
+    fn entity_table(&mut self, instance: &wasmtime::Instance) -> wasmtime::Result<Vec<String>> {
+        let mut global = |name: &str| {
+            let value = instance
+                .get_global(&mut self.store, name)?
+                .get(&mut self.store);
+            // Addresses are i64s in modules with a 64-bit memory
+            value
+                .i32()
+                .map(|value| value as u32 as usize)
+                .or_else(|| value.i64().map(|value| value as usize))
+        };
+        let (Some(table), Some(count)) = (global("entity_table"), global("entity_count")) else {
+            return Ok(Vec::new());
+        };
+        let memory = instance
+            .get_memory(&mut self.store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("module does not export its memory"))?;
+        let data = memory.data(&self.store);
+        (0..count)
+            .map(|idx| {
+                let ptr = read_u32(data, table + idx * 4)?;
+                Ok(read_string(data, ptr as i32)?.to_string())
+            })
+            .collect()
+    }
+}
+
+/// Attribute names from the module's custom section, if it has one
+fn attribute_names(wasm: &[u8]) -> wasmtime::Result<Vec<String>> {
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::CustomSection(section) = payload? {
+            if section.name() == ATTRIBUTES_SECTION {
+                let mut reader = wasmparser::BinaryReader::new(section.data(), 0);
+                let count = reader.read_var_u32()?;
+                return (0..count)
+                    .map(|_| Ok(reader.read_string()?.to_string()))
+                    .collect();
+            }
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Policies listed in the module's custom section
+fn policy_table(wasm: &[u8]) -> wasmtime::Result<Vec<(String, PolicyEffect)>> {
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::CustomSection(section) = payload? {
+            if section.name() == POLICIES_SECTION {
+                let mut reader = wasmparser::BinaryReader::new(section.data(), 0);
+                let count = reader.read_var_u32()?;
+                return (0..count)
+                    .map(|_| {
+                        let id = reader.read_string()?.to_string();
+                        let effect = match reader.read_u8()? {
+                            byte if byte == Decision::Deny as u8 => PolicyEffect::Forbid,
+                            _ => PolicyEffect::Permit,
+                        };
+                        Ok((id, effect))
+                    })
+                    .collect();
+            }
+        }
+    }
+    Ok(Vec::new())
+}
+
+fn define_runtime_functions(linker: &mut Linker<Host>) -> wasmtime::Result<()> {
+    use runtime_functions::{NAMES, *};
+
+    let name = |func: u32| NAMES[func as usize];
+    linker.func_wrap(
+        MODULE,
+        name(STRING_EQ),
+        |mut caller: Caller<'_, Host>, a: i32, b: i32| -> wasmtime::Result<i32> {
+            let (memory, _) = memory(&mut caller)?;
+            Ok((read_string(memory, a)? == read_string(memory, b)?) as i32)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(GET_ATTRIBUTE),
+        |mut caller: Caller<'_, Host>, entity: i32, attr: i32| -> wasmtime::Result<i64> {
+            let (memory, host) = memory(&mut caller)?;
+            if is_record_literal(entity) {
+                return match read_record_field(memory, entity, attr)? {
+                    Some(value) => Ok(value),
+                    None => {
+                        let message = format!("record has no attribute {}", attr_name(host, attr));
+                        report_error(&mut caller, message)?;
+                        Ok(0)
+                    }
+                };
+            }
+            let value = host
+                .entities
+                .get_attribute(&host.handles, &host.attributes, entity, attr)
+                .cloned();
+            match value {
+                Some(value) => encode(memory, host, &value),
+                None => {
+                    let uid = host.handles.uid(entity).unwrap_or("?");
+                    let message = format!("{} has no attribute {}", uid, attr_name(host, attr));
+                    report_error(&mut caller, message)?;
+                    Ok(0)
+                }
+            }
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(HAS_ATTRIBUTE),
+        |mut caller: Caller<'_, Host>, entity: i32, attr: i32| -> wasmtime::Result<i32> {
+            let (memory, host) = memory(&mut caller)?;
+            if is_record_literal(entity) {
+                return Ok(read_record_field(memory, entity, attr)?.is_some() as i32);
+            }
+            Ok(host
+                .entities
+                .has_attribute(&host.handles, &host.attributes, entity, attr))
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(ENTITY_IN),
+        |caller: Caller<'_, Host>, child: i32, parent: i32| {
+            let host = caller.data();
+            host.entities.entity_in(&host.handles, child, parent)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(RESOLVE_SECRET),
+        |mut caller: Caller<'_, Host>, id: i32| -> wasmtime::Result<i32> {
+            let message = format!("redacted string {} cannot be resolved", id);
+            report_error(&mut caller, message)?;
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(STRING_LIKE),
+        |mut caller: Caller<'_, Host>, s: i32, pattern: i32| -> wasmtime::Result<i32> {
+            let (memory, _) = memory(&mut caller)?;
+            Ok(like(read_string(memory, s)?, read_string(memory, pattern)?) as i32)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(SET_CONTAINS),
+        |mut caller: Caller<'_, Host>, set: i32, value: i64| -> wasmtime::Result<i32> {
+            let (memory, _) = memory(&mut caller)?;
+            let (kind, elems) = read_set(memory, set)?;
+            Ok(contains(memory, kind, &elems, value)? as i32)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(SET_CONTAINS_ALL),
+        |mut caller: Caller<'_, Host>, set: i32, other: i32| -> wasmtime::Result<i32> {
+            let (memory, _) = memory(&mut caller)?;
+            let (kind, elems) = read_set(memory, set)?;
+            let (_, others) = read_set(memory, other)?;
+            for value in others {
+                if !contains(memory, kind, &elems, value)? {
+                    return Ok(0);
+                }
+            }
+            Ok(1)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(SET_EQUAL),
+        |mut caller: Caller<'_, Host>, a: i32, b: i32| -> wasmtime::Result<i32> {
+            let (memory, _) = memory(&mut caller)?;
+            let (kind, elems) = read_set(memory, a)?;
+            let (other_kind, others) = read_set(memory, b)?;
+            if elems.is_empty() || others.is_empty() {
+                return Ok((elems.is_empty() && others.is_empty()) as i32);
+            }
+            if kind != other_kind {
+                return Ok(0);
+            }
+            for (set, values) in [(&elems, &others), (&others, &elems)] {
+                for &value in values {
+                    if !contains(memory, kind, set, value)? {
+                        return Ok(0);
+                    }
+                }
+            }
+            Ok(1)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(SET_CONTAINS_ANY),
+        |mut caller: Caller<'_, Host>, set: i32, other: i32| -> wasmtime::Result<i32> {
+            let (memory, _) = memory(&mut caller)?;
+            let (kind, elems) = read_set(memory, set)?;
+            let (_, others) = read_set(memory, other)?;
+            for value in others {
+                if contains(memory, kind, &elems, value)? {
+                    return Ok(1);
+                }
+            }
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(ENTITY_IS),
+        |mut caller: Caller<'_, Host>, entity: i32, type_name: i32| -> wasmtime::Result<i32> {
+            let (memory, host) = memory(&mut caller)?;
+            let type_name = read_string(memory, type_name)?;
+            let is = host.handles.uid(entity).is_some_and(|uid| {
+                uid.strip_prefix(type_name)
+                    .is_some_and(|rest| rest.starts_with("::\""))
+            });
+            Ok(is as i32)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(IP),
+        |mut caller: Caller<'_, Host>, s: i32| -> wasmtime::Result<i32> {
+            let (memory, _) = memory(&mut caller)?;
+            let text = read_string(memory, s)?;
+            if IpRange::parse(text).is_none() {
+                let message = format!("invalid IP address {:?}", text);
+                report_error(&mut caller, message)?;
+            }
+            Ok(s)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(IP_IS_IN_RANGE),
+        |mut caller: Caller<'_, Host>, ip: i32, range: i32| -> wasmtime::Result<i32> {
+            let (memory, _) = memory(&mut caller)?;
+            let ip = read_ip(memory, ip)?;
+            Ok(ip.is_in(&read_ip(memory, range)?) as i32)
+        },
+    )?;
+    linker.func_wrap(
+        MODULE,
+        name(PARSE_REQUEST),
+        |mut caller: Caller<'_, Host>,
+         json: i32,
+         len: i32,
+         request: i32|
+         -> wasmtime::Result<i32> {
+            let (memory, host) = memory(&mut caller)?;
+            let start = json as u32 as usize;
+            let bytes = memory
+                .get(start..start + len as u32 as usize)
+                .ok_or_else(|| {
+                    wasmtime::Error::msg(format!("request at {} is out of bounds", json))
+                })?;
+            let Some(handles) = parse_request(host, bytes) else {
+                return Ok(0);
+            };
+            let start = request as u32 as usize;
+            let out = memory.get_mut(start..start + 16).ok_or_else(|| {
+                wasmtime::Error::msg(format!("request at {} is out of bounds", request))
+            })?;
+            for (chunk, handle) in out.chunks_exact_mut(4).zip(handles) {
+                chunk.copy_from_slice(&handle.to_le_bytes());
+            }
+            Ok(1)
+        },
+    )?;
+    for func in [IP_IS_IPV4, IP_IS_IPV6, IP_IS_LOOPBACK, IP_IS_MULTICAST] {
+        linker.func_wrap(
+            MODULE,
+            name(func),
+            move |mut caller: Caller<'_, Host>, ip: i32| -> wasmtime::Result<i32> {
+                let (memory, _) = memory(&mut caller)?;
+                let ip = read_ip(memory, ip)?;
+                let holds = match func {
+                    IP_IS_IPV4 => !ip.v6,
+                    IP_IS_IPV6 => ip.v6,
+                    IP_IS_LOOPBACK => ip.is_loopback(),
+                    _ => ip.is_multicast(),
+                };
+                Ok(holds as i32)
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Entity handles of a JSON request, storing its context as the
+/// [`JSON_CONTEXT`] entity; `None` if it isn't a valid request
+fn parse_request(host: &mut Host, json: &[u8]) -> Option<[i32; 4]> {
+    let request: serde_json::Value = serde_json::from_slice(json).ok()?;
+    let mut handle = |field: &str| Some(host.handles.handle(request.get(field)?.as_str()?));
+    let (principal, action, resource) =
+        (handle("principal")?, handle("action")?, handle("resource")?);
+    let context = match request.get("context") {
+        None | Some(serde_json::Value::Null) => 0,
+        Some(context) => {
+            let attrs = context
+                .as_object()?
+                .iter()
+                .map(|(name, value)| Some((name.clone(), json_value(value)?)))
+                .collect::<Option<Vec<_>>>()?;
+            host.entities.add_entity(JSON_CONTEXT, attrs, []);
+            host.handles.handle(JSON_CONTEXT)
+        }
+    };
+    Some([principal, action, resource, context])
+}
+
+/// Attribute value of a JSON context field: a bool, integer, string or set
+/// of them, or an entity as `{"__entity": "Type::\"id\""}`
+fn json_value(value: &serde_json::Value) -> Option<Value> {
+    use serde_json::Value as Json;
+
+    Some(match value {
+        Json::Bool(b) => Value::Bool(*b),
+        Json::Number(n) => Value::Long(n.as_i64()?),
+        Json::String(s) => Value::String(s.clone()),
+        Json::Array(elems) => Value::Set(elems.iter().map(json_value).collect::<Option<_>>()?),
+        Json::Object(fields) => Value::Entity(fields.get("__entity")?.as_str()?.to_string()),
+        Json::Null => return None,
+    })
+}
+
+/// The module's exported linear memory, and the host state
+fn memory<'a>(caller: &'a mut Caller<'_, Host>) -> wasmtime::Result<(&'a mut [u8], &'a mut Host)> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Ok(memory.data_and_store_mut(caller)),
+        _ => Err(wasmtime::Error::msg("module does not export its memory")),
+    }
+}
+
+/// Record an evaluation error and set the module's error flag
+fn report_error(caller: &mut Caller<'_, Host>, message: String) -> wasmtime::Result<()> {
+    caller.data_mut().errors.push(message);
+    match caller.get_export("error") {
+        Some(Extern::Global(error)) => error.set(caller, Val::I32(1)),
+        _ => Err(wasmtime::Error::msg(
+            "module does not export its error flag",
+        )),
+    }
+}
+
+/// Encode an attribute value as `get_attribute` returns it, writing
+/// strings and sets below the string pool
+fn encode(memory: &mut [u8], host: &mut Host, value: &Value) -> wasmtime::Result<i64> {
+    Ok(match value {
+        Value::Bool(b) => *b as i64,
+        Value::Long(i) => *i,
+        Value::Entity(uid) => host.handles.handle(uid) as u32 as i64,
+        Value::String(s) => {
+            let ptr = write(memory, host, &(s.len() as u32).to_le_bytes())?;
+            write(memory, host, s.as_bytes())?;
+            ptr as i64
+        }
+        Value::Set(elems) => {
+            let kind = match elems.first() {
+                Some(Value::Bool(_)) | None => ValueKind::Bool,
+                Some(Value::Long(_)) => ValueKind::Long,
+                Some(Value::String(_)) => ValueKind::String,
+                Some(Value::Entity(_)) => ValueKind::Entity,
+                Some(Value::Set(_)) => {
+                    return Err(wasmtime::Error::msg("sets of sets are not supported"))
+                }
+            };
+            // Elements first, so the set itself is contiguous
+            let values = elems
+                .iter()
+                .map(|elem| encode(memory, host, elem))
+                .collect::<wasmtime::Result<Vec<_>>>()?;
+            let ptr = write(memory, host, &(values.len() as u32).to_le_bytes())?;
+            write(memory, host, &(kind as u32).to_le_bytes())?;
+            for value in values {
+                write(memory, host, &value.to_le_bytes())?;
+            }
+            ptr as i64
+        }
+    })
+}
+
+/// Copy bytes to the next free 4-aligned address below the string pool
+fn write(memory: &mut [u8], host: &mut Host, bytes: &[u8]) -> wasmtime::Result<u32> {
+    let start = host.next_value.next_multiple_of(4);
+    let end = start + bytes.len() as u32;
+    if end > memory::STRING_POOL_START {
+        return Err(wasmtime::Error::msg(
+            "attribute values do not fit below the string pool",
+        ));
+    }
+    memory[start as usize..end as usize].copy_from_slice(bytes);
+    host.next_value = end;
+    Ok(start)
+}
+
+/// Little-endian u32 at `addr`
+fn read_u32(memory: &[u8], addr: usize) -> wasmtime::Result<u32> {
+    let bytes = memory
+        .get(addr..addr + 4)
+        .ok_or_else(|| wasmtime::Error::msg(format!("address {} is out of bounds", addr)))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Length-prefixed UTF-8 string at `ptr`
+fn read_string(memory: &[u8], ptr: i32) -> wasmtime::Result<&str> {
+    let start = ptr as u32 as usize + 4;
+    let len = read_u32(memory, ptr as u32 as usize)? as usize;
+    let bytes = memory
+        .get(start..start + len)
+        .ok_or_else(|| wasmtime::Error::msg(format!("string at {} is out of bounds", ptr)))?;
+    Ok(std::str::from_utf8(bytes)?)
+}
+
+/// Element kind and values of the set at `ptr`
+fn read_set(memory: &[u8], ptr: i32) -> wasmtime::Result<(u32, Vec<i64>)> {
+    let addr = ptr as u32 as usize;
+    let count = read_u32(memory, addr)? as usize;
+    let kind = read_u32(memory, addr + 4)?;
+    let elems = (0..count)
+        .map(|i| {
+            let at = addr + 8 + i * 8;
+            let bytes = memory
+                .get(at..at + 8)
+                .ok_or_else(|| wasmtime::Error::msg(format!("set at {} is out of bounds", ptr)))?;
+            Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+        })
+        .collect::<wasmtime::Result<_>>()?;
+    Ok((kind, elems))
+}
+
+/// Whether an attribute read's target is a record literal in the pool rather
+/// than an entity handle
+fn is_record_literal(target: i32) -> bool {
+    target as u32 >= memory::STRING_POOL_START
+}
+
+/// Name of attribute `attr`, for error messages
+fn attr_name(host: &Host, attr: i32) -> &str {
+    host.attributes
+        .get(attr as usize)
+        .map_or("?", String::as_str)
+}
+
+/// Value of field `attr` of the record literal at `ptr`, if it has one
+fn read_record_field(memory: &[u8], ptr: i32, attr: i32) -> wasmtime::Result<Option<i64>> {
+    let addr = ptr as u32 as usize;
+    let count = read_u32(memory, addr)? as usize;
+    for i in 0..count {
+        let at = addr + 4 + i * 16;
+        if read_u32(memory, at)? == attr as u32 {
+            let bytes = memory.get(at + 8..at + 16).ok_or_else(|| {
+                wasmtime::Error::msg(format!("record at {} is out of bounds", ptr))
+            })?;
+            return Ok(Some(i64::from_le_bytes(bytes.try_into().unwrap())));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether `value` is among `elems`, comparing strings by content
+fn contains(memory: &[u8], kind: u32, elems: &[i64], value: i64) -> wasmtime::Result<bool> {
+    if kind != ValueKind::String as u32 {
+        return Ok(elems.contains(&value));
+    }
+    let value = read_string(memory, value as i32)?;
+    for elem in elems {
+        if read_string(memory, *elem as i32)? == value {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// IP address or range, with an address being a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IpRange {
+    /// Address bits, right-aligned for IPv4
+    addr: u128,
+    prefix: u32,
+    v6: bool,
+}
+
+impl IpRange {
+    /// Parse `addr` or `addr/prefix`
+    fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse().ok()?)),
+            None => (s, None),
+        };
+        let (addr, v6) = match addr.parse().ok()? {
+            std::net::IpAddr::V4(addr) => (u32::from(addr) as u128, false),
+            std::net::IpAddr::V6(addr) => (u128::from(addr), true),
+        };
+        let width = if v6 { 128 } else { 32 };
+        let prefix = prefix.unwrap_or(width);
+        (prefix <= width).then_some(Self { addr, prefix, v6 })
+    }
+
+    /// Whether every address of `self` is in `range`
+    fn is_in(&self, range: &IpRange) -> bool {
+        let width = if self.v6 { 128 } else { 32 };
+        let network = |addr: u128| addr.checked_shr(width - range.prefix).unwrap_or(0);
+        self.v6 == range.v6
+            && self.prefix >= range.prefix
+            && network(self.addr) == network(range.addr)
+    }
+
+    fn is_loopback(&self) -> bool {
+        let range = if self.v6 { "::1" } else { "127.0.0.0/8" };
+        self.is_in(&IpRange::parse(range).unwrap())
+    }
+
+    fn is_multicast(&self) -> bool {
+        let range = if self.v6 { "ff00::/8" } else { "224.0.0.0/4" };
+        self.is_in(&IpRange::parse(range).unwrap())
+    }
+}
+
+/// IP value at `ptr`, which `ip` has already validated
+fn read_ip(memory: &[u8], ptr: i32) -> wasmtime::Result<IpRange> {
+    let text = read_string(memory, ptr)?;
+    IpRange::parse(text)
+        .ok_or_else(|| wasmtime::Error::msg(format!("invalid IP address {:?}", text)))
+}
+
+/// Match `s` against a `like` pattern: `*` matches any run of characters,
+/// `\*` a literal asterisk and `\\` a literal backslash
+fn like(s: &str, pattern: &str) -> bool {
+    // `None` is a wildcard
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '*' => None,
+            '\\' => Some(chars.next().unwrap_or('\\')),
+            c => Some(c),
+        });
+    }
+    let text: Vec<char> = s.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last wildcard and the text position it was tried at
+    let mut backtrack = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(None) => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(Some(c)) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[p..].iter().all(Option::is_none)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compiler;
+
+    #[test]
+    fn test_permit_all() {
+        let wasm = Compiler::new()
+            .compile_str("permit(principal, action, resource);")
+            .unwrap();
+        let mut evaluator = Evaluator::new(&wasm).unwrap();
+
+        assert_eq!(evaluator.evaluate(0, 0, 0, 0).unwrap(), Decision::Permit);
+    }
+
+    #[test]
+    fn test_policy_table() {
+        let wasm = Compiler::new()
+            .compile_policy_set_to_bytes(
+                r#"@id("admins")
+                permit(principal == User::"admin", action, resource);
+                forbid(principal, action, resource) when { resource.locked };"#,
+            )
+            .unwrap();
+        let evaluator = Evaluator::new(&wasm).unwrap();
+
+        assert_eq!(
+            evaluator.policies(),
+            [
+                ("admins".to_string(), PolicyEffect::Permit),
+                ("policy1".to_string(), PolicyEffect::Forbid)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forbid_unless_overrides_permit() {
+        let wasm = Compiler::new()
+            .compile_policy_set_to_bytes(
+                "permit(principal, action, resource);
+                forbid(principal, action, resource) unless { principal.admin };",
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        for (uid, admin) in [(r#"User::"alice""#, true), (r#"User::"bob""#, false)] {
+            entities.add_entity(uid, [("admin".to_string(), Value::Bool(admin))], []);
+        }
+        let mut evaluator = Evaluator::with_entities(&wasm, entities.clone()).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(evaluator.evaluate(bob, 0, 0, 0).unwrap(), Decision::Deny);
+
+        // On its own, the forbid applies in scope when its condition is false
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"forbid(principal is User, action, resource) unless { principal.admin };"#,
+            )
+            .unwrap();
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        let service = evaluator.handle(r#"Service::"backup""#);
+        assert_eq!(evaluator.evaluate(bob, 0, 0, 0).unwrap(), Decision::Deny);
+        for principal in [alice, service] {
+            assert_eq!(
+                evaluator.evaluate(principal, 0, 0, 0).unwrap(),
+                Decision::NoDecision
+            );
+        }
+    }
+
+    #[test]
+    fn test_module_data() {
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"forbid(principal is User, action, resource)
+                when { "report.pdf" like "*.pdf" && ["a", "b"].contains("b") };"#,
+            )
+            .unwrap();
+        let mut evaluator = Evaluator::new(&wasm).unwrap();
+
+        // Only entity literals are typed; handle 0 is not in the table
+        assert_eq!(
+            evaluator.evaluate(0, 1, 2, 0).unwrap(),
+            Decision::NoDecision
+        );
+
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"forbid(principal is User, action, resource)
+                when { principal in User::"alice" };"#,
+            )
+            .unwrap();
+        let mut evaluator = Evaluator::new(&wasm).unwrap();
+        assert_eq!(evaluator.evaluate(0, 1, 2, 0).unwrap(), Decision::Deny);
+        assert_eq!(
+            evaluator.evaluate(1, 1, 2, 0).unwrap(),
+            Decision::NoDecision
+        );
+    }
+
+    #[test]
+    fn test_memory64() {
+        let wasm = Compiler::with_options(crate::CompilerOptions {
+            memory64: true,
+            ..Default::default()
+        })
+        .compile_str(
+            r#"permit(principal, action, resource)
+            when { principal in User::"alice" && principal.name like "a*" };"#,
+        )
+        .unwrap();
+        let mut entities = EntityStore::new();
+        let name = Value::String("alice".to_string());
+        entities.add_entity(r#"User::"alice""#, [("name".to_string(), name)], []);
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        // The entity table and strings are read from the 64-bit memory
+        let alice = evaluator.handle(r#"User::"alice""#);
+        assert_eq!(alice, 0);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+    }
+
+    #[test]
+    fn test_missing_attribute_skips_policy() {
+        let wasm = Compiler::new()
+            .compile_str("permit(principal, action, resource) when { principal.level > 2 };")
+            .unwrap();
+        let mut evaluator = Evaluator::new(&wasm).unwrap();
+
+        assert_eq!(
+            evaluator.evaluate(0, 1, 2, 0).unwrap(),
+            Decision::NoDecision
+        );
+        assert_eq!(evaluator.errors(), ["? has no attribute level"]);
+
+        // The other policies of a set still apply
+        let policy_set = crate::ast::adapter::parse_policy_set(
+            r#"forbid(principal, action, resource) when { principal.level > 2 };
+            permit(principal, action, resource);"#,
+        )
+        .unwrap();
+        let wasm = Compiler::new().compile_policy_set(&policy_set).unwrap();
+        let mut evaluator = Evaluator::new(&wasm).unwrap();
+        let alice = evaluator.handle(r#"User::"alice""#);
+
+        assert_eq!(
+            evaluator.evaluate(alice, 1, 2, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.errors(),
+            [r#"User::"alice" has no attribute level"#]
+        );
+    }
+
+    #[test]
+    fn test_entity_store() {
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when { principal in Group::"admins" && principal.level > 2 };"#,
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        entities
+            .add_entity(
+                r#"User::"alice""#,
+                [("level".to_string(), Value::Long(3))],
+                [r#"Group::"admins""#.to_string()],
+            )
+            .add_entity(
+                r#"User::"bob""#,
+                [("level".to_string(), Value::Long(1))],
+                [r#"Group::"admins""#.to_string()],
+            )
+            .add_entity(
+                r#"User::"eve""#,
+                [("level".to_string(), Value::Long(5))],
+                [],
+            );
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        let eve = evaluator.handle(r#"User::"eve""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+        assert_eq!(
+            evaluator.evaluate(eve, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+    }
+
+    #[test]
+    fn test_context() {
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"permit(principal, action, resource) when { context.authenticated == true };"#,
+            )
+            .unwrap();
+        // The host defines the context records, here as attribute-only
+        // entities with handles past the module's entity table
+        let mut entities = EntityStore::new();
+        entities
+            .add_entity(
+                r#"Context::"signed_in""#,
+                [("authenticated".to_string(), Value::Bool(true))],
+                [],
+            )
+            .add_entity(
+                r#"Context::"anonymous""#,
+                [("authenticated".to_string(), Value::Bool(false))],
+                [],
+            );
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let signed_in = evaluator.handle(r#"Context::"signed_in""#);
+        let anonymous = evaluator.handle(r#"Context::"anonymous""#);
+        assert_eq!(
+            evaluator.evaluate(0, 0, 0, signed_in).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(0, 0, 0, anonymous).unwrap(),
+            Decision::NoDecision
+        );
+    }
+
+    #[test]
+    fn test_linked_template() {
+        let template = crate::ast::adapter::parse_template(
+            "permit(principal == ?principal, action, resource in ?resource);",
+        )
+        .unwrap();
+        let compiler = Compiler::new();
+        let linked = compiler.compile_template_linked(&template).unwrap();
+        let alice = linked
+            .instantiate(r#"User::"alice""#, r#"Folder::"shared""#)
+            .unwrap();
+        let bob = linked
+            .instantiate(r#"User::"bob""#, r#"Folder::"shared""#)
+            .unwrap();
+
+        let mut entities = EntityStore::new();
+        entities.add_entity(r#"Doc::"plan""#, [], [r#"Folder::"shared""#.to_string()]);
+        // Each module permits only the principal it was linked to
+        let links = [
+            (&alice, r#"User::"alice""#, r#"User::"bob""#),
+            (&bob, r#"User::"bob""#, r#"User::"alice""#),
+        ];
+        for (wasm, linked, other) in links {
+            let mut evaluator = Evaluator::with_entities(wasm, entities.clone()).unwrap();
+            let permitted = evaluator.handle(linked);
+            let other = evaluator.handle(other);
+            let plan = evaluator.handle(r#"Doc::"plan""#);
+            assert_eq!(
+                evaluator.evaluate(permitted, 0, plan, 0).unwrap(),
+                Decision::Permit
+            );
+            assert_eq!(
+                evaluator.evaluate(other, 0, plan, 0).unwrap(),
+                Decision::NoDecision
+            );
+        }
+    }
+
+    #[test]
+    fn test_datetime() {
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when { context.now < datetime("2024-01-01T00:00:00Z") };"#,
+            )
+            .unwrap();
+        // Datetimes are milliseconds since the epoch
+        let mut entities = EntityStore::new();
+        for (uid, now) in [("before", 1_704_067_199_999), ("after", 1_704_067_200_000)] {
+            let now = ("now".to_string(), Value::Long(now));
+            entities.add_entity(format!(r#"Context::"{}""#, uid), [now], []);
+        }
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let before = evaluator.handle(r#"Context::"before""#);
+        let after = evaluator.handle(r#"Context::"after""#);
+        assert_eq!(
+            evaluator.evaluate(0, 0, 0, before).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(0, 0, 0, after).unwrap(),
+            Decision::NoDecision
+        );
+    }
+
+    #[test]
+    fn test_json_requests() {
+        let options = crate::CompilerOptions {
+            abi: crate::Abi::JsonRequest,
+            ..crate::CompilerOptions::default()
+        };
+        let wasm = Compiler::with_options(options)
+            .compile_str(
+                r#"permit(principal == User::"alice", action, resource)
+                when { context.authenticated == true };"#,
+            )
+            .unwrap();
+        let mut evaluator = Evaluator::new(&wasm).unwrap();
+
+        let request = |principal: &str, authenticated: bool| {
+            serde_json::json!({
+                "principal": principal,
+                "action": r#"Action::"view""#,
+                "resource": r#"Photo::"vacation.jpg""#,
+                "context": { "authenticated": authenticated },
+            })
+            .to_string()
+        };
+        assert_eq!(
+            evaluator
+                .evaluate_json(&request(r#"User::"alice""#, true))
+                .unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator
+                .evaluate_json(&request(r#"User::"alice""#, false))
+                .unwrap(),
+            Decision::NoDecision
+        );
+        assert_eq!(
+            evaluator
+                .evaluate_json(&request(r#"User::"bob""#, true))
+                .unwrap(),
+            Decision::NoDecision
+        );
+        assert_eq!(
+            evaluator.evaluate_json(r#"{"principal": 1}"#).unwrap(),
+            Decision::Error
+        );
+        assert!(evaluator.evaluate(0, 0, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_initialize_loads_the_pool() {
+        let options = crate::CompilerOptions {
+            initialize: true,
+            ..crate::CompilerOptions::default()
+        };
+        let wasm = Compiler::with_options(options)
+            .compile_str(
+                r#"permit(principal in Group::"admins", action, resource)
+                when { principal.name == "alice" };"#,
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        for name in ["alice", "bob"] {
+            entities.add_entity(
+                format!("User::{:?}", name),
+                [("name".to_string(), Value::String(name.to_string()))],
+                [r#"Group::"admins""#.to_string()],
+            );
+        }
+        // The entity table and the literal are only in memory once the
+        // evaluator has called `_initialize`
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+    }
+
+    #[test]
+    fn test_disjunction_of_equalities() {
+        let source = r#"permit(principal, action, resource) when {
+            principal.role == "a" || principal.role == "b" || principal.role == "c"
+        };"#;
+        let schema = crate::Schema::from_cedarschema_str("entity User { role: String };").unwrap();
+        let mut entities = EntityStore::new();
+        for (uid, role) in [(r#"User::"alice""#, "b"), (r#"User::"bob""#, "x")] {
+            let role = Value::String(role.to_string());
+            entities.add_entity(uid, [("role".to_string(), role)], []);
+        }
+
+        // Collapsed into one membership check, with and without a schema
+        // typing the operand
+        for compiler in [
+            Compiler::new().with_opt_level(2),
+            Compiler::new().with_opt_level(2).with_schema(schema),
+        ] {
+            let wasm = compiler.compile_str(source).unwrap();
+            let mut evaluator = Evaluator::with_entities(&wasm, entities.clone()).unwrap();
+            let alice = evaluator.handle(r#"User::"alice""#);
+            let bob = evaluator.handle(r#"User::"bob""#);
+            assert_eq!(
+                evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+                Decision::Permit
+            );
+            assert_eq!(
+                evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+                Decision::NoDecision
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_equality() {
+        let decide = |condition: &str, roles: &[&str]| {
+            let source = format!(
+                "permit(principal, action, resource) when {{ {} }};",
+                condition
+            );
+            // Unoptimized, so literal sets are compared by the host too
+            let wasm = Compiler::new()
+                .with_opt_level(0)
+                .compile_str(&source)
+                .unwrap();
+            let roles = roles.iter().map(|r| Value::String(r.to_string())).collect();
+            let mut entities = EntityStore::new();
+            entities.add_entity(
+                r#"User::"alice""#,
+                [("roles".to_string(), Value::Set(roles))],
+                [],
+            );
+            let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+            let alice = evaluator.handle(r#"User::"alice""#);
+            evaluator.evaluate(alice, 0, 0, 0).unwrap()
+        };
+
+        assert_eq!(decide(r#"["a", "b"] == ["b", "a"]"#, &[]), Decision::Permit);
+        assert_eq!(
+            decide(r#"["a", "b"] != ["b", "a", "a"]"#, &[]),
+            Decision::NoDecision
+        );
+        assert_eq!(decide(r#"[1, 2] == [1]"#, &[]), Decision::NoDecision);
+        assert_eq!(decide(r#"[] == []"#, &[]), Decision::Permit);
+        assert_eq!(
+            decide(r#"principal.roles == ["b", "a"]"#, &["a", "b", "a"]),
+            Decision::Permit
+        );
+        assert_eq!(
+            decide(r#"principal.roles == ["a", "c"]"#, &["a", "b"]),
+            Decision::NoDecision
+        );
+        assert_eq!(decide(r#"principal.roles != []"#, &["a"]), Decision::Permit);
+        assert_eq!(decide(r#"["a"] == "a""#, &[]), Decision::NoDecision);
+    }
+
+    #[test]
+    fn test_short_circuit_suppresses_errors() {
+        let mut entities = EntityStore::new();
+        entities.add_entity(r#"User::"alice""#, [], []);
+        let evaluate = |policy: &str| {
+            let wasm = Compiler::new().compile_str(policy).unwrap();
+            let mut evaluator = Evaluator::with_entities(&wasm, entities.clone()).unwrap();
+            let alice = evaluator.handle(r#"User::"alice""#);
+            let decision = evaluator.evaluate(alice, 0, 0, 0).unwrap();
+            (decision, evaluator.errors().len())
+        };
+
+        // Reading `level` traps, but is never reached
+        assert_eq!(
+            evaluate(
+                r#"permit(principal, action, resource)
+                when { principal == User::"bob" && principal.level > 2 };"#
+            ),
+            (Decision::NoDecision, 0)
+        );
+        assert_eq!(
+            evaluate(
+                r#"permit(principal, action, resource)
+                when { principal == User::"alice" || principal.level > 2 };"#
+            ),
+            (Decision::Permit, 0)
+        );
+        assert_eq!(
+            evaluate(
+                r#"permit(principal == User::"bob", action, resource)
+                when { principal.level > 2 };"#
+            ),
+            (Decision::NoDecision, 0)
+        );
+        assert_eq!(
+            evaluate(
+                r#"permit(principal, action, resource)
+                when { principal == User::"alice" && principal.level > 2 };"#
+            ),
+            (Decision::NoDecision, 1)
+        );
+    }
+
+    #[test]
+    fn test_returned_values() {
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when {
+                    principal.name like "a*" &&
+                    principal.tags.contains("x") &&
+                    principal.manager == User::"carol" &&
+                    principal.active
+                };"#,
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        entities.add_entity(
+            r#"User::"alice""#,
+            [
+                ("name".to_string(), Value::String("alice".to_string())),
+                (
+                    "tags".to_string(),
+                    Value::Set(vec![
+                        Value::String("w".to_string()),
+                        Value::String("x".to_string()),
+                    ]),
+                ),
+                (
+                    "manager".to_string(),
+                    Value::Entity(r#"User::"carol""#.to_string()),
+                ),
+                ("active".to_string(), Value::Bool(true)),
+            ],
+            [],
+        );
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        // Values are written afresh for every request
+        for _ in 0..3 {
+            assert_eq!(
+                evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+                Decision::Permit
+            );
+        }
+    }
+
+    #[test]
+    fn test_schema_typed_attributes() {
+        let schema = crate::Schema::from_cedarschema_str(
+            r#"entity User {
+                name: String,
+                tags: Set<String>,
+                manager: User,
+                active: Bool,
+                level: Long,
+            };"#,
+        )
+        .unwrap();
+        let wasm = Compiler::new()
+            .with_schema(schema)
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when {
+                    principal.name == "alice" &&
+                    principal.tags.contains("x") &&
+                    principal.manager == User::"carol" &&
+                    principal.active &&
+                    principal.level > 2
+                };"#,
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        for (uid, level) in [(r#"User::"alice""#, 3), (r#"User::"bob""#, 1)] {
+            entities.add_entity(
+                uid,
+                [
+                    ("name".to_string(), Value::String("alice".to_string())),
+                    (
+                        "tags".to_string(),
+                        Value::Set(vec![Value::String("x".to_string())]),
+                    ),
+                    (
+                        "manager".to_string(),
+                        Value::Entity(r#"User::"carol""#.to_string()),
+                    ),
+                    ("active".to_string(), Value::Bool(true)),
+                    ("level".to_string(), Value::Long(level)),
+                ],
+                [],
+            );
+        }
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+    }
+
+    #[test]
+    fn test_negation() {
+        let mut entities = EntityStore::new();
+        for (uid, balance) in [
+            (r#"Account::"a""#, -5),
+            (r#"Account::"b""#, 5),
+            (r#"Account::"c""#, i64::MIN),
+        ] {
+            let balance = Value::Long(balance);
+            entities.add_entity(uid, [("balance".to_string(), balance)], []);
+        }
+        let decide = |policy: &str, uid: &str| {
+            let wasm = Compiler::new().compile_str(policy).unwrap();
+            let mut evaluator = Evaluator::with_entities(&wasm, entities.clone()).unwrap();
+            let resource = evaluator.handle(uid);
+            evaluator.evaluate(0, 0, resource, 0).unwrap()
+        };
+
+        // A negative literal
+        let literal = "permit(principal, action, resource) when { resource.balance == -5 };";
+        assert_eq!(decide(literal, r#"Account::"a""#), Decision::Permit);
+        assert_eq!(decide(literal, r#"Account::"b""#), Decision::NoDecision);
+
+        // Negating an attribute; the smallest long overflows, which skips
+        // the policy even though it is negated in an `unless`
+        let negated = "permit(principal, action, resource) unless { -resource.balance != 5 };";
+        assert_eq!(decide(negated, r#"Account::"a""#), Decision::Permit);
+        assert_eq!(decide(negated, r#"Account::"b""#), Decision::NoDecision);
+        assert_eq!(decide(negated, r#"Account::"c""#), Decision::NoDecision);
+    }
+
+    #[test]
+    fn test_action_scope() {
+        let mut entities = EntityStore::new();
+        entities.add_entity(r#"Action::"list""#, [], [r#"Action::"read""#.to_string()]);
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"permit(
+                    principal,
+                    action in [Action::"read", Action::"write", Action::"share"],
+                    resource
+                );"#,
+            )
+            .unwrap();
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let mut decide = |action: &str| {
+            let action = evaluator.handle(action);
+            evaluator.evaluate(0, action, 0, 0).unwrap()
+        };
+        assert_eq!(decide(r#"Action::"read""#), Decision::Permit);
+        assert_eq!(decide(r#"Action::"write""#), Decision::Permit);
+        assert_eq!(decide(r#"Action::"share""#), Decision::Permit);
+        // A member of an action group in the list
+        assert_eq!(decide(r#"Action::"list""#), Decision::Permit);
+        assert_eq!(decide(r#"Action::"delete""#), Decision::NoDecision);
+    }
+
+    #[test]
+    fn test_fuel_within_max_fuel() {
+        use crate::ast::adapter::parse_policy_set;
+        use crate::ast::lowering::LoweredModule;
+
+        let source = r#"
+            permit(principal, action, resource)
+            when { principal.role == "admin" || principal.role == "owner" };
+            permit(
+                principal in Group::"staff",
+                action in [Action::"read", Action::"list", Action::"share"],
+                resource
+            );
+            forbid(principal, action, resource) when { principal.level > 3 && principal.locked };
+        "#;
+        let max_fuel = LoweredModule::from_policy_set(&parse_policy_set(source).unwrap())
+            .unwrap()
+            .max_fuel();
+        let mut entities = EntityStore::new();
+        for (uid, role, level, locked) in [
+            (r#"User::"alice""#, "admin", 1, false),
+            (r#"User::"bob""#, "guest", 5, false),
+            (r#"User::"eve""#, "owner", 5, true),
+        ] {
+            let attrs = [
+                ("role".to_string(), Value::String(role.to_string())),
+                ("level".to_string(), Value::Long(level)),
+                ("locked".to_string(), Value::Bool(locked)),
+            ];
+            entities.add_entity(uid, attrs, []);
+        }
+        let wasm = Compiler::new()
+            .with_opt_level(0)
+            .with_error_codes(true)
+            .compile_policy_set_to_bytes(source)
+            .unwrap();
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let action = evaluator.handle(r#"Action::"edit""#);
+        let mut fuel = Vec::new();
+        for (user, decision) in [
+            (r#"User::"alice""#, Decision::Permit),
+            (r#"User::"bob""#, Decision::NoDecision),
+            (r#"User::"eve""#, Decision::Deny),
+        ] {
+            let principal = evaluator.handle(user);
+            assert_eq!(
+                evaluator.evaluate(principal, action, 0, 0).unwrap(),
+                decision
+            );
+            fuel.push(evaluator.fuel());
+        }
+        // Bob's request runs every policy to its end. The bound charges
+        // both arms of each branch and the batch-mode memo of every
+        // attribute read, but stays within a small factor of it.
+        let worst = *fuel.iter().max().unwrap();
+        assert!(worst <= max_fuel, "{} > {}", worst, max_fuel);
+        assert!(max_fuel < 3 * worst, "{} is far above {}", max_fuel, worst);
+    }
+
+    #[test]
+    fn test_in_set_of_parents() {
+        let mut entities = EntityStore::new();
+        entities
+            .add_entity(r#"User::"alice""#, [], [r#"Group::"admins""#.to_string()])
+            .add_entity(r#"User::"bob""#, [], [r#"Group::"ops""#.to_string()])
+            .add_entity(r#"User::"eve""#, [], [r#"Group::"guests""#.to_string()]);
+        for opt_level in [0, 2] {
+            let wasm = Compiler::new()
+                .with_opt_level(opt_level)
+                .compile_str(
+                    r#"permit(principal, action, resource)
+                    when { principal in [Group::"admins", Group::"ops"] };"#,
+                )
+                .unwrap();
+            let mut evaluator = Evaluator::with_entities(&wasm, entities.clone()).unwrap();
+
+            let mut decide = |principal: &str| {
+                let principal = evaluator.handle(principal);
+                evaluator.evaluate(principal, 0, 0, 0).unwrap()
+            };
+            assert_eq!(decide(r#"User::"alice""#), Decision::Permit);
+            assert_eq!(decide(r#"User::"bob""#), Decision::Permit);
+            assert_eq!(decide(r#"Group::"ops""#), Decision::Permit);
+            assert_eq!(decide(r#"User::"eve""#), Decision::NoDecision);
+        }
+    }
+
+    #[cfg(feature = "wasm-opt")]
+    #[test]
+    fn test_wasm_opt_keeps_decisions() {
+        use crate::CompilerOptions;
+
+        let source = r#"permit(principal == User::"alice", action, resource)
+            when { resource.size < 10 || resource.public };"#;
+        let mut entities = EntityStore::new();
+        entities.add_entity(
+            r#"Doc::"small""#,
+            [("size".to_string(), Value::Long(3))],
+            [],
+        );
+        let compile = |wasm_opt_level| {
+            Compiler::with_options(CompilerOptions {
+                wasm_opt_level,
+                ..CompilerOptions::default()
+            })
+            .compile_str(source)
+            .unwrap()
+        };
+        let (plain, optimized) = (compile(None), compile(Some(3)));
+        wasmparser::validate(&optimized).unwrap();
+        // `wasm-opt` ran rather than falling back
+        assert_ne!(plain, optimized);
+
+        for wasm in [plain, optimized] {
+            let mut evaluator = Evaluator::with_entities(&wasm, entities.clone()).unwrap();
+            let alice = evaluator.handle(r#"User::"alice""#);
+            let bob = evaluator.handle(r#"User::"bob""#);
+            let doc = evaluator.handle(r#"Doc::"small""#);
+            assert_eq!(
+                evaluator.evaluate(alice, 0, doc, 0).unwrap(),
+                Decision::Permit
+            );
+            assert_eq!(
+                evaluator.evaluate(bob, 0, doc, 0).unwrap(),
+                Decision::NoDecision
+            );
+        }
+    }
+
+    #[test]
+    fn test_schema_type_error() {
+        let schema =
+            crate::Schema::from_cedarschema_str("entity User { level: Long, name: String };")
+                .unwrap();
+        let source = r#"permit(principal, action, resource)
+            when { principal.level > 2 && principal.name < 3 };"#;
+        let wasm = Compiler::new()
+            .with_schema(schema.clone())
+            .with_runtime_type_errors(true)
+            .compile_str(source)
+            .unwrap();
+        let mut entities = EntityStore::new();
+        for (uid, level) in [(r#"User::"alice""#, 3), (r#"User::"bob""#, 1)] {
+            let level = Value::Long(level);
+            entities.add_entity(uid, [("level".to_string(), level)], []);
+        }
+        let mut evaluator = Evaluator::with_entities(&wasm, entities.clone()).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(evaluator.evaluate(alice, 0, 0, 0).unwrap(), Decision::Error);
+        // `&&` short-circuits before the type error
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+
+        // In a set the failing policy is skipped
+        let policy_set = crate::ast::adapter::parse_policy_set(&format!(
+            "{}\npermit(principal, action, resource);",
+            source
+        ))
+        .unwrap();
+        let wasm = Compiler::new()
+            .with_schema(schema)
+            .with_runtime_type_errors(true)
+            .compile_policy_set(&policy_set)
+            .unwrap();
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+        let alice = evaluator.handle(r#"User::"alice""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let schema =
+            crate::Schema::from_cedarschema_str("entity User { level: Long, name: String };")
+                .unwrap();
+        let compiler = Compiler::new()
+            .with_schema(schema)
+            .with_runtime_type_errors(true)
+            .with_error_codes(true);
+        let wasm = compiler
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when { principal.level > 2 && principal.name < 3 };"#,
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        for (uid, level) in [(r#"User::"alice""#, 3), (r#"User::"bob""#, 1)] {
+            let level = Value::Long(level);
+            entities.add_entity(uid, [("level".to_string(), level)], []);
+        }
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(evaluator.evaluate(alice, 0, 0, 0).unwrap(), Decision::Error);
+        assert_eq!(evaluator.error_code(), 1);
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+        assert_eq!(evaluator.error_code(), 0);
+
+        // Multi-value is not part of the MVP
+        assert!(Compiler::target_mvp()
+            .with_error_codes(true)
+            .compile_str("permit(principal, action, resource);")
+            .is_err());
+    }
+
+    #[test]
+    fn test_duplicated_operand_is_computed_once() {
+        use crate::ast::lowering::Instruction;
+
+        let source =
+            "permit(principal, action, resource) when { principal.level == principal.level };";
+        let compiler = Compiler::new().with_opt_level(2);
+        let ir = compiler
+            .optimize_ir(compiler.lower_str(source).unwrap())
+            .unwrap();
+        let count =
+            |wanted: fn(&Instruction) -> bool| ir.instructions().filter(|i| wanted(i)).count();
+        assert_eq!(count(|i| matches!(i, Instruction::GetAttribute(_))), 1);
+        assert_eq!(count(|i| matches!(i, Instruction::Dup)), 1);
+
+        let wasm = compiler.compile_str(source).unwrap();
+        let mut entities = EntityStore::new();
+        entities.add_entity(
+            r#"User::"alice""#,
+            [("level".to_string(), Value::Long(3))],
+            [],
+        );
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+    }
+
+    #[test]
+    fn test_cached_attribute_reads() {
+        let wasm = Compiler::new()
+            .with_opt_level(2)
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when { principal.role == "a" || principal.role == "b" };"#,
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        for (uid, role) in [(r#"User::"alice""#, "b"), (r#"User::"bob""#, "c")] {
+            let role = Value::String(role.to_string());
+            entities.add_entity(uid, [("role".to_string(), role)], []);
+        }
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+    }
+
+    #[test]
+    fn test_if_then_else() {
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when { (if principal has x then 1 else 2) == 1 };"#,
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        entities.add_entity(r#"User::"alice""#, [("x".to_string(), Value::Long(0))], []);
+        entities.add_entity(r#"User::"bob""#, [], []);
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+
+        // Only the branch taken is evaluated, so bob's missing role is no
+        // error, and the string literal is widened to the attribute's i64
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when { (if principal has role then principal.role else "guest") == "guest" };"#,
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        let role = Value::String("admin".to_string());
+        entities.add_entity(r#"User::"alice""#, [("role".to_string(), role)], []);
+        entities.add_entity(r#"User::"bob""#, [], []);
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+        assert_eq!(evaluator.evaluate(bob, 0, 0, 0).unwrap(), Decision::Permit);
+        assert!(evaluator.errors().is_empty());
+    }
+
+    #[test]
+    fn test_not_equal() {
+        let wasm = Compiler::new()
+            .compile_str(r#"permit(principal, action, resource) when { principal.team != "red" };"#)
+            .unwrap();
+        let mut entities = EntityStore::new();
+        for (uid, team) in [(r#"User::"alice""#, "blue"), (r#"User::"bob""#, "red")] {
+            let team = Value::String(team.to_string());
+            entities.add_entity(uid, [("team".to_string(), team)], []);
+        }
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+    }
+
+    #[test]
+    fn test_ip() {
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"permit(principal, action, resource)
+                when { principal.ip.isInRange(ip("10.0.0.0/8")) && !principal.ip.isLoopback() };"#,
+            )
+            .unwrap();
+        let mut entities = EntityStore::new();
+        for (uid, ip) in [
+            (r#"User::"alice""#, "10.1.2.3"),
+            (r#"User::"bob""#, "192.168.0.1"),
+        ] {
+            let ip = Value::String(ip.to_string());
+            entities.add_entity(uid, [("ip".to_string(), ip)], []);
+        }
+        let mut evaluator = Evaluator::with_entities(&wasm, entities).unwrap();
+
+        let alice = evaluator.handle(r#"User::"alice""#);
+        let bob = evaluator.handle(r#"User::"bob""#);
+        assert_eq!(
+            evaluator.evaluate(alice, 0, 0, 0).unwrap(),
+            Decision::Permit
+        );
+        assert_eq!(
+            evaluator.evaluate(bob, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+
+        let wasm = Compiler::new()
+            .compile_str(
+                r#"permit(principal, action, resource) when { ip("10.0.0.256").isIpv4() };"#,
+            )
+            .unwrap();
+        let mut evaluator = Evaluator::new(&wasm).unwrap();
+        assert_eq!(
+            evaluator.evaluate(0, 0, 0, 0).unwrap(),
+            Decision::NoDecision
+        );
+        assert_eq!(evaluator.errors(), [r#"invalid IP address "10.0.0.256""#]);
+    }
+
+    #[test]
+    fn test_record_literals() {
+        // Unoptimized, so the host reads the fields from the pool
+        let policy_set = crate::ast::adapter::parse_policy_set(
+            r#"permit(principal, action, resource) when { { level: 3, tag: "x" }.level > 2 && { level: 3 } has level };
+            permit(principal, action, resource) when { { level: 3 }.name == "x" };"#,
+        )
+        .unwrap();
+        let wasm = Compiler::new()
+            .with_opt_level(0)
+            .compile_policy_set(&policy_set)
+            .unwrap();
+        let mut evaluator = Evaluator::new(&wasm).unwrap();
+        assert_eq!(evaluator.evaluate(0, 0, 0, 0).unwrap(), Decision::Permit);
+        assert_eq!(evaluator.errors(), ["record has no attribute name"]);
+    }
+
+    #[test]
+    fn test_ip_ranges() {
+        let ip = |s: &str| IpRange::parse(s).unwrap();
+
+        assert!(ip("10.1.2.3").is_in(&ip("10.0.0.0/8")));
+        assert!(ip("10.1.0.0/16").is_in(&ip("10.0.0.0/8")));
+        assert!(!ip("10.0.0.0/8").is_in(&ip("10.1.0.0/16")));
+        assert!(!ip("11.0.0.1").is_in(&ip("10.0.0.0/8")));
+        assert!(ip("11.0.0.1").is_in(&ip("0.0.0.0/0")));
+        assert!(ip("::1").is_in(&ip("::/0")));
+        assert!(!ip("::ffff:10.0.0.1").is_in(&ip("10.0.0.0/8")));
+
+        assert!(ip("127.0.0.1").is_loopback());
+        assert!(ip("::1").is_loopback());
+        assert!(!ip("127.0.0.0/7").is_loopback());
+        assert!(ip("224.0.0.1").is_multicast());
+        assert!(ip("ff02::1").is_multicast());
+
+        assert_eq!(IpRange::parse("10.0.0.0/33"), None);
+        assert_eq!(IpRange::parse("example.com"), None);
+    }
+
+    #[test]
+    fn test_like() {
+        assert!(like("report.pdf", "*.pdf"));
+        assert!(like("a*b", "a\\*b"));
+        assert!(!like("axb", "a\\*b"));
+        assert!(like("a\\b", "a\\\\b"));
+        assert!(like("", "*"));
+        assert!(!like("abc", "a*d"));
+    }
+}
@@ -0,0 +1,378 @@
+//! Runtime support functions for compiled policies
+//!
+//! # Host ABI
+//!
+//! A compiled module exports `evaluate(principal: i32, action: i32,
+//! resource: i32, context: i32) -> i32`, where each entity is an index into
+//! the module's entity table, `context` points at the request context in
+//! linear memory (0 when there is none) and the result is a [`Decision`].
+//!
+//! Modules compiled with per-policy exports also export each policy of the
+//! set as `evaluate_<id>`, named by its `@id` annotation or else its policy
+//! ID, with the same signature; its result is that policy's decision alone.
+//!
+//! Every module lists its policies, in policy-set order, in the
+//! [`POLICIES_SECTION`](crate::wasm::codegen::POLICIES_SECTION) custom
+//! section: each policy's ID and effect, so a host evaluating policies one
+//! by one can report which policy denied a request.
+//!
+//! Modules that read entity data import the [`runtime_functions`] from the
+//! [`runtime_functions::MODULE`] namespace; the host implements them against
+//! its entity store.
+//!
+//! ## Templates
+//!
+//! Modules compiled from templates with slots take two more parameters,
+//! `evaluate(principal, action, resource, context, principal_slot,
+//! resource_slot)`: the entity handles bound to `?principal` and
+//! `?resource`. One module serves every link of its templates; a slot a
+//! template doesn't use is ignored. Such modules have no `evaluate_batch`.
+//!
+//! ## Entities
+//!
+//! Entities are i32 handles: indices into the module's entity table, which
+//! the compiler assigns in order of first appearance. Entity literals in
+//! policies are compiled to their index, and the host passes request
+//! entities and implements `entity_in` over the same indices. The module
+//! exports the table as immutable globals: `entity_table` is the address of
+//! `entity_count` u32 string pointers, the i-th holding UID i (`Type::"id"`).
+//! Both are absent when the policies reference no entity literals. UIDs
+//! missing from the table cannot match any literal; the host may number
+//! them from `entity_count` upwards.
+//!
+//! ## Values
+//!
+//! A string is a pointer to a little-endian u32 byte length followed by the
+//! UTF-8 bytes. Literals live in the string pool at
+//! [`memory::STRING_POOL_START`]; strings the host returns (attribute values,
+//! resolved secrets) must be written below it.
+//!
+//! `get_attribute` returns every value as an i64: longs as themselves,
+//! decimals scaled by 10^4 (`1.5` is 15000), booleans as 0 or 1, strings as
+//! a pointer and entities as a handle, both zero-extended.
+//!
+//! ## Sets
+//!
+//! A set is a pointer to a little-endian u32 element count, a u32
+//! [`ValueKind`] shared by all elements, then the elements as i64s in the
+//! `get_attribute` encoding. Set literals live in the string pool; sets the
+//! host returns from `get_attribute` must be written below it, like strings.
+//! `set_contains` receives the value in the same i64 encoding. Hosts compare
+//! string elements by content, not by pointer. `==` and `!=` on sets call
+//! `set_equal`, which ignores order and duplicates: two empty sets are equal
+//! whatever their element kinds, and otherwise sets of different kinds are
+//! not.
+//!
+//! ## Records
+//!
+//! `context` and record-valued attributes are i32 record pointers the host
+//! defines, and `get_attribute` and `has_attribute` accept them in place of
+//! an entity handle; `context.auth.level` reads `auth` from the context,
+//! then `level` from the result. Record literals live in the string pool: a
+//! little-endian u32 field count, then per field, in name order, a u32
+//! index into the attribute-name table, a u32 [`ValueKind`] and the value as
+//! an i64 in the `get_attribute` encoding. Records the host defines must
+//! stay below [`memory::STRING_POOL_START`], so a pointer at or above it is
+//! a literal, and entity handles must stay below it too.
+//!
+//! ## IP addresses
+//!
+//! IP addresses and ranges (`ip("10.0.0.0/8")`) are i32 values the host
+//! defines: `ip` turns a string into one, and `get_attribute` returns them
+//! zero-extended. The `ip_*` functions implement the methods of Cedar's
+//! `ipaddr` extension on them. An address is a range with a full-length
+//! prefix; `isLoopback` and `isMulticast` hold when the whole range is in
+//! `127.0.0.0/8` or `::1`, and `224.0.0.0/4` or `ff00::/8`, respectively.
+//!
+//! ## Redacted strings
+//!
+//! String literals in `@sensitive` policies, or matching the compiler's
+//! sensitive pattern, are not embedded in the module. The module calls
+//! `resolve_secret(id)` instead, and the host returns a pointer to the
+//! string; ids index the secret list the compiler reports alongside the
+//! module.
+//!
+//! ## Errors
+//!
+//! Modules export `error`, a mutable i32 global. A runtime function
+//! in [`runtime_functions::FALLIBLE`] reports an evaluation error, such as a
+//! missing attribute, by setting it to a nonzero value; its return value is
+//! then ignored. Integer overflow, such as negating the smallest long,
+//! sets it too. As in Cedar, a policy that errors is skipped: it yields
+//! [`Decision::NoDecision`] and the other policies still apply. After
+//! `evaluate` returns, `error` is nonzero if any policy errored. A policy
+//! the compiler knows to fail at some point, such as a comparison its
+//! schema shows to be mistyped when compiled
+//! [with runtime type errors](crate::Compiler::with_runtime_type_errors),
+//! sets `error` and returns [`Decision::Error`] when it gets there; a policy
+//! set skips it like any other. Traps are not
+//! caught and abort the whole evaluation. Modules built for the WASM MVP,
+//! which cannot export mutable globals, have no `error`; their hosts can
+//! only trap.
+//!
+//! ## Error codes
+//!
+//! Modules compiled with error codes return two values from `evaluate`:
+//! the decision and an error code, which is the value of `error` when the
+//! decision is [`Decision::Error`] and 0 otherwise, so hosts need not read
+//! the global. This takes the multi-value feature, so MVP modules cannot
+//! have error codes. Per-policy exports and `evaluate_batch` keep
+//! returning the decision alone.
+//!
+//! ## Fuel
+//!
+//! Compiled policies contain no loops: string comparison and entity
+//! hierarchy checks are single host calls, so the fuel `evaluate` consumes
+//! is bounded at compile time by
+//! [`LoweredModule::max_fuel`](crate::ast::lowering::LoweredModule::max_fuel),
+//! excluding time spent in the host. `evaluate_batch` costs at most that
+//! much per request. Coverage instrumentation is not included in the bound.
+//!
+//! ## Coverage
+//!
+//! Modules compiled with coverage instrumentation export `coverage`, the
+//! address of a bitmap, and `coverage_bits`, its length in bits. Every
+//! condition (`if`, the left operand of `&&` and `||`, and each policy's
+//! own condition) owns two consecutive bits, numbered in code order: the
+//! first is set when it was true, the second when it was false. Bit `n` is
+//! bit `n % 8` of byte `n / 8`. The bitmap accumulates across calls; the
+//! host clears it to start a new measurement.
+//!
+//! ## Batch evaluation
+//!
+//! Modules compiled with batch evaluation also export
+//! `evaluate_batch(requests: i32, count: i32, results: i32)`. `requests`
+//! points at `count` consecutive `(principal, action, resource, context)`
+//! tuples of little-endian i32s (16 bytes each) and `results` at room for `count` i32
+//! decisions. Within one batch, each attribute read remembers the last entity
+//! it fetched for, so requests sharing a principal fetch its attributes once.
+//! The memo is reset at the start of every `evaluate_batch` and `evaluate`
+//! call, so the host only has to keep entity data stable for one call.
+//!
+//! ## 64-bit memory
+//!
+//! Modules compiled with `memory64` declare a 64-bit linear memory, so
+//! `entity_table`, `coverage` and the `evaluate_batch` buffers are i64
+//! addresses. Values keep their 32-bit encoding: string, set and record
+//! pointers, including those in the entity table, are offsets into the first
+//! 4 GiB, where the string pool and the data the host writes below it live.
+//!
+//! ## Initialization
+//!
+//! Modules compiled with [`CompilerOptions::initialize`](crate::CompilerOptions::initialize)
+//! keep the string pool, entity table included, in a passive data segment
+//! and export `_initialize() -> ()`, which copies it into memory. The host
+//! calls it once after instantiation, as with a WASI reactor, and before
+//! anything reads the pool: `evaluate` and the `entity_table` it points to.
+//! Other modules load the pool when they are instantiated.
+//!
+//! ## JSON requests
+//!
+//! Modules compiled with [`Abi::JsonRequest`](crate::compiler::Abi::JsonRequest)
+//! export `evaluate(json: i32, len: i32) -> i32` instead, taking a request
+//! the host wrote to memory below [`memory::STRING_POOL_START`] as `len`
+//! bytes of UTF-8 JSON:
+//!
+//! ```text
+//! {
+//!   "principal": "User::\"alice\"",
+//!   "action": "Action::\"view\"",
+//!   "resource": "Photo::\"vacation.jpg\"",
+//!   "context": { "authenticated": true }
+//! }
+//! ```
+//!
+//! `principal`, `action` and `resource` are entity UIDs, and `context`, which
+//! may be left out, is the request context. The module passes the JSON to
+//! the host's `parse_request`, which turns the UIDs into entity handles and
+//! the context into a record pointer (0 for none) as `evaluate` would take
+//! them, and evaluates the policies on the result. A request the host can't
+//! parse sets `error`, where modules have it, and yields
+//! [`Decision::Error`]. Batch evaluation, error codes and templates with
+//! slots keep the handle-based `evaluate`.
+//!
+//! ## Evaluator
+//!
+//! With the `evaluator` feature, [`Evaluator`] runs
+//! compiled modules in-process with wasmtime, for testing policies. Its
+//! entity data comes from an [`EntityStore`], which other hosts can use to
+//! implement the attribute and hierarchy imports too.
+
+mod entity_store;
+#[cfg(feature = "evaluator")]
+mod evaluator;
+
+pub use entity_store::{EntityHandles, EntityStore, Value};
+
+#[cfg(feature = "evaluator")]
+pub use evaluator::{Evaluator, EvaluatorError};
+
+/// Runtime decision values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Decision {
+    /// Policy doesn't apply (scope doesn't match or condition is false)
+    NoDecision = -1,
+    /// Explicit deny
+    Deny = 0,
+    /// Explicit permit
+    Permit = 1,
+    /// Evaluation error
+    Error = 2,
+}
+
+impl Decision {
+    /// The decision an `evaluate` result encodes, or `None` if it encodes
+    /// none
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            -1 => Some(Decision::NoDecision),
+            0 => Some(Decision::Deny),
+            1 => Some(Decision::Permit),
+            2 => Some(Decision::Error),
+            _ => None,
+        }
+    }
+
+    /// The `evaluate` result encoding this decision
+    pub fn to_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+/// Type of the elements of a set, or of a record field, in linear memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ValueKind {
+    Bool = 0,
+    Long = 1,
+    /// String pointer
+    String = 2,
+    /// Entity handle
+    Entity = 3,
+}
+
+/// Runtime function indices
+/// These are helper functions that will be included in the WASM module
+pub mod runtime_functions {
+    /// Import module name for all runtime functions
+    pub const MODULE: &str = "cedar";
+
+    /// `string_eq(a: i32, b: i32) -> i32`
+    pub const STRING_EQ: u32 = 0;
+    /// `get_attribute(entity: i32, attr: i32) -> i64`, where `attr` indexes
+    /// the module's attribute-name table
+    pub const GET_ATTRIBUTE: u32 = 1;
+    /// `has_attribute(entity: i32, attr: i32) -> i32`
+    pub const HAS_ATTRIBUTE: u32 = 2;
+    /// `entity_in(child: i32, parent: i32) -> i32`
+    pub const ENTITY_IN: u32 = 3;
+    /// `resolve_secret(id: i32) -> i32`, returning a string pointer
+    pub const RESOLVE_SECRET: u32 = 4;
+    /// `string_like(s: i32, pattern: i32) -> i32`, matching a string against
+    /// a `like` pattern in the string pool: `*` matches any sequence of
+    /// characters, `\*` a literal asterisk and `\\` a literal backslash
+    pub const STRING_LIKE: u32 = 5;
+
+    /// `set_contains(set: i32, value: i64) -> i32`
+    pub const SET_CONTAINS: u32 = 6;
+    /// `set_contains_all(set: i32, other: i32) -> i32`, whether every
+    /// element of `other` is in `set`
+    pub const SET_CONTAINS_ALL: u32 = 7;
+    /// `set_contains_any(set: i32, other: i32) -> i32`, whether the sets
+    /// share an element
+    pub const SET_CONTAINS_ANY: u32 = 8;
+
+    /// `entity_is(entity: i32, type_name: i32) -> i32`, where `type_name`
+    /// points at the entity type name (e.g. `App::User`) in the string pool
+    pub const ENTITY_IS: u32 = 9;
+
+    /// `ip(s: i32) -> i32`, parsing the string at `s` as an IP address or
+    /// range; an invalid one is an error
+    pub const IP: u32 = 10;
+    /// `ip_is_in_range(ip: i32, range: i32) -> i32`
+    pub const IP_IS_IN_RANGE: u32 = 11;
+    /// `ip_is_ipv4(ip: i32) -> i32`
+    pub const IP_IS_IPV4: u32 = 12;
+    /// `ip_is_ipv6(ip: i32) -> i32`
+    pub const IP_IS_IPV6: u32 = 13;
+    /// `ip_is_loopback(ip: i32) -> i32`
+    pub const IP_IS_LOOPBACK: u32 = 14;
+    /// `ip_is_multicast(ip: i32) -> i32`
+    pub const IP_IS_MULTICAST: u32 = 15;
+
+    /// `set_equal(a: i32, b: i32) -> i32`, whether the sets have the same
+    /// elements, in any order and however often each appears
+    pub const SET_EQUAL: u32 = 16;
+
+    /// `parse_request(json: i32, len: i32, request: i32) -> i32`, parsing
+    /// the `len` bytes of JSON at `json` into the `(principal, action,
+    /// resource, context)` i32s it writes at `request`; returns 0 if they
+    /// aren't a valid request and 1 otherwise
+    pub const PARSE_REQUEST: u32 = 17;
+
+    /// Functions that may report an error through the `error` global
+    pub const FALLIBLE: [u32; 3] = [GET_ATTRIBUTE, RESOLVE_SECRET, IP];
+
+    /// Number of runtime functions; defined functions are indexed after them
+    pub const COUNT: u32 = 18;
+
+    /// Import names, indexed by the constants above
+    pub const NAMES: [&str; COUNT as usize] = [
+        "string_eq",
+        "get_attribute",
+        "has_attribute",
+        "entity_in",
+        "resolve_secret",
+        "string_like",
+        "set_contains",
+        "set_contains_all",
+        "set_contains_any",
+        "entity_is",
+        "ip",
+        "ip_is_in_range",
+        "ip_is_ipv4",
+        "ip_is_ipv6",
+        "ip_is_loopback",
+        "ip_is_multicast",
+        "set_equal",
+        "parse_request",
+    ];
+}
+
+/// Memory layout for the linear memory
+pub mod memory {
+    /// Initial memory size in WASM pages (64KB each); modules whose string
+    /// pool doesn't fit start with as many pages as it needs
+    pub const INITIAL_PAGES: u32 = 1;
+
+    /// Default maximum memory size in WASM pages; the compiler's
+    /// `max_memory_pages` option overrides it
+    pub const MAX_PAGES: u32 = 16;
+
+    /// String data starts at this offset; the bytes below it are free for
+    /// host-written buffers such as batch requests and results
+    pub const STRING_POOL_START: u32 = 0x1000;
+
+    /// Size of one request in an `evaluate_batch` buffer
+    pub const BATCH_REQUEST_SIZE: u32 = 16;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decision_round_trip() {
+        for decision in [
+            Decision::NoDecision,
+            Decision::Deny,
+            Decision::Permit,
+            Decision::Error,
+        ] {
+            assert_eq!(Decision::from_i32(decision.to_i32()), Some(decision));
+        }
+        assert_eq!(Decision::from_i32(3), None);
+        assert_eq!(Decision::from_i32(-2), None);
+    }
+}
@@ -1,9 +1,14 @@
 //! Cedar type to WebAssembly type mapping
 
+use alloc::vec;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
 use wasm_encoder::ValType;
 
 /// Maps Cedar types to WebAssembly types
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum WasmType {
     /// Boolean (i32 in WASM: 0 = false, 1 = true)
     Bool,
@@ -13,8 +18,29 @@ pub enum WasmType {
     String,
     /// Entity reference (i32 index into entity table)
     Entity,
+    /// Record (i32 pointer to host-provided data in linear memory)
+    Record,
+    /// Set (i32 pointer to a set in linear memory)
+    Set,
+    /// IP address or range (i32 value the host hands out)
+    Ip,
+}
+
+impl core::fmt::Display for WasmType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            WasmType::Bool => "bool",
+            WasmType::Int => "int",
+            WasmType::String => "string",
+            WasmType::Entity => "entity",
+            WasmType::Record => "record",
+            WasmType::Set => "set",
+            WasmType::Ip => "ip",
+        })
+    }
 }
 
+#[cfg(feature = "std")]
 impl WasmType {
     /// Convert to wasm-encoder ValType
     pub fn to_val_type(self) -> ValType {
@@ -23,6 +49,9 @@ impl WasmType {
             WasmType::Int => ValType::I64,
             WasmType::String => ValType::I32, // Pointer to string in linear memory
             WasmType::Entity => ValType::I32,  // Index into entity table
+            WasmType::Record => ValType::I32,  // Pointer into linear memory
+            WasmType::Set => ValType::I32,     // Pointer into linear memory
+            WasmType::Ip => ValType::I32,      // Host-defined
         }
     }
 }
@@ -40,12 +69,27 @@ impl FunctionSignature {
     }
 
     /// Create signature for the main policy evaluation function
-    /// Input: none (uses global state)
+    /// Input: principal, action, resource as i32 indices into the entity
+    /// table, then a pointer to the request context
     /// Output: i32 (-1 = no decision, 0 = deny, 1 = permit, 2 = error)
     pub fn policy_entry() -> Self {
         Self {
-            params: vec![],
+            params: vec![
+                WasmType::Entity,
+                WasmType::Entity,
+                WasmType::Entity,
+                WasmType::Record,
+            ],
             results: vec![WasmType::Bool], // Use Bool which maps to i32
         }
     }
+
+    /// Signature of the evaluation function of templates with slots: the
+    /// policy entry parameters, then the entities bound to `?principal` and
+    /// `?resource`
+    pub fn template_entry() -> Self {
+        let mut sig = Self::policy_entry();
+        sig.params.extend([WasmType::Entity, WasmType::Entity]);
+        sig
+    }
 }
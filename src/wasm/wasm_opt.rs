@@ -0,0 +1,174 @@
+//! Post-processing compiled modules with binaryen's `wasm-opt`
+//!
+//! Available with the `wasm-opt` feature, through the `wasm-opt` crate's
+//! bindings to binaryen. They only read and write modules as files, so each
+//! run goes through a directory of its own in the system's temporary
+//! directory, removed when it is done. If binaryen fails, or its output
+//! doesn't validate, the module is returned as it was, with a warning
+//! logged through `tracing`.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ::wasm_opt::{Feature, OptimizationOptions};
+use wasmparser::{Validator, WasmFeatures};
+
+/// Highest optimization level `wasm-opt` takes (`-O4`)
+pub const MAX_LEVEL: u8 = 4;
+
+/// Settings of one `wasm-opt` run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WasmOptSettings {
+    /// Optimization level, clamped to [`MAX_LEVEL`]
+    pub level: u8,
+    /// Keep to WASM MVP features
+    pub mvp_only: bool,
+    /// The module declares a 64-bit memory
+    pub memory64: bool,
+    /// Keep the `name` section
+    pub debug_names: bool,
+}
+
+/// Optimize a compiled module with `wasm-opt`, falling back to the module
+/// as it was if binaryen fails or its output doesn't validate
+pub fn optimize(wasm: &[u8], settings: WasmOptSettings) -> Vec<u8> {
+    match run(wasm, settings) {
+        Ok(optimized) => optimized,
+        Err(message) => {
+            tracing::warn!("skipping wasm-opt: {}", message);
+            wasm.to_vec()
+        }
+    }
+}
+
+/// Binaryen options for `settings`, enabling the features the code
+/// generator may use: MVP only, or multi-value for error codes, bulk memory
+/// for `_initialize`, exported mutable globals for the `error` global, and
+/// `memory64` when asked for
+fn options(settings: WasmOptSettings) -> OptimizationOptions {
+    let mut options = match settings.level.min(MAX_LEVEL) {
+        0 => OptimizationOptions::new_opt_level_0(),
+        1 => OptimizationOptions::new_opt_level_1(),
+        2 => OptimizationOptions::new_opt_level_2(),
+        3 => OptimizationOptions::new_opt_level_3(),
+        _ => OptimizationOptions::new_opt_level_4(),
+    };
+    if settings.mvp_only {
+        options.mvp_features_only();
+    } else {
+        options
+            .enable_feature(Feature::Multivalue)
+            .enable_feature(Feature::BulkMemory)
+            .enable_feature(Feature::MutableGlobals);
+        if settings.memory64 {
+            options.enable_feature(Feature::Memory64);
+        }
+    }
+    options.debug_info(settings.debug_names);
+    options
+}
+
+/// A directory for one run, unique across the threads and processes
+/// compiling at the same time
+fn scratch_dir() -> PathBuf {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "cedar-compile-wasm-opt-{}-{}",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+fn run(wasm: &[u8], settings: WasmOptSettings) -> Result<Vec<u8>, String> {
+    let dir = scratch_dir();
+    fs::create_dir(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let (input, output) = (dir.join("input.wasm"), dir.join("output.wasm"));
+    let optimized = fs::write(&input, wasm)
+        .map_err(|e| e.to_string())
+        .and_then(|()| {
+            options(settings)
+                .run(&input, &output)
+                .map_err(|e| e.to_string())
+        })
+        .and_then(|()| fs::read(&output).map_err(|e| e.to_string()));
+    // Whatever happened, nothing is left behind
+    let _ = fs::remove_dir_all(&dir);
+    let optimized = optimized?;
+
+    let mut features = if settings.mvp_only {
+        WasmFeatures::MVP
+    } else {
+        WasmFeatures::default()
+    };
+    features.set(WasmFeatures::MEMORY64, settings.memory64);
+    Validator::new_with_features(features)
+        .validate_all(&optimized)
+        .map_err(|e| format!("binaryen produced an invalid module: {}", e))?;
+    Ok(optimized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: WasmOptSettings = WasmOptSettings {
+        level: 2,
+        mvp_only: false,
+        memory64: false,
+        debug_names: false,
+    };
+
+    #[test]
+    fn test_failure_falls_back() {
+        let not_wasm = b"\0asm but not really".to_vec();
+
+        assert!(run(&not_wasm, SETTINGS).is_err());
+        assert_eq!(optimize(&not_wasm, SETTINGS), not_wasm);
+    }
+
+    #[test]
+    fn test_wasm_opt_accepts_every_codegen_feature() {
+        use crate::{Compiler, CompilerOptions};
+
+        let source = r#"permit(principal, action, resource) when { principal.name == "alice" };"#;
+
+        // Multi-value results and exported mutable globals
+        let wasm = Compiler::new()
+            .with_error_codes(true)
+            .compile_str(source)
+            .unwrap();
+        run(&wasm, SETTINGS).unwrap();
+        // Bulk memory
+        let wasm = Compiler::with_options(CompilerOptions {
+            initialize: true,
+            ..CompilerOptions::default()
+        })
+        .compile_str(source)
+        .unwrap();
+        run(&wasm, SETTINGS).unwrap();
+        let wasm = Compiler::with_options(CompilerOptions {
+            memory64: true,
+            ..CompilerOptions::default()
+        })
+        .compile_str(source)
+        .unwrap();
+        run(
+            &wasm,
+            WasmOptSettings {
+                memory64: true,
+                ..SETTINGS
+            },
+        )
+        .unwrap();
+        let wasm = Compiler::target_mvp().compile_str(source).unwrap();
+        run(
+            &wasm,
+            WasmOptSettings {
+                mvp_only: true,
+                ..SETTINGS
+            },
+        )
+        .unwrap();
+    }
+}
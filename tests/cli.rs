@@ -0,0 +1,155 @@
+//! End-to-end tests for the `cedar-compile` binary
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn test_stdin_to_stdout() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cedar-compile"))
+        .args(["-", "-o", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"permit(principal, action, resource);")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    // Only the module is written to stdout; progress goes to stderr
+    assert_eq!(&output.stdout[0..4], b"\0asm");
+    wasmparser::validate(&output.stdout).unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Compilation successful"));
+}
+
+#[test]
+fn test_parse_error_shows_location() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cedar-compile"))
+        .args(["-", "-o", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"permit(principal, action, resource)\nwhen { principal.x == };")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Parse error at line 2, column 23"),
+        "{}",
+        stderr
+    );
+    // The offending line is quoted with the parser's label
+    assert!(stderr.contains("when { principal.x == };"), "{}", stderr);
+}
+
+#[test]
+fn test_validate_creates_no_output() {
+    let dir = std::env::temp_dir().join(format!("cedar-compile-validate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("valid.cedar"),
+        "permit(principal, action, resource) when { principal.level > 2 };",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("unsupported.cedar"),
+        "permit(principal, action, resource) when { principal.level * 2 > 2 };",
+    )
+    .unwrap();
+    let validate = |policy: &str| {
+        Command::new(env!("CARGO_BIN_EXE_cedar-compile"))
+            .args([policy, "--validate"])
+            .current_dir(&dir)
+            .output()
+            .unwrap()
+    };
+
+    let output = validate("valid.cedar");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Validation successful"));
+
+    let output = validate("unsupported.cedar");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unsupported multiplication"));
+
+    assert!(!dir.join("output.wasm").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dump_ir() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cedar-compile"))
+        .args(["-", "--validate", "--dump-ir"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"permit(principal, action, resource) when { principal.level > 2 };")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in [
+        "policy0 (permit):",
+        "  load_var principal",
+        r#"  get_attr "level""#,
+        "  push_int 2",
+        "  gt",
+    ] {
+        assert!(stderr.lines().any(|l| l == line), "{}", stderr);
+    }
+    assert!(stderr.contains("Validation successful"));
+}
+
+#[test]
+fn test_directory_input() {
+    let dir = std::env::temp_dir().join(format!("cedar-compile-dir-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("permit.cedar"),
+        "permit(principal, action, resource);",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("forbid.cedar"),
+        r#"forbid(principal == User::"banned", action, resource);"#,
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cedar-compile"))
+        .arg(&dir)
+        .args(["-o", "-", "--emit", "wat"])
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success());
+    // Both files' policies are listed in the one module
+    let wat = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        wat.contains("policy0") && wat.contains("policy1"),
+        "{}",
+        wat
+    );
+}
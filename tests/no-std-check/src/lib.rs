@@ -0,0 +1,17 @@
+//! Uses the IR and the optimization passes from a `no_std` crate. It defines
+//! the panic handler, which `std` also defines, so it only builds if nothing
+//! it depends on links `std`.
+
+#![no_std]
+
+use cedar_policy_compiler::ast::lowering::LoweredModule;
+use cedar_policy_compiler::optimization::PassManager;
+
+pub fn optimize(module: LoweredModule) -> LoweredModule {
+    PassManager::for_opt_level(2).run(module)
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,29 @@
+//! Build check of the crate without its `std` feature, where only the IR and
+//! the optimization passes are compiled, as a `no_std` library
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn test_core_modules_build_without_std() {
+    // tests/no-std-check defines its own panic handler, so linking `std`
+    // anywhere in its dependencies fails the build with a duplicate
+    // `panic_impl` lang item, even on a target that has `std`. It gets a
+    // target directory of its own, so the build doesn't wait on the lock of
+    // the one running this test.
+    let check = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/no-std-check");
+    let output = Command::new(env!("CARGO"))
+        .arg("build")
+        .arg("--manifest-path")
+        .arg(check.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(Path::new(env!("CARGO_TARGET_TMPDIR")).join("no-std-check"))
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}